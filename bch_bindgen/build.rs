@@ -56,6 +56,7 @@ fn main() {
         .allowlist_function("bcache_fs_open")
         .allowlist_function("bcache_fs_close")
         .allowlist_function("bio_.*")
+        .allowlist_function("blkdev_region_.*")
         .allowlist_function("derive_passphrase")
         .allowlist_function("request_key")
         .allowlist_function("add_key")
@@ -72,13 +73,21 @@ fn main() {
         .allowlist_var("__bch2.*")
         .allowlist_var("__BTREE_ITER.*")
         .allowlist_var("BTREE_ITER.*")
+        .allowlist_var("BTREE_TRANS_MEM_MAX")
+        .allowlist_var("KEY_TYPE_XATTR_INDEX_.*")
         .blocklist_item("bch2_bkey_ops")
         .allowlist_type("bch_.*")
         .allowlist_type("fsck_err_opts")
         .rustified_enum("fsck_err_opts")
         .allowlist_type("nonce")
+        .allowlist_type("jset.*")
+        .allowlist_type("logged_op_finsert_state")
+        .allowlist_type("quota_types")
+        .allowlist_type("quota_counters")
         .no_debug("bch_replicas_padded")
         .newtype_enum("bch_kdf_types")
+        .newtype_enum("btree_update_flags")
+        .newtype_enum("bkey_invalid_flags")
         .rustified_enum("bch_key_types")
         .opaque_type("gendisk")
         .opaque_type("gc_stripe")
@@ -0,0 +1,223 @@
+//! Btree iteration for async code, with the `tokio` feature
+//!
+//! This doesn't make the C layer async: btree lookups still block. An
+//! [`AsyncFs`] opens the filesystem on a dedicated thread, which owns it and
+//! runs every C call, so the runtime's worker threads never block on btree
+//! IO or locks and the filesystem never changes threads. Keys are handed back
+//! as [`BkeyOwned`], since borrowed keys can't outlive the transaction's
+//! locks.
+//!
+//! The filesystem thread serves one request at a time, for all iterators
+//! and streams, and drops its btree locks between requests - so a slow
+//! consumer doesn't hold up other btree users, but keys from different
+//! requests aren't from a single consistent view of the btree.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::task::{Context, Poll};
+use std::thread;
+use futures_core::Stream;
+use tokio::sync::oneshot;
+use crate::c;
+use crate::bkey::BkeyOwned;
+use crate::btree::{BtreeIter, BtreeIterFlags, BtreeTrans};
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::PosRange;
+
+/* Keys fetched per request by a KeyStream: */
+const SCAN_BATCH: usize = 64;
+
+/*
+ * Keys from one request, then where the next request should start: None at
+ * the end of the range, or the error that stopped this one
+ */
+type Batch = (Vec<BkeyOwned>, Result<Option<c::bpos>, bch_errcode>);
+
+struct PeekCmd {
+    btree:  c::btree_id,
+    range:  PosRange,
+    flags:  BtreeIterFlags,
+    nr:     usize,
+    reply:  oneshot::Sender<Batch>,
+}
+
+fn peek_batch(trans: &BtreeTrans, cmd: &PeekCmd) -> Batch {
+    let mut iter = BtreeIter::new_range(trans, cmd.btree, cmd.range, cmd.flags);
+    let mut keys = Vec::new();
+
+    while keys.len() < cmd.nr {
+        match iter.peek_and_restart() {
+            Ok(Some(k)) => keys.push(k.to_owned()),
+            Ok(None)    => return (keys, Ok(None)),
+            Err(e)      => return (keys, Err(e)),
+        }
+        iter.advance();
+    }
+    (keys, Ok(Some(iter.pos())))
+}
+
+fn fs_thread(fs: Fs, cmds: mpsc::Receiver<PeekCmd>) {
+    let trans = BtreeTrans::new(&fs);
+
+    for cmd in cmds {
+        let ret = peek_batch(&trans, &cmd);
+
+        /* don't hold btree locks while waiting for the next request: */
+        trans.begin_shared();
+        let _ = cmd.reply.send(ret);
+    }
+}
+
+/// A filesystem opened on, and owned by, a dedicated thread that runs btree
+/// iteration for async code
+///
+/// The filesystem is stopped once this, its clones, and every iterator and
+/// stream from it have been dropped.
+#[derive(Clone)]
+pub struct AsyncFs {
+    cmds:   mpsc::Sender<PeekCmd>,
+}
+
+impl AsyncFs {
+    /// Open a filesystem, as with [`Fs::open`], on a new thread
+    pub async fn open(devs: Vec<PathBuf>, opts: c::bch_opts) -> Result<AsyncFs, bch_errcode> {
+        let (opened_tx, opened_rx) = oneshot::channel();
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            match Fs::open(&devs, opts) {
+                Ok(fs) => {
+                    let _ = opened_tx.send(Ok(()));
+                    fs_thread(fs, rx);
+                }
+                Err(e) => {
+                    let _ = opened_tx.send(Err(e));
+                }
+            }
+        });
+
+        opened_rx.await.expect("filesystem thread exited")?;
+        Ok(AsyncFs { cmds: tx })
+    }
+
+    fn peek(&self, btree: c::btree_id, range: PosRange, flags: BtreeIterFlags, nr: usize) -> oneshot::Receiver<Batch> {
+        let (tx, rx) = oneshot::channel();
+
+        self.cmds.send(PeekCmd { btree, range, flags, nr, reply: tx })
+            .expect("filesystem thread exited");
+        rx
+    }
+
+    /// An iterator over the keys in `range` of a btree
+    pub fn iter(&self, btree: c::btree_id, range: PosRange, flags: BtreeIterFlags) -> AsyncBtreeIter {
+        AsyncBtreeIter { fs: self.clone(), btree, range, flags, next: None }
+    }
+
+    /// Scan the keys in `range` of a btree as a [`Stream`]
+    ///
+    /// Keys are fetched in batches, so the scan runs ahead of the consumer by
+    /// a bounded number of keys; see the [module docs](crate::async_iter) for
+    /// consistency.
+    pub fn scan(&self, btree: c::btree_id, range: PosRange, flags: BtreeIterFlags) -> KeyStream {
+        KeyStream {
+            fs:         self.clone(),
+            btree,
+            range,
+            flags,
+            keys:       VecDeque::new(),
+            pending:    None,
+            err:        None,
+            done:       false,
+        }
+    }
+}
+
+/// A btree iterator whose `peek` and `advance` run on the filesystem thread,
+/// returning futures; from [`AsyncFs::iter`]
+pub struct AsyncBtreeIter {
+    fs:     AsyncFs,
+    btree:  c::btree_id,
+    range:  PosRange,
+    flags:  BtreeIterFlags,
+    /* the position after the key last returned by peek: */
+    next:   Option<c::bpos>,
+}
+
+impl AsyncBtreeIter {
+    /// The next key in the range, or None at the end; doesn't move the
+    /// iterator
+    pub async fn peek(&mut self) -> Result<Option<BkeyOwned>, bch_errcode> {
+        let (mut keys, next) = self.fs.peek(self.btree, self.range, self.flags, 1).await
+            .expect("filesystem thread exited");
+
+        self.next = next?;
+        Ok(keys.pop())
+    }
+
+    /// Move past the key last returned by `peek`
+    pub async fn advance(&mut self) {
+        if self.next.is_none() {
+            let _ = self.peek().await;
+        }
+
+        if let Some(next) = self.next.take() {
+            self.range.start = next;
+        }
+    }
+}
+
+/// A [`Stream`] of the keys in a range, from [`AsyncFs::scan`]
+///
+/// The scan stops at the first error, which is the last item; dropping the
+/// stream stops the scan.
+pub struct KeyStream {
+    fs:         AsyncFs,
+    btree:      c::btree_id,
+    range:      PosRange,
+    flags:      BtreeIterFlags,
+    keys:       VecDeque<BkeyOwned>,
+    pending:    Option<oneshot::Receiver<Batch>>,
+    err:        Option<bch_errcode>,
+    done:       bool,
+}
+
+impl Stream for KeyStream {
+    type Item = Result<BkeyOwned, bch_errcode>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let s = &mut *self;
+
+        loop {
+            if let Some(k) = s.keys.pop_front() {
+                return Poll::Ready(Some(Ok(k)));
+            }
+            if let Some(e) = s.err.take() {
+                return Poll::Ready(Some(Err(e)));
+            }
+            if s.done {
+                return Poll::Ready(None);
+            }
+
+            let pending = s.pending.get_or_insert_with(||
+                s.fs.peek(s.btree, s.range, s.flags, SCAN_BATCH));
+
+            let (keys, next) = match Pin::new(pending).poll(cx) {
+                Poll::Pending   => return Poll::Pending,
+                Poll::Ready(r)  => r.expect("filesystem thread exited"),
+            };
+
+            s.pending   = None;
+            s.keys      = keys.into();
+
+            match next {
+                Ok(Some(next))  => s.range.start = next,
+                Ok(None)        => s.done = true,
+                Err(e)          => { s.err = Some(e); s.done = true; }
+            }
+        }
+    }
+}
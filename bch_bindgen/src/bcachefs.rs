@@ -59,6 +59,21 @@ impl bch_sb {
         uuid::Uuid::from_bytes(self.user_uuid.b)
     }
 
+    /// Get a superblock section by type, along with its size in bytes
+    /// (including the bch_sb_field header)
+    ///
+    /// Safety: T must be the struct corresponding to `ty`, beginning with a
+    /// `struct bch_sb_field field` member
+    pub unsafe fn field_get<T>(&self, ty: bch_sb_field_type) -> Option<(&T, usize)> {
+        let f = bch2_sb_field_get_id(self as *const _ as *mut _, ty);
+        if f.is_null() {
+            None
+        } else {
+            let bytes = u32::from_le((*f).u64s) as usize * 8;
+            Some((&*(f as *const T), bytes))
+        }
+    }
+
     /// Get the nonce used to encrypt the superblock
     pub fn nonce(&self) -> nonce {
         use byteorder::{LittleEndian, ReadBytesExt};
@@ -80,6 +95,48 @@ impl bch_sb_handle {
     }
 }
 
+// bch_ioctl_data* are blocklisted from bindgen (packed structs containing a
+// packed bpos); these are laid out by hand to match bcachefs_ioctl.h
+#[repr(C, align(8))]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bch_ioctl_data_migrate {
+    pub dev: u32,
+    pub pad: u32,
+}
+
+#[repr(C, align(8))]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bch_ioctl_data {
+    pub op: u16,
+    pub start_btree: u8,
+    pub end_btree: u8,
+    pub flags: u32,
+    pub start_pos: bpos,
+    pub end_pos: bpos,
+    pub migrate: bch_ioctl_data_migrate,
+    pub pad: [u64; 7],
+}
+
+#[repr(C, align(8))]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bch_ioctl_data_progress {
+    pub data_type: u8,
+    pub btree_id: u8,
+    pub pad: [u8; 2],
+    pub pos: bpos,
+    pub sectors_done: u64,
+    pub sectors_total: u64,
+}
+
+#[repr(C, align(8))]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bch_ioctl_data_event {
+    pub type_: u8,
+    pub pad: [u8; 7],
+    pub p: bch_ioctl_data_progress,
+    pub pad2: [u64; 10],
+}
+
 // #[repr(u8)]
 pub enum rhash_lock_head {}
 pub enum srcu_struct {}
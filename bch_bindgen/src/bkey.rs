@@ -3,10 +3,17 @@
 use crate::c;
 use crate::fs::Fs;
 use crate::btree::BtreeIter;
+use crate::errcode::bch_errcode;
 use crate::printbuf_to_formatter;
+use bitflags::bitflags;
+use std::cmp::Ordering;
+use std::ffi::CStr;
 use std::fmt;
 use std::marker::PhantomData;
-use std::mem::transmute;
+use std::mem::{align_of, size_of, transmute};
+
+/* from bcachefs_format.h: */
+const KEY_FORMAT_CURRENT: u8 = 1;
 
 pub struct BkeySC<'a> {
     pub k:              &'a c::bkey,
@@ -51,11 +58,66 @@ pub enum BkeyValC<'a> {
     logged_op_finsert(&'a c::bch_logged_op_finsert),
 }
 
+/* Stands in for the value of keys C returns without one (holes): */
+static EMPTY_VAL: u64 = 0;
+
 impl<'a, 'b> BkeySC<'a> {
-    unsafe fn to_raw(&self) -> c::bkey_s_c {
+    /// Wrap a `bkey_s_c` from C: None if there's no key
+    ///
+    /// Holes returned for empty slots (extents, and `SLOTS` iteration) have
+    /// a null value pointer; they get an empty value, which is all a
+    /// `KEY_TYPE_deleted` key has anyways.
+    pub(crate) unsafe fn from_raw(k: c::bkey_s_c) -> Option<BkeySC<'a>> {
+        if k.k.is_null() {
+            return None;
+        }
+
+        let v = if !k.v.is_null() {
+            &*k.v
+        } else {
+            &*(&EMPTY_VAL as *const u64 as *const c::bch_val)
+        };
+
+        Some(BkeySC { k: &*k.k, v, iter: PhantomData })
+    }
+
+    pub(crate) unsafe fn to_raw(&self) -> c::bkey_s_c {
         c::bkey_s_c { k: self.k, v: self.v }
     }
 
+    /// Copy the key and value out of the btree, so it can outlive the
+    /// iterator
+    pub fn to_owned(&self) -> BkeyOwned {
+        let u64s = self.k.u64s as usize;
+        let mut buf = vec![0u64; u64s];
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.k as *const c::bkey as *const u64,
+                buf.as_mut_ptr(), size_of::<c::bkey>() / 8);
+            std::ptr::copy_nonoverlapping(self.v as *const c::bch_val as *const u64,
+                buf.as_mut_ptr().add(size_of::<c::bkey>() / 8),
+                u64s - size_of::<c::bkey>() / 8);
+        }
+
+        BkeyOwned { buf }
+    }
+
+    /// A deleted key (`KEY_TYPE_deleted`): these are normally skipped, and
+    /// only seen with [`BtreeIter::include_deleted`]
+    pub fn is_deleted(&self) -> bool {
+        self.k.type_ == c::bch_bkey_type::KEY_TYPE_deleted as u8
+    }
+
+    /// A snapshot whiteout (`KEY_TYPE_whiteout`), hiding the key at the same
+    /// position in ancestor snapshots; seen when iterating with
+    /// `ALL_SNAPSHOTS`
+    ///
+    /// Distinct from deleted keys, and from the hash whiteouts left in hashed
+    /// btrees (dirents, xattrs) to keep probe chains intact.
+    pub fn is_whiteout(&self) -> bool {
+        self.k.type_ == c::bch_bkey_type::KEY_TYPE_whiteout as u8
+    }
+
     pub fn to_text(&'a self, fs: &'b Fs) -> BkeySCToText<'a, 'b> {
         BkeySCToText { k: self, fs }
     }
@@ -105,6 +167,103 @@ impl<'a, 'b> BkeySC<'a> {
     }
 }
 
+bitflags! {
+    /// Context a key is checked in, see [`BkeySC::validate`]
+    pub struct BkeyValidateFlags: u32 {
+        /// The key is about to be written out in a btree node
+        const WRITE = c::bkey_invalid_flags::BKEY_INVALID_WRITE.0;
+        /// The key is being inserted by a transaction commit: also checks
+        /// that the key type is allowed in the btree
+        const COMMIT = c::bkey_invalid_flags::BKEY_INVALID_COMMIT.0;
+        /// The key was read from the journal
+        const JOURNAL = c::bkey_invalid_flags::BKEY_INVALID_JOURNAL.0;
+    }
+}
+
+impl<'a> BkeySC<'a> {
+    fn invalid(&self, fs: &Fs, btree: c::btree_id, level: u32, flags: BkeyValidateFlags) -> Result<(), (bch_errcode, String)> {
+        /* enum btree_node_type: interior nodes are all BKEY_TYPE_btree */
+        let node_type = if level > 0 { 0 } else { btree as u32 + 1 };
+        let mut buf = c::printbuf::new();
+
+        let ret = unsafe {
+            c::bch2_bkey_invalid_nocount(fs.raw, self.k, self.v,
+                node_type, flags.bits, &mut buf)
+        };
+
+        if ret == 0 {
+            return Ok(());
+        }
+
+        let msg = if buf.buf.is_null() {
+            String::new()
+        } else {
+            unsafe { CStr::from_ptr(buf.buf) }.to_string_lossy().into_owned()
+        };
+        Err((bch_errcode::from_ret(ret), msg))
+    }
+
+    /// Check a key against the on disk format rules for a key in `btree` at
+    /// `level` (0 == leaf), without inserting it
+    ///
+    /// Returns `BCH_ERR_invalid_bkey` if the key is malformed; use
+    /// [`BkeySC::invalid_reason`] for a description of what's wrong. Unlike
+    /// invalid keys found at runtime, failures aren't counted in the
+    /// superblock's error counters.
+    pub fn validate(&self, fs: &Fs, btree: c::btree_id, level: u32, flags: BkeyValidateFlags) -> Result<(), bch_errcode> {
+        self.invalid(fs, btree, level, flags).map_err(|(err, _)| err)
+    }
+
+    /// Like [`BkeySC::validate`], but returns the validation error message,
+    /// or `None` if the key is valid
+    pub fn invalid_reason(&self, fs: &Fs, btree: c::btree_id, level: u32, flags: BkeyValidateFlags) -> Option<String> {
+        self.invalid(fs, btree, level, flags).err().map(|(_, msg)| msg)
+    }
+}
+
+impl<'a> BkeySC<'a> {
+    /// Borrow a key from a raw buffer laid out as a `bkey_i` - e.g. captured
+    /// from an export or a journal entry - without a live iterator
+    ///
+    /// The buffer must be 8 byte aligned and hold at least the key's declared
+    /// size; bytes past that are ignored. Packed keys, as found in btree
+    /// nodes, can't be used. Any of these fail with `BCH_ERR_invalid_bkey`:
+    /// only the layout is checked, use [`BkeySC::validate`] to check the
+    /// value.
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<BkeySC<'a>, bch_errcode> {
+        if bytes.as_ptr() as usize % align_of::<u64>() != 0 {
+            return Err(bch_errcode::BCH_ERR_invalid_bkey);
+        }
+        if bytes.len() < size_of::<c::bkey>() {
+            return Err(bch_errcode::BCH_ERR_invalid_bkey);
+        }
+
+        let k = unsafe { &*(bytes.as_ptr() as *const c::bkey) };
+        let u64s = k.u64s as usize;
+
+        if k.format() != KEY_FORMAT_CURRENT {
+            return Err(bch_errcode::BCH_ERR_invalid_bkey);
+        }
+        if u64s < size_of::<c::bkey>() / 8 || u64s * 8 > bytes.len() {
+            return Err(bch_errcode::BCH_ERR_invalid_bkey);
+        }
+
+        Ok(BkeySC {
+            k,
+            v:      unsafe { &*(bytes.as_ptr().add(size_of::<c::bkey>()) as *const c::bch_val) },
+            iter:   PhantomData,
+        })
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for BkeySC<'a> {
+    type Error = bch_errcode;
+
+    fn try_from(bytes: &'a [u8]) -> Result<BkeySC<'a>, bch_errcode> {
+        BkeySC::from_bytes(bytes)
+    }
+}
+
 impl<'a> From<&'a c::bkey_i> for BkeySC<'a> {
     fn from(k: &'a c::bkey_i) -> Self {
         BkeySC { k: &k.k, v: &k.v, iter: PhantomData }
@@ -123,3 +282,212 @@ impl<'a, 'b> fmt::Display for BkeySCToText<'a, 'b> {
         }
     }
 }
+
+impl<'a> BkeySC<'a> {
+    /// The key header and value, in u64s
+    fn u64s(&self) -> (&[u64], &[u64]) {
+        let header = size_of::<c::bkey>() / 8;
+
+        unsafe {
+            (std::slice::from_raw_parts(self.k as *const c::bkey as *const u64, header),
+             std::slice::from_raw_parts(self.v as *const c::bch_val as *const u64,
+                                        self.k.u64s as usize - header))
+        }
+    }
+}
+
+/// Keys are equal if their headers and values are identical
+impl<'a> PartialEq for BkeySC<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.u64s() == other.u64s()
+    }
+}
+
+impl<'a> Eq for BkeySC<'a> {}
+
+/// Keys are ordered by position, as with bkey_cmp(); keys at the same
+/// position (e.g. from two different scans) are ordered by their raw
+/// contents, so that ordering stays consistent with equality
+impl<'a> Ord for BkeySC<'a> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (l, r) = (self.k.p, other.k.p);
+
+        l.cmp(&r)
+            .then_with(|| self.u64s().cmp(&other.u64s()))
+    }
+}
+
+impl<'a> PartialOrd for BkeySC<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// An owned copy of a key, laid out exactly as a `bkey_i`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BkeyOwned {
+    buf:    Vec<u64>,
+}
+
+impl BkeyOwned {
+    /// From a raw key, as returned by [`BkeyOwned::as_u64s`]; `None` if the
+    /// key's size field doesn't match
+    pub(crate) fn from_u64s(buf: Vec<u64>) -> Option<BkeyOwned> {
+        let header = size_of::<c::bkey>() / 8;
+
+        if buf.len() < header || buf.len() > u8::MAX as usize {
+            return None;
+        }
+
+        let ret = BkeyOwned { buf };
+        (ret.k().u64s as usize == ret.buf.len()).then_some(ret)
+    }
+
+    pub fn as_bkey_i(&self) -> &c::bkey_i {
+        unsafe { &*(self.buf.as_ptr() as *const c::bkey_i) }
+    }
+
+    pub fn k(&self) -> &c::bkey {
+        &self.as_bkey_i().k
+    }
+
+    /// Trim an extent to `[start, end)`, adjusting its pointers (or inline
+    /// data) to match
+    pub(crate) fn cut(&mut self, start: c::bpos, end: c::bpos) {
+        unsafe {
+            let k = self.buf.as_mut_ptr() as *mut c::bkey_i;
+            let k_start = c::bpos { offset: (*k).k.p.offset - (*k).k.size as u64, ..(*k).k.p };
+
+            if start > k_start {
+                c::bch2_cut_front(start, k);
+            }
+            if end < (*k).k.p {
+                c::bch2_cut_back(end, k);
+            }
+        }
+
+        /* Cutting inline data shrinks the value: */
+        let u64s = self.k().u64s as usize;
+        self.buf.truncate(u64s);
+    }
+
+    /// Borrow as a [`BkeySC`], e.g. to decode the value with [`BkeySC::v`]
+    pub fn as_sc(&self) -> BkeySC {
+        BkeySC::from(self.as_bkey_i())
+    }
+
+    /// The raw key and value, in u64s
+    pub fn as_u64s(&self) -> &[u64] {
+        &self.buf
+    }
+}
+
+/// Same ordering as [`BkeySC`]: by position, then by contents
+impl Ord for BkeyOwned {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (l, r) = (self.k().p, other.k().p);
+
+        l.cmp(&r)
+            .then_with(|| self.buf.cmp(&other.buf))
+    }
+}
+
+impl PartialOrd for BkeyOwned {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Iterator adaptor returned by [`BtreeIter::owned`]
+pub struct BtreeIterOwned<'t> {
+    iter:   BtreeIter<'t>,
+    done:   bool,
+}
+
+impl<'t> BtreeIter<'t> {
+    /// Convert into an iterator over owned copies of each key, e.g. for
+    /// collecting into a `Vec` or sending to another thread
+    pub fn owned(self) -> BtreeIterOwned<'t> {
+        BtreeIterOwned { iter: self, done: false }
+    }
+}
+
+impl<'t> Iterator for BtreeIterOwned<'t> {
+    type Item = Result<BkeyOwned, bch_errcode>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let ret = match self.iter.peek_and_restart() {
+            Ok(Some(k)) => Some(Ok(k.to_owned())),
+            Ok(None)    => None,
+            Err(e)      => Some(Err(e)),
+        };
+
+        match ret {
+            Some(Ok(_)) => self.iter.advance(),
+            _           => self.done = true,
+        }
+        ret
+    }
+}
+
+/// Iterator adaptor returned by [`BtreeIter::map_typed`] and
+/// [`BtreeIter::filter_type`]
+pub struct BtreeIterMap<'t, F> {
+    iter:   BtreeIter<'t>,
+    done:   bool,
+    f:      F,
+}
+
+impl<'t> BtreeIter<'t> {
+    /// Convert into an iterator over the results of `f` on each key, skipping
+    /// keys for which `f` returns None; e.g. to decode only dirents:
+    ///
+    /// ```ignore
+    /// iter.map_typed(|k| match k.v() {
+    ///     BkeyValC::dirent(d) => Some(d.d_inum),
+    ///     _                   => None,
+    /// })
+    /// ```
+    pub fn map_typed<T, F>(self, f: F) -> BtreeIterMap<'t, F>
+    where F: FnMut(&BkeySC) -> Option<T> {
+        BtreeIterMap { iter: self, done: false, f }
+    }
+
+    /// Convert into an iterator over owned copies of keys of the given types
+    ///
+    /// Types are matched on whatever the iterator returns: with `SLOTS`, the
+    /// deleted keys synthesized for empty positions only pass the filter if
+    /// `KEY_TYPE_deleted` is one of `types`.
+    pub fn filter_type(self, types: &[c::bch_bkey_type]) -> BtreeIterMap<'t, impl FnMut(&BkeySC) -> Option<BkeyOwned>> {
+        let types: Vec<u8> = types.iter().map(|&t| t as u8).collect();
+
+        self.map_typed(move |k| types.contains(&k.k.type_).then(|| k.to_owned()))
+    }
+}
+
+impl<'t, T, F> Iterator for BtreeIterMap<'t, F>
+where F: FnMut(&BkeySC) -> Option<T> {
+    type Item = Result<T, bch_errcode>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let ret = match self.iter.peek_and_restart() {
+                Ok(Some(k)) => (self.f)(&k).map(Ok),
+                Ok(None)    => { self.done = true; None }
+                Err(e)      => { self.done = true; Some(Err(e)) }
+            };
+
+            if !self.done {
+                self.iter.advance();
+            }
+            if ret.is_some() {
+                return ret;
+            }
+        }
+        None
+    }
+}
@@ -1,25 +1,478 @@
 use crate::SPOS_MAX;
+use crate::PosRange;
 use crate::c;
-use crate::bkey::BkeySC;
+use crate::bkey::{BkeySC, BkeyOwned};
+use crate::cancel::{CancelToken, ScanStatus};
 use crate::fs::Fs;
-use crate::errcode::{bch_errcode, errptr_to_result_c};
+use crate::errcode::{bch_errcode, errptr_to_result_c, ret_to_result};
 use crate::printbuf_to_formatter;
+use std::cell::Cell;
 use std::fmt;
 use std::marker::PhantomData;
-use std::mem::MaybeUninit;
+use std::mem::{transmute, MaybeUninit};
+use std::ptr;
 use bitflags::bitflags;
 
+const BTREE_TRANS_MEM_MAX: usize = c::BTREE_TRANS_MEM_MAX as usize;
+
 pub struct BtreeTrans<'f> {
-    raw:    *mut c::btree_trans,
-    fs:     PhantomData<&'f Fs>
+    raw:        *mut c::btree_trans,
+    fs:         PhantomData<&'f Fs>,
+    /* transaction memory wanted by alloc(), to be grown at the next begin(): */
+    mem_wanted: Cell<usize>,
+}
+
+/// How [`BtreeTrans::transact_with`] handles transaction restarts
+pub struct RetryPolicy<R: FnMut(u32)> {
+    /// Give up after this many attempts; None retries indefinitely
+    pub max_attempts:   Option<u32>,
+    /// Called after each restart with the number of restarts so far, e.g.
+    /// for logging or metrics
+    pub on_restart:     R,
+}
+
+impl Default for RetryPolicy<fn(u32)> {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts:   None,
+            on_restart:     |_| {},
+        }
+    }
 }
 
 impl<'f> BtreeTrans<'f> {
     pub fn new(fs: &'f Fs) -> BtreeTrans {
         unsafe {
-            BtreeTrans { raw: &mut *c::__bch2_trans_get(fs.raw, 0), fs: PhantomData }
+            BtreeTrans {
+                raw:        &mut *c::__bch2_trans_get(fs.raw, 0),
+                fs:         PhantomData,
+                mem_wanted: Cell::new(0),
+            }
+        }
+    }
+
+    /// Start (or restart) the transaction, for callers running their own
+    /// restart loop
+    ///
+    /// This drops the locks taken by iterators and unstages any updates; it
+    /// takes `&mut self` so that iterators from the previous attempt (which
+    /// borrow the transaction) can't be used afterwards. The usual pattern
+    /// is:
+    ///
+    /// ```ignore
+    /// let ret = loop {
+    ///     trans.begin();
+    ///
+    ///     let ret = (|| {
+    ///         let mut iter = BtreeIter::new(&trans, btree, pos, BtreeIterFlags::INTENT);
+    ///         /* lookups, updates */
+    ///         trans.commit(0)
+    ///     })();
+    ///
+    ///     match ret {
+    ///         Err(e) if e.is_transaction_restart() => continue,
+    ///         r => break r,
+    ///     }
+    /// };
+    /// ```
+    pub fn begin(&mut self) {
+        self.begin_shared();
+
+        /* Grow transaction memory now, while nothing can point into it: */
+        let wanted = self.mem_wanted.take();
+        unsafe {
+            if wanted > (*self.raw).mem_bytes as usize {
+                c::__bch2_trans_kmalloc(self.raw, wanted);
+                self.begin_shared();
+            }
+        }
+    }
+
+    /// Run `f` in a restart loop: [`BtreeTrans::begin`], then `f`, until it
+    /// returns something other than a transaction restart error
+    ///
+    /// `f` does the lookups and updates and commits; it's rerun from scratch
+    /// on every restart. Like the C `lockrestart_do()` this retries
+    /// indefinitely - see [`BtreeTrans::transact_with`] to bound that.
+    pub fn transact<T, F>(&mut self, f: F) -> Result<T, bch_errcode>
+    where F: FnMut(&BtreeTrans<'f>) -> Result<T, bch_errcode> {
+        self.transact_with(RetryPolicy::default(), f)
+    }
+
+    /// [`BtreeTrans::transact`], with a [`RetryPolicy`] to limit and observe
+    /// restarts
+    ///
+    /// Once `policy.max_attempts` attempts have restarted, the last restart
+    /// error is returned.
+    pub fn transact_with<T, F, R>(&mut self, mut policy: RetryPolicy<R>, mut f: F) -> Result<T, bch_errcode>
+    where F: FnMut(&BtreeTrans<'f>) -> Result<T, bch_errcode>,
+          R: FnMut(u32) {
+        let mut attempts = 0;
+
+        loop {
+            self.begin();
+            attempts += 1;
+
+            match f(self) {
+                Err(e) if e.is_transaction_restart() => {
+                    if policy.max_attempts.is_some_and(|max| attempts >= max) {
+                        return Err(e);
+                    }
+                    (policy.on_restart)(attempts);
+                }
+                r => return r,
+            }
+        }
+    }
+
+    /// Allocate zeroed scratch memory for a `T` from the transaction, e.g.
+    /// for building a key to insert, freed when the transaction restarts
+    ///
+    /// Transaction memory is a bump allocator that's reset when the
+    /// transaction restarts, and may be moved by C code growing it. `alloc`
+    /// borrows the transaction mutably, so the memory must be done with
+    /// before anything else can use the transaction - including
+    /// [`BtreeTrans::begin`]. If the buffer is full this returns a
+    /// transaction restart error, and the next `begin` grows it; allocations
+    /// are limited to `BTREE_TRANS_MEM_MAX` per attempt in total, beyond
+    /// which this returns `ENOMEM_trans_kmalloc`. `T` must not need more than
+    /// 8 byte alignment.
+    pub fn alloc<T>(&mut self) -> Result<&mut MaybeUninit<T>, bch_errcode> {
+        assert!(std::mem::align_of::<T>() <= 8);
+
+        let t = unsafe { &mut *self.raw };
+        let size = (std::mem::size_of::<T>() + 7) & !7;
+        let top = t.mem_top as usize;
+
+        if top + size > BTREE_TRANS_MEM_MAX {
+            return Err(bch_errcode::BCH_ERR_ENOMEM_trans_kmalloc);
+        }
+
+        if top + size > t.mem_bytes as usize {
+            self.mem_wanted.set((top + size).next_power_of_two());
+            return Err(bch_errcode::BCH_ERR_transaction_restart_mem_realloced);
+        }
+
+        unsafe {
+            let p = (t.mem as *mut u8).add(top);
+            ptr::write_bytes(p, 0, size);
+            t.mem_top += size as u32;
+            Ok(&mut *(p as *mut MaybeUninit<T>))
         }
     }
+
+    pub(crate) fn raw(&self) -> *mut c::btree_trans {
+        self.raw
+    }
+
+    pub(crate) fn fs_raw(&self) -> *mut c::bch_fs {
+        unsafe { (*self.raw).c }
+    }
+
+    /* For internal restart loops, where iterators are scoped to one attempt: */
+    pub(crate) fn begin_shared(&self) {
+        unsafe { c::bch2_trans_begin(self.raw); }
+    }
+
+    /// Stage `k` to replace the key at `iter`'s position; `k` must stay alive
+    /// until the transaction is committed
+    pub(crate) unsafe fn update(&self, iter: &mut BtreeIter, k: &BkeyOwned) -> Result<(), bch_errcode> {
+        let ret = c::bch2_trans_update(self.raw, &mut iter.raw,
+            k.as_bkey_i() as *const _ as *mut _,
+            c::btree_update_flags(0));
+        ret_to_result(ret).map(|_| ())
+    }
+
+    /// Commit the updates staged since [`BtreeTrans::begin`]; `flags` are
+    /// `bch_trans_commit_flags`
+    ///
+    /// A transaction restart error means nothing was committed, and the
+    /// transaction must be restarted with [`BtreeTrans::begin`] and retried.
+    pub fn commit(&self, flags: u32) -> Result<(), bch_errcode> {
+        let ret = unsafe {
+            (*self.raw).disk_res    = ptr::null_mut();
+            (*self.raw).journal_seq = ptr::null_mut();
+            c::__bch2_trans_commit(self.raw, flags)
+        };
+        ret_to_result(ret).map(|_| ())
+    }
+
+    /// Drop the transaction's btree locks, e.g. while doing expensive work on
+    /// a key in the middle of a scan, so other btree users aren't stalled
+    ///
+    /// Iterators keep their positions; take the locks again with
+    /// [`BtreeTrans::relock`] before using them. Nodes stay pinned in memory
+    /// but not locked, so for pauses of many seconds it's better to restart
+    /// the transaction with [`BtreeTrans::begin`] instead.
+    ///
+    /// # Safety
+    ///
+    /// Keys returned by iterators before unlocking point into btree nodes that
+    /// may change once unlocked: they must not be used after this call (copy
+    /// them with `to_owned` first), even if `relock` succeeds - re-peek to get
+    /// them again, which is cheap.
+    pub unsafe fn unlock(&self) {
+        c::bch2_trans_unlock(self.raw);
+    }
+
+    /// Retake the locks dropped by [`BtreeTrans::unlock`]
+    ///
+    /// This fails with a transaction restart error if any node the
+    /// transaction's iterators were on changed while unlocked. Then nothing
+    /// the transaction did before unlocking can be relied on: restart it
+    /// (with [`BtreeTrans::begin`], or by returning the error from a
+    /// [`BtreeTrans::transact`] closure). Iterators keep their positions, so a
+    /// read only scan can just re-peek where it left off.
+    pub fn relock(&self) -> Result<(), bch_errcode> {
+        ret_to_result(unsafe { c::bch2_trans_relock(self.raw) }).map(|_| ())
+    }
+
+    /// Insert a batch of keys in a single commit, overwriting whatever is at
+    /// the same positions; on transaction restart, everything is staged again
+    ///
+    /// Keys are inserted exactly as given, at their own snapshot, and extents
+    /// aren't merged or trimmed against existing keys. Every update is held in
+    /// transaction memory and needs journal space until the commit, so very
+    /// large batches are slow to restart and may fail to get a journal
+    /// reservation: a few hundred keys per batch is a good tradeoff.
+    pub fn update_many(&self, updates: &[(c::btree_id, BkeyOwned)]) -> Result<(), bch_errcode> {
+        loop {
+            self.begin_shared();
+
+            let ret = updates.iter().try_for_each(|(btree, k)| {
+                let mut iter = BtreeIter::new(self, *btree, k.k().p,
+                    BtreeIterFlags::ALL_SNAPSHOTS|BtreeIterFlags::NOT_EXTENTS|BtreeIterFlags::INTENT);
+                unsafe { self.update(&mut iter, k) }
+            }).and_then(|_| self.commit(0));
+
+            match ret {
+                Err(e) if e.is_transaction_restart() => continue,
+                r => return r,
+            }
+        }
+    }
+
+    /// Rewrite keys in `range`: for each key, `f` returns either a replacement
+    /// key at the same position, or `None` to leave it unchanged
+    ///
+    /// Updates are committed every `batch` replaced keys, to keep
+    /// transactions small. On transaction restart the current batch is
+    /// retried, so `f` may see the same key more than once and should be a
+    /// pure function of the key. Extents are rewritten without taking a disk
+    /// reservation, so replacements must not add new data.
+    pub fn rewrite_each<F>(&self, btree: c::btree_id, range: PosRange, batch: usize, mut f: F) -> Result<(), bch_errcode>
+    where F: FnMut(&BkeySC) -> Option<BkeyOwned> {
+        let mut start = range.start;
+
+        loop {
+            self.begin_shared();
+
+            let mut iter = BtreeIter::new_range(self, btree, PosRange { start, end: range.end },
+                BtreeIterFlags::INTENT);
+            let mut staged: Vec<BkeyOwned> = Vec::new();
+
+            let ret = (|| -> Result<bool, bch_errcode> {
+                let (flags, end) = (iter.raw.flags, iter.end);
+
+                while staged.len() < batch.max(1) {
+                    let new = match iter.peek()? {
+                        Some(k) if !key_past_end(flags, end, k.k) => {
+                            let new = f(&k);
+                            if let Some(n) = &new {
+                                assert!(n.k().p == k.k.p, "rewrite_each: replacement key moved");
+                            }
+                            new
+                        }
+                        _ => return Ok(true),
+                    };
+
+                    if let Some(new) = new {
+                        staged.push(new);
+                        unsafe { self.update(&mut iter, staged.last().unwrap())? };
+                    }
+                    iter.advance();
+                }
+                Ok(false)
+            })();
+
+            let ret = ret.and_then(|done| {
+                self.commit(c::bch_trans_commit_flags::BCH_TRANS_COMMIT_no_enospc as u32)?;
+                Ok(done)
+            });
+
+            match ret {
+                Ok(true) => return Ok(()),
+                Ok(false) => start = iter.pos(),
+                Err(e) if e.is_transaction_restart() => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Delete the keys in `range` for which `pred` returns true, committing
+    /// every `batch` deletions; returns the number of keys deleted
+    ///
+    /// This is the safe way to delete while scanning: deletions are staged
+    /// in the transaction, not applied under the iterator, so the scan
+    /// continues from the key after each deleted one, and after each commit
+    /// it restarts from where it left off. As with
+    /// [`BtreeTrans::rewrite_each`], a transaction restart retries the
+    /// current batch, so `pred` may see a key more than once. Extents
+    /// straddling the end of `range` are only deleted up to the end.
+    pub fn drain<F>(&self, btree: c::btree_id, range: PosRange, batch: usize, mut pred: F) -> Result<u64, bch_errcode>
+    where F: FnMut(&BkeySC) -> bool {
+        let mut start = range.start;
+        let mut nr = 0;
+
+        loop {
+            self.begin_shared();
+
+            let mut iter = BtreeIter::new_range(self, btree, PosRange { start, end: range.end },
+                BtreeIterFlags::INTENT);
+            let mut staged = 0;
+
+            let ret = (|| -> Result<bool, bch_errcode> {
+                let (flags, end) = (iter.raw.flags, iter.end);
+
+                while staged < batch.max(1) {
+                    let delete_end = match iter.peek()? {
+                        Some(k) if !key_past_end(flags, end, k.k) => {
+                            let k_end = k.k.p;
+                            pred(&k).then_some(if k_end < end { k_end } else { end })
+                        }
+                        _ => return Ok(true),
+                    };
+
+                    if let Some(delete_end) = delete_end {
+                        /* extents: delete from the iterator position, which
+                         * may be in the middle of the first key */
+                        let len = if flags & c::BTREE_ITER_IS_EXTENTS as u16 != 0 {
+                            (delete_end.offset - iter.raw.pos.offset) as u32
+                        } else {
+                            0
+                        };
+
+                        ret_to_result(unsafe {
+                            c::bch2_btree_delete_extent_at(self.raw, &mut iter.raw, len, 0)
+                        })?;
+                        staged += 1;
+                    }
+                    iter.advance();
+                }
+                Ok(false)
+            })();
+
+            let ret = ret.and_then(|done| {
+                self.commit(c::bch_trans_commit_flags::BCH_TRANS_COMMIT_no_enospc as u32)?;
+                Ok(done)
+            });
+
+            match ret {
+                Ok(done) => {
+                    nr += staged as u64;
+                    if done {
+                        return Ok(nr);
+                    }
+                    start = iter.pos();
+                }
+                Err(e) if e.is_transaction_restart() => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Delete all keys in `[start, end]`, committing as it goes
+    ///
+    /// For extents btrees the range is `[start, end)` in sectors: extents
+    /// straddling `start` or `end` are trimmed rather than deleted, so only
+    /// the part inside the range is removed.
+    pub fn delete_range(&self, btree: c::btree_id, start: c::bpos, end: c::bpos) -> Result<(), bch_errcode> {
+        let ret = unsafe {
+            c::bch2_btree_delete_range_trans(self.raw, btree, start, end, 0, ptr::null_mut())
+        };
+
+        match ret_to_result(ret) {
+            Err(bch_errcode::BCH_ERR_transaction_restart_nested) => Ok(()),
+            r => r.map(|_| ()),
+        }
+    }
+
+    /// Report what [`BtreeTrans::delete_range`] would delete, without
+    /// changing anything
+    pub fn plan_delete_range(&self, btree: c::btree_id, start: c::bpos, end: c::bpos) -> Result<UpdatePlan, bch_errcode> {
+        loop {
+            self.begin_shared();
+
+            let mut plan = UpdatePlan::default();
+            let mut iter = BtreeIter::new(self, btree, start, BtreeIterFlags::INTENT);
+
+            let ret = (|| -> Result<(), bch_errcode> {
+                while let Some(k) = iter.peek_upto(end)? {
+                    plan.add_affected(btree, &k);
+                    iter.advance();
+                }
+                Ok(())
+            })();
+
+            match ret {
+                Err(e) if e.is_transaction_restart() => continue,
+                r => return r.map(|_| plan),
+            }
+        }
+    }
+
+    /// Report what [`BtreeTrans::update_many`] would change, without
+    /// changing anything
+    ///
+    /// Updates identical to the existing key aren't counted.
+    pub fn plan_update_many(&self, updates: &[(c::btree_id, BkeyOwned)]) -> Result<UpdatePlan, bch_errcode> {
+        loop {
+            self.begin_shared();
+
+            let mut plan = UpdatePlan::default();
+
+            let ret = updates.iter().try_for_each(|(btree, k)| {
+                let mut iter = BtreeIter::new(self, *btree, k.k().p,
+                    BtreeIterFlags::ALL_SNAPSHOTS|BtreeIterFlags::NOT_EXTENTS);
+                match iter.peek_slot()? {
+                    None => {
+                        plan.keys_inserted += 1;
+                        plan.positions.push((*btree, k.k().p));
+                    }
+                    Some(old) if old != k.as_sc() => plan.add_affected(*btree, &old),
+                    Some(_) => {}
+                }
+                Ok(())
+            });
+
+            match ret {
+                Err(e) if e.is_transaction_restart() => continue,
+                r => return r.map(|_| plan),
+            }
+        }
+    }
+}
+
+/// What an update would change, from [`BtreeTrans::plan_delete_range`] or
+/// [`BtreeTrans::plan_update_many`]
+#[derive(Clone, Debug, Default)]
+pub struct UpdatePlan {
+    /// Existing keys that would be deleted or overwritten
+    pub keys_affected:  u64,
+    /// Total size of those keys, including key headers
+    pub bytes_affected: u64,
+    /// Keys that would be inserted where there's no existing key
+    pub keys_inserted:  u64,
+    /// Every position that would change
+    pub positions:      Vec<(c::btree_id, c::bpos)>,
+}
+
+impl UpdatePlan {
+    fn add_affected(&mut self, btree: c::btree_id, k: &BkeySC) {
+        self.keys_affected  += 1;
+        self.bytes_affected += k.k.u64s as u64 * 8;
+        self.positions.push((btree, k.k.p));
+    }
 }
 
 impl<'f> Drop for BtreeTrans<'f> {
@@ -45,11 +498,54 @@ bitflags! {
         const NOPRESERVE = c::BTREE_ITER_NOPRESERVE as u16;
         const CACHED_NOFILL = c::BTREE_ITER_CACHED_NOFILL as u16;
         const KEY_CACHE_FILL = c::BTREE_ITER_KEY_CACHE_FILL as u16;
+        /* Keys at every level, interior nodes included: this libbcachefs
+         * doesn't iterate over all levels, the bit is only checked by
+         * validate() */
+        const ALL_LEVELS = 1 << c::__BTREE_ITER_FLAGS_END;
+    }
+}
+
+impl BtreeIterFlags {
+    /// Check for flag combinations the C iterator code would assert on:
+    ///
+    /// - `IS_EXTENTS` with `NOT_EXTENTS`
+    /// - `IS_EXTENTS` with `ALL_SNAPSHOTS`: extents are only iterated in a
+    ///   single snapshot
+    /// - `ALL_SNAPSHOTS` with `FILTER_SNAPSHOTS`
+    /// - `CACHED` (key cache iterators) with `IS_EXTENTS` or `SLOTS`
+    /// - `CACHED_NOFILL` without `CACHED`
+    /// - `SLOTS` with `ALL_LEVELS`: interior node keys don't cover every
+    ///   position
+    pub fn validate(self) -> Result<(), bch_errcode> {
+        let invalid = [
+            (Self::IS_EXTENTS,      Self::NOT_EXTENTS),
+            (Self::IS_EXTENTS,      Self::ALL_SNAPSHOTS),
+            (Self::ALL_SNAPSHOTS,   Self::FILTER_SNAPSHOTS),
+            (Self::CACHED,          Self::IS_EXTENTS),
+            (Self::CACHED,          Self::SLOTS),
+            (Self::SLOTS,           Self::ALL_LEVELS),
+        ];
+
+        if invalid.iter().any(|&(a, b)| self.contains(a | b)) ||
+           (self.contains(Self::CACHED_NOFILL) && !self.contains(Self::CACHED)) {
+            return Err(bch_errcode::BCH_ERR_invalid);
+        }
+        Ok(())
+    }
+}
+
+fn key_past_end(iter_flags: u16, end: c::bpos, k: &c::bkey) -> bool {
+    if iter_flags & c::BTREE_ITER_IS_EXTENTS as u16 != 0 {
+        let start = c::bpos { offset: k.p.offset - k.size as u64, ..k.p };
+        start >= end
+    } else {
+        k.p > end
     }
 }
 
 pub struct BtreeIter<'t> {
     raw:    c::btree_iter,
+    end:    c::bpos,
     trans:  PhantomData<&'t BtreeTrans<'t>>,
 }
 
@@ -65,20 +561,107 @@ impl<'t> BtreeIter<'t> {
                 pos,
                 flags.bits as u32);
 
-            BtreeIter { raw: iter.assume_init(), trans: PhantomData }
+            BtreeIter { raw: iter.assume_init(), end: SPOS_MAX, trans: PhantomData }
         }
     }
 
+    /// Like [`BtreeIter::new`], but first checks `flags` with
+    /// [`BtreeIterFlags::validate`]
+    pub fn try_new(trans: &'t BtreeTrans<'t>, btree: c::btree_id, pos: c::bpos, flags: BtreeIterFlags) -> Result<BtreeIter<'t>, bch_errcode> {
+        flags.validate()?;
+        Ok(BtreeIter::new(trans, btree, pos, flags))
+    }
+
+    /// Create an iterator bounded by `range`: [`BtreeIter::peek`] and
+    /// [`BtreeIter::peek_and_restart`] return None past `range.end`
+    pub fn new_range(trans: &'t BtreeTrans<'t>, btree: c::btree_id, range: PosRange, flags: BtreeIterFlags) -> BtreeIter<'t> {
+        let mut iter = BtreeIter::new(trans, btree, range.start, flags);
+        iter.end = range.end;
+        iter
+    }
+
+    fn past_end(&self, k: &c::bkey) -> bool {
+        key_past_end(self.raw.flags, self.end, k)
+    }
+
     pub fn peek_upto<'i>(&'i mut self, end: c::bpos) -> Result<Option<BkeySC>, bch_errcode> {
         unsafe {
             let k = c::bch2_btree_iter_peek_upto(&mut self.raw, end);
             errptr_to_result_c(k.k)
-                .map(|_| if !k.k.is_null() { Some(BkeySC { k: &*k.k, v: &*k.v, iter: PhantomData }) } else { None } )
+                .map(|_| BkeySC::from_raw(k))
+        }
+    }
+
+    /// Like `peek_upto`, but also return the iterator's position afterwards:
+    /// the key's position if one was found, `end` if not
+    pub fn peek_upto_pos(&mut self, end: c::bpos) -> Result<(Option<BkeySC>, c::bpos), bch_errcode> {
+        unsafe {
+            let k = c::bch2_btree_iter_peek_upto(&mut self.raw, end);
+            errptr_to_result_c(k.k)?;
+
+            Ok((BkeySC::from_raw(k), self.raw.pos))
+        }
+    }
+
+    /// Like `peek_upto`, but also report whether another key exists before
+    /// `end` after the one returned
+    ///
+    /// The lookahead is done on a copy of the iterator, which shares the
+    /// btree path with this one: when the next key is in the same leaf node
+    /// this costs no extra traversal, only at node boundaries does it walk to
+    /// the next leaf. The returned key remains valid, as this iterator's path
+    /// is left untouched.
+    pub fn peek_upto_with_more(&mut self, end: c::bpos) -> Result<Option<(BkeySC, bool)>, bch_errcode> {
+        unsafe {
+            let k = c::bch2_btree_iter_peek_upto(&mut self.raw, end);
+            errptr_to_result_c(k.k)?;
+            let Some(k) = BkeySC::from_raw(k) else {
+                return Ok(None);
+            };
+
+            let mut next = self.duplicate();
+
+            let more = if next.raw.pos == end {
+                false
+            } else {
+                c::bch2_btree_iter_advance(&mut next.raw);
+                let n = c::bch2_btree_iter_peek_upto(&mut next.raw, end);
+                errptr_to_result_c(n.k)?;
+                !n.k.is_null()
+            };
+
+            Ok(Some((k, more)))
         }
     }
 
     pub fn peek(&mut self) -> Result<Option<BkeySC>, bch_errcode> {
-        self.peek_upto(SPOS_MAX)
+        self.peek_upto(self.end)
+    }
+
+    /// Return the key at exactly the iterator's position, or None if the
+    /// slot is empty (slot lookup)
+    ///
+    /// An empty slot may come back from C as a hole, a deleted key, or (from
+    /// the key cache) no key at all; these are all None.
+    pub fn peek_slot(&mut self) -> Result<Option<BkeySC>, bch_errcode> {
+        unsafe {
+            let k = c::bch2_btree_iter_peek_slot(&mut self.raw);
+            errptr_to_result_c(k.k)?;
+            Ok(BkeySC::from_raw(k).filter(|k| !k.is_deleted()))
+        }
+    }
+
+    /// Point lookup: reposition the iterator and return the key at exactly
+    /// `pos`, or None if the slot is empty
+    ///
+    /// Meant to be called in a loop: the iterator keeps its path between
+    /// lookups, so successive positions that are close together (e.g.
+    /// sequential inode numbers) mostly land in the same leaf node and don't
+    /// re-traverse from the root. `bench_lookup_slot` in `tests/btree.rs`
+    /// compares this with creating a new iterator per lookup.
+    pub fn lookup_slot(&mut self, pos: c::bpos) -> Result<Option<BkeySC>, bch_errcode> {
+        self.set_pos(pos);
+        self.peek_slot()
     }
 
     pub fn peek_and_restart(&mut self) -> Result<Option<BkeySC>, bch_errcode> {
@@ -86,7 +669,7 @@ impl<'t> BtreeIter<'t> {
             let k = c::bch2_btree_iter_peek_and_restart_outlined(&mut self.raw);
 
             errptr_to_result_c(k.k)
-                .map(|_| if !k.k.is_null() { Some(BkeySC{ k: &*k.k, v: &*k.v, iter: PhantomData }) } else { None } )
+                .map(|_| BkeySC::from_raw(k).filter(|k| !self.past_end(k.k)))
         }
     }
 
@@ -95,12 +678,173 @@ impl<'t> BtreeIter<'t> {
             c::bch2_btree_iter_advance(&mut self.raw);
         }
     }
+
+    /// The iterator's current position, for reporting progress or resuming
+    /// a scan later
+    ///
+    /// Updated by `advance` and `set_pos` as well as by peeking: after a peek
+    /// finds a key this is that key's position (its start, for extents), and
+    /// once a peek has run off the end of the range it's the end of the range.
+    pub fn pos(&self) -> c::bpos {
+        self.raw.pos
+    }
+
+    pub fn set_pos(&mut self, pos: c::bpos) {
+        unsafe {
+            c::bch2_btree_iter_set_pos_outlined(&mut self.raw, pos);
+        }
+    }
+
+    /// The btree node the iterator's path has at `level`
+    ///
+    /// None above the root; where the path has no node, e.g. before the
+    /// iterator has been traversed, this is the `no_btree_node_*` error
+    /// saying why.
+    pub fn node_at(&self, level: u32) -> Result<Option<&c::btree>, bch_errcode> {
+        unsafe {
+            let path = &*(*self.raw.trans).paths.add(self.raw.path as usize);
+            let b = path.l.get(level as usize).map_or(ptr::null_mut(), |l| l.b);
+
+            errptr_to_result_c(b).map(|b| b.as_ref())
+        }
+    }
+
+    /// An independent iterator at the same position, for looking further
+    /// ahead without losing this iterator's place
+    ///
+    /// Both iterators borrow the same transaction, so neither can outlive it;
+    /// the copy shares btree paths with the original until it's moved, and
+    /// releases them when dropped.
+    pub fn duplicate(&self) -> BtreeIter<'t> {
+        unsafe {
+            let mut iter: MaybeUninit<c::btree_iter> = MaybeUninit::uninit();
+
+            c::bch2_trans_copy_iter(iter.as_mut_ptr(), &self.raw as *const _ as *mut _);
+
+            BtreeIter {
+                raw:    iter.assume_init(),
+                end:    self.end,
+                trans:  PhantomData,
+            }
+        }
+    }
+
+    /// Count keys from the current position to the end of the iterator's
+    /// range, stopping early if `cancel` is triggered
+    pub fn count(&mut self, cancel: &CancelToken) -> Result<(u64, ScanStatus), bch_errcode> {
+        let mut nr = 0;
+        let status = self.for_each(cancel, |_| { nr += 1; Ok(()) })?;
+        Ok((nr, status))
+    }
+
+    /// Collect the keys from the current position to the end of the
+    /// iterator's range, failing with [`CollectError::LimitExceeded`] if
+    /// there are more than `max_keys` - e.g. for tooling that scans
+    /// user-supplied ranges, to fail cleanly rather than run out of memory
+    ///
+    /// The keys collected before the limit was hit are returned in the
+    /// error; the iterator is left at the first key not collected.
+    pub fn collect_range(&mut self, max_keys: Option<u64>) -> Result<Vec<BkeyOwned>, CollectError> {
+        let mut keys = Vec::new();
+
+        while let Some(k) = self.peek_and_restart()? {
+            if max_keys.is_some_and(|max| keys.len() as u64 >= max) {
+                return Err(CollectError::LimitExceeded { count: keys.len() as u64, keys });
+            }
+
+            keys.push(k.to_owned());
+            self.advance();
+        }
+        Ok(keys)
+    }
+
+    /// [`BtreeIter::for_each`], failing with [`CollectError::LimitExceeded`]
+    /// (with no keys, as they've all been passed to `f`) if there are more
+    /// than `max_keys` keys
+    pub fn for_each_limited<F>(&mut self, cancel: &CancelToken, max_keys: u64, mut f: F) -> Result<ScanStatus, CollectError>
+    where F: FnMut(&BkeySC) -> Result<(), bch_errcode> {
+        let mut nr = 0;
+
+        loop {
+            if cancel.is_cancelled() {
+                return Ok(ScanStatus::Cancelled);
+            }
+
+            match self.peek_and_restart()? {
+                Some(_) if nr >= max_keys => {
+                    return Err(CollectError::LimitExceeded { count: nr, keys: Vec::new() });
+                }
+                Some(k)     => f(&k)?,
+                None        => return Ok(ScanStatus::Completed),
+            }
+            nr += 1;
+            self.advance();
+        }
+    }
+
+    /// Call `f` on each key from the current position to the end of the
+    /// iterator's range, checking `cancel` between keys
+    pub fn for_each<F>(&mut self, cancel: &CancelToken, mut f: F) -> Result<ScanStatus, bch_errcode>
+    where F: FnMut(&BkeySC) -> Result<(), bch_errcode> {
+        loop {
+            if cancel.is_cancelled() {
+                return Ok(ScanStatus::Cancelled);
+            }
+
+            match self.peek_and_restart()? {
+                Some(k)     => f(&k)?,
+                None        => return Ok(ScanStatus::Completed),
+            }
+            self.advance();
+        }
+    }
+}
+
+/// Error from [`BtreeIter::collect_range`] and
+/// [`BtreeIter::for_each_limited`]
+#[derive(Debug)]
+pub enum CollectError {
+    /// The range had more keys than the limit: `count` keys were
+    /// collected (or passed on) before stopping
+    LimitExceeded { count: u64, keys: Vec<BkeyOwned> },
+    Btree(bch_errcode),
+}
+
+impl From<bch_errcode> for CollectError {
+    fn from(e: bch_errcode) -> CollectError {
+        CollectError::Btree(e)
+    }
+}
+
+impl fmt::Display for CollectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CollectError::LimitExceeded { count, .. } => write!(f, "more than {} keys in range", count),
+            CollectError::Btree(e) => write!(f, "{}", e),
+        }
+    }
 }
 
+impl std::error::Error for CollectError {}
+
 impl<'t> Drop for BtreeIter<'t> {
     fn drop(&mut self) {
         unsafe { c::bch2_trans_iter_exit(self.raw.trans, &mut self.raw) }
-    }             
+    }
+}
+
+/* Only reads fields of self.raw: Debug must not touch iterator state */
+impl<'t> fmt::Debug for BtreeIter<'t> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let p = self.raw.pos;
+
+        f.debug_struct("BtreeIter")
+            .field("btree", &format_args!("{}", self.raw.btree_id()))
+            .field("pos",   &format_args!("{}:{}:{}", p.inode, p.offset, p.snapshot))
+            .field("end",   &format_args!("{}:{}:{}", self.end.inode, self.end.offset, self.end.snapshot))
+            .field("flags", &BtreeIterFlags::from_bits_truncate(self.raw.flags))
+            .finish()
+    }
 }
 
 pub struct BtreeNodeIter<'t> {
@@ -115,19 +859,26 @@ impl<'t> BtreeNodeIter<'t> {
         locks_want: u32,
         depth:      u32,
         flags: BtreeIterFlags) -> BtreeNodeIter {
-        unsafe {
-            let mut iter: MaybeUninit<c::btree_iter> = MaybeUninit::uninit();
-            c::bch2_trans_node_iter_init(
-                trans.raw,
-                iter.as_mut_ptr(),
-                btree,
-                pos,
-                locks_want,
-                depth,
-                flags.bits as u32);
+        unsafe { BtreeNodeIter::init(trans.raw, btree, pos, locks_want, depth, flags) }
+    }
 
-            BtreeNodeIter { raw: iter.assume_init(), trans: PhantomData }
-        }
+    unsafe fn init(trans: *mut c::btree_trans,
+        btree:      c::btree_id,
+        pos:        c::bpos,
+        locks_want: u32,
+        depth:      u32,
+        flags: BtreeIterFlags) -> BtreeNodeIter<'t> {
+        let mut iter: MaybeUninit<c::btree_iter> = MaybeUninit::uninit();
+        c::bch2_trans_node_iter_init(
+            trans,
+            iter.as_mut_ptr(),
+            btree,
+            pos,
+            locks_want,
+            depth,
+            flags.bits as u32);
+
+        BtreeNodeIter { raw: iter.assume_init(), trans: PhantomData }
     }
 
     pub fn peek<'i>(&'i mut self) -> Result<Option<&'i c::btree>, bch_errcode> {
@@ -156,6 +907,22 @@ impl<'t> BtreeNodeIter<'t> {
             errptr_to_result_c(b).map(|b| if !b.is_null() { Some(&*b) } else { None })
         }
     }
+
+    /// Call `f` on each remaining node, checking `cancel` between nodes
+    pub fn for_each<F>(&mut self, cancel: &CancelToken, mut f: F) -> Result<ScanStatus, bch_errcode>
+    where F: FnMut(&c::btree) -> Result<(), bch_errcode> {
+        loop {
+            if cancel.is_cancelled() {
+                return Ok(ScanStatus::Cancelled);
+            }
+
+            match self.peek_and_restart()? {
+                Some(b)     => f(b)?,
+                None        => return Ok(ScanStatus::Completed),
+            }
+            self.advance();
+        }
+    }
 }
 
 impl<'t> Drop for BtreeNodeIter<'t> {
@@ -164,7 +931,281 @@ impl<'t> Drop for BtreeNodeIter<'t> {
     }             
 }
 
+/// Iterator returned by [`BtreeIter::include_deleted`]
+pub struct BtreeIterDeleted<'t> {
+    nodes:      BtreeNodeIter<'t>,
+    node_iter:  c::btree_node_iter,
+    /* Current leaf, or null if we need to get the next one: */
+    b:          *mut c::btree,
+    unpacked:   c::bkey,
+    start:      c::bpos,
+    end:        c::bpos,
+    flags:      u16,
+}
+
+impl<'t> BtreeIter<'t> {
+    /// Convert into a low level iterator over the keys physically present in
+    /// the btree's leaf nodes from the current position, including deleted
+    /// keys
+    ///
+    /// This is intended for recovery and forensic tooling, e.g. undelete.
+    /// Deleted keys are dropped whenever a node is compacted or rewritten, so
+    /// what's found depends on the node's history; a node may also have more
+    /// than one key at the same position (in different bsets), and keys
+    /// still in the journal aren't seen. Snapshot whiteouts are returned
+    /// regardless of the iterator's snapshot flags.
+    pub fn include_deleted(self) -> BtreeIterDeleted<'t> {
+        let nodes = unsafe {
+            BtreeNodeIter::init(self.raw.trans, self.raw.btree_id(), self.raw.pos,
+                0, 0,
+                BtreeIterFlags::PREFETCH)
+        };
+
+        BtreeIterDeleted {
+            nodes,
+            node_iter:  Default::default(),
+            b:          ptr::null_mut(),
+            unpacked:   Default::default(),
+            start:      self.raw.pos,
+            end:        self.end,
+            flags:      self.raw.flags,
+        }
+    }
+}
+
+impl<'t> BtreeIterDeleted<'t> {
+    /// The next key, deleted or not; use [`BkeySC::is_deleted`] to tell them
+    /// apart
+    pub fn peek(&mut self) -> Result<Option<BkeySC>, bch_errcode> {
+        loop {
+            if self.b.is_null() {
+                let b = match self.nodes.peek_and_restart()? {
+                    Some(b) => b as *const c::btree as *mut c::btree,
+                    None    => return Ok(None),
+                };
+
+                /* Walking from level 0 also returns interior nodes: */
+                if unsafe { (*b).level() } != 0 {
+                    self.nodes.advance();
+                    continue;
+                }
+
+                self.b = b;
+                unsafe { c::bch2_btree_node_iter_init_from_start(&mut self.node_iter, self.b) };
+            }
+
+            let k = unsafe {
+                c::bch2_btree_node_iter_peek_all_unpack(&mut self.node_iter, self.b, &mut self.unpacked)
+            };
+
+            if k.k.is_null() {
+                self.b = ptr::null_mut();
+                self.nodes.advance();
+                continue;
+            }
+
+            if self.unpacked.p < self.start {
+                self.advance();
+                continue;
+            }
+
+            if key_past_end(self.flags, self.end, &self.unpacked) {
+                return Ok(None);
+            }
+
+            return Ok(unsafe { BkeySC::from_raw(c::bkey_s_c { k: &self.unpacked, v: k.v }) });
+        }
+    }
+
+    pub fn advance(&mut self) {
+        if !self.b.is_null() {
+            unsafe { c::bch2_btree_node_iter_advance(&mut self.node_iter, self.b) };
+        }
+    }
+}
+
+/// Iterator returned by [`BtreeIter::packed`]
+pub struct BtreeIterPacked<'t> {
+    nodes:      BtreeNodeIter<'t>,
+    node_iter:  c::btree_node_iter,
+    /* Current leaf, or null if we need to get the next one: */
+    b:          *mut c::btree,
+    start:      c::bpos,
+    end:        c::bpos,
+}
+
+impl<'t> BtreeIter<'t> {
+    /// Convert into an iterator over just the positions and types of the keys
+    /// in the btree's leaf nodes, from the current position, for scans that
+    /// don't need values (e.g. counting keys)
+    ///
+    /// Keys in btree nodes are packed, and a normal peek unpacks the whole
+    /// key header; this unpacks only the position (`bench_peek_packed` in
+    /// `tests/btree.rs` compares the two on a full scan). Like
+    /// [`BtreeIter::include_deleted`] it reads leaf nodes directly, so it's
+    /// only accurate when the btree nodes are the whole picture:
+    ///  - keys still in the journal or the btree key cache aren't seen, so
+    ///    on a filesystem that's been written to since it was opened (or
+    ///    opened with `keep_journal`) results may be stale
+    ///  - snapshots aren't filtered and whiteouts are returned, so a position
+    ///    may appear once per snapshot
+    ///  - a position may appear more than once if it was overwritten in a
+    ///    newer bset that hasn't been compacted yet
+    ///  - for extents the position is the extent's end; keys are returned
+    ///    while that's <= the range end
+    pub fn packed(self) -> BtreeIterPacked<'t> {
+        let nodes = unsafe {
+            BtreeNodeIter::init(self.raw.trans, self.raw.btree_id(), self.raw.pos,
+                0, 0,
+                BtreeIterFlags::PREFETCH)
+        };
+
+        BtreeIterPacked {
+            nodes,
+            node_iter:  Default::default(),
+            b:          ptr::null_mut(),
+            start:      self.raw.pos,
+            end:        self.end,
+        }
+    }
+}
+
+impl<'t> BtreeIterPacked<'t> {
+    /// Position and type of the next key, or None at the end of the range
+    pub fn peek_packed(&mut self) -> Result<Option<(c::bpos, c::bch_bkey_type)>, bch_errcode> {
+        loop {
+            if self.b.is_null() {
+                let b = match self.nodes.peek_and_restart()? {
+                    Some(b) => b as *const c::btree as *mut c::btree,
+                    None    => return Ok(None),
+                };
+
+                /* Walking from level 0 also returns interior nodes: */
+                if unsafe { (*b).level() } != 0 {
+                    self.nodes.advance();
+                    continue;
+                }
+
+                self.b = b;
+                unsafe { c::bch2_btree_node_iter_init_from_start(&mut self.node_iter, self.b) };
+            }
+
+            let mut p: c::bpos = Default::default();
+            let mut type_ = 0u8;
+
+            if !unsafe { c::bch2_btree_node_iter_peek_pos(&mut self.node_iter, self.b, &mut p, &mut type_) } {
+                self.b = ptr::null_mut();
+                self.nodes.advance();
+                continue;
+            }
+
+            if p < self.start {
+                self.advance();
+                continue;
+            }
+
+            if p > self.end {
+                return Ok(None);
+            }
+
+            return Ok(Some((p, unsafe { transmute(type_ as u32) })));
+        }
+    }
+
+    pub fn advance(&mut self) {
+        if !self.b.is_null() {
+            unsafe { c::bch2_btree_node_iter_advance(&mut self.node_iter, self.b) };
+        }
+    }
+}
+
+/// Node and key counts for a single level of a btree
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BtreeLevelStats {
+    pub nodes:  u64,
+    pub keys:   u64,
+}
+
+/// Structural size of a btree, see [`Fs::btree_stats`]
+#[derive(Clone, Debug, Default)]
+pub struct BtreeStats {
+    /// Level of the root node (0 == the root is a leaf)
+    pub depth:  u32,
+    pub nodes:  u64,
+    pub keys:   u64,
+    /// Breakdown by level, indexed by level
+    pub levels: Vec<BtreeLevelStats>,
+}
+
+impl Fs {
+    /// Walk every node of a btree, counting nodes and keys at each level
+    pub fn btree_stats(&self, btree: c::btree_id) -> Result<BtreeStats, bch_errcode> {
+        self.btree_stats_cancellable(btree, &CancelToken::new()).map(|(stats, _)| stats)
+    }
+
+    /// Like [`Fs::btree_stats`], but stops early if `cancel` is triggered,
+    /// returning the stats for the nodes walked so far
+    pub fn btree_stats_cancellable(&self, btree: c::btree_id, cancel: &CancelToken) -> Result<(BtreeStats, ScanStatus), bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut stats = BtreeStats::default();
+
+        /* Walking from level 0 also returns interior nodes, on the way up: */
+        let mut iter = BtreeNodeIter::new(&trans, btree, crate::POS_MIN,
+            0, 0,
+            BtreeIterFlags::PREFETCH);
+
+        let status = iter.for_each(cancel, |b| {
+            let level = b.level() as usize;
+
+            if stats.levels.len() <= level {
+                stats.levels.resize(level + 1, BtreeLevelStats::default());
+            }
+
+            stats.levels[level].nodes   += 1;
+            stats.levels[level].keys    += b.nr_keys() as u64;
+            stats.nodes                 += 1;
+            stats.keys                  += b.nr_keys() as u64;
+            stats.depth = stats.depth.max(b.level());
+            Ok(())
+        })?;
+
+        Ok((stats, status))
+    }
+}
+
+/// A btree node, as returned by [`BtreeNodeIter`]
+pub type BtreeNode = c::btree;
+
 impl<'b, 'f> c::btree {
+    /// Depth of this node (0 == leaf)
+    pub fn level(&self) -> u32 {
+        self.c.level as u32
+    }
+
+    pub fn btree_id(&self) -> c::btree_id {
+        unsafe { std::mem::transmute(self.c.btree_id as u32) }
+    }
+
+    /// Smallest position covered by this node (inclusive)
+    pub fn min_key(&self) -> c::bpos {
+        unsafe { (*self.data).min_key }
+    }
+
+    /// Largest position covered by this node (inclusive)
+    pub fn max_key(&self) -> c::bpos {
+        self.key.k.p
+    }
+
+    /// Number of bsets currently in this node
+    pub fn nsets(&self) -> u32 {
+        self.nsets as u32
+    }
+
+    /// Number of live keys in this node
+    pub fn nr_keys(&self) -> u32 {
+        self.nr.packed_keys as u32 + self.nr.unpacked_keys as u32
+    }
+
     pub fn to_text(&'b self, fs: &'f Fs) -> BtreeNodeToText<'b, 'f> {
         BtreeNodeToText { b: &self, fs }
     }
@@ -0,0 +1,29 @@
+use crate::c;
+use crate::errcode::{bch_errcode, ret_to_result};
+use crate::fs::Fs;
+
+impl Fs {
+    /// Move everything out of a bucket, so it can be reused
+    ///
+    /// Dirty data is rewritten elsewhere (according to the background
+    /// target, if set) rather than dropped; cached data is dropped. Fails
+    /// with `BCH_ERR_erofs_no_writes` if the filesystem isn't read-write, or
+    /// with an ENOSPC error if there's nowhere to move the data to.
+    pub fn evict_bucket(&self, dev_idx: u32, bucket: u64) -> Result<(), bch_errcode> {
+        let ret = unsafe { c::bch2_dev_evacuate_bucket(self.raw, dev_idx, bucket) };
+        ret_to_result(ret).map(|_| ())
+    }
+
+    /// Issue discards for free buckets on device `dev_idx` that haven't been
+    /// discarded yet, and wait for them to finish
+    ///
+    /// This runs the background discard worker, which handles every device.
+    /// Buckets that are still open, or waiting on a journal commit before
+    /// they can be reused, are skipped as usual. Fails with
+    /// `BCH_ERR_ENOENT_dev_idx_not_found` if there's no such device, and
+    /// `EROFS` if the filesystem isn't read-write.
+    pub fn discard_free_buckets(&self, dev_idx: u32) -> Result<(), bch_errcode> {
+        let ret = unsafe { c::bch2_dev_discard_free_buckets(self.raw, dev_idx) };
+        ret_to_result(ret).map(|_| ())
+    }
+}
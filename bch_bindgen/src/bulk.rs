@@ -0,0 +1,91 @@
+use crate::c;
+use crate::bkey::BkeyOwned;
+use crate::btree::BtreeTrans;
+use crate::errcode::bch_errcode;
+
+/// Batch sizes for [`BulkInserter`]: a batch is committed once either limit
+/// is reached
+#[derive(Clone, Copy, Debug)]
+pub struct BulkInserterOpts {
+    pub max_keys:   usize,
+    /// Total size of pending keys, including key headers
+    pub max_bytes:  usize,
+}
+
+impl Default for BulkInserterOpts {
+    fn default() -> Self {
+        BulkInserterOpts {
+            max_keys:   128,
+            max_bytes:  64 << 10,
+        }
+    }
+}
+
+/// Inserts keys in batches, with one transaction commit per batch, for bulk
+/// loading
+///
+/// Pending keys are kept until their batch is committed by
+/// [`BtreeTrans::update_many`], so on transaction restart the whole batch is
+/// staged again: nothing is inserted twice or lost. Call
+/// [`BulkInserter::flush`] when done; keys still pending when the inserter is
+/// dropped are discarded.
+pub struct BulkInserter<'t, 'f> {
+    trans:          &'t BtreeTrans<'f>,
+    opts:           BulkInserterOpts,
+    pending:        Vec<(c::btree_id, BkeyOwned)>,
+    pending_bytes:  usize,
+    nr_keys:        u64,
+    nr_commits:     u64,
+}
+
+impl<'t, 'f> BulkInserter<'t, 'f> {
+    pub fn new(trans: &'t BtreeTrans<'f>, opts: BulkInserterOpts) -> BulkInserter<'t, 'f> {
+        BulkInserter {
+            trans,
+            opts,
+            pending:        Vec::new(),
+            pending_bytes:  0,
+            nr_keys:        0,
+            nr_commits:     0,
+        }
+    }
+
+    /// Queue a key for insertion, committing the current batch if it's full
+    pub fn insert(&mut self, btree: c::btree_id, k: BkeyOwned) -> Result<(), bch_errcode> {
+        self.pending_bytes += k.as_u64s().len() * 8;
+        self.pending.push((btree, k));
+
+        if self.pending.len() >= self.opts.max_keys.max(1) ||
+           self.pending_bytes >= self.opts.max_bytes {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Commit any pending keys
+    ///
+    /// On error the batch is kept, so flush can be retried.
+    pub fn flush(&mut self) -> Result<(), bch_errcode> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        self.trans.update_many(&self.pending)?;
+
+        self.nr_keys        += self.pending.len() as u64;
+        self.nr_commits     += 1;
+        self.pending.clear();
+        self.pending_bytes  = 0;
+        Ok(())
+    }
+
+    /// Keys committed so far
+    pub fn nr_keys(&self) -> u64 {
+        self.nr_keys
+    }
+
+    /// Transaction commits so far, not counting restarts
+    pub fn nr_commits(&self) -> u64 {
+        self.nr_commits
+    }
+}
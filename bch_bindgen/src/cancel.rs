@@ -0,0 +1,51 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Stops a long running scan from another thread (e.g. a Ctrl-C handler),
+/// or after a timeout
+///
+/// Clones share the same cancellation flag. Checking it is a relaxed atomic
+/// load (plus a clock read, with a timeout), done between keys or nodes; a
+/// cancelled scan returns [`ScanStatus::Cancelled`], and its iterator and
+/// transaction are released as usual when dropped.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled:  Arc<AtomicBool>,
+    deadline:   Option<Instant>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// A token driven by an existing flag, e.g. one set by a signal handler
+    pub fn from_flag(flag: Arc<AtomicBool>) -> CancelToken {
+        CancelToken { cancelled: flag, deadline: None }
+    }
+
+    /// A token that also cancels once `timeout` has elapsed
+    pub fn with_timeout(timeout: Duration) -> CancelToken {
+        CancelToken {
+            cancelled:  Arc::new(AtomicBool::new(false)),
+            deadline:   Some(Instant::now() + timeout),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) ||
+            self.deadline.map_or(false, |d| Instant::now() >= d)
+    }
+}
+
+/// How a cancellable scan finished
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanStatus {
+    Completed,
+    Cancelled,
+}
@@ -0,0 +1,34 @@
+use crate::fs::Fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadWrite {
+    Read    = 0,
+    Write   = 1,
+}
+
+impl Fs {
+    /// Current value of the read or write io clock, in sectors
+    ///
+    /// The io clocks advance by the number of sectors read or written, not
+    /// with wall time; bucket read/write times in alloc keys are in these
+    /// units, so `io_clock(rw) - io_time[rw]` is how much IO has happened
+    /// since. May lag the true value by a small number of sectors buffered
+    /// per cpu.
+    pub fn io_clock(&self, rw: ReadWrite) -> u64 {
+        unsafe {
+            let now = &(*self.raw).io_clock[rw as usize].now.counter;
+            (*(now as *const u64 as *const AtomicU64)).load(Ordering::Relaxed)
+        }
+    }
+
+    /// Current wall clock time, in nanoseconds since the Unix epoch, as used
+    /// for inode timestamps (see [`Fs::timespec_to_time`] to convert to
+    /// filesystem time units)
+    pub fn now_ns(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64)
+    }
+}
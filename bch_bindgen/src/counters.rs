@@ -0,0 +1,166 @@
+use crate::c;
+use crate::clock::ReadWrite;
+use crate::errcode::{bch_errcode, ret_to_result};
+use crate::fs::Fs;
+use crate::name_table;
+use crate::time_stats::TimeStatSnapshot;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Filesystem event counters, see [`Fs::counters`]
+///
+/// These are persistent: they count from when the filesystem was formatted,
+/// not from mount. Fields not otherwise documented are event counts.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// Data read and written by users, in bytes
+    pub io_read_bytes:          u64,
+    pub io_write_bytes:         u64,
+    /// Data moved by copygc, rebalance and data jobs, in bytes
+    pub io_move_bytes:          u64,
+    pub read_retry:             u64,
+    pub bucket_alloc:           u64,
+    pub bucket_alloc_fail:      u64,
+    pub bucket_discard:         u64,
+    pub btree_node_read:        u64,
+    pub btree_node_write:       u64,
+    pub btree_node_split:       u64,
+    pub btree_node_merge:       u64,
+    pub btree_node_compact:     u64,
+    pub journal_write:          u64,
+    /// Times the journal filled up and updates had to wait for reclaim
+    pub journal_full:           u64,
+    pub transaction_commit:     u64,
+    /// Transaction restarts, of all kinds
+    pub trans_restart:          u64,
+    pub copygc:                 u64,
+    pub gc_gens_start:          u64,
+    pub write_super:            u64,
+    /// Every counter this version knows about, by name, including the ones
+    /// above (with their raw values, e.g. sectors for `io_read`)
+    pub all:                    BTreeMap<String, u64>,
+}
+
+impl Counters {
+    /// A counter by name, as in [`Counters::all`]
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.all.get(name).copied()
+    }
+}
+
+/// IO done by a member device, see [`Fs::dev_io_counters`]
+///
+/// These are in memory only: they count from when the filesystem was
+/// opened, and start from zero again on every mount.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DevIoCounters {
+    /// Sectors read and written, indexed by [`ReadWrite`] and then by
+    /// `bch_data_type`
+    pub sectors:    [Vec<u64>; 2],
+    /// Read and write latencies, indexed by [`ReadWrite`]
+    pub latency:    [TimeStatSnapshot; 2],
+}
+
+impl DevIoCounters {
+    pub fn sectors(&self, rw: ReadWrite, data_type: c::bch_data_type) -> u64 {
+        self.sectors[rw as usize].get(data_type as usize).copied().unwrap_or(0)
+    }
+
+    /// Superblock, journal and btree sectors
+    pub fn metadata_sectors(&self, rw: ReadWrite) -> u64 {
+        use c::bch_data_type::*;
+
+        [BCH_DATA_sb, BCH_DATA_journal, BCH_DATA_btree].iter()
+            .map(|&t| self.sectors(rw, t))
+            .sum()
+    }
+
+    /// User data, cached data and erasure coding sectors
+    pub fn data_sectors(&self, rw: ReadWrite) -> u64 {
+        self.total_sectors(rw) - self.metadata_sectors(rw)
+    }
+
+    pub fn total_sectors(&self, rw: ReadWrite) -> u64 {
+        self.sectors[rw as usize].iter().sum()
+    }
+}
+
+impl Fs {
+    /// Sectors read and written by a member device since the filesystem was
+    /// opened, by data type, and its IO latencies
+    pub fn dev_io_counters(&self, dev_idx: u32) -> Result<DevIoCounters, bch_errcode> {
+        let mut io: c::io_count = Default::default();
+        ret_to_result(unsafe { c::bch2_dev_io_done_read(self.raw, dev_idx, &mut io) })?;
+
+        let mut ret = DevIoCounters {
+            sectors: [io.sectors[0].to_vec(), io.sectors[1].to_vec()],
+            ..Default::default()
+        };
+
+        for rw in [ReadWrite::Read, ReadWrite::Write] {
+            let mut s: c::bch_time_stats_snapshot = Default::default();
+            ret_to_result(unsafe { c::bch2_dev_io_latency_read(self.raw, dev_idx, rw as u32, &mut s) })?;
+            ret.latency[rw as usize] = TimeStatSnapshot::from(&s);
+        }
+
+        Ok(ret)
+    }
+
+    fn counter_names(&self) -> Vec<String> {
+        name_table(unsafe { c::bch2_fs_counter_names.as_ptr() })
+    }
+
+    fn counter_read(&self, idx: usize) -> u64 {
+        unsafe {
+            let p = (*self.raw).counters.add(idx);
+            (*(p as *const AtomicU64)).load(Ordering::Relaxed)
+        }
+    }
+
+    /// Read the filesystem's event counters, as shown in sysfs under
+    /// `counters/`
+    pub fn counters(&self) -> Counters {
+        let all: BTreeMap<String, u64> = self.counter_names()
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| (name, self.counter_read(i)))
+            .collect();
+
+        let get = |name: &str| all.get(name).copied().unwrap_or(0);
+
+        Counters {
+            io_read_bytes:          get("io_read") << 9,
+            io_write_bytes:         get("io_write") << 9,
+            io_move_bytes:          get("io_move") << 9,
+            read_retry:             get("read_retry"),
+            bucket_alloc:           get("bucket_alloc"),
+            bucket_alloc_fail:      get("bucket_alloc_fail"),
+            bucket_discard:         get("bucket_discard"),
+            btree_node_read:        get("btree_node_read"),
+            btree_node_write:       get("btree_node_write"),
+            btree_node_split:       get("btree_node_split"),
+            btree_node_merge:       get("btree_node_merge"),
+            btree_node_compact:     get("btree_node_compact"),
+            journal_write:          get("journal_write"),
+            journal_full:           get("journal_full"),
+            transaction_commit:     get("transaction_commit"),
+            trans_restart:          all.iter()
+                .filter(|(n, _)| n.starts_with("trans_restart_"))
+                .map(|(_, v)| v)
+                .sum(),
+            copygc:                 get("copygc"),
+            gc_gens_start:          get("gc_gens_start"),
+            write_super:            get("write_super"),
+            all,
+        }
+    }
+
+    /// Read a single counter by name, e.g. "journal_write"; None if this
+    /// version doesn't have it
+    pub fn counter_by_name(&self, name: &str) -> Option<u64> {
+        self.counter_names()
+            .iter()
+            .position(|n| n == name)
+            .map(|i| self.counter_read(i))
+    }
+}
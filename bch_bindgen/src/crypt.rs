@@ -0,0 +1,121 @@
+use crate::c;
+use crate::errcode::{bch_errcode, BchError};
+use crate::fs::Fs;
+use crate::keyutils;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+const BCH_KEY_MAGIC: u64 = u64::from_le_bytes(*b"bch**key");
+
+/// Overwrite a buffer that held key material, in a way the compiler can't
+/// optimize out
+fn memzero_explicit<T: Copy + Default>(buf: &mut [T]) {
+    for i in buf.iter_mut() {
+        unsafe { std::ptr::write_volatile(i, T::default()) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+struct SbHandle(c::bch_sb_handle);
+
+impl SbHandle {
+    fn read(device: &Path) -> Result<SbHandle, bch_errcode> {
+        let path = CString::new(device.as_os_str().as_bytes()).unwrap();
+        let mut opts = c::bch_opts::default();
+        opts.noexcl = 1;
+        opts.set_noexcl_defined(1);
+
+        let mut sb = std::mem::MaybeUninit::zeroed();
+        let ret = unsafe { c::bch2_read_super_silent(path.as_ptr(), &mut opts, sb.as_mut_ptr()) };
+
+        if ret != 0 {
+            Err(bch_errcode::from_ret(ret))
+        } else {
+            Ok(SbHandle(unsafe { sb.assume_init() }))
+        }
+    }
+}
+
+impl Drop for SbHandle {
+    fn drop(&mut self) {
+        unsafe { c::bch2_free_super(&mut self.0) }
+    }
+}
+
+/// Whether the filesystem on `device` is encrypted
+pub fn is_encrypted(device: &Path) -> Result<bool, BchError> {
+    Ok(SbHandle::read(device)?.0.sb().crypt().is_some())
+}
+
+/// Whether the filesystem on `device` has its key encrypted with a
+/// passphrase, and the key isn't in the keyring yet - i.e. it needs
+/// [`Fs::unlock`] before it can be opened
+pub fn is_locked(device: &Path) -> Result<bool, BchError> {
+    let sb = SbHandle::read(device)?;
+    Ok(unsafe { c::bch2_sb_is_encrypted_and_locked(sb.0.sb) })
+}
+
+impl Fs {
+    /// Unlock an encrypted filesystem: derive the key from `passphrase`, and
+    /// add it to the user keyring, where both the kernel and [`Fs::open`]
+    /// look for it
+    ///
+    /// Returns [`BchError::PassphraseIncorrect`] if the passphrase doesn't
+    /// decrypt the superblock key, and [`BchError::NotEncrypted`] if there's
+    /// no passphrase to check.
+    pub fn unlock(device: &Path, passphrase: &str) -> Result<(), BchError> {
+        let handle = SbHandle::read(device)?;
+        let sb = handle.0.sb();
+
+        let crypt = sb.crypt().ok_or(BchError::NotEncrypted)?;
+        if u64::from_le(crypt.key().magic) == BCH_KEY_MAGIC {
+            return Err(BchError::NotEncrypted);
+        }
+
+        let mut pass: Vec<u8> = Vec::with_capacity(passphrase.len() + 1);
+        pass.extend_from_slice(passphrase.as_bytes());
+        pass.push(0);
+
+        let mut passphrase_key = unsafe {
+            c::derive_passphrase(crypt as *const _ as *mut _, pass.as_ptr() as *const c_char)
+        };
+        memzero_explicit(&mut pass);
+
+        let mut sb_key = *crypt.key();
+        let ret = unsafe {
+            c::bch2_chacha_encrypt_key(&mut passphrase_key,
+                                       sb.nonce(),
+                                       &mut sb_key as *mut _ as *mut _,
+                                       std::mem::size_of::<c::bch_encrypted_key>())
+        };
+        let magic = u64::from_le(sb_key.magic);
+        memzero_explicit(&mut sb_key.key.key);
+
+        let ret = if ret != 0 {
+            Err(bch_errcode::from_ret(ret).into())
+        } else if magic != BCH_KEY_MAGIC {
+            Err(BchError::PassphraseIncorrect)
+        } else {
+            let description = CString::new(format!("bcachefs:{}", sb.uuid())).unwrap();
+            let ret = unsafe {
+                keyutils::add_key(b"user\0".as_ptr() as *const c_char,
+                                  description.as_ptr(),
+                                  &passphrase_key as *const _ as *const _,
+                                  std::mem::size_of::<c::bch_key>(),
+                                  keyutils::KEY_SPEC_USER_KEYRING)
+            };
+
+            if ret < 0 {
+                Err(BchError::KeyringAddFailed)
+            } else {
+                Ok(())
+            }
+        };
+
+        memzero_explicit(&mut passphrase_key.key);
+        ret
+    }
+}
@@ -0,0 +1,139 @@
+use crate::c;
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::errcode::{bch_errcode, ret_to_result};
+use crate::fs::Fs;
+use crate::{pos, PosRange};
+use bitflags::bitflags;
+use std::fmt;
+
+bitflags! {
+    /// What a device state change or removal may do to the filesystem's
+    /// redundancy; by default, anything that leaves data degraded is refused
+    pub struct DevForceFlags: u32 {
+        const DATA_LOST             = c::BCH_FORCE_IF_DATA_LOST;
+        const METADATA_LOST         = c::BCH_FORCE_IF_METADATA_LOST;
+        const DATA_DEGRADED         = c::BCH_FORCE_IF_DATA_DEGRADED;
+        const METADATA_DEGRADED     = c::BCH_FORCE_IF_METADATA_DEGRADED;
+    }
+}
+
+/// Error from [`Fs::resize_device`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResizeError {
+    /// Shrinking would need `buckets` buckets past the new end, holding
+    /// `sectors` sectors of dirty data, to be evacuated first
+    ShrinkHasData { buckets: u64, sectors: u64 },
+    /// Shrinking isn't supported yet, even with nothing to move
+    ShrinkNotSupported,
+    Resize(bch_errcode),
+}
+
+impl From<bch_errcode> for ResizeError {
+    fn from(e: bch_errcode) -> ResizeError {
+        ResizeError::Resize(e)
+    }
+}
+
+impl fmt::Display for ResizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResizeError::ShrinkHasData { buckets, sectors } =>
+                write!(f, "{} buckets past the new end have data ({} sectors)", buckets, sectors),
+            ResizeError::ShrinkNotSupported => write!(f, "shrinking devices not supported"),
+            ResizeError::Resize(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ResizeError {}
+
+impl Fs {
+    fn dev(&self, dev_idx: u32) -> Result<*mut c::bch_dev, bch_errcode> {
+        let ca = unsafe { (*self.raw).devs.get(dev_idx as usize).copied().unwrap_or(std::ptr::null_mut()) };
+
+        if ca.is_null() {
+            Err(bch_errcode::BCH_ERR_ENOENT_dev_idx_not_found)
+        } else {
+            Ok(ca)
+        }
+    }
+
+    /// Buckets in `[start, end)` on a device holding dirty data, and how many
+    /// sectors of dirty data they hold
+    fn dev_dirty_buckets(&self, dev_idx: u32, start: u64, end: u64) -> Result<(u64, u64), bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut iter = BtreeIter::new_range(&trans, c::btree_id::BTREE_ID_alloc,
+            PosRange::new(pos(dev_idx as u64, start), pos(dev_idx as u64, end - 1)),
+            BtreeIterFlags::PREFETCH);
+        let (mut buckets, mut sectors) = (0, 0);
+
+        while let Some(k) = iter.peek_and_restart()? {
+            let mut a: c::bch_alloc_v4 = Default::default();
+            unsafe { c::__bch2_alloc_to_v4(k.to_raw(), &mut a) };
+
+            if a.dirty_sectors != 0 {
+                buckets += 1;
+                sectors += a.dirty_sectors as u64;
+            }
+            iter.advance();
+        }
+
+        Ok((buckets, sectors))
+    }
+
+    /// Resize a member device to `nbuckets` buckets, e.g. after growing the
+    /// underlying block device, and write the superblock
+    ///
+    /// The new size must fit on the device (`BCH_ERR_device_size_too_small`
+    /// otherwise). Shrinking isn't supported yet: it fails with
+    /// [`ResizeError::ShrinkHasData`] if there's dirty data past the new end
+    /// that would have to be moved first, and
+    /// [`ResizeError::ShrinkNotSupported`] if not.
+    pub fn resize_device(&self, dev_idx: u32, nbuckets: u64) -> Result<(), ResizeError> {
+        let ca = self.dev(dev_idx)?;
+        let old_nbuckets = unsafe { (*ca).mi.nbuckets };
+
+        if nbuckets < old_nbuckets {
+            let (buckets, sectors) = self.dev_dirty_buckets(dev_idx, nbuckets, old_nbuckets)?;
+
+            return Err(if buckets != 0 {
+                ResizeError::ShrinkHasData { buckets, sectors }
+            } else {
+                ResizeError::ShrinkNotSupported
+            });
+        }
+
+        let ret = unsafe { c::bch2_dev_resize(self.raw, ca, nbuckets) };
+        ret_to_result(ret)?;
+        Ok(())
+    }
+
+    /// Resize a member device to `bytes`, rounded down to a whole number of
+    /// buckets; see [`Fs::resize_device`]
+    pub fn resize_device_bytes(&self, dev_idx: u32, bytes: u64) -> Result<(), ResizeError> {
+        let ca = self.dev(dev_idx)?;
+        let bucket_bytes = unsafe { (*ca).mi.bucket_size as u64 } << 9;
+
+        self.resize_device(dev_idx, bytes / bucket_bytes)
+    }
+
+    /// Change a member device's state (rw, ro, failed or spare), as
+    /// `bcachefs device set-state` does, and write the superblock
+    ///
+    /// Devices leaving the rw state stop being written to, and data that's
+    /// on failed devices is treated as missing, i.e. read from other replicas
+    /// and rereplicated by rebalance. A change that would leave fewer rw
+    /// devices than the replication settings need, or leave data without
+    /// any readable copy, fails with `device_state_not_allowed` unless
+    /// permitted by `flags`.
+    pub fn set_device_state(&self, dev_idx: u32, state: c::bch_member_state, flags: DevForceFlags) -> Result<(), bch_errcode> {
+        let ca = self.dev(dev_idx)?;
+        let ret = unsafe { c::bch2_dev_set_state(self.raw, ca, state, flags.bits() as i32) };
+
+        if ret != 0 {
+            Err(bch_errcode::from_ret(ret))
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::superblock::SuperBlock;
+
+/// A disk group (label), with its position in the group hierarchy
+#[derive(Clone, Debug)]
+pub struct DiskGroup {
+    pub idx:    u32,
+    /// This group's own label, e.g. "fast"
+    pub label:  String,
+    /// Full dotted path, e.g. "ssd.fast"
+    pub path:   String,
+    pub parent: Option<u32>,
+    /// Member devices labelled with exactly this group
+    pub devs:   Vec<u32>,
+}
+
+impl Fs {
+    pub(crate) fn superblock(&self) -> SuperBlock {
+        SuperBlock::from_sb(unsafe { &*(*self.raw).disk_sb.sb })
+    }
+
+    /// All live disk groups; a device labelled "ssd.fast" is listed under
+    /// "ssd.fast", and is also a member of the "ssd" target
+    pub fn disk_groups(&self) -> Vec<DiskGroup> {
+        let sb = self.superblock();
+        let groups = &sb.disk_groups;
+
+        let path = |mut i: usize| {
+            let mut p = Vec::new();
+
+            /* bounded, in case of a cycle in a corrupt superblock: */
+            while p.len() < groups.len() {
+                p.push(groups[i].label.as_str());
+
+                match groups[i].parent {
+                    0                                       => break,
+                    parent if parent as usize <= groups.len() => i = parent as usize - 1,
+                    _                                       => break,
+                }
+            }
+
+            p.reverse();
+            p.join(".")
+        };
+
+        groups.iter()
+            .filter(|g| !g.deleted)
+            .map(|g| DiskGroup {
+                idx:    g.idx,
+                label:  g.label.clone(),
+                path:   path(g.idx as usize),
+                parent: g.parent.checked_sub(1),
+                devs:   sb.members.iter()
+                    .filter(|m| m.exists() && m.group as u32 == g.idx + 1)
+                    .map(|m| m.idx)
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Resolve a target, as used by foreground_target and friends, to member
+    /// device indices: either a disk group path (including devices in nested
+    /// groups), or a device index
+    pub fn resolve_target(&self, name: &str) -> Result<Vec<u32>, bch_errcode> {
+        let groups = self.disk_groups();
+
+        let Some(target) = groups.iter().find(|g| g.path == name) else {
+            return match name.parse::<u32>() {
+                Ok(dev) if self.superblock().members.get(dev as usize).map_or(false, |m| m.exists()) => Ok(vec![dev]),
+                _ => Err(bch_errcode::BCH_ERR_ENOENT_dev_not_found),
+            };
+        };
+
+        let prefix = format!("{}.", target.path);
+        let mut devs: Vec<u32> = groups.iter()
+            .filter(|g| g.idx == target.idx || g.path.starts_with(&prefix))
+            .flat_map(|g| g.devs.iter().copied())
+            .collect();
+
+        devs.sort();
+        devs.dedup();
+        Ok(devs)
+    }
+}
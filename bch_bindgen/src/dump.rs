@@ -0,0 +1,118 @@
+use crate::c;
+use crate::bkey::{BkeyOwned, BkeySC};
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::POS_MIN;
+use std::mem::ManuallyDrop;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+/* Keys buffered per btree, ahead of the consumer: */
+const DUMP_QUEUE_DEPTH: usize = 256;
+
+/// Output order for [`Fs::dump_merged`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeOrder {
+    /// By position across all btrees; keys at the same position are
+    /// ordered by btree, in the order given
+    Pos,
+    /// One key from each btree in turn: each btree's keys stay in order,
+    /// without waiting on the slowest btree to decide what's next
+    Interleaved,
+}
+
+struct FsPtr(*mut c::bch_fs);
+
+/* bch_fs does its own locking; each scan thread has its own transaction: */
+unsafe impl Send for FsPtr {}
+unsafe impl Sync for FsPtr {}
+
+fn dump_btree(fs: &FsPtr, btree: c::btree_id, tx: SyncSender<Result<BkeyOwned, bch_errcode>>) {
+    /* borrowed from the caller, who still owns it: */
+    let fs = ManuallyDrop::new(Fs { raw: fs.0 });
+    let trans = BtreeTrans::new(&fs);
+    let mut iter = BtreeIter::new(&trans, btree, POS_MIN,
+        BtreeIterFlags::ALL_SNAPSHOTS|BtreeIterFlags::PREFETCH);
+
+    loop {
+        let k = match iter.peek_and_restart() {
+            Ok(Some(k)) => Ok(k.to_owned()),
+            Ok(None)    => return,
+            Err(e)      => Err(e),
+        };
+        let done = k.is_err();
+
+        /* the consumer hung up, or this is the last item: */
+        if tx.send(k).is_err() || done {
+            return;
+        }
+        iter.advance();
+    }
+}
+
+fn next_key(rx: &Receiver<Result<BkeyOwned, bch_errcode>>) -> Result<Option<BkeyOwned>, bch_errcode> {
+    rx.recv().ok().transpose()
+}
+
+impl Fs {
+    /// Scan several btrees concurrently, one thread and transaction each, and
+    /// pass their keys (in all snapshots) to `f` in a single merged order
+    ///
+    /// Each scan runs at most a fixed number of keys ahead of `f`, so a slow
+    /// consumer doesn't cause unbounded buffering. Stops at the first error
+    /// from any scan.
+    pub fn dump_merged<F>(&self, btrees: &[c::btree_id], order: MergeOrder, mut f: F) -> Result<(), bch_errcode>
+    where F: FnMut(c::btree_id, BkeySC) {
+        let fs = FsPtr(self.raw);
+
+        thread::scope(|s| {
+            let mut scans: Vec<(c::btree_id, Receiver<_>)> = Vec::new();
+
+            for &btree in btrees {
+                let (tx, rx) = sync_channel(DUMP_QUEUE_DEPTH);
+                let fs = &fs;
+
+                s.spawn(move || dump_btree(fs, btree, tx));
+                scans.push((btree, rx));
+            }
+
+            /* Returning drops the receivers, which stops the scans: */
+            match order {
+                MergeOrder::Pos => {
+                    let mut heads = Vec::new();
+                    for (_, rx) in &scans {
+                        heads.push(next_key(rx)?);
+                    }
+
+                    loop {
+                        let next = heads.iter()
+                            .enumerate()
+                            .filter_map(|(i, k)| k.as_ref().map(|k| (i, k.k().p)))
+                            .min_by_key(|&(i, p)| (p, i));
+                        let Some((i, _)) = next else { break };
+
+                        let k = heads[i].take().unwrap();
+                        f(scans[i].0, k.as_sc());
+                        heads[i] = next_key(&scans[i].1)?;
+                    }
+                }
+                MergeOrder::Interleaved => {
+                    while !scans.is_empty() {
+                        let mut i = 0;
+                        while i < scans.len() {
+                            match next_key(&scans[i].1)? {
+                                Some(k) => {
+                                    f(scans[i].0, k.as_sc());
+                                    i += 1;
+                                }
+                                None    => { scans.remove(i); }
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
@@ -11,27 +11,310 @@ impl fmt::Display for bch_errcode {
     }
 }
 
-/* Can we make a function generic over ptr constness? */
+use libc::{EACCES, EINVAL, EIO, EKEYREJECTED, ENOENT, ENOMEM, ENOSPC, EPERM, EROFS};
 
-pub fn errptr_to_result<T>(p: *mut T) -> Result<*mut T, bch_errcode> {
-    let addr = p as usize;
-    let max_err: isize = -4096;
-    if addr > max_err as usize {
-        let addr = addr as i32;
-        let err: bch_errcode = unsafe { std::mem::transmute(-addr) };
-        Err(err)
+impl bch_errcode {
+    /// What [`bch_errcode::from_ret`] returns for errors that have no
+    /// bcachefs error code: it matches no other code, and has no errno class
+    pub const UNKNOWN: bch_errcode = bch_errcode::BCH_ERR_START;
+
+    /// Convert a negative error return from C code
+    ///
+    /// Most of libbcachefs returns private error codes, but plain errnos can
+    /// still come from the kernel compatibility layer. Those don't have a
+    /// code of their own, and aren't mapped to an unrelated one: `EINVAL` is
+    /// `BCH_ERR_invalid`, and any other errno - or a value that isn't an
+    /// error return at all - is [`bch_errcode::UNKNOWN`]. Use
+    /// [`BchError::try_from`] to keep the errno.
+    pub fn from_ret(ret: i32) -> bch_errcode {
+        Self::try_from_ret(ret).unwrap_or(match ret.checked_neg() {
+            Some(EINVAL)    => bch_errcode::BCH_ERR_invalid,
+            _               => bch_errcode::UNKNOWN,
+        })
+    }
+
+    /// Like [`bch_errcode::from_ret`], but returns None for anything that
+    /// isn't a bcachefs error code
+    pub fn try_from_ret(ret: i32) -> Option<bch_errcode> {
+        let err = ret.checked_neg()?;
+
+        if err > bch_errcode::BCH_ERR_START as i32 &&
+           err < bch_errcode::BCH_ERR_MAX as i32 {
+            Some(unsafe { std::mem::transmute(err as u32) })
+        } else {
+            None
+        }
+    }
+
+    /// The standard errno this error belongs to (e.g. ENOENT); internal
+    /// errors with no errno class return their root bcachefs error code
+    pub fn class(self) -> i32 {
+        unsafe { -bcachefs::__bch2_err_class(-(self as i32)) }
+    }
+
+    /// Whether this error is `class` or a more specific error derived from
+    /// it, e.g. any `transaction_restart_*` matches `transaction_restart`
+    pub fn matches(self, class: bch_errcode) -> bool {
+        unsafe { bcachefs::__bch2_err_matches(-(self as i32), class as i32) }
+    }
+
+    pub fn is_transaction_restart(self) -> bool {
+        self.matches(bch_errcode::BCH_ERR_transaction_restart)
+    }
+
+    /// Which broad category of error this is
+    pub fn classify(self) -> ErrorClass {
+        if self.is_transaction_restart() {
+            return ErrorClass::TransactionRestart;
+        }
+        if self.matches(bch_errcode::BCH_ERR_fsck) {
+            return ErrorClass::Fsck;
+        }
+
+        errno_class(self.class())
+    }
+}
+
+fn errno_class(errno: i32) -> ErrorClass {
+    match errno {
+        EIO                         => ErrorClass::Io,
+        ENOSPC                      => ErrorClass::NoSpace,
+        ENOMEM                      => ErrorClass::NoMemory,
+        EROFS | EPERM | EACCES |
+        EKEYREJECTED                => ErrorClass::Permission,
+        ENOENT                      => ErrorClass::NotFound,
+        EINVAL                      => ErrorClass::Invalid,
+        _                           => ErrorClass::Other,
+    }
+}
+
+/// Broad categories of errors, see [`bch_errcode::classify`]
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    /// Retry the transaction
+    TransactionRestart,
+    Io,
+    /// Inconsistencies found (and possibly not fixed) by fsck
+    Fsck,
+    NoSpace,
+    NoMemory,
+    /// Read-only filesystem, or not permitted
+    Permission,
+    NotFound,
+    Invalid,
+    Other,
+}
+
+/// Errors callers may want to match on, with names that don't depend on the
+/// libbcachefs version this was built against
+///
+/// Numeric values of [`bch_errcode`] change whenever libbcachefs adds an
+/// error, and errors are often refined into more specific codes: variants
+/// here cover every more specific code derived from them (e.g.
+/// `TransactionRestart` is any `transaction_restart_*`). Anything else is
+/// `Other`, with the raw code, or `Errno` for a plain errno.
+///
+/// Some variants are errors from these bindings rather than from
+/// libbcachefs (e.g. `OptionNotFound`): they have no bcachefs error code.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BchError {
+    TransactionRestart,
+    InodeNotFound,
+    DeviceNotFound,
+    NotASubvolume,
+    /// An inode to be linked already has a dirent pointing to it
+    InodeLinked,
+    OptionNotFound,
+    OptionParse,
+    OptionNotRuntimeSettable,
+    InvalidBkey,
+    InvalidSuperblock,
+    Invalid,
+    FsckErrorsNotFixed,
+    FsckRepairImpossible,
+    NotEncrypted,
+    PassphraseIncorrect,
+    KeyringAddFailed,
+    ReadOnly,
+    /// Asked to go read-only, but already read-only
+    AlreadyReadOnly,
+    /// Asked to go read-write, but already read-write
+    AlreadyReadWrite,
+    NoSpace,
+    NoMemory,
+    Io,
+    Other(bch_errcode),
+    /// A plain errno, with no bcachefs error code
+    Errno(i32),
+}
+
+/* Most specific first: the first matching entry wins */
+const BCH_ERROR_CODES: &[(bch_errcode, BchError)] = &[
+    (bch_errcode::BCH_ERR_transaction_restart,          BchError::TransactionRestart),
+    (bch_errcode::BCH_ERR_ENOENT_inode,                 BchError::InodeNotFound),
+    (bch_errcode::BCH_ERR_ENOENT_dev_not_found,         BchError::DeviceNotFound),
+    (bch_errcode::BCH_ERR_ENOENT_dev_idx_not_found,     BchError::DeviceNotFound),
+    (bch_errcode::BCH_ERR_ENOENT_not_subvol,            BchError::NotASubvolume),
+    (bch_errcode::BCH_ERR_opt_parse_error,              BchError::OptionParse),
+    (bch_errcode::BCH_ERR_invalid_bkey,                 BchError::InvalidBkey),
+    (bch_errcode::BCH_ERR_invalid_sb,                   BchError::InvalidSuperblock),
+    (bch_errcode::BCH_ERR_fsck_errors_not_fixed,        BchError::FsckErrorsNotFixed),
+    (bch_errcode::BCH_ERR_fsck_repair_impossible,       BchError::FsckRepairImpossible),
+    (bch_errcode::BCH_ERR_invalid,                      BchError::Invalid),
+];
+
+/*
+ * Then anything else in these errno classes, including the plain errno; the
+ * code is the representative one for converting back to bch_errcode:
+ */
+const BCH_ERROR_CLASSES: &[(i32, bch_errcode, BchError)] = &[
+    (EROFS,     bch_errcode::BCH_ERR_erofs_no_writes,           BchError::ReadOnly),
+    (ENOSPC,    bch_errcode::BCH_ERR_ENOSPC_disk_reservation,   BchError::NoSpace),
+    (ENOMEM,    bch_errcode::BCH_ERR_ENOMEM_fs_other_alloc,     BchError::NoMemory),
+    (EIO,       bch_errcode::BCH_ERR_btree_node_read_error,     BchError::Io),
+    (EINVAL,    bch_errcode::BCH_ERR_invalid,                   BchError::Invalid),
+];
+
+/* Errors from these bindings, with no bcachefs error code: */
+const BCH_ERROR_TOOLS: &[(BchError, ErrorClass, &str)] = &[
+    (BchError::OptionNotFound,              ErrorClass::NotFound,   "option not found"),
+    (BchError::OptionNotRuntimeSettable,    ErrorClass::Invalid,    "option can't be set at runtime"),
+    (BchError::InodeLinked,                 ErrorClass::Invalid,    "inode already linked"),
+    (BchError::NotEncrypted,                ErrorClass::Invalid,    "filesystem not encrypted"),
+    (BchError::PassphraseIncorrect,         ErrorClass::Permission, "incorrect passphrase"),
+    (BchError::AlreadyReadOnly,             ErrorClass::Invalid,    "filesystem already read-only"),
+    (BchError::AlreadyReadWrite,            ErrorClass::Invalid,    "filesystem already read-write"),
+    (BchError::KeyringAddFailed,            ErrorClass::Other,      "error adding key to keyring"),
+];
+
+impl BchError {
+    fn tools_error(self) -> Option<(ErrorClass, &'static str)> {
+        BCH_ERROR_TOOLS.iter()
+            .find(|&&(e, _, _)| e == self)
+            .map(|&(_, class, msg)| (class, msg))
+    }
+
+    fn from_errno(errno: i32) -> BchError {
+        BCH_ERROR_CLASSES.iter()
+            .find(|&&(class, _, _)| class == errno)
+            .map(|&(_, _, e)| e)
+            .unwrap_or(BchError::Errno(errno))
+    }
+
+    pub fn classify(self) -> ErrorClass {
+        if let Some((class, _)) = self.tools_error() {
+            return class;
+        }
+
+        match self {
+            BchError::Errno(errno)  => errno_class(errno),
+            err                     => bch_errcode::from(err).classify(),
+        }
+    }
+}
+
+impl From<bch_errcode> for BchError {
+    fn from(err: bch_errcode) -> BchError {
+        if let Some(&(_, e)) = BCH_ERROR_CODES.iter().find(|&&(code, _)| err.matches(code)) {
+            return e;
+        }
+
+        let class = err.class();
+        BCH_ERROR_CLASSES.iter()
+            .find(|&&(errno, _, _)| errno == class)
+            .map(|&(_, _, e)| e)
+            .unwrap_or(BchError::Other(err))
+    }
+}
+
+/// For variants covering several codes this gives a representative one:
+/// converting to [`bch_errcode`] and back gives the same variant, but not the
+/// same code the variant was converted from. Errors from these bindings, and
+/// plain errnos other than `EINVAL`, are [`bch_errcode::UNKNOWN`].
+impl From<BchError> for bch_errcode {
+    fn from(err: BchError) -> bch_errcode {
+        match err {
+            BchError::Other(code)   => return code,
+            BchError::Errno(errno)  => return bch_errcode::from_ret(-errno),
+            _                       => {}
+        }
+
+        BCH_ERROR_CODES.iter()
+            .find(|&&(_, e)| e == err)
+            .map(|&(code, _)| code)
+            .or_else(|| BCH_ERROR_CLASSES.iter()
+                .find(|&&(_, _, e)| e == err)
+                .map(|&(_, code, _)| code))
+            .unwrap_or(bch_errcode::UNKNOWN)
+    }
+}
+
+/// From a C return value, keeping plain errnos: fails for return values that
+/// aren't errors
+impl TryFrom<i32> for BchError {
+    type Error = i32;
+
+    fn try_from(ret: i32) -> Result<BchError, i32> {
+        if let Some(code) = bch_errcode::try_from_ret(ret) {
+            return Ok(code.into());
+        }
+
+        match ret.checked_neg() {
+            Some(errno) if errno > 0 && errno < bch_errcode::BCH_ERR_START as i32
+                        => Ok(BchError::from_errno(errno)),
+            _           => Err(ret),
+        }
+    }
+}
+
+impl fmt::Display for BchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BchError::Other(code) => write!(f, "{}", code),
+            BchError::Errno(errno) => {
+                let s = unsafe { CStr::from_ptr(bcachefs::bch2_err_str(*errno)) };
+                write!(f, "{:?}", s)
+            }
+            err => match err.tools_error() {
+                Some((_, msg))  => write!(f, "{}", msg),
+                None            => write!(f, "{}", bch_errcode::from(*err)),
+            },
+        }
+    }
+}
+
+impl std::error::Error for BchError {}
+
+/// Convert a C integer return code: negative values are errors
+pub fn ret_to_result(ret: i32) -> Result<i32, bch_errcode> {
+    if ret < 0 {
+        Err(bch_errcode::from_ret(ret))
     } else {
-        Ok(p)
+        Ok(ret)
+    }
+}
+
+/// Like [`ret_to_result`], but keeping plain errnos
+pub fn ret_to_bch_result(ret: i32) -> Result<i32, BchError> {
+    if ret < 0 {
+        Err(BchError::try_from(ret).unwrap_or(BchError::Other(bch_errcode::UNKNOWN)))
+    } else {
+        Ok(ret)
     }
 }
 
+/* Can we make a function generic over ptr constness? */
+
+pub fn errptr_to_result<T>(p: *mut T) -> Result<*mut T, bch_errcode> {
+    errptr_to_result_c(p).map(|p| p as *mut T)
+}
+
 pub fn errptr_to_result_c<T>(p: *const T) -> Result<*const T, bch_errcode> {
-    let addr = p as usize;
-    let max_err: isize = -4096;
-    if addr > max_err as usize {
-        let addr = addr as i32;
-        let err: bch_errcode = unsafe { std::mem::transmute(-addr) };
-        Err(err)
+    let addr = p as isize;
+    if (-4095..0).contains(&addr) {
+        Err(bch_errcode::from_ret(addr as i32))
     } else {
         Ok(p)
     }
@@ -0,0 +1,229 @@
+use crate::c;
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceErrorKind {
+    Read,
+    Write,
+    Checksum,
+}
+
+const DEVICE_ERROR_KINDS: [DeviceErrorKind; 3] = [
+    DeviceErrorKind::Read,
+    DeviceErrorKind::Write,
+    DeviceErrorKind::Checksum,
+];
+
+/// Something that happened on a filesystem, see [`Fs::subscribe_errors`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FsEvent {
+    /// `nr` new IO errors on a member device
+    DeviceErrors {
+        dev:    u32,
+        kind:   DeviceErrorKind,
+        nr:     u64,
+    },
+    /// A member device changed state (e.g. to failed), or went offline or
+    /// came back
+    DeviceState {
+        dev:    u32,
+        state:  c::bch_member_state,
+        online: bool,
+    },
+    /// `nr` new errors of a type counted in the superblock (as listed by
+    /// show-super), e.g. `btree_node_read_error`
+    Error {
+        id:     u32,
+        name:   String,
+        nr:     u64,
+    },
+}
+
+#[derive(Default)]
+struct ErrorSnapshot {
+    dev_errors: BTreeMap<(u32, DeviceErrorKind), u64>,
+    dev_state:  BTreeMap<u32, (c::bch_member_state, bool)>,
+    errors:     BTreeMap<u32, u64>,
+}
+
+pub(crate) fn sb_error_name(id: u32) -> String {
+    if id < c::bch_sb_error_id::BCH_SB_ERR_MAX as u32 {
+        unsafe { CStr::from_ptr(*c::bch2_sb_error_strs.as_ptr().add(id as usize)) }
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        format!("(unknown error {})", id)
+    }
+}
+
+/// A type of error counted in the superblock, e.g. `btree_node_read_error`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ErrorType(pub u32);
+
+impl ErrorType {
+    pub fn name(&self) -> String {
+        sb_error_name(self.0)
+    }
+}
+
+impl fmt::Display for ErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+/// Running count of one type of error, see [`Fs::error_counts`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorCount {
+    pub ty:         ErrorType,
+    pub nr:         u64,
+    /// When the error was last seen, if recorded
+    pub last:       Option<SystemTime>,
+}
+
+impl Fs {
+    /// The running tally of errors seen over the lifetime of the filesystem,
+    /// by error type
+    ///
+    /// These are the counters kept in the errors superblock section (as
+    /// listed by show-super), updated whenever an error is seen, not only by
+    /// fsck. They're per filesystem, not per device: see
+    /// [`Fs::subscribe_errors`] for per device IO error counts.
+    pub fn error_counts(&self) -> Result<Vec<ErrorCount>, bch_errcode> {
+        Ok(self.error_counts_raw().iter()
+            .map(|e| ErrorCount {
+                ty:     ErrorType(e.id() as u32),
+                nr:     e.nr(),
+                last:   (e.last_error_time != 0)
+                    .then(|| UNIX_EPOCH + Duration::from_secs(e.last_error_time)),
+            })
+            .collect())
+    }
+
+    pub(crate) fn error_counts_raw(&self) -> Vec<c::bch_sb_error_entry_cpu> {
+        let mut ret: Vec<c::bch_sb_error_entry_cpu> = Vec::new();
+
+        loop {
+            let nr = unsafe {
+                c::bch2_fs_error_counts_read(self.raw, ret.as_mut_ptr(), ret.capacity() as u32)
+            } as usize;
+
+            if nr <= ret.capacity() {
+                unsafe { ret.set_len(nr) };
+                return ret;
+            }
+            ret.reserve_exact(nr);
+        }
+    }
+
+    fn error_snapshot(&self) -> ErrorSnapshot {
+        let mut ret = ErrorSnapshot::default();
+
+        unsafe {
+            let c = &*self.raw;
+
+            for dev in 0..c.sb.nr_devices as u32 {
+                let ca = c.devs[dev as usize];
+                if ca.is_null() {
+                    continue;
+                }
+                let ca = &*ca;
+
+                for (i, &kind) in DEVICE_ERROR_KINDS.iter().enumerate() {
+                    let nr = (*(&ca.errors[i].counter as *const u64 as *const AtomicU64)).load(Ordering::Relaxed);
+                    ret.dev_errors.insert((dev, kind), nr);
+                }
+
+                ret.dev_state.insert(dev, (std::mem::transmute(ca.mi.state as u32), !ca.disk_sb.bdev.is_null()));
+            }
+        }
+
+        for e in self.error_counts_raw() {
+            ret.errors.insert(e.id() as u32, e.nr());
+        }
+
+        ret
+    }
+
+    /// Watch for errors, by polling error counters every `interval`
+    ///
+    /// There is no event channel for errors in userspace, so events are
+    /// reported up to `interval` after they happen, and errors of the same
+    /// kind between two polls are reported as a single event with a count.
+    /// Only changes after the call are reported.
+    pub fn subscribe_errors(&self, interval: Duration) -> ErrorStream {
+        ErrorStream {
+            fs:         self,
+            interval,
+            last:       self.error_snapshot(),
+            pending:    VecDeque::new(),
+        }
+    }
+}
+
+/// Iterator over [`FsEvent`]s, returned by [`Fs::subscribe_errors`]
+///
+/// `next()` blocks until there's an event, and never returns None.
+pub struct ErrorStream<'f> {
+    fs:         &'f Fs,
+    interval:   Duration,
+    last:       ErrorSnapshot,
+    pending:    VecDeque<FsEvent>,
+}
+
+impl<'f> ErrorStream<'f> {
+    /// Check the counters now, returning any events since the last check
+    /// without waiting
+    pub fn poll(&mut self) -> Vec<FsEvent> {
+        let now = self.fs.error_snapshot();
+        let mut ret: Vec<FsEvent> = self.pending.drain(..).collect();
+
+        for (&(dev, kind), &nr) in &now.dev_errors {
+            let prev = self.last.dev_errors.get(&(dev, kind)).copied().unwrap_or(0);
+
+            if nr > prev {
+                ret.push(FsEvent::DeviceErrors { dev, kind, nr: nr - prev });
+            }
+        }
+
+        for (&dev, &(state, online)) in &now.dev_state {
+            if self.last.dev_state.get(&dev) != Some(&(state, online)) {
+                ret.push(FsEvent::DeviceState { dev, state, online });
+            }
+        }
+
+        for (&id, &nr) in &now.errors {
+            let prev = self.last.errors.get(&id).copied().unwrap_or(0);
+
+            if nr > prev {
+                ret.push(FsEvent::Error { id, name: sb_error_name(id), nr: nr - prev });
+            }
+        }
+
+        self.last = now;
+        ret
+    }
+}
+
+impl<'f> Iterator for ErrorStream<'f> {
+    type Item = FsEvent;
+
+    fn next(&mut self) -> Option<FsEvent> {
+        loop {
+            if let Some(e) = self.pending.pop_front() {
+                return Some(e);
+            }
+
+            thread::sleep(self.interval);
+            let events = self.poll();
+            self.pending.extend(events);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crate::c;
+use crate::bkey::BkeyOwned;
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::bulk::{BulkInserter, BulkInserterOpts};
+use crate::fs::Fs;
+use crate::POS_MIN;
+use anyhow::{anyhow, bail};
+use std::io::{self, Read, Write};
+
+/*
+ * Export format: a header of magic, then the on disk version as a
+ * little endian u16, followed by one record per key:
+ *
+ *   u32    btree id
+ *   u32    key size in bytes (a multiple of 8)
+ *   [u8]   the unpacked key and value, as a struct bkey_i
+ *
+ * All integers are little endian; keys are in the in-memory (unpacked)
+ * format, which is only portable between little endian machines.
+ */
+const EXPORT_MAGIC: &[u8; 8] = b"BCHKEYS1";
+
+/* Keys per transaction commit on import: */
+const IMPORT_BATCH: usize = 128;
+
+impl Fs {
+    fn sb_version(&self) -> u16 {
+        unsafe { (*self.raw).sb.version }
+    }
+
+    /// Write every key in `btrees`, in all snapshots, to `writer`
+    ///
+    /// Keys are streamed one at a time, so memory use doesn't depend on the
+    /// size of the filesystem. Returns the number of keys written.
+    pub fn export<W: Write>(&self, btrees: &[c::btree_id], mut writer: W) -> anyhow::Result<u64> {
+        writer.write_all(EXPORT_MAGIC)?;
+        writer.write_all(&self.sb_version().to_le_bytes())?;
+
+        let trans = BtreeTrans::new(self);
+        let mut nr = 0;
+
+        for &btree in btrees {
+            let mut iter = BtreeIter::new(&trans, btree, POS_MIN,
+                BtreeIterFlags::ALL_SNAPSHOTS|BtreeIterFlags::NOT_EXTENTS|BtreeIterFlags::PREFETCH);
+
+            while let Some(k) = iter.peek_and_restart()? {
+                let k = k.to_owned();
+
+                writer.write_all(&(btree as u32).to_le_bytes())?;
+                writer.write_all(&((k.as_u64s().len() * 8) as u32).to_le_bytes())?;
+                for w in k.as_u64s() {
+                    writer.write_all(&w.to_le_bytes())?;
+                }
+
+                nr += 1;
+                iter.advance();
+            }
+        }
+
+        writer.flush()?;
+        Ok(nr)
+    }
+
+    /// Insert keys written by [`Fs::export`], committing in batches
+    ///
+    /// Keys are inserted exactly as exported, overwriting whatever is at
+    /// the same position; the export must be from a filesystem with the same
+    /// on disk version. Returns the number of keys imported.
+    pub fn import<R: Read>(&self, mut reader: R, trans: &BtreeTrans) -> anyhow::Result<u64> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != EXPORT_MAGIC {
+            bail!("not a bcachefs key export");
+        }
+
+        let mut version = [0u8; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version != self.sb_version() {
+            bail!("export is from on disk version {}, filesystem is version {}", version, self.sb_version());
+        }
+
+        let mut bulk = BulkInserter::new(trans, BulkInserterOpts {
+            max_keys: IMPORT_BATCH,
+            ..Default::default()
+        });
+
+        while let Some((btree, k)) = read_record(&mut reader)? {
+            bulk.insert(btree, k)?;
+        }
+        bulk.flush()?;
+
+        Ok(bulk.nr_keys())
+    }
+}
+
+fn read_record<R: Read>(reader: &mut R) -> anyhow::Result<Option<(c::btree_id, BkeyOwned)>> {
+    let mut hdr = [0u8; 8];
+    let mut got = 0;
+
+    /* EOF is only clean between records, not partway through a header: */
+    while got < hdr.len() {
+        match reader.read(&mut hdr[got..]) {
+            Ok(0) if got == 0                                   => return Ok(None),
+            Ok(0)                                               => bail!("truncated record header in export"),
+            Ok(n)                                               => got += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted    => {},
+            Err(e)                                              => return Err(e.into()),
+        }
+    }
+
+    let btree = u32::from_le_bytes(hdr[..4].try_into().unwrap());
+    let bytes = u32::from_le_bytes(hdr[4..].try_into().unwrap()) as usize;
+
+    if btree >= c::btree_id::BTREE_ID_NR as u32 {
+        bail!("invalid btree id {} in export", btree);
+    }
+    if bytes % 8 != 0 {
+        bail!("invalid key size {} in export", bytes);
+    }
+
+    let mut buf = vec![0u8; bytes];
+    reader.read_exact(&mut buf)?;
+
+    let k = buf.chunks_exact(8)
+        .map(|w| u64::from_le_bytes(w.try_into().unwrap()))
+        .collect();
+    let k = BkeyOwned::from_u64s(k).ok_or_else(|| anyhow!("invalid key in export"))?;
+
+    Ok(Some((unsafe { std::mem::transmute(btree) }, k)))
+}
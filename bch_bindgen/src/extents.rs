@@ -0,0 +1,212 @@
+use crate::c;
+use crate::bkey::{BkeySC, BkeyValC};
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::reflink::ReflinkPtr;
+use crate::{spos, PosRange};
+use std::mem::size_of;
+
+/// Checksum and compression info covering an extent pointer
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CrcInfo {
+    /// Size on disk, in sectors: the checksum covers this whole region
+    pub compressed_size:    u32,
+    pub uncompressed_size:  u32,
+    /// Size of the part still referenced, and its offset into the
+    /// uncompressed data
+    pub live_size:          u32,
+    pub offset:             u16,
+    pub nonce:              u16,
+    pub csum_type:          u8,
+    pub compression_type:   u8,
+    pub csum:               c::bch_csum,
+}
+
+/// A pointer to a replica of an extent's data (or of a btree node)
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ExtentPtr {
+    pub dev:        u32,
+    /// Start of the checksummed region on the device, in sectors
+    pub offset:     u64,
+    pub gen:        u8,
+    pub cached:     bool,
+    pub unwritten:  bool,
+    pub crc:        CrcInfo,
+}
+
+impl ExtentPtr {
+    fn from_decoded(p: &c::extent_ptr_decoded) -> ExtentPtr {
+        ExtentPtr {
+            dev:        p.ptr.dev() as u32,
+            offset:     p.ptr.offset(),
+            gen:        p.ptr.gen() as u8,
+            cached:     p.ptr.cached() != 0,
+            unwritten:  p.ptr.unwritten() != 0,
+            crc: CrcInfo {
+                compressed_size:    p.crc.compressed_size,
+                uncompressed_size:  p.crc.uncompressed_size,
+                live_size:          p.crc.live_size,
+                offset:             p.crc.offset,
+                nonce:              p.crc.nonce,
+                csum_type:          p.crc.csum_type,
+                compression_type:   p.crc.compression_type,
+                csum:               p.crc.csum,
+            },
+        }
+    }
+}
+
+impl<'a> BkeySC<'a> {
+    /// The data pointers in an extent, reflink_v, stripe or btree pointer
+    /// key, with the crc entry that applies to each; other key types have
+    /// none
+    pub fn pointers(&self) -> impl Iterator<Item = ExtentPtr> {
+        let mut ptrs: Vec<c::extent_ptr_decoded> = Vec::new();
+
+        loop {
+            let nr = unsafe {
+                c::bch2_bkey_ptrs_decode(self.to_raw(), ptrs.as_mut_ptr(), ptrs.capacity() as u32)
+            } as usize;
+
+            if nr <= ptrs.capacity() {
+                unsafe { ptrs.set_len(nr) };
+                break;
+            }
+            ptrs.reserve_exact(nr);
+        }
+
+        ptrs.iter()
+            .map(ExtentPtr::from_decoded)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+/// What a range of a file maps to
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ExtentData {
+    /// Data on disk, one pointer per replica
+    Ptrs(Vec<ExtentPtr>),
+    Inline(Vec<u8>),
+    /// Space reserved by fallocate, with no data written
+    Reservation,
+    /// A reflink pointer to data in the reflink btree, when not resolved
+    Reflink(ReflinkPtr),
+    /// Data that was lost, and reads return an error
+    Error,
+}
+
+/// A range of a file's data, see [`Fs::file_extents`]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ExtentInfo {
+    /// Start, in sectors: the file offset, or for extents returned by
+    /// [`Fs::resolve_reflink`], the offset in the reflink btree
+    pub offset:     u64,
+    pub size:       u64,
+    pub data:       ExtentData,
+    /// For data in the reflink btree, the number of reflink pointers
+    /// sharing it; None for data owned by a single file
+    pub refcount:   Option<u64>,
+}
+
+impl ExtentInfo {
+    /// Decode an extents or reflink btree key; None for key types that don't
+    /// map data (e.g. whiteouts)
+    pub fn from_key(k: &BkeySC) -> Option<ExtentInfo> {
+        let val_bytes = (k.k.u64s as usize * 8).saturating_sub(size_of::<c::bkey>());
+
+        let (data, refcount) = match k.v() {
+            BkeyValC::extent(_)         => (ExtentData::Ptrs(k.pointers().collect()), None),
+            BkeyValC::reflink_v(v)      => (ExtentData::Ptrs(k.pointers().collect()), Some(v.refcount)),
+            BkeyValC::inline_data(v)    => (ExtentData::Inline(unsafe { v.data.as_slice(val_bytes) }.to_vec()), None),
+            BkeyValC::indirect_inline_data(v) => {
+                let len = val_bytes.saturating_sub(size_of::<u64>());
+                (ExtentData::Inline(unsafe { v.data.as_slice(len) }.to_vec()), Some(v.refcount))
+            },
+            BkeyValC::reservation(_)    => (ExtentData::Reservation, None),
+            BkeyValC::reflink_p(_)      => (ExtentData::Reflink(ReflinkPtr::from_key(k)?), None),
+            BkeyValC::error             => (ExtentData::Error, None),
+            _                           => return None,
+        };
+
+        Some(ExtentInfo {
+            offset:     k.k.p.offset - k.k.size as u64,
+            size:       k.k.size as u64,
+            data,
+            refcount:   refcount.map(u64::from_le),
+        })
+    }
+}
+
+impl Fs {
+    /// The indirect extents a reflink pointer refers to, trimmed to the range
+    /// it references, with offsets in the reflink btree
+    ///
+    /// A gap in the result means part of the pointer is dangling (the
+    /// indirect extent is missing), which fsck would repair.
+    pub fn resolve_reflink(&self, p: &ReflinkPtr) -> Result<Vec<ExtentInfo>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let range = PosRange::new(spos(0, p.idx, 0), spos(0, p.idx + p.size, 0));
+        let mut iter = BtreeIter::new_range(&trans, c::btree_id::BTREE_ID_reflink, range,
+            BtreeIterFlags::empty());
+        let mut ret = Vec::new();
+
+        while let Some(k) = iter.peek_and_restart()? {
+            let mut k = k.to_owned();
+            k.cut(range.start, range.end);
+
+            ret.extend(ExtentInfo::from_key(&k.as_sc()));
+            iter.advance();
+        }
+
+        Ok(ret)
+    }
+
+    /// A file's data, as visible from the given snapshot, in file order;
+    /// holes are omitted
+    ///
+    /// With `resolve_reflink`, reflink pointers are replaced by the indirect
+    /// extents they point to (with offsets translated back to file offsets),
+    /// and `refcount` says how many reflink pointers share each one;
+    /// otherwise they're returned as [`ExtentData::Reflink`].
+    pub fn file_extents(&self, inode: u64, snapshot: u32, resolve_reflink: bool) -> Result<Vec<ExtentInfo>, bch_errcode> {
+        let mut extents = Vec::new();
+
+        {
+            let trans = BtreeTrans::new(self);
+            let mut iter = BtreeIter::new_range(&trans, c::btree_id::BTREE_ID_extents,
+                PosRange::new(spos(inode, 0, snapshot), spos(inode, u64::MAX, snapshot)),
+                BtreeIterFlags::empty());
+
+            while let Some(k) = iter.peek_and_restart()? {
+                extents.extend(ExtentInfo::from_key(&k));
+                iter.advance();
+            }
+        }
+
+        if !resolve_reflink {
+            return Ok(extents);
+        }
+
+        /* Done after dropping our transaction; resolve_reflink() uses its own: */
+        let mut ret = Vec::new();
+        for e in extents {
+            match e.data {
+                ExtentData::Reflink(p) => {
+                    for mut i in self.resolve_reflink(&p)? {
+                        i.offset = i.offset - p.idx + e.offset;
+                        ret.push(i);
+                    }
+                },
+                _ => ret.push(e),
+            }
+        }
+
+        Ok(ret)
+    }
+}
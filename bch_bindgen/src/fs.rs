@@ -1,14 +1,75 @@
 use std::ffi::CString;
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use crate::c;
-use crate::errcode::{bch_errcode, errptr_to_result};
+use crate::errcode::{bch_errcode, errptr_to_result, ret_to_result, BchError};
+use memoffset::offset_of;
+
+const BCACHE_MAGIC: [u8; 16] = [0xc6, 0x85, 0x73, 0xf6, 0x4e, 0x1a, 0x45, 0xca,
+                                0x82, 0x65, 0xf5, 0x7f, 0x48, 0xba, 0x6d, 0x81];
+const BCHFS_MAGIC: [u8; 16]  = [0xc6, 0x85, 0x73, 0xf6, 0x66, 0xce, 0x90, 0xa9,
+                                0xd9, 0x6a, 0x60, 0xcf, 0x80, 0x3d, 0xf7, 0xef];
 
 pub struct Fs {
     pub raw: *mut c::bch_fs,
 }
 
+/// Seconds and nanoseconds since the Unix epoch
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timespec {
+    pub tv_sec:     i64,
+    pub tv_nsec:    u32,
+}
+
+/// Extra ways to open a filesystem, for [`Fs::open_with`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FsOpenOpts {
+    /// **Forensic use only.** Open best-effort despite unsupported on disk
+    /// versions in btree nodes or the journal, to get data off a filesystem
+    /// that won't otherwise open. Metadata from an incompatible version may
+    /// be misinterpreted.
+    ///
+    /// This forces `nochanges` and `read_only`: nothing is ever written, and
+    /// writing to a filesystem opened this way would corrupt it. Errors are
+    /// continued past and "fixed" in memory only: journal entries with an
+    /// unsupported version are reported and read anyway, and a btree whose
+    /// nodes can't be read (e.g. its root has an incompatible version) comes
+    /// up empty instead of failing the open.
+    ///
+    /// The superblock version is still checked: a filesystem whose
+    /// superblock version isn't supported fails to open regardless.
+    pub ignore_version: bool,
+    /// Open with member devices missing, even if that means data or metadata
+    /// is missing
+    pub very_degraded:  bool,
+}
+
 impl Fs {
+    /// Open a filesystem with [`FsOpenOpts`], which are applied on top of
+    /// `opts`
+    pub fn open_with(devs: &Vec<PathBuf>, mut opts: c::bch_opts, open_opts: FsOpenOpts) -> Result<Fs, bch_errcode> {
+        if open_opts.very_degraded {
+            opts.very_degraded = 1;
+            opts.set_very_degraded_defined(1);
+        }
+
+        if open_opts.ignore_version {
+            opts.nochanges = 1;
+            opts.set_nochanges_defined(1);
+            opts.read_only = 1;
+            opts.set_read_only_defined(1);
+            opts.errors = c::bch_error_actions::BCH_ON_ERROR_continue as u8;
+            opts.set_errors_defined(1);
+            opts.fix_errors = c::fsck_err_opts::FSCK_FIX_yes as u8;
+            opts.set_fix_errors_defined(1);
+        }
+
+        Fs::open(devs, opts)
+    }
+
+    /// Open a filesystem; `devs` may be block devices or regular image files
     pub fn open(devs: &Vec<PathBuf>, opts: c::bch_opts) -> Result<Fs, bch_errcode> {
         let devs: Vec<_> = devs.iter()
             .map(|i| CString::new(i.as_os_str().as_bytes()).unwrap().into_raw())
@@ -18,6 +79,158 @@ impl Fs {
 
         errptr_to_result(ret).map(|fs| Fs { raw: fs})
     }
+
+    /// Open a filesystem with member devices opened `O_DIRECT` (`direct`) or
+    /// through the page cache
+    ///
+    /// Devices that don't support `O_DIRECT` (e.g. image files on tmpfs) fall
+    /// back to buffered IO, with a warning in the filesystem's log. Reads
+    /// through [`Fs::read_at`] are always block aligned, so no alignment is
+    /// required of the caller.
+    pub fn open_direct(devs: &Vec<PathBuf>, mut opts: c::bch_opts, direct: bool) -> Result<Fs, bch_errcode> {
+        opts.direct_io = direct as u8;
+        opts.set_direct_io_defined(1);
+
+        let fs = Fs::open(devs, opts)?;
+        if direct {
+            unsafe { c::bch2_fs_warn_direct_unsupported(fs.raw) };
+        }
+        Ok(fs)
+    }
+
+    /// Open a filesystem that lives inside a larger file or device, e.g. a
+    /// partition within a raw disk image, without a loop device
+    ///
+    /// Only the region `offset` bytes in and `size` bytes long (or to the end,
+    /// if None) is used; `offset` must be a multiple of 4096. Fails with
+    /// `invalid_sb_magic` if there's no bcachefs superblock where one would
+    /// be expected in that region.
+    pub fn open_at_offset(dev: &Path, offset: u64, size: Option<u64>, opts: c::bch_opts) -> Result<Fs, bch_errcode> {
+        let mut magic = [0u8; 16];
+        std::fs::File::open(dev)
+            .and_then(|f| f.read_exact_at(&mut magic, offset +
+                    (c::BCH_SB_SECTOR as u64) * 512 + offset_of!(c::bch_sb, magic) as u64))
+            .map_err(|_| bch_errcode::BCH_ERR_invalid_sb_magic)?;
+
+        if magic != BCHFS_MAGIC && magic != BCACHE_MAGIC {
+            return Err(bch_errcode::BCH_ERR_invalid_sb_magic);
+        }
+
+        let path = CString::new(dev.as_os_str().as_bytes()).unwrap();
+        ret_to_result(unsafe { c::blkdev_region_add(path.as_ptr(), offset, size.unwrap_or(0)) })?;
+
+        /* the region only needs to exist while the device is being opened: */
+        let ret = Fs::open(&vec![dev.to_path_buf()], opts);
+        unsafe { c::blkdev_region_del(path.as_ptr()) };
+        ret
+    }
+
+    /// Stop the filesystem, reporting errors from the final journal flush
+    ///
+    /// Dropping an `Fs` stops it the same way but can't report errors. Either
+    /// way `bch2_fs_stop()` first goes read-only - stopping background work
+    /// (copygc, rebalance, journal reclaim) and waiting for it to finish,
+    /// flushing the key cache and marking the superblock clean - and only
+    /// then frees the filesystem, so no background thread outlives the `Fs`.
+    /// Transactions borrow the `Fs`, so they're all gone by this point.
+    ///
+    /// On success, everything committed before the call is on stable
+    /// storage. A filesystem that's read-only has nothing to flush, so this
+    /// returns Ok without doing any IO.
+    pub fn shutdown(self) -> Result<(), bch_errcode> {
+        let rw = unsafe { (*self.raw).flags } & (1 << c::bch_fs_flags::BCH_FS_rw as u32) != 0;
+
+        let ret = if rw {
+            unsafe { c::bch2_journal_flush(&mut (*self.raw).journal) }
+        } else {
+            0
+        };
+
+        drop(self);
+        ret_to_result(ret).map(|_| ())
+    }
+
+    /// Wait for everything committed so far to be written to the journal on
+    /// stable storage
+    ///
+    /// Transaction commits only add to the in-memory journal buffer: they're
+    /// ordered, but not durable until the journal entry is written out, which
+    /// otherwise happens on a timer or when the filesystem is stopped.
+    pub fn flush_journal(&self) -> Result<(), bch_errcode> {
+        let ret = unsafe { c::bch2_journal_flush(&mut (*self.raw).journal) };
+        ret_to_result(ret).map(|_| ())
+    }
+
+    /// Flush everything to stable storage: dirty key cache entries, the
+    /// btree write buffer and btree nodes are written back, and then the
+    /// journal is flushed
+    ///
+    /// Unlike [`Fs::flush_journal`], once this returns recovery has nothing
+    /// to replay for updates committed before the call. A read-only
+    /// filesystem has nothing to flush, so this returns Ok without doing any
+    /// IO.
+    pub fn sync(&self) -> Result<(), bch_errcode> {
+        ret_to_result(unsafe { c::bch2_fs_sync(self.raw) }).map(|_| ())
+    }
+
+    /// Go read-only: stop background work, wait for in flight writes to
+    /// finish, flush and mark the superblock clean
+    ///
+    /// Once this returns nothing more will be written to the devices, so
+    /// they can be snapshotted or copied. Returns
+    /// [`BchError::AlreadyReadOnly`] if the filesystem is already read-only.
+    pub fn go_read_only(&self) -> Result<(), BchError> {
+        match ret_to_result(unsafe { c::bch2_fs_set_ro(self.raw) })? {
+            1 => Err(BchError::AlreadyReadOnly),
+            _ => Ok(()),
+        }
+    }
+
+    /// Go read-write, restarting background work
+    ///
+    /// Returns [`BchError::AlreadyReadWrite`] if the filesystem is already
+    /// read-write, or [`BchError::ReadOnly`] if it can't go read-write (e.g.
+    /// it was opened with nochanges or norecovery).
+    pub fn go_read_write(&self) -> Result<(), BchError> {
+        match ret_to_result(unsafe { c::bch2_fs_set_rw(self.raw) })? {
+            1 => Err(BchError::AlreadyReadWrite),
+            _ => Ok(()),
+        }
+    }
+
+    /// Convert a filesystem timestamp (e.g. inode atime/mtime/ctime), in units
+    /// of the superblock's time_precision, to a Unix timespec
+    pub fn time_to_timespec(&self, t: i64) -> Timespec {
+        let sb = unsafe { &(*self.raw).sb };
+        let t = t + sb.time_base_lo as i64;
+        let units = sb.time_units_per_sec as i64;
+
+        Timespec {
+            tv_sec:     t.div_euclid(units),
+            tv_nsec:    (t.rem_euclid(units) * sb.nsec_per_time_unit as i64) as u32,
+        }
+    }
+
+    /// Inverse of [`Fs::time_to_timespec`]; rounds down to the filesystem's
+    /// time precision
+    pub fn timespec_to_time(&self, ts: Timespec) -> i64 {
+        let sb = unsafe { &(*self.raw).sb };
+
+        ts.tv_sec * sb.time_units_per_sec as i64 +
+            (ts.tv_nsec / sb.nsec_per_time_unit) as i64 -
+            sb.time_base_lo as i64
+    }
+
+    /// Convert a filesystem timestamp to a [`SystemTime`]
+    pub fn time_to_system(&self, t: i64) -> SystemTime {
+        let ts = self.time_to_timespec(t);
+
+        if ts.tv_sec >= 0 {
+            UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec)
+        } else {
+            UNIX_EPOCH - Duration::from_secs(ts.tv_sec.unsigned_abs()) + Duration::from_nanos(ts.tv_nsec as u64)
+        }
+    }
 }
 
 impl Drop for Fs {
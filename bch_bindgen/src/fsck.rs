@@ -0,0 +1,240 @@
+use crate::c;
+use crate::bkey::BkeyOwned;
+use crate::errcode::{bch_errcode, ret_to_result};
+use crate::fs::Fs;
+use crate::events::sb_error_name;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::mem::size_of;
+use std::os::raw::c_void;
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::Mutex;
+
+/// One key fsck would change, see [`Fs::check_dry_run`]
+#[derive(Clone, Debug)]
+pub struct RepairChange {
+    /// Index of the transaction commit this change was part of, in commit
+    /// order: changes in the same commit depend on each other, and later
+    /// repairs may depend on earlier ones
+    pub commit:         u64,
+    /// The function that ran the transaction, e.g. "check_dirents"
+    pub transaction:    String,
+    pub btree:          c::btree_id,
+    /// Nonzero for btree node pointers
+    pub level:          u32,
+    pub pos:            c::bpos,
+    /// None where there was no key
+    pub before:         Option<BkeyOwned>,
+    /// None for a deletion
+    pub after:          Option<BkeyOwned>,
+}
+
+/// What fsck would repair, from [`Fs::check_dry_run`]
+#[derive(Clone, Debug, Default)]
+pub struct RepairDiff {
+    /// Key changes, in the order they would be made
+    pub changes:    Vec<RepairChange>,
+    /// Errors found, by name, with counts. Not every repair is a key change
+    /// (e.g. superblock fixes, or allocation info rebuilt in memory), so
+    /// errors here may have no corresponding entries in `changes`
+    pub errors:     Vec<(String, u64)>,
+}
+
+/// Outcome of [`Fs::check`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// Errors found: `errors_fixed + errors_not_fixed`
+    pub errors_found:       u64,
+    pub errors_fixed:       u64,
+    /// Errors that weren't fixed, because they were ignored (e.g. with
+    /// `fix_errors=no`) or have no repair
+    pub errors_not_fixed:   u64,
+    /// The filesystem still has errors, including errors found outside of
+    /// fsck proper, e.g. by journal replay
+    pub has_errors:         bool,
+    /// Set if fsck couldn't complete, e.g. it hit an error it can't continue
+    /// past, or the filesystem couldn't be opened. Error counts aren't
+    /// available for aborted runs.
+    pub aborted:            Option<bch_errcode>,
+}
+
+impl CheckReport {
+    /// Exit status for `fsck`, as a bitmask following fsck(8): 0 for a clean
+    /// filesystem, 1 if errors were corrected, 4 if errors were left
+    /// uncorrected, and 8 if the run was aborted
+    pub fn exit_code(&self) -> i32 {
+        if self.aborted.is_some() {
+            return 8;
+        }
+
+        let mut ret = 0;
+        if self.errors_fixed != 0 {
+            ret |= 1;
+        }
+        if self.errors_not_fixed != 0 || self.has_errors {
+            ret |= 4;
+        }
+        ret
+    }
+}
+
+#[derive(Default)]
+struct Recorder {
+    /* changes staged by transactions that haven't committed yet: */
+    pending:    HashMap<usize, Vec<RepairChange>>,
+    diff:       RepairDiff,
+    nr_commits: u64,
+}
+
+fn key_owned(k: &c::bkey, v: *const c::bch_val) -> Option<BkeyOwned> {
+    if k.type_ == c::bch_bkey_type::KEY_TYPE_deleted as u8 {
+        return None;
+    }
+
+    let header = size_of::<c::bkey>() / 8;
+    let mut buf = vec![0u64; k.u64s as usize];
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(k as *const c::bkey as *const u64, buf.as_mut_ptr(), header);
+        if !v.is_null() {
+            std::ptr::copy_nonoverlapping(v as *const u64, buf.as_mut_ptr().add(header), buf.len() - header);
+        }
+    }
+    BkeyOwned::from_u64s(buf)
+}
+
+unsafe extern "C" fn record_commit(data: *mut c_void, trans: *mut c::btree_trans, committed: bool) {
+    let recorder = &*(data as *const Mutex<Recorder>);
+    let mut r = recorder.lock().unwrap();
+    let trans = &*trans;
+
+    if committed {
+        let nr = r.nr_commits;
+        r.nr_commits += 1;
+
+        if let Some(changes) = r.pending.remove(&(trans as *const _ as usize)) {
+            r.diff.changes.extend(changes.into_iter().map(|c| RepairChange { commit: nr, ..c }));
+        }
+        return;
+    }
+
+    let name = if trans.fn_.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(trans.fn_).to_string_lossy().into_owned()
+    };
+
+    let changes = (0..trans.nr_updates as usize)
+        .map(|i| {
+            let u = &*trans.updates.add(i);
+            let k = &*u.k;
+
+            RepairChange {
+                commit:         0,
+                transaction:    name.clone(),
+                btree:          u.btree_id(),
+                level:          u.level() as u32,
+                pos:            k.k.p,
+                before:         key_owned(&u.old_k, u.old_v),
+                after:          key_owned(&k.k, &k.v),
+            }
+        })
+        .collect();
+
+    /* a restarted attempt replaces the previous one: */
+    r.pending.insert(trans as *const _ as usize, changes);
+}
+
+impl Fs {
+    /// Run fsck on a filesystem, fixing errors according to
+    /// `opts.fix_errors`, and report what was found
+    ///
+    /// Mirrors `bcachefs fsck` run offline: the filesystem is opened with
+    /// `fsck` set, which runs the fsck recovery passes, then stopped. Errors
+    /// are counted by the outcome of the last instance of each kind of error
+    /// in each pass, which only differs from the outcome of every instance
+    /// when asking whether to fix them.
+    pub fn check(devs: &Vec<PathBuf>, mut opts: c::bch_opts) -> CheckReport {
+        let aborted = |e| CheckReport { aborted: Some(e), ..Default::default() };
+
+        opts.fsck = 1;
+        opts.set_fsck_defined(1);
+        /* Start it ourselves, so errors are counted from the start: */
+        opts.nostart = 1;
+        opts.set_nostart_defined(1);
+
+        let fs = match Fs::open(devs, opts) {
+            Ok(fs)  => fs,
+            Err(e)  => return aborted(e),
+        };
+        if let Err(e) = ret_to_result(unsafe { c::bch2_fs_fsck_err_count_start(fs.raw) }) {
+            return aborted(e);
+        }
+
+        let ret = ret_to_result(unsafe { c::bch2_fs_start(fs.raw) });
+        let (mut errors_fixed, mut errors_not_fixed) = (0, 0);
+        unsafe { c::bch2_fs_fsck_err_count_stop(fs.raw, &mut errors_fixed, &mut errors_not_fixed) };
+
+        if let Err(e) = ret {
+            return aborted(e);
+        }
+
+        let flags = unsafe { (*fs.raw).flags };
+        let flag = |f: c::bch_fs_flags| flags & (1 << f as u32) != 0;
+
+        CheckReport {
+            errors_found:       errors_fixed + errors_not_fixed,
+            errors_fixed,
+            errors_not_fixed,
+            has_errors:         flag(c::bch_fs_flags::BCH_FS_error) ||
+                                flag(c::bch_fs_flags::BCH_FS_errors_not_fixed),
+            aborted:            None,
+        }
+    }
+
+    /// Run fsck on a filesystem without changing anything, and report the
+    /// key changes its repairs would make
+    ///
+    /// The filesystem is opened with `nochanges` and errors are fixed in
+    /// memory only, so later passes see (and may depend on) the results of
+    /// earlier repairs, as in a real fsck. Only commits on this filesystem
+    /// are recorded, so other filesystems may be in use concurrently.
+    ///
+    /// Changes are recorded as fsck makes them: updates made by triggers
+    /// (e.g. to allocation info) aren't included.
+    pub fn check_dry_run(devs: &Vec<PathBuf>, mut opts: c::bch_opts) -> Result<RepairDiff, bch_errcode> {
+        let recorder = Box::new(Mutex::new(Recorder::default()));
+
+        opts.fsck = 1;
+        opts.set_fsck_defined(1);
+        opts.fix_errors = c::fsck_err_opts::FSCK_FIX_yes as u8;
+        opts.set_fix_errors_defined(1);
+        opts.nochanges = 1;
+        opts.set_nochanges_defined(1);
+
+        /* Start it ourselves, so the hook is set before recovery and fsck: */
+        opts.nostart = 1;
+        opts.set_nostart_defined(1);
+
+        let fs = Fs::open(devs, opts)?;
+        let raw = fs.raw;
+        ret_to_result(unsafe {
+            c::bch2_fs_commit_hook_set(raw, Some(record_commit),
+                &*recorder as *const _ as *mut c_void)
+        })?;
+
+        let errors = ret_to_result(unsafe { c::bch2_fs_start(raw) }).map(|_| {
+            fs.error_counts_raw().iter()
+                .map(|e| (sb_error_name(e.id() as u32), e.nr()))
+                .collect()
+        });
+        /* Stopping the filesystem may commit too: */
+        drop(fs);
+        unsafe { c::bch2_fs_commit_hook_set(raw, None, ptr::null_mut()) };
+
+        let mut diff = recorder.into_inner().unwrap().diff;
+        diff.errors = errors?;
+        Ok(diff)
+    }
+}
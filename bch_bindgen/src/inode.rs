@@ -0,0 +1,215 @@
+use crate::c;
+use crate::bkey::BkeyValC;
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::errcode::{bch_errcode, ret_to_bch_result, BchError};
+use crate::fs::Fs;
+use crate::spos;
+use std::ffi::CString;
+use std::mem::transmute;
+
+/// An unpacked inode
+///
+/// Timestamps are in filesystem time units, as stored on disk.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Inode {
+    pub inum:           u64,
+    pub snapshot:       u32,
+    pub mode:           u16,
+    pub flags:          u32,
+    pub size:           u64,
+    pub sectors:        u64,
+    pub uid:            u32,
+    pub gid:            u32,
+    pub project:        u32,
+    pub nlink:          u32,
+    pub generation:     u32,
+    pub dev:            u32,
+    pub atime:          u64,
+    pub ctime:          u64,
+    pub mtime:          u64,
+    pub otime:          u64,
+    pub subvol:         u32,
+    pub parent_subvol:  u32,
+    pub dir:            u64,
+    pub dir_offset:     u64,
+}
+
+impl Inode {
+    pub(crate) fn from_unpacked(u: &c::bch_inode_unpacked, snapshot: u32) -> Inode {
+        Inode {
+            inum:           u.bi_inum,
+            snapshot,
+            mode:           u.bi_mode,
+            flags:          u.bi_flags,
+            size:           u.bi_size,
+            sectors:        u.bi_sectors,
+            uid:            u.bi_uid,
+            gid:            u.bi_gid,
+            project:        u.bi_project,
+            nlink:          u.bi_nlink,
+            generation:     u.bi_generation,
+            dev:            u.bi_dev,
+            atime:          u.bi_atime,
+            ctime:          u.bi_ctime,
+            mtime:          u.bi_mtime,
+            otime:          u.bi_otime,
+            subvol:         u.bi_subvol,
+            parent_subvol:  u.bi_parent_subvol,
+            dir:            u.bi_dir,
+            dir_offset:     u.bi_dir_offset,
+        }
+    }
+}
+
+const DT_DIR: u8 = 4;
+const DT_SUBVOL: u8 = 16;
+const S_IFMT: u16 = 0o170000;
+const S_IFDIR: u16 = 0o040000;
+
+impl Inode {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_unlinked(&self) -> bool {
+        self.flags & c::bch_inode_flags::BCH_INODE_unlinked as u32 != 0
+    }
+
+    /// The link count as seen by stat(): on disk, `nlink` is stored biased
+    /// by the minimum for the file type (1, or 2 for directories), and
+    /// unlinked inodes have the unlinked flag set instead
+    pub fn nlink(&self) -> u32 {
+        if self.is_unlinked() {
+            0
+        } else {
+            self.nlink + if self.is_dir() { 2 } else { 1 }
+        }
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+pub(crate) fn bkey_is_inode(k: &c::bkey) -> bool {
+    use c::bch_bkey_type::*;
+    let ty: c::bch_bkey_type = unsafe { transmute(k.type_ as u32) };
+
+    matches!(ty, KEY_TYPE_inode | KEY_TYPE_inode_v2 | KEY_TYPE_inode_v3)
+}
+
+impl Fs {
+    /// Look up and unpack an inode, as visible from the given snapshot
+    pub fn inode(&self, inode: u64, snapshot: u32) -> Result<Option<Inode>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_inodes,
+            spos(0, inode, snapshot),
+            BtreeIterFlags::SLOTS);
+        let k = match iter.peek_slot()? {
+            Some(k) if bkey_is_inode(k.k) => k,
+            _ => return Ok(None),
+        };
+
+        let mut u: c::bch_inode_unpacked = Default::default();
+        let ret = unsafe { c::bch2_inode_unpack(k.to_raw(), &mut u) };
+        if ret != 0 {
+            return Err(bch_errcode::from_ret(ret));
+        }
+
+        Ok(Some(Inode::from_unpacked(&u, k.k.p.snapshot)))
+    }
+
+    /// Count the links to an inode in the given snapshot, as stat() would
+    /// report them: the dirents pointing to it, plus for directories `.` and
+    /// the `..` of each subdirectory
+    ///
+    /// This scans the whole dirents btree.
+    pub fn count_links(&self, inode: u64, snapshot: u32) -> Result<u32, bch_errcode> {
+        let (links, subdirs) = self.count_dirents(inode, snapshot)?;
+
+        let is_dir = self.inode(inode, snapshot)?
+            .ok_or(bch_errcode::BCH_ERR_ENOENT_inode)?
+            .is_dir();
+
+        Ok(if is_dir { links + 1 + subdirs } else { links })
+    }
+
+    /* (dirents pointing to @inode, subdirectories of @inode): */
+    fn count_dirents(&self, inode: u64, snapshot: u32) -> Result<(u32, u32), bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_dirents,
+            spos(0, 0, snapshot),
+            BtreeIterFlags::empty());
+        let mut links = 0;
+        let mut subdirs = 0;
+
+        while let Some(k) = iter.peek_and_restart()? {
+            if let BkeyValC::dirent(d) = k.v() {
+                if d.d_type != DT_SUBVOL {
+                    if u64::from_le(unsafe { d.__bindgen_anon_1.d_inum }) == inode {
+                        links += 1;
+                    }
+                    if k.k.p.inode == inode && d.d_type == DT_DIR {
+                        subdirs += 1;
+                    }
+                }
+            }
+            iter.advance();
+        }
+
+        Ok((links, subdirs))
+    }
+
+    /// Return the (stored, actual) link counts of an inode, for spotting
+    /// mismatches
+    pub fn check_nlink(&self, inode: u64, snapshot: u32) -> Result<(u32, u32), bch_errcode> {
+        let stored = self.inode(inode, snapshot)?
+            .ok_or(bch_errcode::BCH_ERR_ENOENT_inode)?
+            .nlink();
+
+        Ok((stored, self.count_links(inode, snapshot)?))
+    }
+
+    /// Link an orphaned inode - one with no dirents pointing to it - into
+    /// directory `parent` as `name`, in subvolume `subvol`
+    ///
+    /// The dirent, the inode's link count and backpointer, and the parent's
+    /// timestamps (and link count, for directories) are all updated in one
+    /// transaction. Fails with [`BchError::InodeLinked`] if the inode is
+    /// still linked somewhere, `EEXIST` if `name` already exists in `parent`,
+    /// `ENOENT_not_directory` if `parent` isn't a directory, and `ENOENT` if
+    /// either inode doesn't exist.
+    ///
+    /// Checking for existing links scans the whole dirents btree, before and
+    /// outside the transaction that creates the new link: the filesystem
+    /// must be quiescent, as for fsck. A link created concurrently between
+    /// the check and the commit would leave the inode with two dirents and a
+    /// link count of one.
+    pub fn relink_orphan(&self, subvol: u32, inode: u64, parent: u64, name: &str) -> Result<(), BchError> {
+        let snapshot = self.subvol_snapshot(subvol)?;
+
+        self.inode(inode, snapshot)?
+            .ok_or(bch_errcode::BCH_ERR_ENOENT_inode)?;
+
+        if self.count_dirents(inode, snapshot)?.0 != 0 {
+            return Err(BchError::InodeLinked);
+        }
+
+        let name = CString::new(name).map_err(|_| BchError::Invalid)?;
+        let trans = BtreeTrans::new(self);
+
+        loop {
+            trans.begin_shared();
+
+            let ret = ret_to_bch_result(unsafe {
+                c::bch2_relink_inode_trans(trans.raw(), subvol, parent, inode, name.as_ptr())
+            }).and_then(|_| trans.commit(0).map_err(BchError::from));
+
+            match ret {
+                Err(BchError::TransactionRestart) => continue,
+                r => return r,
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use crate::c;
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::spos;
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::io;
+
+/// A zeroed, page aligned buffer, for reads that go straight to the device
+pub(crate) struct AlignedBuf {
+    ptr:    *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    pub(crate) fn new(size: usize) -> AlignedBuf {
+        let layout = Layout::from_size_align(size.max(1), 4096).unwrap();
+        let ptr = unsafe { alloc_zeroed(layout) };
+
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        AlignedBuf { ptr, layout }
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) }
+    }
+}
+
+impl Fs {
+    pub fn block_bytes(&self) -> u64 {
+        unsafe { (*self.raw).opts.block_size as u64 }
+    }
+
+    /// Bytes per sector, the unit of offsets in extents-style btrees: the
+    /// superblock gives the block size both in bytes and in sectors
+    fn sector_bytes(&self) -> u64 {
+        let block_sectors = unsafe { u16::from_le((*(*self.raw).disk_sb.sb).block_size) };
+
+        self.block_bytes() / block_sectors as u64
+    }
+
+    /// The offset of `p` in bytes, in an extents-style btree (extents,
+    /// reflink): for an extent key, this is where the extent ends
+    pub fn offset_bytes(&self, p: c::bpos) -> u64 {
+        p.offset * self.sector_bytes()
+    }
+
+    /// The position of the start of the filesystem block containing byte
+    /// `bytes` of `inode`, in an extents-style btree
+    pub fn pos_at_byte(&self, inode: u64, bytes: u64, snapshot: u32) -> c::bpos {
+        let block = self.block_bytes();
+
+        spos(inode, bytes / block * block / self.sector_bytes(), snapshot)
+    }
+
+    /// Read file data through the normal read path (decompressing, decrypting
+    /// and verifying checksums), like pread()
+    ///
+    /// `snapshot` must be a leaf snapshot, i.e. belong to a subvolume. Holes
+    /// read as zeroes; returns a short count when reading past EOF.
+    pub fn read_at(&self, inode: u64, snapshot: u32, offset: u64, buf: &mut [u8]) -> Result<usize, bch_errcode> {
+        let subvol = self.snapshot_subvol(snapshot)?
+            .ok_or(bch_errcode::BCH_ERR_ENOENT_not_subvol)?;
+        let i_size = self.inode(inode, snapshot)?
+            .ok_or(bch_errcode::BCH_ERR_ENOENT_inode)?
+            .size;
+
+        self.read_at_subvol(subvol, inode, i_size, offset, buf)
+    }
+
+    fn read_at_subvol(&self, subvol: u32, inode: u64, i_size: u64, offset: u64, buf: &mut [u8]) -> Result<usize, bch_errcode> {
+        let end = i_size.min(offset + buf.len() as u64);
+        if end <= offset {
+            return Ok(0);
+        }
+        let len = (end - offset) as usize;
+
+        let block = self.block_bytes();
+        let aligned_start = offset / block * block;
+        let aligned_end = (end + block - 1) / block * block;
+        let pad_start = (offset - aligned_start) as usize;
+
+        let bounce = AlignedBuf::new((aligned_end - aligned_start) as usize);
+        let ret = unsafe {
+            c::bch2_read_buf(self.raw, subvol, inode,
+                aligned_start, (aligned_end - aligned_start) as usize,
+                bounce.ptr as *mut _)
+        };
+        if ret != 0 {
+            return Err(bch_errcode::from_ret(ret));
+        }
+
+        buf[..len].copy_from_slice(&bounce.as_slice()[pad_start..pad_start + len]);
+        Ok(len)
+    }
+}
+
+/// A file's contents, as a byte stream: see [`Fs::open_file`]
+pub struct FileReader<'f> {
+    fs:     &'f Fs,
+    subvol: u32,
+    inode:  u64,
+    size:   u64,
+    pos:    u64,
+}
+
+impl Fs {
+    /// Open a regular file for reading with [`std::io::Read`] and
+    /// [`std::io::Seek`], e.g. to copy it out with `std::io::copy`
+    ///
+    /// The file size is read once, at open time.
+    pub fn open_file(&self, inode: u64, snapshot: u32) -> Result<FileReader, bch_errcode> {
+        let subvol = self.snapshot_subvol(snapshot)?
+            .ok_or(bch_errcode::BCH_ERR_ENOENT_not_subvol)?;
+        let size = self.inode(inode, snapshot)?
+            .ok_or(bch_errcode::BCH_ERR_ENOENT_inode)?
+            .size;
+
+        Ok(FileReader { fs: self, subvol, inode, size, pos: 0 })
+    }
+}
+
+impl<'f> FileReader<'f> {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<'f> io::Read for FileReader<'f> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        /* Bound the size of the bounce buffer: */
+        let len = buf.len().min(1 << 20);
+
+        let n = self.fs.read_at_subvol(self.subvol, self.inode, self.size, self.pos, &mut buf[..len])
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'f> io::Seek for FileReader<'f> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            io::SeekFrom::Start(p)      => Some(p),
+            io::SeekFrom::End(d)        => self.size.checked_add_signed(d),
+            io::SeekFrom::Current(d)    => self.pos.checked_add_signed(d),
+        };
+
+        self.pos = new.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        Ok(self.pos)
+    }
+}
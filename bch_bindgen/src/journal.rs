@@ -0,0 +1,350 @@
+use crate::c;
+use crate::bkey::BkeySC;
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use std::mem::{size_of, transmute};
+
+/// Journal space on one member device
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JournalDevStats {
+    pub dev:                u32,
+    /// Journal buckets on this device
+    pub nr_buckets:         u32,
+    /// In sectors
+    pub bucket_size:        u32,
+    /// Space left in the bucket currently being written, in sectors
+    pub sectors_free:       u32,
+    /// Indices into the device's journal buckets, in ring order: buckets
+    /// from `discard_idx` to `dirty_idx_ondisk` can be discarded, and from
+    /// `dirty_idx` to `cur_idx` still hold entries that are needed
+    pub discard_idx:        u32,
+    pub dirty_idx_ondisk:   u32,
+    pub dirty_idx:          u32,
+    pub cur_idx:            u32,
+}
+
+impl JournalDevStats {
+    /// Buckets holding journal entries that haven't been reclaimed yet,
+    /// including the current one
+    pub fn dirty_buckets(&self) -> u32 {
+        if self.nr_buckets == 0 {
+            return 0;
+        }
+        (self.cur_idx + self.nr_buckets - self.dirty_idx) % self.nr_buckets + 1
+    }
+}
+
+/// Journal state, see [`Fs::journal_stats`]
+///
+/// Write counts are since the filesystem was opened.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JournalStats {
+    /// Sequence number of the current (open) journal entry
+    pub seq:                    u64,
+    pub seq_ondisk:             u64,
+    /// Oldest entry still pinned: entries before it have been reclaimed
+    pub last_seq:               u64,
+    pub last_seq_ondisk:        u64,
+    pub flushed_seq_ondisk:     u64,
+    /// Entries not yet reclaimed, and the most there can be
+    pub dirty_entries:          u64,
+    pub max_dirty_entries:      u64,
+    pub nr_flush_writes:        u64,
+    pub nr_noflush_writes:      u64,
+    pub bytes_written:          u64,
+    pub nr_direct_reclaim:      u64,
+    pub nr_background_reclaim:  u64,
+    /// Max size of the current entry, in sectors
+    pub cur_entry_sectors:      u32,
+    /// Nonzero if new journal entries can't be opened
+    pub blocked:                u32,
+    /// Every device with journal buckets
+    pub devices:                Vec<JournalDevStats>,
+}
+
+impl JournalStats {
+    /// Average size of a journal write, in bytes
+    pub fn avg_entry_bytes(&self) -> u64 {
+        let nr = self.nr_flush_writes + self.nr_noflush_writes;
+
+        if nr != 0 { self.bytes_written / nr } else { 0 }
+    }
+}
+
+impl Fs {
+    pub fn journal_stats(&self) -> Result<JournalStats, bch_errcode> {
+        let mut s: c::bch_journal_stats = Default::default();
+        unsafe { c::bch2_journal_stats_read(self.raw, &mut s) };
+
+        let mut devices = Vec::new();
+        let nr_devices = unsafe { (*self.raw).sb.nr_devices as u32 };
+
+        for dev in 0..nr_devices {
+            let mut d: c::bch_journal_dev_stats = Default::default();
+
+            match unsafe { c::bch2_journal_dev_stats_read(self.raw, dev, &mut d) } {
+                0 if d.nr != 0 => devices.push(JournalDevStats {
+                    dev,
+                    nr_buckets:         d.nr,
+                    bucket_size:        d.bucket_size,
+                    sectors_free:       d.sectors_free,
+                    discard_idx:        d.discard_idx,
+                    dirty_idx_ondisk:   d.dirty_idx_ondisk,
+                    dirty_idx:          d.dirty_idx,
+                    cur_idx:            d.cur_idx,
+                }),
+                0 => {}
+                ret => match bch_errcode::from_ret(ret) {
+                    bch_errcode::BCH_ERR_ENOENT_dev_idx_not_found => {}
+                    e => return Err(e),
+                },
+            }
+        }
+
+        Ok(JournalStats {
+            seq:                    s.seq,
+            seq_ondisk:             s.seq_ondisk,
+            last_seq:               s.last_seq,
+            last_seq_ondisk:        s.last_seq_ondisk,
+            flushed_seq_ondisk:     s.flushed_seq_ondisk,
+            dirty_entries:          s.dirty_entries,
+            max_dirty_entries:      s.max_dirty_entries,
+            nr_flush_writes:        s.nr_flush_writes,
+            nr_noflush_writes:      s.nr_noflush_writes,
+            bytes_written:          s.entry_bytes_written,
+            nr_direct_reclaim:      s.nr_direct_reclaim,
+            nr_background_reclaim:  s.nr_background_reclaim,
+            cur_entry_sectors:      s.cur_entry_sectors,
+            blocked:                s.blocked,
+            devices,
+        })
+    }
+
+    /// Force journal reclaim, returning the number of journal entries
+    /// reclaimed
+    ///
+    /// Runs journal reclaim directly, as when the journal is filling up: this
+    /// flushes what's pinning the oldest journal entries - dirty btree nodes,
+    /// key cache entries and the btree write buffer - up to reclaim's target
+    /// of keeping the journal at most half full. It then waits for the
+    /// resulting btree node writes, and writes a journal entry recording the
+    /// new `last_seq` so the space can be reused. In flight updates aren't
+    /// waited on: if nothing can be reclaimed this returns Ok(0) promptly.
+    /// Use [`Fs::sync`] to flush everything. Compare
+    /// [`Fs::journal_stats`] before and after to see the effect on
+    /// `dirty_entries` and per device dirty buckets.
+    ///
+    /// A read-only filesystem has nothing to reclaim, so this returns Ok(0).
+    pub fn journal_reclaim(&self) -> Result<u64, bch_errcode> {
+        let mut nr_reclaimed = 0;
+        let ret = unsafe { c::bch2_fs_journal_reclaim(self.raw, &mut nr_reclaimed) };
+
+        if ret != 0 {
+            return Err(bch_errcode::from_ret(ret));
+        }
+        Ok(nr_reclaimed)
+    }
+}
+
+/* enum from bcachefs_format.h: */
+const JSET_ENTRY_BTREE_KEYS: u8         = 0;
+const JSET_ENTRY_BTREE_ROOT: u8         = 1;
+const JSET_ENTRY_BLACKLIST: u8          = 3;
+const JSET_ENTRY_BLACKLIST_V2: u8       = 4;
+const JSET_ENTRY_CLOCK: u8              = 7;
+const JSET_ENTRY_LOG: u8                = 9;
+const JSET_ENTRY_OVERWRITE: u8          = 10;
+const JSET_ENTRY_WRITE_BUFFER_KEYS: u8  = 11;
+const JSET_ENTRY_DATETIME: u8           = 12;
+
+/// One item within a journal entry, see [`Jset::entries`]
+pub enum JsetEntry<'a> {
+    /// Btree updates
+    BtreeKeys {
+        btree:  c::btree_id,
+        level:  u32,
+        keys:   Vec<BkeySC<'a>>,
+    },
+    /// The keys the preceding `BtreeKeys` entry overwrote, with the
+    /// journal_transaction_names option
+    Overwrite {
+        btree:  c::btree_id,
+        level:  u32,
+        keys:   Vec<BkeySC<'a>>,
+    },
+    /// Updates going to the btree write buffer
+    WriteBufferKeys {
+        btree:  c::btree_id,
+        keys:   Vec<BkeySC<'a>>,
+    },
+    /// The pointer to a btree's root node
+    BtreeRoot {
+        btree:  c::btree_id,
+        level:  u32,
+        key:    Option<BkeySC<'a>>,
+    },
+    /// Journal sequence numbers to ignore, inclusive
+    Blacklist {
+        start:  u64,
+        end:    u64,
+    },
+    /// IO clock value; `rw` is 0 for reads and 1 for writes
+    Clock {
+        rw:     u8,
+        time:   u64,
+    },
+    /// A message, e.g. the name of the transaction that made the following
+    /// updates
+    Log(String),
+    /// Wall clock time the entry was written, in seconds since the epoch
+    Datetime(u64),
+    /// Anything else: usage accounting, or an entry for an unknown btree
+    Other {
+        type_:  u8,
+        /// Size of the entry's payload
+        u64s:   u16,
+    },
+}
+
+/// A journal entry, as read from disk at recovery time
+#[derive(Clone, Copy)]
+pub struct Jset<'f> {
+    raw:    &'f c::jset,
+}
+
+unsafe fn jset_entry_keys<'a>(e: *const c::jset_entry, end: *const u64) -> Vec<BkeySC<'a>> {
+    let mut k = (e as *const u64).add(1);
+    let mut ret = Vec::new();
+
+    while k < end {
+        let i = &*(k as *const c::bkey_i);
+        if i.k.u64s == 0 {
+            break;
+        }
+        ret.push(BkeySC::from(i));
+        k = k.add(i.k.u64s as usize);
+    }
+    ret
+}
+
+unsafe fn jset_entry_u64(e: *const c::jset_entry, idx: usize) -> u64 {
+    u64::from_le(*(e as *const u64).add(1 + idx))
+}
+
+impl<'f> Jset<'f> {
+    pub fn seq(&self) -> u64 {
+        u64::from_le(self.raw.seq)
+    }
+
+    /// Oldest entry that was still dirty when this one was written
+    pub fn last_seq(&self) -> u64 {
+        u64::from_le(self.raw.last_seq)
+    }
+
+    /// Decode the items in this entry, in order
+    pub fn entries(&self) -> Vec<JsetEntry<'f>> {
+        let mut ret = Vec::new();
+
+        unsafe {
+            let start = (self.raw as *const c::jset as *const u8).add(size_of::<c::jset>()) as *const u64;
+            let end = start.add(u32::from_le(self.raw.u64s) as usize);
+            let mut e = start as *const c::jset_entry;
+
+            while (e as *const u64) < end {
+                let u64s = u16::from_le((*e).u64s);
+                let e_end = (e as *const u64).add(1 + u64s as usize).min(end);
+                /* payload actually present, in u64s: u64s may overrun the jset */
+                let avail = (e_end as usize).saturating_sub((e as *const u64).add(1) as usize) / 8;
+                let btree = (*e).btree_id as u32;
+                let level = (*e).level as u32;
+                let type_ = (*e).type_;
+
+                let entry = if btree >= c::btree_id::BTREE_ID_NR as u32 &&
+                    [JSET_ENTRY_BTREE_KEYS,
+                     JSET_ENTRY_OVERWRITE,
+                     JSET_ENTRY_WRITE_BUFFER_KEYS,
+                     JSET_ENTRY_BTREE_ROOT].contains(&type_) {
+                    JsetEntry::Other { type_, u64s }
+                } else {
+                    match type_ {
+                        JSET_ENTRY_BTREE_KEYS => JsetEntry::BtreeKeys {
+                            btree: transmute(btree), level, keys: jset_entry_keys(e, e_end),
+                        },
+                        JSET_ENTRY_OVERWRITE => JsetEntry::Overwrite {
+                            btree: transmute(btree), level, keys: jset_entry_keys(e, e_end),
+                        },
+                        JSET_ENTRY_WRITE_BUFFER_KEYS => JsetEntry::WriteBufferKeys {
+                            btree: transmute(btree), keys: jset_entry_keys(e, e_end),
+                        },
+                        JSET_ENTRY_BTREE_ROOT => JsetEntry::BtreeRoot {
+                            btree: transmute(btree), level, key: jset_entry_keys(e, e_end).into_iter().next(),
+                        },
+                        JSET_ENTRY_BLACKLIST if avail >= 1 => {
+                            let seq = jset_entry_u64(e, 0);
+                            JsetEntry::Blacklist { start: seq, end: seq }
+                        }
+                        JSET_ENTRY_BLACKLIST_V2 if avail >= 2 => JsetEntry::Blacklist {
+                            start:  jset_entry_u64(e, 0),
+                            end:    jset_entry_u64(e, 1),
+                        },
+                        JSET_ENTRY_CLOCK if avail >= 2 => JsetEntry::Clock {
+                            rw:     *((e as *const u8).add(size_of::<c::jset_entry>())),
+                            time:   jset_entry_u64(e, 1),
+                        },
+                        JSET_ENTRY_LOG => {
+                            let d = std::slice::from_raw_parts((e as *const u64).add(1) as *const u8,
+                                                               avail * 8);
+                            let len = d.iter().position(|&b| b == 0).unwrap_or(d.len());
+                            JsetEntry::Log(String::from_utf8_lossy(&d[..len]).into_owned())
+                        }
+                        JSET_ENTRY_DATETIME if avail >= 1 => JsetEntry::Datetime(jset_entry_u64(e, 0)),
+                        _ => JsetEntry::Other { type_, u64s },
+                    }
+                };
+
+                ret.push(entry);
+                e = e_end as *const c::jset_entry;
+            }
+        }
+        ret
+    }
+}
+
+impl<'f> std::fmt::Debug for Jset<'f> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Jset")
+            .field("seq", &self.seq())
+            .field("last_seq", &self.last_seq())
+            .finish()
+    }
+}
+
+/// Iterator over the journal entries read at recovery time, see
+/// [`Fs::journal_entries`]
+pub struct JournalEntries<'f> {
+    fs:     &'f Fs,
+    idx:    usize,
+}
+
+impl<'f> Iterator for JournalEntries<'f> {
+    type Item = Jset<'f>;
+
+    fn next(&mut self) -> Option<Jset<'f>> {
+        let j = unsafe { c::bch2_journal_entry_next(self.fs.raw, &mut self.idx) };
+
+        (!j.is_null()).then(|| Jset { raw: unsafe { &*j } })
+    }
+}
+
+impl Fs {
+    /// The journal entries read when the filesystem was opened, oldest first,
+    /// skipping blacklisted entries
+    ///
+    /// Entries are normally freed once recovery is done: the filesystem must
+    /// be opened with the `keep_journal` option, and it only reads the
+    /// journal if it wasn't cleanly shut down (or with `fsck`). Otherwise
+    /// this is empty.
+    pub fn journal_entries(&self) -> JournalEntries<'_> {
+        JournalEntries { fs: self, idx: 0 }
+    }
+}
@@ -0,0 +1,79 @@
+use crate::c;
+use crate::PosRange;
+use crate::bkey::BkeySC;
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use serde::Serialize;
+use std::ffi::CStr;
+use std::io::Write;
+
+/// One line of [`BtreeTrans::list_json`] output
+#[derive(Serialize)]
+struct JsonKey {
+    /// Key type name; None for types this version doesn't know
+    #[serde(rename = "type")]
+    ty:         Option<String>,
+    type_id:    u8,
+    pos:        c::bpos,
+    size:       u32,
+    /// Decoded value, as printed by `bcachefs list`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value:      Option<String>,
+    /// Raw value, for unknown key types
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_hex:  Option<String>,
+}
+
+impl JsonKey {
+    fn new(fs: *mut c::bch_fs, k: &BkeySC) -> JsonKey {
+        let known = (k.k.type_ as u32) < c::bch_bkey_type::KEY_TYPE_MAX as u32;
+
+        let (ty, value, value_hex) = if known {
+            let ty: c::bch_bkey_type = unsafe { std::mem::transmute(k.k.type_ as u32) };
+            let mut buf = c::printbuf::new();
+
+            unsafe { c::bch2_val_to_text(&mut buf, fs, k.to_raw()) };
+            let value = if buf.buf.is_null() {
+                String::new()
+            } else {
+                unsafe { CStr::from_ptr(buf.buf) }.to_string_lossy().into_owned()
+            };
+
+            (Some(ty.to_string()), Some(value), None)
+        } else {
+            let val_bytes = (k.k.u64s as usize - std::mem::size_of::<c::bkey>() / 8) * 8;
+            let val = unsafe { std::slice::from_raw_parts(k.v as *const c::bch_val as *const u8, val_bytes) };
+
+            (None, None, Some(val.iter().map(|b| format!("{:02x}", b)).collect()))
+        };
+
+        JsonKey {
+            ty,
+            type_id:    k.k.type_,
+            pos:        k.k.p,
+            size:       k.k.size,
+            value,
+            value_hex,
+        }
+    }
+}
+
+impl<'f> BtreeTrans<'f> {
+    /// Write the keys in `range` to `writer` as JSON lines: one object per
+    /// key, with its type, position, size and decoded value
+    ///
+    /// Keys are written as they're read, so output can be piped to e.g. `jq`
+    /// without holding the btree in memory. Keys of types this version
+    /// doesn't know are written with their type number and value in hex.
+    pub fn list_json<W: Write>(&self, btree: c::btree_id, range: PosRange, flags: BtreeIterFlags, mut writer: W) -> anyhow::Result<()> {
+        let mut iter = BtreeIter::new_range(self, btree, range, flags);
+
+        while let Some(k) = iter.peek_and_restart()? {
+            serde_json::to_writer(&mut writer, &JsonKey::new(self.fs_raw(), &k))?;
+            writer.write_all(b"\n")?;
+            iter.advance();
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,52 @@
+use crate::c;
+use crate::errcode::{bch_errcode, ret_to_result};
+use crate::fs::Fs;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// Btree key cache counters, see [`Fs::key_cache_stats`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyCacheStats {
+    /// Keys currently cached
+    pub nr_keys:    u64,
+    /// Cached keys with updates not yet written back to the btree; these pin
+    /// the journal until flushed
+    pub nr_dirty:   u64,
+    /// Freed entries waiting to be reused or released
+    pub nr_freed:   u64,
+}
+
+fn atomic_long_read(v: &i64) -> u64 {
+    let p = v as *const i64 as *const AtomicI64;
+    unsafe { (*p).load(Ordering::Relaxed) }.max(0) as u64
+}
+
+impl Fs {
+    /// Current size of the btree key cache, used for iterators with the
+    /// `CACHED` flag (e.g. the alloc and subvolumes btrees)
+    pub fn key_cache_stats(&self) -> KeyCacheStats {
+        let bc = unsafe { &(*self.raw).btree_key_cache };
+
+        KeyCacheStats {
+            nr_keys:    atomic_long_read(&bc.nr_keys.counter),
+            nr_dirty:   atomic_long_read(&bc.nr_dirty.counter),
+            nr_freed:   atomic_long_read(&bc.nr_freed.counter),
+        }
+    }
+
+    /// Write back all dirty key cache entries to the btree
+    ///
+    /// This flushes every journal pin, which is how the key cache is written
+    /// back, so it also flushes btree node writes and anything else pinning
+    /// the journal, then waits for a journal write. Safe to call while the
+    /// filesystem is in use; entries dirtied concurrently may remain dirty.
+    pub fn flush_key_cache(&self) -> Result<(), bch_errcode> {
+        let ret = unsafe {
+            let j = &mut (*self.raw).journal;
+
+            c::bch2_journal_flush_pins(j, u64::MAX);
+            /* and make the result durable, or report a journal error: */
+            c::bch2_journal_flush(j)
+        };
+        ret_to_result(ret).map(|_| ())
+    }
+}
@@ -0,0 +1,107 @@
+use crate::c;
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::superblock::label_to_string;
+use anyhow::anyhow;
+use std::ffi::{CStr, CString};
+
+const LABEL_SIZE: usize = c::BCH_SB_LABEL_SIZE as usize;
+
+impl Fs {
+    /// The filesystem label
+    pub fn label(&self) -> Result<String, bch_errcode> {
+        Ok(label_to_string(unsafe { &(*(*self.raw).disk_sb.sb).label }))
+    }
+
+    /// Set the filesystem label, and write the superblock
+    ///
+    /// The filesystem must have been opened read-write for this to persist.
+    pub fn set_label(&self, label: &str) -> anyhow::Result<()> {
+        if label.len() > LABEL_SIZE {
+            return Err(anyhow!("label {:?} too long: {} bytes, max {}", label, label.len(), LABEL_SIZE));
+        }
+
+        let label = CString::new(label)?;
+        let ret = unsafe { c::bch2_fs_label_set(self.raw, label.as_ptr()) };
+        if ret != 0 {
+            let err = bch_errcode::from_ret(ret);
+            return Err(anyhow!(err));
+        }
+        Ok(())
+    }
+
+    /// The user visible filesystem UUID
+    pub fn uuid(&self) -> uuid::Uuid {
+        uuid::Uuid::from_bytes(unsafe { (*(*self.raw).disk_sb.sb).user_uuid.b })
+    }
+
+    /// Change the user visible filesystem UUID, and write the superblock to
+    /// all members
+    ///
+    /// **Warning:** this must only be done on a filesystem that isn't mounted
+    /// or otherwise in use anywhere else: the kernel, udev, `/etc/fstab`
+    /// entries, and anything else that finds the filesystem by UUID will lose
+    /// track of it. Never change the UUID of a filesystem whose devices are
+    /// shared with another host.
+    ///
+    /// The internal UUID, used in metadata magic numbers, is left unchanged.
+    /// If the superblock write fails the old UUID is written back, so that
+    /// members don't disagree.
+    pub fn set_uuid(&self, uuid: uuid::Uuid) -> anyhow::Result<()> {
+        if uuid.is_nil() {
+            return Err(anyhow!("refusing to set nil filesystem UUID"));
+        }
+
+        let uuid = c::__uuid_t { b: *uuid.as_bytes() };
+        let ret = unsafe { c::bch2_fs_uuid_set(self.raw, &uuid) };
+        if ret != 0 {
+            let err = bch_errcode::from_ret(ret);
+            return Err(anyhow!(err));
+        }
+        Ok(())
+    }
+
+    /// The label (disk group path, e.g. "ssd.fast") of a member device, if any
+    pub fn device_label(&self, dev_idx: u32) -> Result<Option<String>, bch_errcode> {
+        unsafe {
+            let sb = (*self.raw).disk_sb.sb;
+
+            if dev_idx >= (*sb).nr_devices as u32 {
+                return Err(bch_errcode::BCH_ERR_ENOENT_dev_idx_not_found);
+            }
+
+            let m = c::bch2_sb_member_get(sb, dev_idx as i32);
+            let group = (u64::from_le(m.flags) >> 20) & ((1 << 8) - 1);
+            if group == 0 {
+                return Ok(None);
+            }
+
+            let mut buf = c::printbuf::new();
+            c::bch2_disk_path_to_text_sb(&mut buf, sb, group as u32 - 1);
+            Ok(Some(CStr::from_ptr(buf.buf).to_string_lossy().into_owned()))
+        }
+    }
+
+    /// Set the label of a member device, creating disk groups as needed, and
+    /// write the superblock
+    ///
+    /// Each '.' separated component must fit in a disk group label.
+    pub fn set_device_label(&self, dev_idx: u32, label: &str) -> anyhow::Result<()> {
+        if let Some(p) = label.split('.').find(|p| p.len() > LABEL_SIZE) {
+            return Err(anyhow!("label component {:?} too long: {} bytes, max {}", p, p.len(), LABEL_SIZE));
+        }
+
+        let ca = unsafe { (*self.raw).devs.get(dev_idx as usize).copied().unwrap_or(std::ptr::null_mut()) };
+        if ca.is_null() {
+            return Err(anyhow!(bch_errcode::BCH_ERR_ENOENT_dev_idx_not_found));
+        }
+
+        let label = CString::new(label)?;
+        let ret = unsafe { c::bch2_dev_group_set(self.raw, ca, label.as_ptr()) };
+        if ret != 0 {
+            let err = bch_errcode::from_ret(ret);
+            return Err(anyhow!(err));
+        }
+        Ok(())
+    }
+}
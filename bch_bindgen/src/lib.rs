@@ -1,11 +1,49 @@
+#[cfg(feature = "tokio")]
+pub mod async_iter;
 pub mod bcachefs;
 pub mod btree;
 pub mod bkey;
+pub mod buckets;
+pub mod bulk;
+pub mod cancel;
+pub mod clock;
+pub mod counters;
+pub mod crypt;
+pub mod device;
+pub mod disk_groups;
+pub mod dump;
 pub mod errcode;
+pub mod events;
+pub mod export;
+pub mod extents;
 pub mod keyutils;
+pub mod logged_ops;
+pub mod label;
 pub mod sb_io;
+pub mod snapshot;
+pub mod stripe;
+pub mod superblock;
 pub mod fs;
+pub mod fsck;
+pub mod inode;
+pub mod io;
+pub mod journal;
+pub mod key_cache;
+#[cfg(feature = "serde")]
+pub mod json;
 pub mod opts;
+pub mod quota;
+pub mod recovery;
+pub mod reflink;
+pub mod replicas;
+pub mod report;
+pub mod scrub;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod time_stats;
+pub mod usage;
+pub mod verify;
+pub mod xattr;
 pub use paste::paste;
 
 pub mod c {
@@ -26,6 +64,112 @@ pub const POS_MIN:  Bpos = spos(0, 0, 0);
 pub const POS_MAX:  Bpos = spos(u64::MAX, u64::MAX, 0);
 pub const SPOS_MAX: Bpos = spos(u64::MAX, u64::MAX, u32::MAX);
 
+/*
+ * Same as the C helpers in bkey.h: incrementing/decrementing carries from
+ * snapshot into offset into inode, and going past SPOS_MAX/POS_MIN is a bug:
+ */
+impl c::bpos {
+    /// The next position, counting the snapshot field
+    pub fn successor(&self) -> Bpos {
+        let (snapshot, c) = self.snapshot.overflowing_add(1);
+        let (offset, c) = self.offset.overflowing_add(c as u64);
+        let (inode, c) = self.inode.overflowing_add(c as u64);
+        assert!(!c, "successor of SPOS_MAX");
+
+        spos(inode, offset, snapshot)
+    }
+
+    /// The previous position, counting the snapshot field
+    pub fn predecessor(&self) -> Bpos {
+        let (snapshot, b) = self.snapshot.overflowing_sub(1);
+        let (offset, b) = self.offset.overflowing_sub(b as u64);
+        let (inode, b) = self.inode.overflowing_sub(b as u64);
+        assert!(!b, "predecessor of POS_MIN");
+
+        spos(inode, offset, snapshot)
+    }
+
+    /// The next position ignoring snapshots; the result has snapshot 0
+    pub fn nosnap_successor(&self) -> Bpos {
+        let (offset, c) = self.offset.overflowing_add(1);
+        let (inode, c) = self.inode.overflowing_add(c as u64);
+        assert!(!c, "nosnap successor of POS_MAX");
+
+        pos(inode, offset)
+    }
+
+    /// The previous position ignoring snapshots; the result has snapshot 0
+    pub fn nosnap_predecessor(&self) -> Bpos {
+        let (offset, b) = self.offset.overflowing_sub(1);
+        let (inode, b) = self.inode.overflowing_sub(b as u64);
+        assert!(!b, "nosnap predecessor of POS_MIN");
+
+        pos(inode, offset)
+    }
+}
+
+impl c::bkey {
+    /// Where the key starts: its position minus its size, for extents
+    ///
+    /// Fails with `BCH_ERR_invalid_bkey` if the size is bigger than the
+    /// offset, which only a corrupt key can have.
+    pub fn start_pos(&self) -> Result<Bpos, errcode::bch_errcode> {
+        let (p, size) = (self.p, self.size);
+        let offset = p.offset.checked_sub(size as u64)
+            .ok_or(errcode::bch_errcode::BCH_ERR_invalid_bkey)?;
+
+        Ok(spos(p.inode, offset, p.snapshot))
+    }
+
+    /// The position after this key, as the iterator advances past it: for
+    /// extents (nonzero size) the key's position, as extents end there;
+    /// otherwise the next position in all snapshots if `snapshot` is None, or
+    /// the next position in `snapshot`
+    pub fn successor(&self, snapshot: Option<u32>) -> Bpos {
+        let (p, size) = (self.p, self.size);
+
+        if size != 0 {
+            return p;
+        }
+
+        match snapshot {
+            None    => p.successor(),
+            Some(s) => c::bpos { snapshot: s, ..p.nosnap_successor() },
+        }
+    }
+}
+
+/// A range of btree positions, for [`btree::BtreeIter::new_range`]
+///
+/// Both ends are inclusive, matching `bch2_btree_iter_peek_upto()`: a key is in
+/// range if its position is <= `end` - except in extents btrees, where an
+/// extent is in range if it starts before `end`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PosRange {
+    pub start:  Bpos,
+    pub end:    Bpos,
+}
+
+impl PosRange {
+    pub const fn new(start: Bpos, end: Bpos) -> PosRange {
+        PosRange { start, end }
+    }
+
+    /// Every position in a btree
+    pub const fn all() -> PosRange {
+        PosRange::new(POS_MIN, SPOS_MAX)
+    }
+
+    /// Every key for a single inode, in all snapshots
+    pub const fn inode(inode: u64) -> PosRange {
+        PosRange::new(spos(inode, 0, 0), spos(inode, u64::MAX, u32::MAX))
+    }
+
+    pub fn contains(&self, pos: Bpos) -> bool {
+        self.start <= pos && pos <= self.end
+    }
+}
+
 use std::cmp::Ordering;
 
 impl PartialEq for Bpos {
@@ -68,8 +212,19 @@ impl fmt::Display for c::btree_id {
     }
 }
 
+impl fmt::Display for c::bch_data_type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if (*self as u32) < c::bch_data_type::BCH_DATA_NR as u32 {
+            let s = unsafe { CStr::from_ptr(*c::__bch2_data_types.as_ptr().add(*self as usize)) };
+            let s = s.to_str().unwrap();
+            write!(f, "{}", s)
+        } else {
+            write!(f, "(invalid data type)")
+        }
+    }
+}
+
 use std::str::FromStr;
-use std::ffi::CString;
 
 use std::error::Error;
 
@@ -85,22 +240,81 @@ impl fmt::Display for InvalidBtreeId {
 impl Error for InvalidBtreeId {
 }
 
+/// Error for a name not found in one of the C name tables (btree ids, key
+/// types), listing the valid names
+#[derive(Debug)]
+pub struct InvalidName {
+    pub kind:   &'static str,
+    pub name:   String,
+    pub valid:  Vec<String>,
+}
+
+impl fmt::Display for InvalidName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {} {:?}; valid {}s are: {}", self.kind, self.name, self.kind, self.valid.join(", "))
+    }
+}
+
+impl Error for InvalidName {
+}
+
+/// The names in a NULL terminated C string array
+fn name_table(table: *const *const std::os::raw::c_char) -> Vec<String> {
+    let mut ret = Vec::new();
+
+    unsafe {
+        let mut p = table;
+        while !(*p).is_null() {
+            ret.push(CStr::from_ptr(*p).to_string_lossy().into_owned());
+            p = p.add(1);
+        }
+    }
+    ret
+}
+
+/// Case insensitive lookup in a C name table, returning the index
+fn name_table_lookup(kind: &'static str, table: *const *const std::os::raw::c_char, s: &str) -> Result<usize, InvalidName> {
+    let names = name_table(table);
+
+    names.iter()
+        .position(|n| n.eq_ignore_ascii_case(s))
+        .ok_or_else(|| InvalidName { kind, name: s.to_string(), valid: names })
+}
+
 impl FromStr for c::btree_id {
-    type Err = InvalidBtreeId;
+    type Err = InvalidName;
 
+    /// Parse a btree name, e.g. "extents", case insensitively
+    ///
+    /// Accepts every btree this version knows about, whether or not it
+    /// exists on a particular filesystem.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let s = CString::new(s).unwrap();
-        let p = s.as_ptr();
+        let v = name_table_lookup("btree id", unsafe { c::__bch2_btree_ids.as_ptr() }, s)?;
+        Ok(unsafe { std::mem::transmute(v as u32) })
+    }
+}
 
-        let v = unsafe {c::match_string(c::__bch2_btree_ids[..].as_ptr(), (-(1 as isize)) as usize, p)};
-        if v >= 0 {
-            Ok(unsafe { std::mem::transmute(v) })
+impl fmt::Display for c::bch_bkey_type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if (*self as u32) < c::bch_bkey_type::KEY_TYPE_MAX as u32 {
+            let s = unsafe { CStr::from_ptr(*c::bch2_bkey_types.as_ptr().add(*self as usize)) };
+            write!(f, "{}", s.to_string_lossy())
         } else {
-            Err(InvalidBtreeId)
+            write!(f, "(invalid key type)")
         }
     }
 }
 
+impl FromStr for c::bch_bkey_type {
+    type Err = InvalidName;
+
+    /// Parse a key type name, e.g. "extent", case insensitively
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v = name_table_lookup("key type", unsafe { c::bch2_bkey_types.as_ptr() }, s)?;
+        Ok(unsafe { std::mem::transmute(v as u32) })
+    }
+}
+
 impl c::printbuf {
     fn new() -> c::printbuf {
         let mut buf: c::printbuf = Default::default();
@@ -128,10 +342,27 @@ impl fmt::Display for Bpos {
     }
 }
 
+/// Error for a string that isn't a position, see [`c::bpos`]'s `FromStr`
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidBpos(pub String);
+
+impl fmt::Display for InvalidBpos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid position {:?}; expected inode:offset[:snapshot], POS_MIN, POS_MAX or SPOS_MAX", self.0)
+    }
+}
+
+impl Error for InvalidBpos {
+}
+
 impl FromStr for c::bpos {
-    type Err = InvalidBtreeId;
+    type Err = InvalidBpos;
 
+    /// Parse a position: `inode:offset[:snapshot]`, or one of `POS_MIN`,
+    /// `POS_MAX` and `SPOS_MAX`
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidBpos(s.to_string());
+
         if s == "POS_MIN" {
             return Ok(POS_MIN);
         }
@@ -145,18 +376,49 @@ impl FromStr for c::bpos {
         }
 
         let mut fields = s.split(':');
-        let ino_str = fields.next().ok_or(InvalidBtreeId)?;
-        let off_str = fields.next().ok_or(InvalidBtreeId)?;
+        let ino_str = fields.next().ok_or_else(invalid)?;
+        let off_str = fields.next().ok_or_else(invalid)?;
         let snp_str = fields.next();
 
-        let ino: u64    = ino_str.parse().map_err(|_| InvalidBtreeId)?;
-        let off: u64    = off_str.parse().map_err(|_| InvalidBtreeId)?;
-        let snp: u32    = snp_str.map(|s| s.parse().ok()).flatten().unwrap_or(0);
+        if fields.next().is_some() {
+            return Err(invalid());
+        }
+
+        let ino: u64    = ino_str.parse().map_err(|_| invalid())?;
+        let off: u64    = off_str.parse().map_err(|_| invalid())?;
+        let snp: u32    = snp_str.map(|s| s.parse()).transpose().map_err(|_| invalid())?.unwrap_or(0);
 
         Ok(c::bpos { inode: ino, offset: off, snapshot: snp })
     }
 }
 
+/// Positions serialize as `inode:offset:snapshot`, which parses back with
+/// `FromStr`
+#[cfg(feature = "serde")]
+impl serde::Serialize for Bpos {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let (inode, offset, snapshot) = (self.inode, self.offset, self.snapshot);
+        s.collect_str(&format_args!("{}:{}:{}", inode, offset, snapshot))
+    }
+}
+
+/// Checksums serialize as hex
+#[cfg(feature = "serde")]
+impl serde::Serialize for c::bch_csum {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let (hi, lo) = (self.hi, self.lo);
+        s.collect_str(&format_args!("{:016x}{:016x}", u64::from_le(hi), u64::from_le(lo)))
+    }
+}
+
+/// Data types serialize by name, as printed by `Display`
+#[cfg(feature = "serde")]
+impl serde::Serialize for c::bch_data_type {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
 pub fn printbuf_to_formatter<F>(f: &mut fmt::Formatter<'_>, func: F) -> fmt::Result
     where F: Fn(*mut c::printbuf) {
     let mut buf = c::printbuf::new();
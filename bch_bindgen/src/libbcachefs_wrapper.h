@@ -1,12 +1,20 @@
 #include "libbcachefs/super-io.h"
 #include "libbcachefs/checksum.h"
 #include "libbcachefs/bcachefs_format.h"
+#include "libbcachefs/bkey_methods.h"
 #include "libbcachefs/btree_cache.h"
 #include "libbcachefs/btree_iter.h"
+#include "libbcachefs/btree_update.h"
 #include "libbcachefs/debug.h"
+#include "libbcachefs/disk_groups.h"
 #include "libbcachefs/errcode.h"
 #include "libbcachefs/error.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/journal_io.h"
+#include "libbcachefs/journal_reclaim.h"
 #include "libbcachefs/opts.h"
+#include "libbcachefs/recovery.h"
 #include "libbcachefs.h"
 #include "crypto.h"
 #include "include/linux/bio.h"
@@ -35,3 +43,7 @@ MARK_FIX_753(blk_mode_t, BLK_OPEN_EXCL);
 
 MARK_FIX_753(__u32, BCH_IOCTL_SUBVOLUME_CREATE);
 MARK_FIX_753(__u32, BCH_IOCTL_SUBVOLUME_DESTROY);
+MARK_FIX_753(__u32, BCH_IOCTL_DATA);
+MARK_FIX_753(__u32, BCH_IOCTL_DEV_USAGE);
+MARK_FIX_753(__u32, BCH_IOCTL_DISK_RESIZE);
+MARK_FIX_753(__u32, BCH_IOCTL_FSCK_ONLINE);
@@ -0,0 +1,110 @@
+use crate::c;
+use crate::bkey::{BkeySC, BkeyValC};
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::POS_MIN;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FinsertState {
+    Start,
+    ShiftExtents,
+    Finish,
+    Unknown(u8),
+}
+
+impl FinsertState {
+    fn from_raw(state: u8) -> FinsertState {
+        use c::logged_op_finsert_state::*;
+
+        match state {
+            x if x == LOGGED_OP_FINSERT_start as u8         => FinsertState::Start,
+            x if x == LOGGED_OP_FINSERT_shift_extents as u8 => FinsertState::ShiftExtents,
+            x if x == LOGGED_OP_FINSERT_finish as u8        => FinsertState::Finish,
+            x                                               => FinsertState::Unknown(x),
+        }
+    }
+}
+
+/// An operation that was in progress at the last shutdown, and will be
+/// finished by recovery
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoggedOp {
+    Truncate {
+        /// Position in the logged_ops btree
+        idx:        u64,
+        subvol:     u32,
+        inum:       u64,
+        new_i_size: u64,
+    },
+    /// fallocate insert/collapse range
+    Finsert {
+        idx:        u64,
+        state:      FinsertState,
+        subvol:     u32,
+        inum:       u64,
+        dst_offset: u64,
+        src_offset: u64,
+        pos:        u64,
+    },
+    /// A key type this version doesn't know how to decode
+    Unknown {
+        idx:        u64,
+        key_type:   u8,
+    },
+}
+
+impl LoggedOp {
+    fn from_key(k: &BkeySC) -> Option<LoggedOp> {
+        let idx = k.k.p.offset;
+
+        Some(match k.v() {
+            BkeyValC::deleted | BkeyValC::whiteout => return None,
+            BkeyValC::logged_op_truncate(op) => LoggedOp::Truncate {
+                idx,
+                subvol:     u32::from_le(op.subvol),
+                inum:       u64::from_le(op.inum),
+                new_i_size: u64::from_le(op.new_i_size),
+            },
+            BkeyValC::logged_op_finsert(op) => LoggedOp::Finsert {
+                idx,
+                state:      FinsertState::from_raw(op.state),
+                subvol:     u32::from_le(op.subvol),
+                inum:       u64::from_le(op.inum),
+                dst_offset: u64::from_le(op.dst_offset),
+                src_offset: u64::from_le(op.src_offset),
+                pos:        u64::from_le(op.pos),
+            },
+            _ => LoggedOp::Unknown { idx, key_type: k.k.type_ },
+        })
+    }
+}
+
+impl Fs {
+    /// Operations pending from an unclean shutdown
+    pub fn logged_ops(&self) -> impl Iterator<Item = Result<LoggedOp, bch_errcode>> {
+        let trans = BtreeTrans::new(self);
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_logged_ops,
+            POS_MIN,
+            BtreeIterFlags::empty());
+        let mut ret = Vec::new();
+
+        loop {
+            match iter.peek_and_restart() {
+                Ok(Some(k)) => {
+                    if let Some(op) = LoggedOp::from_key(&k) {
+                        ret.push(Ok(op));
+                    }
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    ret.push(Err(e));
+                    break;
+                }
+            }
+            iter.advance();
+        }
+
+        ret.into_iter()
+    }
+}
@@ -33,3 +33,170 @@ macro_rules! opt_get {
         }
     };
 }
+
+use crate::c;
+use crate::errcode::{BchError, ret_to_bch_result};
+use crate::fs::Fs;
+use std::ffi::{CStr, CString};
+
+fn opt_lookup(name: &str) -> Result<usize, BchError> {
+    let name = CString::new(name).map_err(|_| BchError::OptionNotFound)?;
+    let id = unsafe { c::bch2_opt_lookup(name.as_ptr()) };
+
+    if id < 0 {
+        Err(BchError::OptionNotFound)
+    } else {
+        Ok(id as usize)
+    }
+}
+
+impl Fs {
+    /// The current value of a filesystem option, e.g. "background_compression",
+    /// formatted as it would be in sysfs
+    pub fn option(&self, name: &str) -> Result<String, BchError> {
+        let id = opt_lookup(name)?;
+
+        unsafe {
+            let opt = c::bch2_opt_table.as_ptr().add(id);
+            let v = c::bch2_opt_get_by_id(&(*self.raw).opts, std::mem::transmute(id as u32));
+            let mut buf = c::printbuf::new();
+
+            c::bch2_opt_to_text(&mut buf, self.raw, (*self.raw).disk_sb.sb, opt, v, 0);
+            Ok(CStr::from_ptr(buf.buf).to_string_lossy().into_owned())
+        }
+    }
+
+    /// Change a filesystem option on the running filesystem, as writing to
+    /// `options/` in sysfs does; options stored in the superblock are
+    /// persisted there too
+    ///
+    /// Options that can only be set at format or mount time return
+    /// [`BchError::OptionNotRuntimeSettable`], unknown options
+    /// [`BchError::OptionNotFound`], and invalid values
+    /// [`BchError::OptionParse`] (or an ERANGE error). The filesystem must
+    /// be read-write.
+    pub fn set_option(&self, name: &str, value: &str) -> Result<(), BchError> {
+        opt_lookup(name)?;
+
+        let name = CString::new(name).unwrap();
+        let value = CString::new(value).map_err(|_| BchError::OptionParse)?;
+
+        /* -EOPNOTSUPP is only returned for options that aren't OPT_RUNTIME: */
+        match unsafe { c::bch2_fs_opt_set(self.raw, name.as_ptr(), value.as_ptr()) } {
+            ret if ret == -libc::EOPNOTSUPP => Err(BchError::OptionNotRuntimeSettable),
+            ret                             => ret_to_bch_result(ret).map(|_| ()),
+        }
+    }
+}
+
+macro_rules! fs_options {
+    ($($variant:ident => $name:literal,)*) => {
+        /// Filesystem options that can be changed at runtime, for
+        /// [`Fs::get_opt`] and [`Fs::set_opt`]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        #[non_exhaustive]
+        pub enum FsOption {
+            $($variant,)*
+        }
+
+        impl FsOption {
+            pub const ALL: &'static [FsOption] = &[$(FsOption::$variant,)*];
+
+            /// The option's name, as used for mount options and in sysfs
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(FsOption::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+fs_options! {
+    Errors                  => "errors",
+    MetadataReplicas        => "metadata_replicas",
+    DataReplicas            => "data_replicas",
+    MetadataChecksum        => "metadata_checksum",
+    DataChecksum            => "data_checksum",
+    Compression             => "compression",
+    BackgroundCompression   => "background_compression",
+    StrHash                 => "str_hash",
+    MetadataTarget          => "metadata_target",
+    ForegroundTarget        => "foreground_target",
+    BackgroundTarget        => "background_target",
+    PromoteTarget           => "promote_target",
+    ErasureCode             => "erasure_code",
+    Inodes32bit             => "inodes_32bit",
+    ShardInodeNumbers       => "shard_inode_numbers",
+    GcReservePercent        => "gc_reserve_percent",
+    WideMacs                => "wide_macs",
+    InlineData              => "inline_data",
+    Verbose                 => "verbose",
+    JournalFlushDelay       => "journal_flush_delay",
+    JournalFlushDisabled    => "journal_flush_disabled",
+    JournalReclaimDelay     => "journal_reclaim_delay",
+    MoveBytesInFlight       => "move_bytes_in_flight",
+    MoveIosInFlight         => "move_ios_in_flight",
+    Nocow                   => "nocow",
+    BtreeNodePrefetch       => "btree_node_prefetch",
+}
+
+/// The value of an option: `Str` for options that take one of a set of
+/// choices (e.g. checksum types) or are parsed specially (compression,
+/// targets)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OptValue {
+    Bool(bool),
+    Uint(u64),
+    Str(String),
+}
+
+impl std::fmt::Display for OptValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OptValue::Bool(v)   => write!(f, "{}", *v as u8),
+            OptValue::Uint(v)   => write!(f, "{}", v),
+            OptValue::Str(v)    => write!(f, "{}", v),
+        }
+    }
+}
+
+impl Fs {
+    fn opt_type(&self, opt: FsOption) -> Result<c::opt_type, BchError> {
+        let id = opt_lookup(opt.name())?;
+        Ok(unsafe { (*c::bch2_opt_table.as_ptr().add(id)).type_ })
+    }
+
+    /// Typed version of [`Fs::option`]
+    pub fn get_opt(&self, opt: FsOption) -> Result<OptValue, BchError> {
+        use c::opt_type::*;
+
+        let id = opt_lookup(opt.name())?;
+        let v = unsafe { c::bch2_opt_get_by_id(&(*self.raw).opts, std::mem::transmute(id as u32)) };
+
+        Ok(match self.opt_type(opt)? {
+            BCH_OPT_BOOL    => OptValue::Bool(v != 0),
+            BCH_OPT_UINT    => OptValue::Uint(v),
+            _               => OptValue::Str(self.option(opt.name())?),
+        })
+    }
+
+    /// Typed version of [`Fs::set_option`]; returns [`BchError::OptionParse`]
+    /// if `v` is the wrong kind of value for `opt`
+    pub fn set_opt(&self, opt: FsOption, v: &OptValue) -> Result<(), BchError> {
+        use c::opt_type::*;
+
+        let ok = match (self.opt_type(opt)?, v) {
+            (BCH_OPT_BOOL, OptValue::Bool(_))   => true,
+            (BCH_OPT_UINT, OptValue::Uint(_))   => true,
+            (BCH_OPT_STR,  OptValue::Str(_))    => true,
+            (BCH_OPT_FN,   OptValue::Str(_))    => true,
+            _                                   => false,
+        };
+
+        if !ok {
+            return Err(BchError::OptionParse);
+        }
+        self.set_option(opt.name(), &v.to_string())
+    }
+}
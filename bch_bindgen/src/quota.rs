@@ -0,0 +1,116 @@
+use crate::c;
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::inode::{Inode, bkey_is_inode};
+use crate::{pos, POS_MIN};
+use std::collections::BTreeMap;
+
+/// Which ids a quota applies to; each type has its own keyspace in the quotas
+/// btree
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaType {
+    User,
+    Group,
+    Project,
+}
+
+impl QuotaType {
+    fn raw(self) -> u64 {
+        match self {
+            QuotaType::User     => c::quota_types::QTYP_USR as u64,
+            QuotaType::Group    => c::quota_types::QTYP_GRP as u64,
+            QuotaType::Project  => c::quota_types::QTYP_PRJ as u64,
+        }
+    }
+
+    fn id(self, inode: &Inode) -> u32 {
+        match self {
+            QuotaType::User     => inode.uid,
+            QuotaType::Group    => inode.gid,
+            QuotaType::Project  => inode.project,
+        }
+    }
+}
+
+/// Usage and limits for one resource; a limit of 0 means no limit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QuotaCounter {
+    pub used:       u64,
+    pub softlimit:  u64,
+    pub hardlimit:  u64,
+}
+
+/// Quota usage and limits for one user, group or project
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QuotaEntry {
+    pub id:         u32,
+    /// In 512 byte sectors
+    pub space:      QuotaCounter,
+    pub inodes:     QuotaCounter,
+}
+
+impl Fs {
+    /// Quota usage and limits for every id of the given type that has either,
+    /// sorted by id
+    ///
+    /// Limits come from the quotas btree. Usage isn't stored on disk, so it's
+    /// computed as the quota code does at mount time, by scanning the inodes
+    /// btree: each inode counts once, using its newest version (the one in
+    /// the lowest numbered snapshot).
+    pub fn quotas(&self, qtype: QuotaType) -> Result<Vec<QuotaEntry>, bch_errcode> {
+        let mut ret: BTreeMap<u32, QuotaEntry> = BTreeMap::new();
+        let trans = BtreeTrans::new(self);
+
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_inodes, POS_MIN,
+            BtreeIterFlags::ALL_SNAPSHOTS|BtreeIterFlags::PREFETCH);
+        let mut last_inum = None;
+
+        while let Some(k) = iter.peek_and_restart()? {
+            let p = k.k.p;
+
+            if bkey_is_inode(k.k) && last_inum != Some(p.offset) {
+                let mut u: c::bch_inode_unpacked = Default::default();
+                let r = unsafe { c::bch2_inode_unpack(k.to_raw(), &mut u) };
+                if r != 0 {
+                    return Err(bch_errcode::from_ret(r));
+                }
+
+                let id = qtype.id(&Inode::from_unpacked(&u, p.snapshot));
+                let e = ret.entry(id).or_insert(QuotaEntry { id, ..Default::default() });
+                e.space.used  += u.bi_sectors;
+                e.inodes.used += 1;
+                last_inum = Some(p.offset);
+            }
+            iter.advance();
+        }
+        drop(iter);
+
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_quotas,
+            pos(qtype.raw(), 0),
+            BtreeIterFlags::empty());
+
+        while let Some(k) = iter.peek_and_restart()? {
+            let p = k.k.p;
+            if p.inode != qtype.raw() {
+                break;
+            }
+
+            if k.k.type_ == c::bch_bkey_type::KEY_TYPE_quota as u8 {
+                let q = unsafe { &*(k.v as *const c::bch_val as *const c::bch_quota) };
+                let id = p.offset as u32;
+                let e = ret.entry(id).or_insert(QuotaEntry { id, ..Default::default() });
+                let spc = &q.c[c::quota_counters::Q_SPC as usize];
+                let ino = &q.c[c::quota_counters::Q_INO as usize];
+
+                e.space.softlimit   = u64::from_le(spc.softlimit);
+                e.space.hardlimit   = u64::from_le(spc.hardlimit);
+                e.inodes.softlimit  = u64::from_le(ino.softlimit);
+                e.inodes.hardlimit  = u64::from_le(ino.hardlimit);
+            }
+            iter.advance();
+        }
+
+        Ok(ret.into_values().collect())
+    }
+}
@@ -0,0 +1,68 @@
+use crate::c;
+use crate::events::sb_error_name;
+use crate::fs::Fs;
+use std::ffi::CStr;
+
+/// What happened when the filesystem was opened, see [`Fs::recovery_info`]
+#[derive(Clone, Debug, Default)]
+pub struct RecoveryInfo {
+    /// Whether the superblock was marked clean, i.e. the filesystem was
+    /// cleanly unmounted and no journal replay was needed; None when fsck or
+    /// keep_journal was set, as the journal is then read regardless
+    pub clean:              Option<bool>,
+    /// Whether fsck was run
+    pub fsck:               bool,
+    /// Range of journal sequence numbers read and replayed, if the journal
+    /// was read
+    pub journal_seq:        Option<(u64, u64)>,
+    /// Recovery passes that ran, in the order they run
+    pub passes:             Vec<String>,
+    /// Errors were found, and all of them fixed
+    pub errors_fixed:       bool,
+    /// Errors were found that weren't fixed
+    pub errors_not_fixed:   bool,
+    /// Error counts from the superblock, by name: these are cumulative over
+    /// the life of the filesystem, not just this mount
+    pub error_counts:       Vec<(String, u64)>,
+}
+
+impl RecoveryInfo {
+    /// Whether anything beyond the normal mount passes was needed
+    pub fn needed_recovery(&self) -> bool {
+        self.clean == Some(false) || self.errors_fixed || self.errors_not_fixed
+    }
+}
+
+fn recovery_pass_name(pass: u32) -> String {
+    unsafe { CStr::from_ptr(*c::bch2_recovery_passes.as_ptr().add(pass as usize)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+impl Fs {
+    /// Report what recovery did when this filesystem was opened: whether the
+    /// journal was replayed, which recovery passes ran and whether errors
+    /// were found
+    pub fn recovery_info(&self) -> RecoveryInfo {
+        let c = unsafe { &*self.raw };
+        let flag = |f: c::bch_fs_flags| c.flags & (1 << f as u32) != 0;
+
+        let journal_read = c.journal_replay_seq_start != 0;
+
+        RecoveryInfo {
+            clean:              (c.opts.fsck == 0 && c.opts.keep_journal == 0).then_some(!journal_read),
+            fsck:               c.opts.fsck != 0,
+            journal_seq:        journal_read.then_some((c.journal_replay_seq_start, c.journal_replay_seq_end)),
+            passes:             (0..64)
+                .filter(|i| c.recovery_passes_complete & (1 << i) != 0)
+                .map(recovery_pass_name)
+                .collect(),
+            errors_fixed:       flag(c::bch_fs_flags::BCH_FS_errors_fixed),
+            errors_not_fixed:   flag(c::bch_fs_flags::BCH_FS_errors_not_fixed) ||
+                                flag(c::bch_fs_flags::BCH_FS_error),
+            error_counts:       self.error_counts_raw().iter()
+                .map(|e| (sb_error_name(e.id() as u32), e.nr()))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use crate::c;
+use crate::bkey::{BkeySC, BkeyValC};
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::spos;
+use std::mem::size_of;
+
+/// A reflink pointer in the extents btree, referring to a range of the
+/// reflink btree
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ReflinkPtr {
+    /// Start of the range in the reflink btree, in sectors
+    pub idx:        u64,
+    pub size:       u64,
+    /// Extra range we hold a reference on, if the indirect extent was split
+    pub front_pad:  u32,
+    pub back_pad:   u32,
+}
+
+impl ReflinkPtr {
+    pub fn from_key(k: &BkeySC) -> Option<ReflinkPtr> {
+        match k.v() {
+            BkeyValC::reflink_p(p) => Some(ReflinkPtr {
+                idx:        u64::from_le(p.idx),
+                size:       k.k.size as u64,
+                front_pad:  u32::from_le(p.front_pad),
+                back_pad:   u32::from_le(p.back_pad),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IndirectData {
+    /// A reflink_v key: the value holds extent pointers, like a normal extent
+    Extent,
+    /// An indirect_inline_data key
+    Inline(Vec<u8>),
+}
+
+/// A key in the reflink btree: the shared extent that reflink pointers refer to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndirectExtent {
+    /// Range covered, in sectors: start inclusive, end exclusive
+    pub start:      u64,
+    pub end:        u64,
+    /// Number of reflink pointers referencing this extent; zero means it's
+    /// unreferenced and pending deletion
+    pub refcount:   u64,
+    pub data:       IndirectData,
+}
+
+impl IndirectExtent {
+    pub fn from_key(k: &BkeySC) -> Option<IndirectExtent> {
+        let val_bytes = (k.k.u64s as usize * 8).saturating_sub(size_of::<c::bkey>());
+        let (refcount, data) = match k.v() {
+            BkeyValC::reflink_v(v) => (v.refcount, IndirectData::Extent),
+            BkeyValC::indirect_inline_data(v) => {
+                let len = val_bytes.saturating_sub(size_of::<u64>());
+                (v.refcount, IndirectData::Inline(unsafe { v.data.as_slice(len) }.to_vec()))
+            },
+            _ => return None,
+        };
+
+        Some(IndirectExtent {
+            start:      k.k.p.offset - k.k.size as u64,
+            end:        k.k.p.offset,
+            refcount:   u64::from_le(refcount),
+            data,
+        })
+    }
+}
+
+impl Fs {
+    /// The indirect extent covering sector `idx` of the reflink btree
+    pub fn indirect_extent(&self, idx: u64) -> Result<Option<IndirectExtent>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_reflink,
+            spos(0, idx, 0),
+            BtreeIterFlags::empty());
+
+        let ret = match iter.peek()? {
+            Some(k) if k.k.p.offset - k.k.size as u64 <= idx => IndirectExtent::from_key(&k),
+            _ => None,
+        };
+        Ok(ret)
+    }
+
+    /// Refcount of the indirect extent covering sector `idx`
+    ///
+    /// Returns `None` if there's no indirect extent there (a dangling reflink
+    /// pointer), and `Some(0)` if it exists but is pending deletion.
+    pub fn reflink_refcount(&self, idx: u64) -> Result<Option<u64>, bch_errcode> {
+        Ok(self.indirect_extent(idx)?.map(|e| e.refcount))
+    }
+}
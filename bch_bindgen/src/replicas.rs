@@ -0,0 +1,182 @@
+use crate::c;
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use std::mem::transmute;
+
+/// A set of devices some data is replicated across, from the filesystem's
+/// replicas table
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ReplicasEntry {
+    pub data_type:      c::bch_data_type,
+    /// Number of devices that must be readable for the data to be readable
+    pub nr_required:    u32,
+    pub devs:           Vec<u32>,
+}
+
+impl ReplicasEntry {
+    pub(crate) unsafe fn from_raw(e: &c::bch_replicas_entry_v1) -> ReplicasEntry {
+        ReplicasEntry {
+            data_type:      transmute(e.data_type as u32),
+            nr_required:    e.nr_required as u32,
+            devs:           e.devs.as_slice(e.nr_devs as usize).iter().map(|&d| d as u32).collect(),
+        }
+    }
+
+    pub fn is_metadata(&self) -> bool {
+        (self.data_type as u32) < c::bch_data_type::BCH_DATA_user as u32
+    }
+}
+
+/// Replication status and usage of a single replicas entry
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ReplicasEntryStatus {
+    pub entry:          ReplicasEntry,
+    /// Sectors of data replicated across exactly this set of devices
+    pub sectors:        u64,
+    /// Devices in this entry that are online and not failed
+    pub nr_online:      u32,
+    /// Configured number of replicas for this data type
+    pub target:         u32,
+    /// Devices in this entry that are missing or failed
+    pub offline_devs:   Vec<u32>,
+    /// Devices in this entry that are failed or no longer members - unlike
+    /// devices that are just offline, these won't come back
+    pub failed_devs:    Vec<u32>,
+}
+
+impl ReplicasEntryStatus {
+    /// Fewer good replicas than the configured target
+    pub fn is_degraded(&self) -> bool {
+        self.nr_online < self.target
+    }
+
+    /// Not enough good replicas to read this data at all
+    pub fn is_lost(&self) -> bool {
+        self.nr_online < self.entry.nr_required
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ReplicasStatus {
+    pub entries:    Vec<ReplicasEntryStatus>,
+}
+
+impl ReplicasStatus {
+    /// Entries with fewer good replicas than their target; empty if healthy
+    pub fn degraded(&self) -> Vec<&ReplicasEntryStatus> {
+        self.entries.iter().filter(|e| e.is_degraded()).collect()
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.entries.iter().any(|e| e.is_degraded())
+    }
+}
+
+impl Fs {
+    /// Whether member device `dev` is present and not failed
+    pub(crate) fn dev_is_readable(&self, dev: u32) -> bool {
+        unsafe {
+            let c = &*self.raw;
+
+            if dev as usize >= c.devs.len() || c.devs[dev as usize].is_null() {
+                return false;
+            }
+
+            let ca = &*c.devs[dev as usize];
+            !ca.disk_sb.bdev.is_null() &&
+                ca.mi.state != c::bch_member_state::BCH_MEMBER_STATE_failed as u8
+        }
+    }
+
+    /* Whether member device @dev has failed, or been removed: */
+    fn dev_is_failed(&self, dev: u32) -> bool {
+        unsafe {
+            let c = &*self.raw;
+
+            dev as usize >= c.devs.len() || c.devs[dev as usize].is_null() ||
+                (*c.devs[dev as usize]).mi.state == c::bch_member_state::BCH_MEMBER_STATE_failed as u8
+        }
+    }
+
+    /// Every entry in the replicas table - each set of devices some data is
+    /// replicated across - with its usage and the status of its devices
+    pub fn replicas(&self) -> Result<Vec<ReplicasEntryStatus>, bch_errcode> {
+        let mut ret = Vec::new();
+
+        unsafe {
+            /* Takes mark_lock, which also keeps the replicas table stable: */
+            let u = c::bch2_fs_usage_read(self.raw);
+            if u.is_null() {
+                return Err(bch_errcode::BCH_ERR_ENOMEM_fs_other_alloc);
+            }
+
+            let c = &*self.raw;
+            let r = &c.replicas;
+            let usage = (*u).u.replicas.as_slice(r.nr as usize);
+
+            for i in 0..r.nr as usize {
+                let e = &*((r.entries as *const u8).add(i * r.entry_size as usize)
+                           as *const c::bch_replicas_entry_v1);
+                let entry = ReplicasEntry::from_raw(e);
+
+                let target = if entry.data_type == c::bch_data_type::BCH_DATA_cached {
+                    1
+                } else if entry.is_metadata() {
+                    c.opts.metadata_replicas
+                } else {
+                    c.opts.data_replicas
+                } as u32;
+
+                let offline_devs: Vec<u32> = entry.devs.iter()
+                    .copied()
+                    .filter(|&d| !self.dev_is_readable(d))
+                    .collect();
+                let failed_devs: Vec<u32> = entry.devs.iter()
+                    .copied()
+                    .filter(|&d| self.dev_is_failed(d))
+                    .collect();
+                let nr_online = (entry.devs.len() - offline_devs.len()) as u32;
+
+                ret.push(ReplicasEntryStatus {
+                    entry,
+                    sectors: usage[i],
+                    nr_online,
+                    target,
+                    offline_devs,
+                    failed_devs,
+                });
+            }
+
+            c::bch2_fs_usage_read_put(self.raw, u);
+        }
+
+        Ok(ret)
+    }
+
+    /// Replicas entries with devices that are currently unavailable (offline,
+    /// failed or removed), i.e. data that needs rereplicating if they don't
+    /// come back; cached data is skipped
+    pub fn degraded_replicas(&self) -> Result<Vec<ReplicasEntryStatus>, bch_errcode> {
+        Ok(self.replicas()?
+            .into_iter()
+            .filter(|e| e.entry.data_type != c::bch_data_type::BCH_DATA_cached &&
+                        !e.offline_devs.is_empty())
+            .collect())
+    }
+
+    /// Check every entry in the replicas table against the configured number
+    /// of replicas and the devices currently available
+    ///
+    /// Cached data is not counted, since it is never required.
+    pub fn replicas_status(&self) -> Result<ReplicasStatus, bch_errcode> {
+        Ok(ReplicasStatus {
+            entries: self.replicas()?
+                .into_iter()
+                .filter(|e| e.entry.data_type != c::bch_data_type::BCH_DATA_cached)
+                .collect(),
+        })
+    }
+}
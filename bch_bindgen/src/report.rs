@@ -0,0 +1,194 @@
+use crate::c;
+use crate::counters::DevIoCounters;
+use crate::replicas::{ReplicasEntryStatus, ReplicasStatus};
+use crate::superblock::SbMember;
+use crate::usage::SpaceReport;
+use std::io::{self, Write};
+use std::mem::transmute;
+
+/// A report that can be written as CSV (and, with the `serde` feature, as
+/// JSON), for `--format csv`/`--format json` style output
+///
+/// Each report is flattened to rows with a fixed set of columns; nested
+/// lists (e.g. the entries in a [`ReplicasStatus`]) become one row per
+/// element. Column names are part of the interface: new columns may be
+/// appended, but existing ones won't be renamed, reordered or removed.
+///
+/// Slices of reports are reports too, written with a single header, so
+/// e.g. the list of members can be written with `members.to_csv(w)`.
+pub trait Report {
+    /// Column names, written as the CSV header
+    const COLUMNS: &'static [&'static str];
+
+    /// Rows of values, each with one field per column
+    fn rows(&self) -> Vec<Vec<String>>;
+
+    /// Write the header and rows as CSV, RFC 4180 style
+    fn to_csv<W: Write>(&self, mut w: W) -> io::Result<()> {
+        write_csv_row(&mut w, Self::COLUMNS.iter().copied())?;
+        for row in self.rows() {
+            write_csv_row(&mut w, row.iter().map(|s| s.as_str()))?;
+        }
+        w.flush()
+    }
+
+    /// Write as a single JSON value; unlike CSV, nested data is kept nested
+    #[cfg(feature = "serde")]
+    fn to_json<W: Write>(&self, mut w: W) -> io::Result<()>
+        where Self: serde::Serialize {
+        serde_json::to_writer(&mut w, self)?;
+        w.write_all(b"\n")?;
+        w.flush()
+    }
+}
+
+fn write_csv_row<'a, W: Write>(w: &mut W, fields: impl Iterator<Item = &'a str>) -> io::Result<()> {
+    for (i, f) in fields.enumerate() {
+        if i > 0 {
+            w.write_all(b",")?;
+        }
+
+        if f.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+            write!(w, "\"{}\"", f.replace('"', "\"\""))?;
+        } else {
+            w.write_all(f.as_bytes())?;
+        }
+    }
+    w.write_all(b"\r\n")
+}
+
+/* Lists of devices are written as one field, space separated: */
+fn dev_list(devs: &[u32]) -> String {
+    devs.iter().map(|d| d.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+impl<T: Report> Report for [T] {
+    const COLUMNS: &'static [&'static str] = T::COLUMNS;
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        self.iter().flat_map(|r| r.rows()).collect()
+    }
+}
+
+/// Columns: capacity, used, free, nr_inodes, data_replicas,
+/// metadata_replicas, user_available, metadata_available; sizes in bytes
+impl Report for SpaceReport {
+    const COLUMNS: &'static [&'static str] = &[
+        "capacity",
+        "used",
+        "free",
+        "nr_inodes",
+        "data_replicas",
+        "metadata_replicas",
+        "user_available",
+        "metadata_available",
+    ];
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        vec![vec![
+            self.capacity.to_string(),
+            self.used.to_string(),
+            self.free.to_string(),
+            self.nr_inodes.to_string(),
+            self.data_replicas.to_string(),
+            self.metadata_replicas.to_string(),
+            self.user_available.to_string(),
+            self.metadata_available.to_string(),
+        ]]
+    }
+}
+
+/// Columns: idx, uuid, nbuckets, first_bucket, bucket_size (sectors),
+/// last_mount, state, discard, data_allowed, group, durability
+impl Report for SbMember {
+    const COLUMNS: &'static [&'static str] = &[
+        "idx",
+        "uuid",
+        "nbuckets",
+        "first_bucket",
+        "bucket_size",
+        "last_mount",
+        "state",
+        "discard",
+        "data_allowed",
+        "group",
+        "durability",
+    ];
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        vec![vec![
+            self.idx.to_string(),
+            self.uuid.to_string(),
+            self.nbuckets.to_string(),
+            self.first_bucket.to_string(),
+            self.bucket_size.to_string(),
+            self.last_mount.to_string(),
+            self.state.to_string(),
+            self.discard.to_string(),
+            self.data_allowed.to_string(),
+            self.group.to_string(),
+            self.durability.to_string(),
+        ]]
+    }
+}
+
+/// Columns: data_type, nr_required, devs, sectors, nr_online, target,
+/// offline_devs, failed_devs, degraded; device lists are space separated
+impl Report for ReplicasEntryStatus {
+    const COLUMNS: &'static [&'static str] = &[
+        "data_type",
+        "nr_required",
+        "devs",
+        "sectors",
+        "nr_online",
+        "target",
+        "offline_devs",
+        "failed_devs",
+        "degraded",
+    ];
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        vec![vec![
+            self.entry.data_type.to_string(),
+            self.entry.nr_required.to_string(),
+            dev_list(&self.entry.devs),
+            self.sectors.to_string(),
+            self.nr_online.to_string(),
+            self.target.to_string(),
+            dev_list(&self.offline_devs),
+            dev_list(&self.failed_devs),
+            self.is_degraded().to_string(),
+        ]]
+    }
+}
+
+/// One row per entry, with the same columns as [`ReplicasEntryStatus`]
+impl Report for ReplicasStatus {
+    const COLUMNS: &'static [&'static str] = ReplicasEntryStatus::COLUMNS;
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        self.entries.rows()
+    }
+}
+
+/// One row per direction and data type: rw ("read" or "write"),
+/// data_type, sectors; latencies aren't included
+impl Report for DevIoCounters {
+    const COLUMNS: &'static [&'static str] = &[
+        "rw",
+        "data_type",
+        "sectors",
+    ];
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        let mut ret = Vec::new();
+
+        for (rw, sectors) in ["read", "write"].iter().zip(&self.sectors) {
+            for (data_type, nr) in sectors.iter().enumerate().take(c::bch_data_type::BCH_DATA_NR as usize) {
+                let data_type: c::bch_data_type = unsafe { transmute(data_type as u32) };
+                ret.push(vec![rw.to_string(), data_type.to_string(), nr.to_string()]);
+            }
+        }
+        ret
+    }
+}
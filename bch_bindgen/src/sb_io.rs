@@ -16,7 +16,7 @@ pub fn read_super_opts(
         unsafe { crate::bcachefs::bch2_read_super(path.as_ptr(), &mut opts, sb.as_mut_ptr()) };
 
     if ret != 0 {
-        let err: bch_errcode = unsafe { ::std::mem::transmute(ret) };
+        let err = bch_errcode::from_ret(ret);
         Err(anyhow!(err))
     } else {
         Ok(unsafe { sb.assume_init() })
@@ -41,7 +41,7 @@ pub fn read_super_silent(
         unsafe { crate::bcachefs::bch2_read_super_silent(path.as_ptr(), &mut opts, sb.as_mut_ptr()) };
 
     if ret != 0 {
-        let err: bch_errcode = unsafe { ::std::mem::transmute(ret) };
+        let err = bch_errcode::from_ret(ret);
         Err(anyhow!(err))
     } else {
         Ok(unsafe { sb.assume_init() })
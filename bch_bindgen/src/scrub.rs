@@ -0,0 +1,245 @@
+use crate::c;
+use crate::bkey::BkeySC;
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::io::AlignedBuf;
+use crate::POS_MIN;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use std::sync::Mutex;
+use std::thread;
+
+#[derive(Clone, Debug)]
+pub struct ScrubOpts {
+    /// Devices to scrub; empty means all online devices
+    pub devices:        Vec<u32>,
+    /// Maximum number of devices scrubbed at once; each worker has at most
+    /// one extent in flight
+    pub concurrency:    usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScrubErrorKind {
+    ChecksumMismatch,
+    /// Read failed, with errno
+    Read(i32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScrubError {
+    pub dev:        u32,
+    /// Start of the extent on the device, in sectors
+    pub offset:     u64,
+    pub sectors:    u32,
+    pub kind:       ScrubErrorKind,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScrubProgress {
+    pub dev:                u32,
+    pub sectors_done:       u64,
+    pub sectors_total:      u64,
+    pub errors:             u64,
+    pub done:               bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ScrubDevResult {
+    pub dev:                u32,
+    pub sectors_checked:    u64,
+    /// Sectors that couldn't be verified: encrypted, or no checksum
+    pub sectors_skipped:    u64,
+    pub errors:             Vec<ScrubError>,
+    /// Set if the device wasn't online, and nothing on it was checked
+    pub offline:            bool,
+}
+
+/// A checksummed region on a device
+#[derive(Clone, Copy)]
+struct ScrubItem {
+    offset:     u64,
+    sectors:    u32,
+    csum_type:  u8,
+    csum:       c::bch_csum,
+}
+
+#[derive(Default)]
+struct DevWork {
+    items:      Vec<ScrubItem>,
+    seen:       HashSet<u64>,
+    skipped:    u64,
+}
+
+struct FsPtr(*mut c::bch_fs);
+
+/* Workers only read the device fd and compute checksums: */
+unsafe impl Send for FsPtr {}
+unsafe impl Sync for FsPtr {}
+
+fn csum_is_verifiable(csum_type: u8) -> bool {
+    use c::bch_csum_type::*;
+
+    csum_type != BCH_CSUM_none as u8 &&
+        csum_type != BCH_CSUM_chacha20_poly1305_80 as u8 &&
+        csum_type != BCH_CSUM_chacha20_poly1305_128 as u8
+}
+
+impl Fs {
+    /// Collect the checksummed regions on each device, from the extents and
+    /// reflink btrees
+    ///
+    /// Reflinked data lives in the reflink btree and is only seen once, and
+    /// regions shared by multiple keys (after an extent was split) are only
+    /// checked once.
+    fn scrub_collect(&self, work: &mut BTreeMap<u32, DevWork>) -> Result<(), bch_errcode> {
+        let trans = BtreeTrans::new(self);
+
+        for btree in [c::btree_id::BTREE_ID_extents, c::btree_id::BTREE_ID_reflink] {
+            let mut iter = BtreeIter::new(&trans, btree, POS_MIN, BtreeIterFlags::ALL_SNAPSHOTS);
+
+            while let Some(k) = iter.peek_and_restart()? {
+                scrub_collect_key(&k, work);
+                iter.advance();
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify checksums of all data on the given devices, reading each device
+    /// from its own worker thread
+    ///
+    /// Errors on one device (read errors, checksum mismatches, the device
+    /// being offline) are reported in that device's result and don't stop
+    /// the others. Encrypted data can't be verified without decrypting and is
+    /// counted as skipped. Btree nodes and journal are not checked.
+    pub fn scrub<F>(&self, opts: ScrubOpts, progress: F) -> Result<Vec<ScrubDevResult>, bch_errcode>
+    where F: Fn(ScrubProgress) + Sync {
+        let nr_devices = unsafe { (*self.raw).sb.nr_devices as u32 };
+        let devices: Vec<u32> = if opts.devices.is_empty() {
+            (0..nr_devices).filter(|&d| self.dev_is_readable(d)).collect()
+        } else {
+            opts.devices.clone()
+        };
+
+        let mut work: BTreeMap<u32, DevWork> = devices.iter().map(|&d| (d, DevWork::default())).collect();
+        self.scrub_collect(&mut work)?;
+
+        let mut results = Vec::new();
+        let mut queue = Vec::new();
+        for (dev, w) in work {
+            if self.dev_is_readable(dev) {
+                queue.push((dev, w));
+            } else {
+                results.push(ScrubDevResult { dev, offline: true, ..Default::default() });
+            }
+        }
+
+        let fs = FsPtr(self.raw);
+        let queue = Mutex::new(queue);
+        let results = Mutex::new(results);
+        let nr_workers = opts.concurrency.max(1).min(queue.lock().unwrap().len());
+
+        thread::scope(|s| {
+            for _ in 0..nr_workers {
+                s.spawn(|| {
+                    loop {
+                        let next = queue.lock().unwrap().pop();
+                        let Some((dev, w)) = next else { break };
+
+                        let r = unsafe { scrub_dev(&fs, dev, w, &progress) };
+                        results.lock().unwrap().push(r);
+                    }
+                });
+            }
+        });
+
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|r| r.dev);
+        Ok(results)
+    }
+}
+
+fn scrub_collect_key(k: &BkeySC, work: &mut BTreeMap<u32, DevWork>) {
+    use c::bch_bkey_type::*;
+
+    let ty = k.k.type_ as u32;
+    if ty != KEY_TYPE_extent as u32 && ty != KEY_TYPE_reflink_v as u32 {
+        return;
+    }
+
+    for p in k.pointers() {
+        if p.unwritten {
+            continue;
+        }
+
+        let Some(w) = work.get_mut(&p.dev) else { continue };
+
+        if !w.seen.insert(p.offset) {
+            continue;
+        }
+
+        if csum_is_verifiable(p.crc.csum_type) {
+            w.items.push(ScrubItem {
+                offset:     p.offset,
+                sectors:    p.crc.compressed_size,
+                csum_type:  p.crc.csum_type,
+                csum:       p.crc.csum,
+            });
+        } else {
+            w.skipped += p.crc.compressed_size as u64;
+        }
+    }
+}
+
+unsafe fn scrub_dev<F>(fs: &FsPtr, dev: u32, w: DevWork, progress: &F) -> ScrubDevResult
+where F: Fn(ScrubProgress) {
+    let ca = &*(*fs.0).devs[dev as usize];
+    let bdev = &*ca.disk_sb.bdev;
+    let file = ManuallyDrop::new(File::from_raw_fd(bdev.bd_fd));
+
+    let mut r = ScrubDevResult {
+        dev,
+        sectors_skipped: w.skipped,
+        ..Default::default()
+    };
+    let mut p = ScrubProgress {
+        dev,
+        sectors_total: w.items.iter().map(|i| i.sectors as u64).sum(),
+        ..Default::default()
+    };
+
+    for i in &w.items {
+        let len = (i.sectors as usize) << 9;
+        let mut buf = AlignedBuf::new(len);
+
+        let kind = match file.read_exact_at(&mut buf.as_mut_slice()[..len], bdev.bd_offset + (i.offset << 9)) {
+            Err(e) => Some(ScrubErrorKind::Read(e.raw_os_error().unwrap_or(0))),
+            Ok(()) => {
+                let csum = c::bch2_checksum(fs.0, i.csum_type as u32, Default::default(),
+                                            buf.as_slice().as_ptr() as *const _, len);
+                if csum.lo != i.csum.lo || csum.hi != i.csum.hi {
+                    Some(ScrubErrorKind::ChecksumMismatch)
+                } else {
+                    None
+                }
+            }
+        };
+
+        if let Some(kind) = kind {
+            r.errors.push(ScrubError { dev, offset: i.offset, sectors: i.sectors, kind });
+            p.errors += 1;
+        }
+
+        r.sectors_checked   += i.sectors as u64;
+        p.sectors_done      += i.sectors as u64;
+        progress(p);
+    }
+
+    p.done = true;
+    progress(p);
+    r
+}
@@ -0,0 +1,410 @@
+use crate::c;
+use crate::bkey::{BkeyOwned, BkeyValC};
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::{pos, spos, POS_MIN};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+/* from bcachefs_format.h, dirent_format.h: */
+const BCACHEFS_ROOT_INO: u64 = 4096;
+const BCACHEFS_ROOT_SUBVOL: u32 = 1;
+const DT_SUBVOL: u8 = 16;
+/* offsetof(struct bch_dirent, d_name): */
+const DIRENT_NAME_OFFSET: usize = 9;
+
+/* Give up resolving a path (there's a loop) after this many components: */
+const SUBVOL_PATH_MAX_DEPTH: usize = 4096;
+
+/// A node in the snapshot tree, from the snapshots btree
+///
+/// Parent IDs are always greater than their children's IDs.
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotNode {
+    pub id:         u32,
+    /// 0 for the root of a snapshot tree
+    pub parent:     u32,
+    pub children:   [u32; 2],
+    /// Subvolume this snapshot belongs to; 0 for interior nodes
+    pub subvol:     u32,
+    pub tree:       u32,
+    pub depth:      u32,
+    /// Deleted, and pending cleanup
+    pub deleted:    bool,
+    pub is_subvol:  bool,
+}
+
+impl SnapshotNode {
+    fn from_raw(id: u32, s: &c::bch_snapshot) -> SnapshotNode {
+        let flags = u32::from_le(s.flags);
+
+        SnapshotNode {
+            id,
+            parent:     u32::from_le(s.parent),
+            children:   s.children.map(u32::from_le),
+            subvol:     u32::from_le(s.subvol),
+            tree:       u32::from_le(s.tree),
+            depth:      u32::from_le(s.depth),
+            deleted:    flags & 1 != 0,
+            is_subvol:  flags & 2 != 0,
+        }
+    }
+}
+
+/// A snapshot tree's entry in the snapshot_trees btree
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotTreeRoot {
+    pub id:             u32,
+    /// Subvolume that other subvolumes in this tree were snapshotted from
+    pub master_subvol:  u32,
+    pub root_snapshot:  u32,
+}
+
+/// Snapshot topology of a filesystem, see [`Fs::snapshot_tree`]
+///
+/// Includes deleted nodes that haven't been cleaned up yet (check
+/// [`SnapshotNode::deleted`]), and nodes from every snapshot tree.
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotTree {
+    pub nodes:  BTreeMap<u32, SnapshotNode>,
+    pub trees:  BTreeMap<u32, SnapshotTreeRoot>,
+}
+
+impl SnapshotTree {
+    pub fn get(&self, id: u32) -> Option<&SnapshotNode> {
+        self.nodes.get(&id)
+    }
+
+    /// Parent of a node; None for a root, or a node that doesn't exist
+    pub fn parent(&self, id: u32) -> Option<u32> {
+        self.get(id)
+            .map(|s| s.parent)
+            .filter(|&p| p != 0)
+    }
+
+    pub fn children(&self, id: u32) -> Vec<u32> {
+        self.get(id)
+            .map(|s| s.children.iter().copied().filter(|&c| c != 0).collect())
+            .unwrap_or_default()
+    }
+
+    /// Ancestors of a node, nearest first, ending with the root of its tree
+    pub fn ancestors(&self, id: u32) -> Vec<u32> {
+        let mut ret = Vec::new();
+        let mut id = id;
+
+        /* parents always have higher IDs: don't loop on a corrupt tree */
+        while let Some(p) = self.parent(id).filter(|&p| p > id) {
+            ret.push(p);
+            id = p;
+        }
+        ret
+    }
+
+    /// Whether `anc` is `desc` or one of its ancestors
+    pub fn is_ancestor(&self, anc: u32, desc: u32) -> bool {
+        anc == desc || self.ancestors(desc).contains(&anc)
+    }
+
+    /// Root node of each snapshot tree
+    pub fn roots(&self) -> impl Iterator<Item = &SnapshotNode> {
+        self.nodes.values().filter(|s| s.parent == 0)
+    }
+
+    /// Nodes in snapshot tree `tree`, in ID order
+    pub fn tree_nodes(&self, tree: u32) -> impl Iterator<Item = &SnapshotNode> {
+        self.nodes.values().filter(move |s| s.tree == tree)
+    }
+}
+
+impl Fs {
+    /// Read the snapshots and snapshot_trees btrees
+    pub fn snapshot_tree(&self) -> Result<SnapshotTree, bch_errcode> {
+        let mut ret = SnapshotTree {
+            nodes: self.snapshots()?.into_iter().map(|s| (s.id, s)).collect(),
+            ..Default::default()
+        };
+
+        let trans = BtreeTrans::new(self);
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_snapshot_trees,
+            POS_MIN,
+            BtreeIterFlags::PREFETCH);
+
+        while let Some(k) = iter.peek_and_restart()? {
+            if let BkeyValC::snapshot_tree(t) = k.v() {
+                let id = k.k.p.offset as u32;
+
+                ret.trees.insert(id, SnapshotTreeRoot {
+                    id,
+                    master_subvol:  u32::from_le(t.master_subvol),
+                    root_snapshot:  u32::from_le(t.root_snapshot),
+                });
+            }
+            iter.advance();
+        }
+
+        Ok(ret)
+    }
+
+    /// Look up a single snapshot node; None if it doesn't exist
+    pub fn snapshot(&self, id: u32) -> Result<Option<SnapshotNode>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_snapshots,
+            pos(0, id as u64),
+            BtreeIterFlags::SLOTS);
+        let k = iter.peek_slot()?;
+
+        Ok(match k.as_ref().map(|k| k.v()) {
+            Some(BkeyValC::snapshot(s)) => Some(SnapshotNode::from_raw(id, s)),
+            _ => None,
+        })
+    }
+
+    /// Every node in the snapshot tree, in ID order, including deleted and
+    /// interior nodes
+    pub fn snapshots(&self) -> Result<Vec<SnapshotNode>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_snapshots,
+            POS_MIN,
+            BtreeIterFlags::PREFETCH);
+        let mut ret = Vec::new();
+
+        while let Some(k) = iter.peek_and_restart()? {
+            if let BkeyValC::snapshot(s) = k.v() {
+                ret.push(SnapshotNode::from_raw(k.k.p.offset as u32, s));
+            }
+            iter.advance();
+        }
+
+        Ok(ret)
+    }
+
+    /// Parent of a snapshot node; None for a root, or a nonexistent node
+    pub fn snapshot_parent(&self, id: u32) -> Result<Option<u32>, bch_errcode> {
+        Ok(self.snapshot(id)?
+            .map(|s| s.parent)
+            .filter(|&p| p != 0))
+    }
+
+    /// Whether `anc` is `desc` or one of its ancestors
+    ///
+    /// Same as bch2_snapshot_is_ancestor(), but walks the snapshots btree
+    /// instead of depending on the in-memory snapshot table, so it works on
+    /// filesystems opened without recovery.
+    pub fn snapshot_is_ancestor(&self, anc: u32, desc: u32) -> Result<bool, bch_errcode> {
+        let mut id = desc;
+
+        while id != 0 && id < anc {
+            id = self.snapshot_parent(id)?.unwrap_or(0);
+        }
+
+        Ok(id == anc)
+    }
+
+    /// The subvolume a snapshot ID belongs to; None for interior snapshot
+    /// nodes, which aren't visible from any subvolume
+    pub fn snapshot_subvol(&self, snapshot: u32) -> Result<Option<u32>, bch_errcode> {
+        Ok(self.snapshot(snapshot)?
+            .map(|s| s.subvol)
+            .filter(|&s| s != 0))
+    }
+}
+
+/// A subvolume, from [`Fs::list_subvolumes`]
+#[derive(Clone, Debug)]
+pub struct SubvolumeInfo {
+    pub subvol:             u32,
+    /// The subvolume's current snapshot ID
+    pub snapshot:           u32,
+    pub root_inode:         u64,
+    /// The subvolume whose directory tree this one's root is linked into; 0
+    /// for the top level subvolume
+    pub parent:             u32,
+    /// For snapshots, the subvolume this is a snapshot of
+    pub creation_parent:    u32,
+    /// Path from the filesystem root, or None for orphaned subvolumes, whose
+    /// path can't be resolved
+    pub path:               Option<PathBuf>,
+    pub read_only:          bool,
+    pub is_snapshot:        bool,
+}
+
+/// A btree as seen from one subvolume, see [`Fs::snapshot_view`]
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotView {
+    pub subvol:     u32,
+    /// The subvolume's current snapshot ID
+    pub snapshot:   u32,
+}
+
+impl SnapshotView {
+    /// Iterate over `btree` from `inode:offset`, returning for each position
+    /// the key visible in this subvolume
+    pub fn iter<'t>(&self, trans: &'t BtreeTrans<'t>, btree: c::btree_id, inode: u64, offset: u64) -> BtreeIter<'t> {
+        BtreeIter::new(trans, btree,
+            spos(inode, offset, self.snapshot),
+            BtreeIterFlags::FILTER_SNAPSHOTS)
+    }
+
+    /// The key at `inode:offset` visible in this subvolume; None if there
+    /// isn't one, or it was deleted in this subvolume's snapshot or an
+    /// ancestor
+    pub fn get(&self, trans: &BtreeTrans, btree: c::btree_id, inode: u64, offset: u64) -> Result<Option<BkeyOwned>, bch_errcode> {
+        let mut iter = self.iter(trans, btree, inode, offset);
+        let k = iter.peek_slot()?;
+
+        Ok(k.filter(|k| !k.is_whiteout()).map(|k| k.to_owned()))
+    }
+}
+
+impl Fs {
+    /// The current snapshot ID of a subvolume
+    pub fn subvol_snapshot(&self, subvol: u32) -> Result<u32, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_subvolumes,
+            pos(0, subvol as u64),
+            BtreeIterFlags::SLOTS);
+        let k = iter.peek_slot()?;
+
+        match k.as_ref().map(|k| k.v()) {
+            Some(BkeyValC::subvolume(s)) => Ok(u32::from_le(s.snapshot)),
+            _ => Err(bch_errcode::BCH_ERR_ENOENT_subvolume),
+        }
+    }
+
+    /// The root directory inode of a subvolume, to start a walk from
+    ///
+    /// For the top level subvolume (`BCACHEFS_ROOT_SUBVOL`) this is
+    /// `BCACHEFS_ROOT_INO`; the inode is in the subvolume's snapshot, from
+    /// [`Fs::subvol_snapshot`].
+    pub fn subvolume_root(&self, subvol: u32) -> Result<u64, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_subvolumes,
+            pos(0, subvol as u64),
+            BtreeIterFlags::SLOTS);
+        let k = iter.peek_slot()?;
+
+        match k.as_ref().map(|k| k.v()) {
+            Some(BkeyValC::subvolume(s)) => Ok(u64::from_le(s.inode)),
+            _ => Err(bch_errcode::BCH_ERR_ENOENT_subvolume),
+        }
+    }
+
+    /// All subvolumes, with their paths
+    ///
+    /// Paths are resolved by walking back from each subvolume's root
+    /// directory to the filesystem root, via each inode's backpointer to the
+    /// dirent linking it.
+    pub fn list_subvolumes(&self) -> Result<Vec<SubvolumeInfo>, bch_errcode> {
+        let mut ret = Vec::new();
+        {
+            let trans = BtreeTrans::new(self);
+            let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_subvolumes, POS_MIN,
+                BtreeIterFlags::PREFETCH);
+
+            while let Some(k) = iter.peek_and_restart()? {
+                if let BkeyValC::subvolume(s) = k.v() {
+                    let flags = u32::from_le(s.flags);
+
+                    ret.push(SubvolumeInfo {
+                        subvol:             k.k.p.offset as u32,
+                        snapshot:           u32::from_le(s.snapshot),
+                        root_inode:         u64::from_le(s.inode),
+                        parent:             u32::from_le(s.fs_path_parent),
+                        creation_parent:    u32::from_le(s.creation_parent),
+                        path:               None,
+                        read_only:          flags & 1 != 0,
+                        is_snapshot:        flags & 2 != 0,
+                    });
+                }
+                iter.advance();
+            }
+        }
+
+        let snapshots: BTreeMap<u32, u32> = ret.iter().map(|s| (s.subvol, s.snapshot)).collect();
+
+        for s in &mut ret {
+            s.path = self.subvol_path(&snapshots, s.subvol, s.root_inode)?;
+        }
+        Ok(ret)
+    }
+
+    /* Name of the dirent at @dir:@offset, if it links @subvol:@inum: */
+    fn dirent_name_linking(&self, view: SnapshotView, dir: u64, offset: u64,
+                           subvol: u32, inum: u64) -> Result<Option<Vec<u8>>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let Some(k) = view.get(&trans, c::btree_id::BTREE_ID_dirents, dir, offset)? else {
+            return Ok(None);
+        };
+        let k = k.as_sc();
+        let BkeyValC::dirent(d) = k.v() else { return Ok(None) };
+
+        let links = if d.d_type == DT_SUBVOL {
+            u32::from_le(unsafe { d.__bindgen_anon_1.__bindgen_anon_1.d_child_subvol }) == subvol
+        } else {
+            u64::from_le(unsafe { d.__bindgen_anon_1.d_inum }) == inum
+        };
+        if !links {
+            return Ok(None);
+        }
+
+        let val_bytes = (k.k.u64s as usize - std::mem::size_of::<c::bkey>() / 8) * 8;
+        let name = unsafe {
+            std::slice::from_raw_parts((d as *const c::bch_dirent as *const u8).add(DIRENT_NAME_OFFSET),
+                val_bytes.saturating_sub(DIRENT_NAME_OFFSET))
+        };
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+
+        Ok(Some(name[..len].to_vec()))
+    }
+
+    fn subvol_path(&self, snapshots: &BTreeMap<u32, u32>, subvol: u32, root: u64) -> Result<Option<PathBuf>, bch_errcode> {
+        let mut names = Vec::new();
+        let (mut subvol, mut inum) = (subvol, root);
+
+        for _ in 0..SUBVOL_PATH_MAX_DEPTH {
+            if subvol == BCACHEFS_ROOT_SUBVOL && inum == BCACHEFS_ROOT_INO {
+                let mut path = PathBuf::from("/");
+                path.extend(names.iter().rev().map(|n: &Vec<u8>| OsStr::from_bytes(n)));
+                return Ok(Some(path));
+            }
+
+            let Some(&snapshot) = snapshots.get(&subvol) else { return Ok(None) };
+            let Some(inode) = self.inode(inum, snapshot)? else { return Ok(None) };
+
+            /* a subvolume root is linked from the parent subvolume: */
+            let dirent_subvol = if inode.subvol != 0 { inode.parent_subvol } else { subvol };
+            let Some(&dirent_snapshot) = snapshots.get(&dirent_subvol) else { return Ok(None) };
+            if inode.dir == 0 {
+                return Ok(None);
+            }
+
+            let view = SnapshotView { subvol: dirent_subvol, snapshot: dirent_snapshot };
+            match self.dirent_name_linking(view, inode.dir, inode.dir_offset, subvol, inum)? {
+                Some(name)  => names.push(name),
+                None        => return Ok(None),
+            }
+
+            subvol  = dirent_subvol;
+            inum    = inode.dir;
+        }
+        Ok(None)
+    }
+
+    /// View btrees as they are in subvolume `subvol`
+    ///
+    /// In btrees with snapshots, each position can have a key in several
+    /// snapshots; a subvolume sees the key from its own snapshot, or else the
+    /// nearest ancestor's, unless that's a whiteout (deleted in the
+    /// subvolume). This is what iterating with `FILTER_SNAPSHOTS` does; the
+    /// view just looks up the subvolume's snapshot ID and sets it up.
+    pub fn snapshot_view(&self, subvol: u32) -> Result<SnapshotView, bch_errcode> {
+        Ok(SnapshotView {
+            subvol,
+            snapshot: self.subvol_snapshot(subvol)?,
+        })
+    }
+}
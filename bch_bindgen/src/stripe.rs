@@ -0,0 +1,131 @@
+use crate::c;
+use crate::bkey::{BkeySC, BkeyValC};
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::POS_MIN;
+use std::mem::size_of;
+
+/* bch_crc_bytes from bcachefs_format.h: */
+const CRC_BYTES: [usize; 8] = [0, 4, 4, 8, 8, 8, 10, 16];
+
+/// One block of a stripe: data or parity, on one device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StripeBlock {
+    pub dev:            u32,
+    /// Start of the block on the device, in sectors
+    pub offset:         u64,
+    pub gen:            u8,
+    /// Number of sectors of live data in the block; `None` if missing from
+    /// the key
+    pub sectors_used:   Option<u16>,
+}
+
+/// An erasure coded stripe, from the stripes btree
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Stripe {
+    pub idx:                    u64,
+    /// Size of each block, in sectors
+    pub sectors:                u16,
+    pub algorithm:              u8,
+    /// Total number of blocks, including `nr_redundant` parity blocks
+    pub nr_blocks:              u8,
+    pub nr_redundant:           u8,
+    pub csum_granularity_bits:  u8,
+    pub csum_type:              u8,
+    /// Data blocks first, then parity; fewer than `nr_blocks` if the key is
+    /// truncated
+    pub blocks:                 Vec<StripeBlock>,
+}
+
+impl Stripe {
+    pub fn from_key(k: &BkeySC) -> Option<Stripe> {
+        let s = match k.v() {
+            BkeyValC::stripe(s) => s,
+            _ => return None,
+        };
+
+        let val_bytes = (k.k.u64s as usize * 8).saturating_sub(size_of::<c::bkey>());
+        let ptrs_bytes = val_bytes.saturating_sub(size_of::<c::bch_stripe>());
+        let nr_ptrs = (s.nr_blocks as usize).min(ptrs_bytes / size_of::<c::bch_extent_ptr>());
+        let ptrs = unsafe { s.ptrs.as_slice(nr_ptrs) };
+
+        /* Block counts follow the pointers and checksums: */
+        let sectors = u16::from_le(s.sectors) as usize;
+        let granularity = 1usize.checked_shl(s.csum_granularity_bits as u32).unwrap_or(usize::MAX);
+        let csums_per_dev = sectors.checked_add(granularity - 1).map_or(1, |n| n / granularity);
+        let csum_bytes = CRC_BYTES.get(s.csum_type as usize).copied();
+        let blockcount_offset = csum_bytes.map(|b|
+            size_of::<c::bch_stripe>() +
+            size_of::<c::bch_extent_ptr>() * s.nr_blocks as usize +
+            s.nr_blocks as usize * csums_per_dev * b);
+
+        let blocks = ptrs.iter().enumerate()
+            .map(|(i, p)| {
+                let sectors_used = blockcount_offset
+                    .map(|o| o + i * size_of::<u16>())
+                    .filter(|&o| o + size_of::<u16>() <= val_bytes)
+                    .map(|o| unsafe {
+                        let p = (s as *const c::bch_stripe as *const u8).add(o);
+                        u16::from_le(std::ptr::read_unaligned(p as *const u16))
+                    });
+
+                StripeBlock {
+                    dev:        p.dev() as u32,
+                    offset:     p.offset(),
+                    gen:        p.gen() as u8,
+                    sectors_used,
+                }
+            })
+            .collect();
+
+        Some(Stripe {
+            idx:                    k.k.p.offset,
+            sectors:                sectors as u16,
+            algorithm:              s.algorithm,
+            nr_blocks:              s.nr_blocks,
+            nr_redundant:           s.nr_redundant,
+            csum_granularity_bits:  s.csum_granularity_bits,
+            csum_type:              s.csum_type,
+            blocks,
+        })
+    }
+
+    pub fn nr_data(&self) -> u8 {
+        self.nr_blocks.saturating_sub(self.nr_redundant)
+    }
+
+    /// Indices of blocks that can't be read: on a missing or failed device,
+    /// or missing from the key
+    pub fn failed_blocks(&self, fs: &Fs) -> Vec<usize> {
+        (0..self.nr_blocks as usize)
+            .filter(|&i| !self.blocks.get(i).map_or(false, |b| fs.dev_is_readable(b.dev)))
+            .collect()
+    }
+
+    /// Whether the stripe's data can still be read or reconstructed, i.e. no
+    /// more than `nr_redundant` blocks have failed
+    pub fn is_recoverable(&self, fs: &Fs) -> bool {
+        self.failed_blocks(fs).len() <= self.nr_redundant as usize
+    }
+}
+
+impl Fs {
+    /// All stripes on the filesystem, in index order
+    pub fn list_stripes(&self) -> Result<Vec<Stripe>, bch_errcode> {
+        let trans = BtreeTrans::new(self);
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_stripes, POS_MIN,
+            BtreeIterFlags::PREFETCH);
+        let mut ret = Vec::new();
+
+        while let Some(k) = iter.peek_and_restart()? {
+            if let Some(s) = Stripe::from_key(&k) {
+                ret.push(s);
+            }
+            iter.advance();
+        }
+        Ok(ret)
+    }
+}
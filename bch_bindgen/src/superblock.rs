@@ -0,0 +1,271 @@
+use crate::c;
+use crate::replicas::ReplicasEntry;
+use crate::sb_io::read_super_silent;
+use std::ffi::CStr;
+use std::mem::size_of;
+use std::path::Path;
+use uuid::Uuid;
+
+/// A member device, from the members section
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SbMember {
+    pub idx:            u32,
+    pub uuid:           Uuid,
+    pub nbuckets:       u64,
+    pub first_bucket:   u16,
+    /// In sectors
+    pub bucket_size:    u16,
+    pub last_mount:     u64,
+    pub state:          u8,
+    pub discard:        bool,
+    pub data_allowed:   u8,
+    /// Disk group index + 1, or 0 for none
+    pub group:          u8,
+    pub durability:     u8,
+}
+
+fn bits(v: u64, start: u32, end: u32) -> u64 {
+    (v >> start) & ((1 << (end - start)) - 1)
+}
+
+impl SbMember {
+    fn from_raw(idx: u32, m: &c::bch_member) -> SbMember {
+        let flags = u64::from_le(m.flags);
+
+        SbMember {
+            idx,
+            uuid:           Uuid::from_bytes(m.uuid.b),
+            nbuckets:       u64::from_le(m.nbuckets),
+            first_bucket:   u16::from_le(m.first_bucket),
+            bucket_size:    u16::from_le(m.bucket_size),
+            last_mount:     u64::from_le(m.last_mount),
+            state:          bits(flags, 0, 4) as u8,
+            discard:        bits(flags, 14, 15) != 0,
+            data_allowed:   bits(flags, 15, 20) as u8,
+            group:          bits(flags, 20, 28) as u8,
+            durability:     bits(flags, 28, 30) as u8,
+        }
+    }
+
+    /// Whether this slot is in use
+    pub fn exists(&self) -> bool {
+        !self.uuid.is_nil()
+    }
+}
+
+/// An entry in the disk_groups section: a label, possibly nested under a
+/// parent group (e.g. "ssd.fast" has parent "ssd")
+#[derive(Clone, Debug)]
+pub struct SbDiskGroup {
+    pub idx:            u32,
+    pub label:          String,
+    pub deleted:        bool,
+    pub data_allowed:   u8,
+    /// Parent group index + 1, or 0 for a top level group
+    pub parent:         u32,
+}
+
+/// Contents of the clean section, present if the filesystem was shut down
+/// cleanly
+#[derive(Clone, Debug)]
+pub struct SbClean {
+    pub flags:          u32,
+    pub journal_seq:    u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct SbCrypt {
+    pub key_type:       u64,
+    pub kdf_flags:      u64,
+}
+
+/// One of the superblock copies listed in the layout
+#[derive(Clone, Debug)]
+pub struct SbCopy {
+    /// In sectors
+    pub offset:         u64,
+    /// Sequence number, or None if this copy couldn't be read
+    pub seq:            Option<u64>,
+    /// Whether this copy matches the one that was used
+    pub matches:        bool,
+}
+
+/// A decoded superblock, as printed by show-super
+#[derive(Clone, Debug)]
+pub struct SuperBlock {
+    pub version:        u16,
+    pub version_min:    u16,
+    pub uuid:           Uuid,
+    pub internal_uuid:  Uuid,
+    pub label:          String,
+    /// Sector offset of the copy this was decoded from
+    pub offset:         u64,
+    pub seq:            u64,
+    /// In sectors
+    pub block_size:     u16,
+    pub dev_idx:        u8,
+    pub nr_devices:     u8,
+    pub time_base_lo:   u64,
+    pub time_precision: u32,
+    pub flags:          [u64; 7],
+    pub features:       [u64; 2],
+    pub compat:         [u64; 2],
+    pub members:        Vec<SbMember>,
+    pub replicas:       Vec<ReplicasEntry>,
+    pub disk_groups:    Vec<SbDiskGroup>,
+    /// Journal buckets on this device, as (start, nr) ranges
+    pub journal:        Vec<(u64, u64)>,
+    pub crypt:          Option<SbCrypt>,
+    pub clean:          Option<SbClean>,
+    /// Every copy in the layout; empty unless read with [`SuperBlock::read`]
+    pub copies:         Vec<SbCopy>,
+}
+
+pub(crate) fn label_to_string(label: &[u8]) -> String {
+    let len = label.iter().position(|&c| c == 0).unwrap_or(label.len());
+    String::from_utf8_lossy(&label[..len]).into_owned()
+}
+
+impl SuperBlock {
+    /// Decode an in memory superblock
+    pub fn from_sb(sb: &c::bch_sb) -> SuperBlock {
+        let mut ret = SuperBlock {
+            version:        u16::from_le(sb.version),
+            version_min:    u16::from_le(sb.version_min),
+            uuid:           Uuid::from_bytes(sb.user_uuid.b),
+            internal_uuid:  Uuid::from_bytes(sb.uuid.b),
+            label:          label_to_string(&sb.label),
+            offset:         u64::from_le(sb.offset),
+            seq:            u64::from_le(sb.seq),
+            block_size:     u16::from_le(sb.block_size),
+            dev_idx:        sb.dev_idx,
+            nr_devices:     sb.nr_devices,
+            time_base_lo:   u64::from_le(sb.time_base_lo),
+            time_precision: u32::from_le(sb.time_precision),
+            flags:          sb.flags.map(u64::from_le),
+            features:       sb.features.map(u64::from_le),
+            compat:         sb.compat.map(u64::from_le),
+            members:        Vec::new(),
+            replicas:       Vec::new(),
+            disk_groups:    Vec::new(),
+            journal:        Vec::new(),
+            crypt:          None,
+            clean:          None,
+            copies:         Vec::new(),
+        };
+
+        unsafe {
+            use c::bch_sb_field_type::*;
+            let hdr = size_of::<c::bch_sb_field>();
+
+            for i in 0..sb.nr_devices as i32 {
+                let m = c::bch2_sb_member_get(sb as *const _ as *mut _, i);
+                ret.members.push(SbMember::from_raw(i as u32, &m));
+            }
+
+            if let Some((r, bytes)) = sb.field_get::<c::bch_sb_field_replicas>(BCH_SB_FIELD_replicas) {
+                let mut p = r.entries.as_ptr() as *const u8;
+                let end = (r as *const _ as *const u8).add(bytes);
+
+                while p < end {
+                    let e = &*(p as *const c::bch_replicas_entry_v1);
+                    if e.nr_devs == 0 {
+                        break;
+                    }
+                    ret.replicas.push(ReplicasEntry::from_raw(e));
+                    p = p.add(3 + e.nr_devs as usize);
+                }
+            }
+
+            if let Some((g, bytes)) = sb.field_get::<c::bch_sb_field_disk_groups>(BCH_SB_FIELD_disk_groups) {
+                let nr = (bytes - hdr) / size_of::<c::bch_disk_group>();
+
+                for (i, e) in g.entries.as_slice(nr).iter().enumerate() {
+                    let flags = u64::from_le(e.flags[0]);
+
+                    ret.disk_groups.push(SbDiskGroup {
+                        idx:            i as u32,
+                        label:          label_to_string(&e.label),
+                        deleted:        bits(flags, 0, 1) != 0,
+                        data_allowed:   bits(flags, 1, 6) as u8,
+                        parent:         bits(flags, 6, 24) as u32,
+                    });
+                }
+            }
+
+            if let Some((j, bytes)) = sb.field_get::<c::bch_sb_field_journal_v2>(BCH_SB_FIELD_journal_v2) {
+                let nr = (bytes - hdr) / size_of::<c::bch_sb_field_journal_v2_entry>();
+
+                ret.journal = j.d.as_slice(nr).iter()
+                    .map(|d| (u64::from_le(d.start), u64::from_le(d.nr)))
+                    .collect();
+            } else if let Some((j, bytes)) = sb.field_get::<c::bch_sb_field_journal>(BCH_SB_FIELD_journal) {
+                let nr = (bytes - hdr) / size_of::<u64>();
+
+                ret.journal = j.buckets.as_slice(nr).iter()
+                    .map(|&b| (u64::from_le(b), 1))
+                    .collect();
+            }
+
+            if let Some(crypt) = sb.crypt() {
+                ret.crypt = Some(SbCrypt {
+                    key_type:   c::bch_crypt_flags(u64::from_le(crypt.flags)).TYPE(),
+                    kdf_flags:  u64::from_le(crypt.kdf_flags),
+                });
+            }
+
+            if let Some((clean, _)) = sb.field_get::<c::bch_sb_field_clean>(BCH_SB_FIELD_clean) {
+                ret.clean = Some(SbClean {
+                    flags:          u32::from_le(clean.flags),
+                    journal_seq:    u64::from_le(clean.journal_seq),
+                });
+            }
+        }
+
+        ret
+    }
+
+    /// Read and decode the superblock on `path`, and check every other copy in
+    /// the superblock layout against it
+    pub fn read(path: &Path) -> anyhow::Result<SuperBlock> {
+        let mut opts = c::bch_opts::default();
+        opts.noexcl = 1;
+        opts.set_noexcl_defined(1);
+
+        let mut sb_handle = read_super_silent(path, opts)?;
+        let mut ret = SuperBlock::from_sb(sb_handle.sb());
+        let layout = sb_handle.sb().layout;
+        unsafe { c::bch2_free_super(&mut sb_handle) };
+
+        for i in 0..layout.nr_superblocks as usize {
+            let offset = u64::from_le(layout.sb_offset[i]);
+
+            opts.sb = offset;
+            opts.set_sb_defined(1);
+
+            let seq = read_super_silent(path, opts).ok().map(|mut h| {
+                let seq = u64::from_le(h.sb().seq);
+                unsafe { c::bch2_free_super(&mut h) };
+                seq
+            });
+
+            ret.copies.push(SbCopy { offset, seq, matches: seq == Some(ret.seq) });
+        }
+
+        Ok(ret)
+    }
+
+    /// Whether every readable copy in the layout agrees with the one used
+    pub fn copies_match(&self) -> bool {
+        self.copies.iter().all(|c| c.matches)
+    }
+
+    /// Name of a superblock field, e.g. for listing which sections are present
+    pub fn field_name(ty: c::bch_sb_field_type) -> String {
+        unsafe {
+            let p = *c::bch2_sb_fields.as_ptr().add(ty as usize);
+            CStr::from_ptr(p).to_string_lossy().into_owned()
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Scratch filesystems on regular files, for exercising the btree API without
+//! root or loop devices
+
+use crate::c;
+use crate::errcode::{bch_errcode, ret_to_result};
+use crate::fs::Fs;
+use crate::io::AlignedBuf;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+extern "C" {
+    static mut optind: c_int;
+}
+
+/// Format parameters for [`TestFs`]
+///
+/// A bcachefs image needs at least `BCH_MIN_NR_NBUCKETS` (64) buckets. With
+/// no explicit bucket size format picks 128k buckets for images of 8MiB and
+/// up, and smaller buckets (down to the block size) below that - so the
+/// absolute minimum is 256k with 4k blocks, but 8MiB or more is recommended
+/// for anything that writes much data; see [`TestFsOpts::min_size`]
+#[derive(Clone, Debug)]
+pub struct TestFsOpts {
+    /// Image size, in bytes
+    pub size:           u64,
+    /// Number of devices, each an image of `size` bytes
+    pub nr_devices:     usize,
+    /// Block size in bytes, or None for the default
+    pub block_size:     Option<u32>,
+    /// Bucket size in bytes, or None for the default
+    pub bucket_size:    Option<u64>,
+    /// Extra arguments passed to format, e.g. "--compression=lz4"
+    pub extra_args:     Vec<String>,
+}
+
+impl Default for TestFsOpts {
+    fn default() -> Self {
+        TestFsOpts {
+            size:           64 << 20,
+            nr_devices:     1,
+            block_size:     None,
+            bucket_size:    None,
+            extra_args:     Vec::new(),
+        }
+    }
+}
+
+/// A freshly formatted filesystem on temporary image files, opened with the
+/// given options; the images are deleted on drop
+pub struct TestFs {
+    fs:     Option<Fs>,
+    paths:  Vec<PathBuf>,
+}
+
+impl TestFsOpts {
+    /// Smallest image size format will accept with these options
+    pub fn min_size(&self) -> u64 {
+        let block_size = self.block_size.unwrap_or(4096) as u64;
+
+        c::BCH_MIN_NR_NBUCKETS as u64 * self.bucket_size.unwrap_or(block_size).max(block_size)
+    }
+}
+
+static TEST_FS_NR: AtomicUsize = AtomicUsize::new(0);
+
+/* cmd_format isn't reentrant: it uses getopt's global state */
+static FORMAT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Format images with the C format command
+fn format(paths: &[PathBuf], opts: &TestFsOpts) -> anyhow::Result<()> {
+    let mut args = vec![
+        "format".to_string(),
+        "--force".to_string(),
+        "--quiet".to_string(),
+        format!("--fs_size={}", opts.size),
+    ];
+
+    if let Some(block_size) = opts.block_size {
+        args.push(format!("--block_size={}", block_size));
+    }
+    if let Some(bucket_size) = opts.bucket_size {
+        args.push(format!("--bucket_size={}", bucket_size));
+    }
+    args.extend(opts.extra_args.iter().cloned());
+    args.extend(paths.iter().map(|p| p.to_string_lossy().into_owned()));
+
+    let args: Vec<_> = args.into_iter().map(|s| CString::new(s).unwrap()).collect();
+    let mut argv: Vec<_> = args.iter().map(|s| s.as_ptr() as *mut c_char).collect();
+
+    let _lock = FORMAT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let ret = unsafe {
+        // cmd_format uses getopt: reset it so we can be called more than once
+        optind = 0;
+        c::cmd_format(argv.len() as c_int, argv.as_mut_ptr())
+    };
+
+    if ret != 0 {
+        Err(anyhow::anyhow!("error formatting {}: {}", paths[0].display(), ret))
+    } else {
+        Ok(())
+    }
+}
+
+impl TestFs {
+    pub fn new(opts: &TestFsOpts, fs_opts: c::bch_opts) -> anyhow::Result<TestFs> {
+        /* format exits the process on a too-small device, so check first: */
+        if opts.size < opts.min_size() {
+            anyhow::bail!("test image too small: {} bytes, need at least {}",
+                          opts.size, opts.min_size());
+        }
+
+        let nr = TEST_FS_NR.fetch_add(1, Ordering::Relaxed);
+
+        /* Constructed before creating images, so they're cleaned up on error: */
+        let mut ret = TestFs { fs: None, paths: Vec::new() };
+
+        for dev in 0..opts.nr_devices.max(1) {
+            let path = std::env::temp_dir().join(format!("bcachefs-test-{}-{}-{}.img",
+                std::process::id(), nr, dev));
+
+            std::fs::File::create(&path)?.set_len(opts.size)?;
+            ret.paths.push(path);
+        }
+
+        format(&ret.paths, opts)?;
+        ret.fs = Some(Fs::open(&ret.paths, fs_opts)?);
+        Ok(ret)
+    }
+
+    pub fn fs(&self) -> &Fs {
+        self.fs.as_ref().unwrap()
+    }
+
+    /// The first device's image
+    pub fn path(&self) -> &Path {
+        &self.paths[0]
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Create a regular file named `name` in directory `parent`, returning
+    /// its inode number
+    pub fn create_file(&self, subvol: u32, parent: u64, name: &str) -> Result<u64, bch_errcode> {
+        let name = CString::new(name).unwrap();
+        let mut inum = 0;
+
+        let ret = unsafe {
+            c::bch2_create_file(self.fs().raw, subvol, parent, name.as_ptr(),
+                libc::S_IFREG | 0o644, &mut inum)
+        };
+        ret_to_result(ret).map(|_| inum)
+    }
+
+    /// Write `data` at `offset` in a file through the normal write path, with
+    /// `nr_replicas` replicas
+    ///
+    /// `offset` must be block aligned. The last block is zero padded, and
+    /// i_size is extended to the end of it.
+    pub fn write_file(&self, subvol: u32, inum: u64, offset: u64, data: &[u8], nr_replicas: u32) -> Result<(), bch_errcode> {
+        let block = self.fs().block_bytes() as usize;
+        let mut offset = offset;
+
+        for chunk in data.chunks(c::BCH_WRITE_BUF_MAX as usize) {
+            let len = (chunk.len() + block - 1) / block * block;
+            let mut buf = AlignedBuf::new(len);
+            buf.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+
+            let ret = unsafe {
+                c::bch2_write_buf(self.fs().raw, subvol, inum, offset, len,
+                    buf.as_slice().as_ptr() as *const _, nr_replicas)
+            };
+            ret_to_result(ret)?;
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Set an xattr; `namespace` is one of the `KEY_TYPE_XATTR_INDEX_*`
+    /// constants
+    pub fn set_xattr(&self, subvol: u32, inum: u64, namespace: u32, name: &str, value: &[u8]) -> Result<(), bch_errcode> {
+        let name = CString::new(name).unwrap();
+
+        let ret = unsafe {
+            c::bch2_xattr_set_buf(self.fs().raw, subvol, inum, namespace,
+                name.as_ptr(), value.as_ptr() as *const _, value.len())
+        };
+        ret_to_result(ret).map(|_| ())
+    }
+}
+
+impl Drop for TestFs {
+    fn drop(&mut self) {
+        /* Stop the filesystem before deleting its image: */
+        self.fs.take();
+        for path in &self.paths {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use crate::c;
+use crate::errcode::{bch_errcode, ret_to_result};
+use crate::fs::Fs;
+
+/// Which operation to get latency stats for, e.g.
+/// `BCH_TIME_journal_flush_write`, `BCH_TIME_btree_node_read`,
+/// `BCH_TIME_btree_interior_update_total`
+pub type TimeStat = c::bch_time_stats;
+
+/// Latency stats for one operation class; all times are in nanoseconds
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TimeStatSnapshot {
+    pub count:          u64,
+    pub min_ns:         u64,
+    pub max_ns:         u64,
+    pub mean_ns:        u64,
+    pub stddev_ns:      u64,
+    /// Time between events
+    pub mean_freq_ns:   u64,
+    pub stddev_freq_ns: u64,
+    /// Approximate latency quantiles, evenly spaced, lowest first; only
+    /// tracked for some stats
+    pub quantiles_ns:   Option<Vec<u64>>,
+}
+
+impl From<&c::bch_time_stats_snapshot> for TimeStatSnapshot {
+    fn from(s: &c::bch_time_stats_snapshot) -> TimeStatSnapshot {
+        TimeStatSnapshot {
+            count:          s.count,
+            min_ns:         s.min_ns,
+            max_ns:         s.max_ns,
+            mean_ns:        s.mean_ns,
+            stddev_ns:      s.stddev_ns,
+            mean_freq_ns:   s.mean_freq_ns,
+            stddev_freq_ns: s.stddev_freq_ns,
+            quantiles_ns:   s.have_quantiles.then(|| s.quantiles_ns.to_vec()),
+        }
+    }
+}
+
+impl Fs {
+    pub fn time_stats(&self, which: TimeStat) -> Result<TimeStatSnapshot, bch_errcode> {
+        let mut s: c::bch_time_stats_snapshot = Default::default();
+
+        ret_to_result(unsafe { c::bch2_time_stats_read(self.raw, which as u32, &mut s) })?;
+
+        Ok(TimeStatSnapshot::from(&s))
+    }
+}
@@ -0,0 +1,69 @@
+use crate::c;
+use crate::fs::Fs;
+
+/* Same as avail_factor() in buckets.h: */
+fn avail_factor(r: u64) -> u64 {
+    (r << 6) / ((1 << 6) + 1)
+}
+
+/// How much can still be written, see [`Fs::space_available`]
+///
+/// All sizes are in bytes. User data and metadata headroom differ: user
+/// writes take a disk reservation, which has to leave a fraction of free
+/// space in reserve, and are replicated `data_replicas` times, while btree
+/// updates may dip into the reserve and are replicated `metadata_replicas`
+/// times. Both assume the filesystem wide replica settings; files with
+/// their own replicas or target options (e.g. a target with less free
+/// space) may run out sooner.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SpaceReport {
+    /// Usable capacity, excluding space reserved for copygc and for
+    /// metadata that's hidden from usage (superblocks, journal)
+    pub capacity:           u64,
+    /// Space used by data and metadata, including replicas and outstanding
+    /// reservations
+    pub used:               u64,
+    /// `capacity - used`, before replication
+    pub free:               u64,
+    pub nr_inodes:          u64,
+    pub data_replicas:      u32,
+    pub metadata_replicas:  u32,
+    /// User data that can be written before ENOSPC
+    pub user_available:     u64,
+    /// Metadata that can be written before ENOSPC
+    pub metadata_available: u64,
+}
+
+impl SpaceReport {
+    /// Whether `bytes` of user data would currently fit
+    pub fn can_write(&self, bytes: u64) -> bool {
+        bytes <= self.user_available
+    }
+}
+
+impl Fs {
+    /// Report free space, and how much user data and metadata can still be
+    /// written
+    ///
+    /// This is a snapshot: it doesn't reserve anything, so concurrent writers
+    /// may use the space first.
+    pub fn space_available(&self) -> SpaceReport {
+        let u = unsafe { c::bch2_fs_usage_read_short(self.raw) };
+        let (data_replicas, metadata_replicas) = unsafe {
+            let opts = &(*self.raw).opts;
+            (opts.data_replicas.max(1) as u32, opts.metadata_replicas.max(1) as u32)
+        };
+
+        SpaceReport {
+            capacity:           u.capacity << 9,
+            used:               u.used << 9,
+            free:               u.free << 9,
+            nr_inodes:          u.nr_inodes,
+            data_replicas,
+            metadata_replicas,
+            user_available:     (avail_factor(u.free) / data_replicas as u64) << 9,
+            metadata_available: (u.free / metadata_replicas as u64) << 9,
+        }
+    }
+}
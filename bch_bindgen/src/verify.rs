@@ -0,0 +1,221 @@
+use crate::c;
+use crate::bkey::BkeySC;
+use crate::btree::{BtreeTrans, BtreeNodeIter, BtreeIterFlags};
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::io::AlignedBuf;
+use crate::POS_MIN;
+use std::fs::File;
+use std::mem::{transmute, ManuallyDrop};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+
+/// Where a piece of metadata checked by [`Fs::verify_metadata`] lives
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataLocation {
+    /// Superblock copy `idx` in the device's superblock layout
+    Superblock { idx: u32 },
+    BtreeNode { btree: c::btree_id, level: u32, pos: c::bpos },
+    /// Entry in journal bucket `bucket` (an index into the device's journal
+    /// buckets)
+    Journal { bucket: u32 },
+}
+
+/// A piece of metadata that failed to verify, see [`MetadataCheckReport`]
+#[derive(Clone, Copy, Debug)]
+pub struct MetadataError {
+    pub dev:        u32,
+    /// Start on the device, in sectors
+    pub offset:     u64,
+    pub location:   MetadataLocation,
+}
+
+/// Result of [`Fs::verify_metadata`]
+#[derive(Clone, Debug, Default)]
+pub struct MetadataCheckReport {
+    /// Superblocks, btree node replicas and journal entries whose checksums
+    /// were checked
+    pub checked:        u64,
+    /// Checksum mismatches
+    pub csum_errors:    Vec<MetadataError>,
+    /// Metadata that couldn't be decoded enough to check its checksum, e.g.
+    /// bad magic, an unknown checksum type or a size that doesn't fit
+    pub decode_errors:  Vec<MetadataError>,
+    /// Read errors, with errno
+    pub read_errors:    Vec<(MetadataError, i32)>,
+}
+
+impl MetadataCheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.csum_errors.is_empty() &&
+            self.decode_errors.is_empty() &&
+            self.read_errors.is_empty()
+    }
+
+    fn nr_errors(&self) -> u64 {
+        (self.csum_errors.len() + self.decode_errors.len() + self.read_errors.len()) as u64
+    }
+
+    fn add(&mut self, err: MetadataError, ret: Result<i32, i32>) {
+        self.checked += 1;
+
+        match ret {
+            Ok(0)   => {}
+            Ok(r) if r == -libc::EBADMSG =>
+                self.csum_errors.push(err),
+            Ok(_)   => self.decode_errors.push(err),
+            Err(e)  => self.read_errors.push((err, e)),
+        }
+    }
+}
+
+/// Progress of [`Fs::verify_metadata`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MetadataCheckProgress {
+    pub checked:    u64,
+    pub errors:     u64,
+}
+
+/* Read @bytes at @sector from a member device, or the errno: */
+unsafe fn dev_read(ca: &c::bch_dev, sector: u64, bytes: usize) -> Result<AlignedBuf, i32> {
+    let bdev = &*ca.disk_sb.bdev;
+    let file = ManuallyDrop::new(File::from_raw_fd(bdev.bd_fd));
+    let mut buf = AlignedBuf::new(bytes);
+
+    file.read_exact_at(&mut buf.as_mut_slice()[..bytes], bdev.bd_offset + (sector << 9))
+        .map_err(|e| e.raw_os_error().unwrap_or(0))?;
+    Ok(buf)
+}
+
+impl Fs {
+    fn dev_ref(&self, dev: u32) -> Option<&c::bch_dev> {
+        self.dev_is_readable(dev).then(|| unsafe { &*(*self.raw).devs[dev as usize] })
+    }
+
+    fn verify_superblocks(&self, r: &mut MetadataCheckReport) {
+        let nr_devices = unsafe { (*self.raw).sb.nr_devices as u32 };
+
+        for dev in 0..nr_devices {
+            let Some(ca) = self.dev_ref(dev) else { continue };
+            let layout = unsafe { &(*ca.disk_sb.sb).layout };
+            let bytes = 512usize << layout.sb_max_size_bits;
+
+            for idx in 0..layout.nr_superblocks as u32 {
+                let offset = u64::from_le(layout.sb_offset[idx as usize]);
+                let ret = unsafe { dev_read(ca, offset, bytes) }.map(|buf| unsafe {
+                    c::bch2_sb_buf_verify(buf.as_slice().as_ptr() as *mut c::bch_sb, bytes)
+                });
+
+                r.add(MetadataError { dev, offset, location: MetadataLocation::Superblock { idx } }, ret);
+            }
+        }
+    }
+
+    fn verify_btree_nodes<F>(&self, r: &mut MetadataCheckReport, progress: &mut F) -> Result<(), bch_errcode>
+    where F: FnMut(MetadataCheckProgress) {
+        let bytes = unsafe { (*self.raw).opts.btree_node_size } as usize;
+        let trans = BtreeTrans::new(self);
+
+        for id in 0..c::btree_id::BTREE_ID_NR as u32 {
+            let btree: c::btree_id = unsafe { transmute(id) };
+            let mut iter = BtreeNodeIter::new(&trans, btree, POS_MIN, 0, 0, BtreeIterFlags::PREFETCH);
+
+            while let Some(b) = iter.peek_and_restart()? {
+                let location = MetadataLocation::BtreeNode {
+                    btree,
+                    level:  b.level(),
+                    pos:    b.key.k.p,
+                };
+
+                for p in BkeySC::from(&b.key).pointers() {
+                    let Some(ca) = self.dev_ref(p.dev) else { continue };
+                    let ret = unsafe { dev_read(ca, p.offset, bytes) }.map(|buf| unsafe {
+                        c::bch2_btree_node_buf_verify(self.raw,
+                            buf.as_slice().as_ptr() as *mut c::btree_node, bytes)
+                    });
+
+                    r.add(MetadataError { dev: p.dev, offset: p.offset, location }, ret);
+                }
+
+                progress(MetadataCheckProgress { checked: r.checked, errors: r.nr_errors() });
+                iter.advance();
+            }
+        }
+        Ok(())
+    }
+
+    fn verify_journal(&self, r: &mut MetadataCheckReport) {
+        let nr_devices = unsafe { (*self.raw).sb.nr_devices as u32 };
+
+        for dev in 0..nr_devices {
+            let Some(ca) = self.dev_ref(dev) else { continue };
+            let ja = &ca.journal;
+            let bucket_size = ca.mi.bucket_size as u64;
+
+            for bucket in 0..ja.nr {
+                let start = unsafe { *ja.buckets.add(bucket as usize) } * bucket_size;
+                let location = MetadataLocation::Journal { bucket };
+
+                let buf = match unsafe { dev_read(ca, start, (bucket_size << 9) as usize) } {
+                    Ok(buf) => buf,
+                    Err(e)  => {
+                        r.add(MetadataError { dev, offset: start, location }, Err(e));
+                        continue;
+                    }
+                };
+
+                /* Same walk as journal_read_bucket(): */
+                let mut offset = 0u64;
+                let mut last_seq = 0u64;
+                let mut saw_bad = false;
+
+                while offset < bucket_size {
+                    let j = unsafe { buf.as_slice().as_ptr().add((offset << 9) as usize) } as *mut c::jset;
+                    let mut sectors = 0u32;
+                    let ret = unsafe {
+                        c::bch2_jset_verify(self.raw, j, ((bucket_size - offset) << 9) as usize, &mut sectors)
+                    };
+
+                    if ret == 1 {
+                        /* no entry: the end of the bucket, unless we're skipping a bad one */
+                        if !saw_bad {
+                            break;
+                        }
+                        sectors = 1 << unsafe { (*self.raw).block_bits };
+                    } else {
+                        /* older entries past the newest are stale: */
+                        let seq = u64::from_le(unsafe { (*j).seq });
+                        if ret == 0 && seq < last_seq {
+                            break;
+                        }
+                        last_seq = last_seq.max(seq);
+                        saw_bad |= ret != 0;
+
+                        r.add(MetadataError { dev, offset: start + offset, location }, Ok(ret));
+                    }
+                    offset += sectors.max(1) as u64;
+                }
+            }
+        }
+    }
+
+    /// Check the checksums of all metadata on disk - superblocks, every
+    /// replica of every btree node and the journal - without validating or
+    /// repairing anything
+    ///
+    /// This is a quick, read only integrity scan, much less thorough than
+    /// fsck: it finds media errors and corruption, not inconsistencies.
+    /// Offline devices are skipped. `progress` is called after each btree
+    /// node.
+    pub fn verify_metadata<F>(&self, mut progress: F) -> Result<MetadataCheckReport, bch_errcode>
+    where F: FnMut(MetadataCheckProgress) {
+        let mut r = MetadataCheckReport::default();
+
+        self.verify_superblocks(&mut r);
+        self.verify_btree_nodes(&mut r, &mut progress)?;
+        self.verify_journal(&mut r);
+
+        progress(MetadataCheckProgress { checked: r.checked, errors: r.nr_errors() });
+        Ok(r)
+    }
+}
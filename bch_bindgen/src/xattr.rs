@@ -0,0 +1,112 @@
+use crate::c;
+use crate::bkey::BkeyValC;
+use crate::btree::{BtreeTrans, BtreeIter, BtreeIterFlags};
+use crate::errcode::bch_errcode;
+use crate::fs::Fs;
+use crate::spos;
+use std::fmt;
+
+/// Xattr namespace, from bch_xattr.x_type
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XattrNamespace {
+    User,
+    PosixAclAccess,
+    PosixAclDefault,
+    Trusted,
+    Security,
+    Unknown(u8),
+}
+
+impl XattrNamespace {
+    pub fn from_raw(x_type: u8) -> XattrNamespace {
+        match x_type as u32 {
+            c::KEY_TYPE_XATTR_INDEX_USER                => XattrNamespace::User,
+            c::KEY_TYPE_XATTR_INDEX_POSIX_ACL_ACCESS    => XattrNamespace::PosixAclAccess,
+            c::KEY_TYPE_XATTR_INDEX_POSIX_ACL_DEFAULT   => XattrNamespace::PosixAclDefault,
+            c::KEY_TYPE_XATTR_INDEX_TRUSTED             => XattrNamespace::Trusted,
+            c::KEY_TYPE_XATTR_INDEX_SECURITY            => XattrNamespace::Security,
+            _                                           => XattrNamespace::Unknown(x_type),
+        }
+    }
+
+    /// Prefix the VFS shows for names in this namespace
+    ///
+    /// POSIX ACLs are stored with an empty name, so the prefix is the whole
+    /// name.
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            XattrNamespace::User            => "user.",
+            XattrNamespace::PosixAclAccess  => "system.posix_acl_access",
+            XattrNamespace::PosixAclDefault => "system.posix_acl_default",
+            XattrNamespace::Trusted         => "trusted.",
+            XattrNamespace::Security        => "security.",
+            XattrNamespace::Unknown(_)      => "",
+        }
+    }
+}
+
+/// Name of an xattr, as stored in the xattrs btree
+///
+/// Note that bcachefs.* and bcachefs_effective.* xattrs are synthesized from
+/// inode options by the kernel and never appear in the xattrs btree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XattrName {
+    pub namespace:  XattrNamespace,
+    pub name:       Vec<u8>,
+}
+
+impl fmt::Display for XattrName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.namespace.prefix(), String::from_utf8_lossy(&self.name))
+    }
+}
+
+impl c::bch_xattr {
+    pub fn name(&self) -> XattrName {
+        XattrName {
+            namespace:  XattrNamespace::from_raw(self.x_type),
+            name:       unsafe { self.x_name.as_slice(self.x_name_len as usize) }.to_vec(),
+        }
+    }
+
+    /// The value immediately follows the name
+    pub fn value(&self) -> &[u8] {
+        unsafe {
+            let p = self.x_name.as_ptr().add(self.x_name_len as usize);
+            std::slice::from_raw_parts(p, u16::from_le(self.x_val_len) as usize)
+        }
+    }
+}
+
+impl Fs {
+    /// All xattrs on an inode, as visible from the given snapshot
+    pub fn xattrs(&self, inode: u64, snapshot: u32) -> impl Iterator<Item = Result<(XattrName, Vec<u8>), bch_errcode>> {
+        let trans = BtreeTrans::new(self);
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_xattrs,
+            spos(inode, 0, snapshot),
+            BtreeIterFlags::empty());
+        let mut ret = Vec::new();
+
+        loop {
+            match iter.peek_and_restart() {
+                Ok(Some(k)) => {
+                    if k.k.p.inode != inode {
+                        break;
+                    }
+
+                    if let BkeyValC::xattr(x) = k.v() {
+                        ret.push(Ok((x.name(), x.value().to_vec())));
+                    }
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    ret.push(Err(e));
+                    break;
+                }
+            }
+            iter.advance();
+        }
+
+        ret.into_iter()
+    }
+}
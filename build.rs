@@ -2,6 +2,12 @@ fn main() {
     println!("cargo:rustc-link-search=.");
     println!("cargo:rerun-if-changed=libbcachefs.a");
     println!("cargo:rustc-link-lib=static:+whole-archive=bcachefs");
+    /* for bch2_fs_commit_hook_set(), in c_src/libbcachefs.c: */
+    println!("cargo:rustc-link-arg=-Wl,--wrap=__bch2_trans_commit");
+    /* for bch2_fs_fsck_err_count_start(): */
+    println!("cargo:rustc-link-arg=-Wl,--wrap=bch2_flush_fsck_errs");
+    /* for bch2_bkey_invalid_nocount(): */
+    println!("cargo:rustc-link-arg=-Wl,--wrap=bch2_sb_error_count");
 
     println!("cargo:rustc-link-lib=urcu");
     println!("cargo:rustc-link-lib=zstd");
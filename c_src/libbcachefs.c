@@ -19,14 +19,37 @@
 #include "libbcachefs.h"
 #include "crypto.h"
 #include "libbcachefs/bcachefs_format.h"
+#include "libbcachefs/bkey_methods.h"
+#include "libbcachefs/bset.h"
 #include "libbcachefs/btree_cache.h"
+#include "libbcachefs/btree_io.h"
+#include "libbcachefs/btree_update_interior.h"
+#include "libbcachefs/btree_update.h"
 #include "libbcachefs/buckets.h"
 #include "libbcachefs/checksum.h"
+#include "libbcachefs/dirent.h"
 #include "libbcachefs/disk_groups.h"
+#include "libbcachefs/error.h"
+#include "libbcachefs/extents.h"
+#include "libbcachefs/fs-common.h"
+#include "libbcachefs/inode.h"
+#include "libbcachefs/super.h"
+#include "libbcachefs/io_read.h"
+#include "libbcachefs/io_write.h"
+#include "libbcachefs/alloc_background.h"
+#include "libbcachefs/alloc_foreground.h"
+#include "libbcachefs/move.h"
+#include "libbcachefs/journal.h"
+#include "libbcachefs/journal_io.h"
+#include "libbcachefs/journal_reclaim.h"
 #include "libbcachefs/journal_seq_blacklist.h"
 #include "libbcachefs/opts.h"
+#include "libbcachefs/rebalance.h"
 #include "libbcachefs/replicas.h"
+#include "libbcachefs/str_hash.h"
+#include "libbcachefs/sb-errors.h"
 #include "libbcachefs/super-io.h"
+#include "libbcachefs/xattr.h"
 #include "tools-util.h"
 
 #define NSEC_PER_SEC	1000000000L
@@ -579,6 +602,1021 @@ int bchu_data(struct bchfs_handle fs, struct bch_ioctl_data cmd)
 	return 0;
 }
 
+/* labels: */
+
+int bch2_fs_label_set(struct bch_fs *c, const char *label)
+{
+	size_t len = strlen(label);
+
+	if (len > BCH_SB_LABEL_SIZE)
+		return -EINVAL;
+
+	mutex_lock(&c->sb_lock);
+	memset(c->disk_sb.sb->label, 0, sizeof(c->disk_sb.sb->label));
+	memcpy(c->disk_sb.sb->label, label, len);
+
+	int ret = bch2_write_super(c);
+	mutex_unlock(&c->sb_lock);
+	return ret;
+}
+
+/*
+ * Only changes the user visible UUID: the internal UUID is baked into every
+ * btree node and journal entry magic number, and can't be changed without
+ * rewriting all metadata.
+ */
+int bch2_fs_uuid_set(struct bch_fs *c, const __uuid_t *uuid)
+{
+	mutex_lock(&c->sb_lock);
+	__uuid_t old = c->disk_sb.sb->user_uuid;
+	c->disk_sb.sb->user_uuid = *uuid;
+
+	/*
+	 * If the write failed on some members, write the old UUID back out with
+	 * a newer seq so the members agree again:
+	 */
+	int ret = bch2_write_super(c);
+	if (ret) {
+		c->disk_sb.sb->user_uuid = old;
+		bch2_write_super(c);
+	}
+	mutex_unlock(&c->sb_lock);
+	return ret;
+}
+
+/* reading file data: */
+
+static void bch2_read_buf_endio(struct bio *bio)
+{
+	closure_put(bio->bi_private);
+}
+
+/*
+ * Read @size bytes at @offset from a file into @buf; @size and @offset must be
+ * block aligned. Holes read as zeroes.
+ */
+int bch2_read_buf(struct bch_fs *c, u32 subvol, u64 inum,
+		  u64 offset, size_t size, void *buf)
+{
+	subvol_inum i = { .subvol = subvol, .inum = inum };
+	struct bch_inode_unpacked inode;
+	struct bch_io_opts io_opts;
+
+	if ((size | offset) & (block_bytes(c) - 1))
+		return -EINVAL;
+
+	int ret = bch2_inode_find_by_inum(c, i, &inode);
+	if (ret)
+		return ret;
+
+	bch2_inode_opts_get(&io_opts, c, &inode);
+
+	struct bch_read_bio rbio;
+	struct bio_vec bv;
+
+	bio_init(&rbio.bio, NULL, &bv, 1, 0);
+	rbio.bio.bi_iter.bi_size	= size;
+	bv.bv_page			= buf;
+	bv.bv_len			= size;
+	bv.bv_offset			= 0;
+
+	bio_set_op_attrs(&rbio.bio, REQ_OP_READ, REQ_SYNC);
+	rbio.bio.bi_iter.bi_sector	= offset >> 9;
+
+	struct closure cl;
+	closure_init_stack(&cl);
+
+	closure_get(&cl);
+	rbio.bio.bi_end_io		= bch2_read_buf_endio;
+	rbio.bio.bi_private		= &cl;
+
+	bch2_read(c, rbio_init(&rbio.bio, io_opts), i);
+
+	closure_sync(&cl);
+
+	return -blk_status_to_errno(rbio.bio.bi_status);
+}
+
+/*
+ * Write @size bytes from @buf at @offset in a file, with @nr_replicas
+ * replicas, extending i_size if needed; @size and @offset must be block
+ * aligned, and @size at most BCH_WRITE_BUF_MAX:
+ */
+int bch2_write_buf(struct bch_fs *c, u32 subvol, u64 inum,
+		   u64 offset, size_t size, const void *buf,
+		   unsigned nr_replicas)
+{
+	subvol_inum i = { .subvol = subvol, .inum = inum };
+	struct bch_inode_unpacked inode;
+	struct bch_io_opts io_opts;
+	struct bch_write_op op;
+	struct bio_vec bv[BCH_WRITE_BUF_MAX / PAGE_SIZE];
+
+	if ((size | offset) & (block_bytes(c) - 1))
+		return -EINVAL;
+	if (size > BCH_WRITE_BUF_MAX)
+		return -EINVAL;
+
+	int ret = bch2_inode_find_by_inum(c, i, &inode);
+	if (ret)
+		return ret;
+
+	bch2_inode_opts_get(&io_opts, c, &inode);
+
+	bio_init(&op.wbio.bio, NULL, bv, ARRAY_SIZE(bv), 0);
+	bch2_bio_map(&op.wbio.bio, (void *) buf, size);
+
+	bch2_write_op_init(&op, c, io_opts);
+	op.write_point	= writepoint_hashed(0);
+	op.nr_replicas	= nr_replicas;
+	op.subvol	= subvol;
+	op.pos		= POS(inum, offset >> 9);
+	op.new_i_size	= offset + size;
+	op.flags	|= BCH_WRITE_SYNC;
+
+	ret = bch2_disk_reservation_get(c, &op.res, size >> 9, nr_replicas, 0);
+	if (ret)
+		return ret;
+
+	closure_call(&op.cl, bch2_write, NULL, NULL);
+
+	BUG_ON(!(op.flags & BCH_WRITE_DONE));
+	return op.error;
+}
+
+/* Create a file (or directory, etc. per @mode) named @name in @parent: */
+int bch2_create_file(struct bch_fs *c, u32 subvol, u64 parent,
+		     const char *name, u32 mode, u64 *inum)
+{
+	struct qstr qstr = QSTR(name);
+	struct bch_inode_unpacked parent_u, inode;
+
+	bch2_inode_init_early(c, &inode);
+
+	int ret = bch2_trans_do(c, NULL, NULL, 0,
+		bch2_create_trans(trans,
+				  (subvol_inum) { subvol, parent }, &parent_u,
+				  &inode, &qstr, 0, 0, mode, 0, NULL, NULL,
+				  (subvol_inum) {}, 0));
+	if (!ret)
+		*inum = inode.bi_inum;
+	return ret;
+}
+
+/* Set an xattr, as setxattr(); @type is a KEY_TYPE_XATTR_INDEX_* namespace: */
+int bch2_xattr_set_buf(struct bch_fs *c, u32 subvol, u64 inum, unsigned type,
+		       const char *name, const void *val, size_t size)
+{
+	subvol_inum i = { .subvol = subvol, .inum = inum };
+	struct bch_inode_unpacked inode;
+
+	int ret = bch2_inode_find_by_inum(c, i, &inode);
+	if (ret)
+		return ret;
+
+	struct bch_hash_info hash_info = bch2_hash_info_init(c, &inode);
+
+	return bch2_trans_do(c, NULL, NULL, 0,
+		bch2_xattr_set(trans, i, &inode, &hash_info, name,
+			       val, size, type, 0));
+}
+
+/*
+ * Move all data out of a bucket: dirty data is rewritten elsewhere, cached
+ * pointers are dropped:
+ */
+int bch2_dev_evacuate_bucket(struct bch_fs *c, unsigned dev, u64 bucket)
+{
+	struct moving_context ctxt;
+	struct bch_move_stats stats;
+	int ret;
+
+	if (!test_bit(BCH_FS_rw, &c->flags))
+		return -EROFS;
+
+	if (!bch2_dev_exists2(c, dev))
+		return -BCH_ERR_ENOENT_dev_idx_not_found;
+
+	if (!bch2_dev_bucket_exists(c, POS(dev, bucket)))
+		return -EINVAL;
+
+	bch2_move_stats_init(&stats, "evacuate_bucket");
+	bch2_moving_ctxt_init(&ctxt, c, NULL, &stats,
+			      writepoint_ptr(&c->copygc_write_point), false);
+
+	ret = bch2_evacuate_bucket(&ctxt, NULL, POS(dev, bucket), -1,
+				   (struct data_update_opts) { 0 });
+
+	bch2_moving_ctxt_exit(&ctxt);
+	bch2_move_stats_exit(&stats, c);
+
+	/* btree nodes aren't freed until their interior updates complete: */
+	bch2_btree_interior_updates_flush(c);
+	return ret;
+}
+
+/*
+ * Run the discard worker and wait for it, for the free buckets on device @dev
+ * that need discarding: it handles every device, so pending discards on other
+ * devices are issued too. Buckets that are open or waiting on a journal commit
+ * are skipped, as always.
+ */
+int bch2_dev_discard_free_buckets(struct bch_fs *c, unsigned dev)
+{
+	if (!test_bit(BCH_FS_rw, &c->flags))
+		return -EROFS;
+
+	if (!bch2_dev_exists2(c, dev))
+		return -BCH_ERR_ENOENT_dev_idx_not_found;
+
+	/* a run that's already in progress may have passed our buckets: */
+	flush_work(&c->discard_work);
+	bch2_do_discards(c);
+	flush_work(&c->discard_work);
+	return 0;
+}
+
+/*
+ * Member devices that don't support O_DIRECT are opened buffered instead (see
+ * bdev_open_by_path()): warn about each one, and return how many there were:
+ */
+unsigned bch2_fs_warn_direct_unsupported(struct bch_fs *c)
+{
+	unsigned nr = 0;
+
+	for_each_online_member(c, ca)
+		if (ca->disk_sb.bdev->bd_direct_unsupported) {
+			bch_warn(c, "%s: O_DIRECT not supported, using buffered IO", ca->name);
+			nr++;
+		}
+
+	return nr;
+}
+
+/* bch2_btree_iter_set_pos() is inline, so not visible to Rust: */
+void bch2_btree_iter_set_pos_outlined(struct btree_iter *iter, struct bpos new_pos)
+{
+	bch2_btree_iter_set_pos(iter, new_pos);
+}
+
+/*
+ * Commit hooks: libbcachefs has no hook of its own, so we link with
+ * -Wl,--wrap=__bch2_trans_commit (see build.rs) and every transaction commit
+ * comes through __wrap___bch2_trans_commit() below:
+ */
+struct commit_hook {
+	struct list_head	list;
+	struct bch_fs		*c;
+	void			(*fn)(void *, struct btree_trans *, bool);
+	void			*data;
+};
+
+static LIST_HEAD(commit_hooks);
+static DEFINE_MUTEX(commit_hooks_lock);
+
+static struct commit_hook *commit_hook_find(struct bch_fs *c)
+{
+	struct commit_hook *h;
+
+	list_for_each_entry(h, &commit_hooks, list)
+		if (h->c == c)
+			return h;
+	return NULL;
+}
+
+/*
+ * Call @fn before each attempt to commit a transaction on @c with @committed
+ * false, and after it's committed with @committed true - e.g. for recording
+ * what a dry run would change. Set before bch2_fs_start() (open with nostart)
+ * to see commits made by recovery and fsck; pass NULL to clear, which must be
+ * done before @c is freed.
+ *
+ * @fn sees the updates the transaction was committed with, before triggers
+ * have run:
+ */
+int bch2_fs_commit_hook_set(struct bch_fs *c,
+			    void (*fn)(void *, struct btree_trans *, bool),
+			    void *data)
+{
+	struct commit_hook *h;
+	int ret = 0;
+
+	mutex_lock(&commit_hooks_lock);
+	h = commit_hook_find(c);
+
+	if (!fn) {
+		if (h) {
+			list_del(&h->list);
+			kfree(h);
+		}
+		goto out;
+	}
+
+	if (!h) {
+		h = kzalloc(sizeof(*h), GFP_KERNEL);
+		if (!h) {
+			ret = -ENOMEM;
+			goto out;
+		}
+		h->c = c;
+		list_add(&h->list, &commit_hooks);
+	}
+
+	h->fn	= fn;
+	h->data	= data;
+out:
+	mutex_unlock(&commit_hooks_lock);
+	return ret;
+}
+
+int __real___bch2_trans_commit(struct btree_trans *, unsigned);
+
+int __wrap___bch2_trans_commit(struct btree_trans *trans, unsigned flags)
+{
+	struct commit_hook *h, hook = { .fn = NULL };
+	int ret;
+
+	if (trans->nr_updates && !list_empty(&commit_hooks)) {
+		mutex_lock(&commit_hooks_lock);
+		h = commit_hook_find(trans->c);
+		if (h)
+			hook = *h;
+		mutex_unlock(&commit_hooks_lock);
+	}
+
+	if (hook.fn)
+		hook.fn(hook.data, trans, false);
+
+	ret = __real___bch2_trans_commit(trans, flags);
+
+	if (hook.fn && !ret)
+		hook.fn(hook.data, trans, true);
+	return ret;
+}
+
+struct fsck_err_count {
+	struct list_head	list;
+	struct bch_fs		*c;
+	u64			fixed;
+	u64			not_fixed;
+};
+
+static LIST_HEAD(fsck_err_counts);
+static DEFINE_MUTEX(fsck_err_counts_lock);
+
+static struct fsck_err_count *fsck_err_count_find(struct bch_fs *c)
+{
+	struct fsck_err_count *e;
+
+	list_for_each_entry(e, &fsck_err_counts, list)
+		if (e->c == c)
+			return e;
+	return NULL;
+}
+
+/*
+ * Add up the fsck errors seen since the last flush, by the outcome of the last
+ * instance of each error; called with fsck_err_counts_lock held:
+ */
+static void fsck_err_count_add(struct fsck_err_count *e)
+{
+	struct fsck_err_state *s;
+
+	mutex_lock(&e->c->fsck_error_msgs_lock);
+	list_for_each_entry(s, &e->c->fsck_error_msgs, list)
+		if (s->ret == -BCH_ERR_fsck_fix)
+			e->fixed += s->nr;
+		else
+			e->not_fixed += s->nr;
+	mutex_unlock(&e->c->fsck_error_msgs_lock);
+}
+
+/*
+ * Start counting fsck errors on @c by outcome, fixed or not: only errors seen
+ * while fsck is running are counted. Start before bch2_fs_start() (open with
+ * nostart) to count errors found by recovery and fsck, and stop with
+ * bch2_fs_fsck_err_count_stop() before @c is freed.
+ */
+int bch2_fs_fsck_err_count_start(struct bch_fs *c)
+{
+	struct fsck_err_count *e;
+	int ret = 0;
+
+	mutex_lock(&fsck_err_counts_lock);
+	if (!fsck_err_count_find(c)) {
+		e = kzalloc(sizeof(*e), GFP_KERNEL);
+		if (!e) {
+			ret = -ENOMEM;
+			goto out;
+		}
+		e->c = c;
+		list_add(&e->list, &fsck_err_counts);
+	}
+out:
+	mutex_unlock(&fsck_err_counts_lock);
+	return ret;
+}
+
+void bch2_fs_fsck_err_count_stop(struct bch_fs *c, u64 *fixed, u64 *not_fixed)
+{
+	struct fsck_err_count *e;
+
+	*fixed = *not_fixed = 0;
+
+	mutex_lock(&fsck_err_counts_lock);
+	e = fsck_err_count_find(c);
+	if (e) {
+		fsck_err_count_add(e);
+		*fixed		= e->fixed;
+		*not_fixed	= e->not_fixed;
+
+		list_del(&e->list);
+		kfree(e);
+	}
+	mutex_unlock(&fsck_err_counts_lock);
+}
+
+void __real_bch2_flush_fsck_errs(struct bch_fs *);
+
+/* Errors are forgotten when flushed, at the end of each recovery pass: */
+void __wrap_bch2_flush_fsck_errs(struct bch_fs *c)
+{
+	struct fsck_err_count *e;
+
+	if (!list_empty(&fsck_err_counts)) {
+		mutex_lock(&fsck_err_counts_lock);
+		e = fsck_err_count_find(c);
+		if (e)
+			fsck_err_count_add(e);
+		mutex_unlock(&fsck_err_counts_lock);
+	}
+
+	__real_bch2_flush_fsck_errs(c);
+}
+
+/*
+ * Key validation counts each error in the superblock's error counters: that's
+ * wrong for keys that were never in the filesystem, so we link with
+ * -Wl,--wrap=bch2_sb_error_count (see build.rs) and don't count errors while
+ * in bch2_bkey_invalid_nocount():
+ */
+static __thread bool sb_error_count_off;
+
+void __real_bch2_sb_error_count(struct bch_fs *, enum bch_sb_error_id);
+
+void __wrap_bch2_sb_error_count(struct bch_fs *c, enum bch_sb_error_id err)
+{
+	if (!sb_error_count_off)
+		__real_bch2_sb_error_count(c, err);
+}
+
+/* bch2_bkey_invalid(), without touching the superblock's error counters: */
+int bch2_bkey_invalid_nocount(struct bch_fs *c, const struct bkey *k,
+			      const struct bch_val *v, unsigned type,
+			      unsigned flags, struct printbuf *err)
+{
+	int ret;
+
+	sb_error_count_off = true;
+	ret = bch2_bkey_invalid(c, (struct bkey_s_c) { .k = k, .v = v },
+				type, flags, err);
+	sb_error_count_off = false;
+	return ret;
+}
+
+/*
+ * Flush everything to stable storage: btree updates in the key cache and
+ * write buffer, btree nodes pinning the journal, and then the journal itself:
+ */
+int bch2_fs_sync(struct bch_fs *c)
+{
+	if (!test_bit(BCH_FS_rw, &c->flags))
+		return 0;
+
+	bch2_journal_flush_all_pins(&c->journal);
+	return bch2_journal_flush(&c->journal);
+}
+
+/*
+ * Go read-only, waiting for in flight writes to finish: returns 1 if already
+ * read-only
+ */
+int bch2_fs_set_ro(struct bch_fs *c)
+{
+	int ret = 0;
+
+	down_write(&c->state_lock);
+	if (test_bit(BCH_FS_rw, &c->flags))
+		bch2_fs_read_only(c);
+	else
+		ret = 1;
+	up_write(&c->state_lock);
+	return ret;
+}
+
+/* Returns 1 if already read-write: */
+int bch2_fs_set_rw(struct bch_fs *c)
+{
+	int ret;
+
+	down_write(&c->state_lock);
+	ret = !test_bit(BCH_FS_rw, &c->flags)
+		? bch2_fs_read_write(c)
+		: 1;
+	up_write(&c->state_lock);
+	return ret;
+}
+
+/*
+ * Force journal reclaim: run direct reclaim, as when the journal is filling up,
+ * wait for the resulting btree node writes, then write a new journal entry so
+ * the reclaimed space can be reused. Nothing is waited on but the writes
+ * reclaim started. Returns the number of journal entries reclaimed in
+ * @nr_reclaimed:
+ */
+int bch2_fs_journal_reclaim(struct bch_fs *c, u64 *nr_reclaimed)
+{
+	struct journal *j = &c->journal;
+	u64 old_last_seq, new_last_seq;
+	int ret;
+
+	*nr_reclaimed = 0;
+
+	if (!test_bit(BCH_FS_rw, &c->flags))
+		return 0;
+
+	ret = bch2_journal_error(j);
+	if (ret)
+		return ret;
+
+	spin_lock(&j->lock);
+	old_last_seq = journal_last_seq(j);
+	spin_unlock(&j->lock);
+
+	mutex_lock(&j->reclaim_lock);
+	ret = bch2_journal_reclaim(j);
+	mutex_unlock(&j->reclaim_lock);
+	if (ret)
+		return ret;
+
+	/* btree node pins are dropped when the node write completes: */
+	bch2_btree_flush_all_writes(c);
+
+	spin_lock(&j->lock);
+	new_last_seq = journal_last_seq(j);
+	spin_unlock(&j->lock);
+
+	if (new_last_seq <= old_last_seq)
+		return 0;
+
+	/* Record the new last_seq on disk, then discard freed buckets: */
+	ret = bch2_journal_meta(j);
+	if (ret)
+		return ret;
+
+	bch2_journal_do_discards(j);
+
+	*nr_reclaimed = new_last_seq - old_last_seq;
+	return 0;
+}
+
+static void time_stats_snapshot(struct time_stats *stats,
+				struct bch_time_stats_snapshot *out)
+{
+	struct quantiles *quantiles = time_stats_to_quantiles(stats);
+
+	memset(out, 0, sizeof(*out));
+
+	spin_lock_irq(&stats->lock);
+	if (stats->buffer) {
+		int cpu;
+
+		for_each_possible_cpu(cpu)
+			__time_stats_clear_buffer(stats, per_cpu_ptr(stats->buffer, cpu));
+	}
+
+	out->count = stats->duration_stats.n;
+	if (out->count) {
+		out->min_ns		= stats->min_duration;
+		out->max_ns		= stats->max_duration;
+		out->mean_ns		= mean_and_variance_get_mean(stats->duration_stats);
+		out->stddev_ns		= mean_and_variance_get_stddev(stats->duration_stats);
+	}
+	if (stats->freq_stats.n) {
+		out->mean_freq_ns	= mean_and_variance_get_mean(stats->freq_stats);
+		out->stddev_freq_ns	= mean_and_variance_get_stddev(stats->freq_stats);
+	}
+
+	if (quantiles) {
+		out->have_quantiles = true;
+		for (unsigned i = 0; i < NR_QUANTILES; i++)
+			out->quantiles_ns[i] = quantiles->entries[QUANTILE_IDX(i)].m;
+	}
+	spin_unlock_irq(&stats->lock);
+}
+
+int bch2_time_stats_read(struct bch_fs *c, unsigned idx,
+			 struct bch_time_stats_snapshot *out)
+{
+	if (idx >= BCH_TIME_STAT_NR)
+		return -EINVAL;
+
+	time_stats_snapshot(&c->times[idx], out);
+	return 0;
+}
+
+/* Sectors read/written by a device since the filesystem was opened: */
+int bch2_dev_io_done_read(struct bch_fs *c, unsigned dev, struct io_count *out)
+{
+	if (!bch2_dev_exists2(c, dev))
+		return -BCH_ERR_ENOENT_dev_idx_not_found;
+
+	struct bch_dev *ca = bch_dev_bkey_exists(c, dev);
+	int cpu;
+
+	memset(out, 0, sizeof(*out));
+
+	for_each_possible_cpu(cpu) {
+		struct io_count *p = per_cpu_ptr(ca->io_done, cpu);
+
+		for (unsigned rw = 0; rw < 2; rw++)
+			for (unsigned i = 0; i < BCH_DATA_NR; i++)
+				out->sectors[rw][i] += p->sectors[rw][i];
+	}
+
+	return 0;
+}
+
+int bch2_dev_io_latency_read(struct bch_fs *c, unsigned dev, unsigned rw,
+			     struct bch_time_stats_snapshot *out)
+{
+	if (!bch2_dev_exists2(c, dev))
+		return -BCH_ERR_ENOENT_dev_idx_not_found;
+	if (rw > WRITE)
+		return -EINVAL;
+
+	time_stats_snapshot(&bch_dev_bkey_exists(c, dev)->io_latency[rw].stats, out);
+	return 0;
+}
+
+void bch2_journal_stats_read(struct bch_fs *c, struct bch_journal_stats *out)
+{
+	struct journal *j = &c->journal;
+
+	spin_lock(&j->lock);
+	out->seq			= journal_cur_seq(j);
+	out->seq_ondisk			= j->seq_ondisk;
+	out->last_seq			= journal_last_seq(j);
+	out->last_seq_ondisk		= j->last_seq_ondisk;
+	out->flushed_seq_ondisk		= j->flushed_seq_ondisk;
+	out->dirty_entries		= fifo_used(&j->pin);
+	out->max_dirty_entries		= j->pin.size;
+	out->nr_flush_writes		= j->nr_flush_writes;
+	out->nr_noflush_writes		= j->nr_noflush_writes;
+	out->entry_bytes_written	= j->entry_bytes_written;
+	out->nr_direct_reclaim		= j->nr_direct_reclaim;
+	out->nr_background_reclaim	= j->nr_background_reclaim;
+	out->cur_entry_sectors		= j->cur_entry_sectors;
+	out->blocked			= j->blocked;
+	spin_unlock(&j->lock);
+}
+
+int bch2_journal_dev_stats_read(struct bch_fs *c, unsigned dev,
+				struct bch_journal_dev_stats *out)
+{
+	struct journal *j = &c->journal;
+
+	if (!bch2_dev_exists2(c, dev))
+		return -BCH_ERR_ENOENT_dev_idx_not_found;
+
+	struct bch_dev *ca = bch_dev_bkey_exists(c, dev);
+	struct journal_device *ja = &ca->journal;
+
+	spin_lock(&j->lock);
+	out->nr			= ja->nr;
+	out->bucket_size	= ca->mi.bucket_size;
+	out->sectors_free	= ja->sectors_free;
+	out->discard_idx	= ja->discard_idx;
+	out->dirty_idx_ondisk	= ja->dirty_idx_ondisk;
+	out->dirty_idx		= ja->dirty_idx;
+	out->cur_idx		= ja->cur_idx;
+	spin_unlock(&j->lock);
+
+	return 0;
+}
+
+/*
+ * Journal entries read at recovery time are only kept around with the
+ * keep_journal option: return the first one at or after radix index *@idx
+ * that isn't blacklisted, and advance *@idx past it, or NULL if there are no
+ * more. Entries are in sequence number order.
+ */
+struct jset *bch2_journal_entry_next(struct bch_fs *c, size_t *idx)
+{
+	struct genradix_iter iter;
+	struct journal_replay **i;
+
+	genradix_for_each_from(&c->journal_entries, iter, i, *idx)
+		if (*i && !(*i)->ignore) {
+			*idx = iter.pos + 1;
+			return &(*i)->j;
+		}
+
+	return NULL;
+}
+
+/*
+ * Link an inode that has no dirents pointing to it into @dir as @name, making
+ * that its only link: unlike bch2_link_trans(), the inode's link count is set
+ * rather than incremented, since it may be stale. For directories, the
+ * parent's link count is incremented for the new subdirectory.
+ *
+ * The caller must check that the inode isn't already linked, and commit.
+ */
+int bch2_relink_inode_trans(struct btree_trans *trans, u32 subvol,
+			    u64 dir, u64 inum, const char *name)
+{
+	struct bch_fs *c = trans->c;
+	struct btree_iter dir_iter = { NULL };
+	struct btree_iter inode_iter = { NULL };
+	struct bch_inode_unpacked dir_u, inode_u;
+	struct bch_hash_info dir_hash;
+	struct qstr qname = QSTR(name);
+	subvol_inum dir_inum = { subvol, dir };
+	subvol_inum inode_inum = { subvol, inum };
+	u64 now = bch2_current_time(c);
+	u64 dir_offset = 0;
+	int ret;
+
+	ret =   bch2_inode_peek(trans, &inode_iter, &inode_u, inode_inum, BTREE_ITER_INTENT) ?:
+		bch2_inode_peek(trans, &dir_iter, &dir_u, dir_inum, BTREE_ITER_INTENT);
+	if (ret)
+		goto err;
+
+	if (!S_ISDIR(dir_u.bi_mode)) {
+		ret = -BCH_ERR_ENOENT_not_directory;
+		goto err;
+	}
+
+	if (S_ISDIR(inode_u.bi_mode)) {
+		/* subdirectory link counts include their children's "..": */
+		inode_u.bi_flags &= ~BCH_INODE_unlinked;
+		dir_u.bi_nlink++;
+	} else {
+		bch2_inode_nlink_set(&inode_u, 1);
+	}
+
+	inode_u.bi_ctime = now;
+	dir_u.bi_mtime = dir_u.bi_ctime = now;
+
+	dir_hash = bch2_hash_info_init(c, &dir_u);
+
+	ret = bch2_dirent_create(trans, dir_inum, &dir_hash,
+				 mode_to_type(inode_u.bi_mode),
+				 &qname, inum, &dir_offset,
+				 BCH_HASH_SET_MUST_CREATE);
+	if (ret)
+		goto err;
+
+	inode_u.bi_dir		= dir;
+	inode_u.bi_dir_offset	= dir_offset;
+
+	ret =   bch2_inode_write(trans, &dir_iter, &dir_u) ?:
+		bch2_inode_write(trans, &inode_iter, &inode_u);
+err:
+	bch2_trans_iter_exit(trans, &dir_iter);
+	bch2_trans_iter_exit(trans, &inode_iter);
+	return ret;
+}
+
+/*
+ * Decode up to @nr pointers (with their checksum/compression info) from an
+ * extent or btree pointer key; returns the total number of pointers, which
+ * may be more than @nr:
+ */
+int bch2_bkey_ptrs_decode(struct bkey_s_c k, struct extent_ptr_decoded *ptrs, unsigned nr)
+{
+	struct bkey_ptrs_c p = bch2_bkey_ptrs_c(k);
+	const union bch_extent_entry *entry;
+	struct extent_ptr_decoded d;
+	unsigned i = 0;
+
+	bkey_for_each_ptr_decode(k.k, p, d, entry) {
+		if (i < nr)
+			ptrs[i] = d;
+		i++;
+	}
+
+	return i;
+}
+
+/*
+ * Like bch2_btree_node_iter_peek_unpack(), but doesn't skip deleted keys - for
+ * looking at what's physically in a btree node:
+ */
+struct bkey_s_c bch2_btree_node_iter_peek_all_unpack(struct btree_node_iter *iter,
+						     struct btree *b,
+						     struct bkey *u)
+{
+	struct bkey_packed *k = bch2_btree_node_iter_peek_all(iter, b);
+
+	return k ? bkey_disassemble(b, k, u) : bkey_s_c_null;
+}
+
+/* Drop the lock taken by bch2_fs_usage_read() and free its result: */
+void bch2_fs_usage_read_put(struct bch_fs *c, struct bch_fs_usage_online *u)
+{
+	percpu_up_read(&c->mark_lock);
+	kfree(u);
+}
+
+/*
+ * Check the checksums of each bset in a btree node read from disk, without
+ * validating the keys: returns -EINVAL if the node can't be decoded, or
+ * -EBADMSG on the first checksum mismatch
+ */
+int bch2_btree_node_buf_verify(struct bch_fs *c, struct btree_node *n, size_t bytes)
+{
+	unsigned offset = 0;
+
+	if (bytes < sizeof(*n) ||
+	    le64_to_cpu(n->magic) != bset_magic(c))
+		return -EINVAL;
+
+	while (offset < bytes >> 9) {
+		struct btree_node_entry *bne = (void *) n + (offset << 9);
+		struct bset *i = offset ? &bne->keys : &n->keys;
+		struct bch_csum csum;
+		unsigned sectors;
+
+		/* the rest of the node hasn't been written: */
+		if (offset && i->seq != n->keys.seq)
+			break;
+
+		if (!bch2_checksum_type_valid(c, BSET_CSUM_TYPE(i)))
+			return -EINVAL;
+
+		if (!offset) {
+			if (vstruct_bytes(n) > bytes)
+				return -EINVAL;
+
+			csum	= csum_vstruct(c, BSET_CSUM_TYPE(i), btree_nonce(i, 0), n);
+			sectors	= vstruct_sectors(n, c->block_bits);
+
+			if (bch2_crc_cmp(csum, n->csum))
+				return -EBADMSG;
+		} else {
+			if ((offset << 9) + vstruct_bytes(bne) > bytes)
+				return -EINVAL;
+
+			csum	= csum_vstruct(c, BSET_CSUM_TYPE(i), btree_nonce(i, offset << 9), bne);
+			sectors	= vstruct_sectors(bne, c->block_bits);
+
+			if (bch2_crc_cmp(csum, bne->csum))
+				return -EBADMSG;
+		}
+
+		offset += sectors;
+	}
+
+	return 0;
+}
+
+/*
+ * Check the checksum of a superblock read from disk, without validating its
+ * contents: errors as bch2_btree_node_buf_verify()
+ */
+int bch2_sb_buf_verify(struct bch_sb *sb, size_t bytes)
+{
+	if (bytes < sizeof(*sb) ||
+	    (!uuid_equal(&sb->magic, &BCACHE_MAGIC) &&
+	     !uuid_equal(&sb->magic, &BCHFS_MAGIC)) ||
+	    vstruct_bytes(sb) > bytes ||
+	    BCH_SB_CSUM_TYPE(sb) >= BCH_CSUM_NR)
+		return -EINVAL;
+
+	struct bch_csum csum = csum_vstruct(NULL, BCH_SB_CSUM_TYPE(sb), null_nonce(), sb);
+
+	return bch2_crc_cmp(csum, sb->csum) ? -EBADMSG : 0;
+}
+
+/*
+ * Check the journal entry at the start of @j, read from disk, without
+ * validating its contents: returns 1 if there's no journal entry there,
+ * otherwise sets @sectors to its size and returns 0, or errors as
+ * bch2_btree_node_buf_verify()
+ */
+int bch2_jset_verify(struct bch_fs *c, struct jset *j, size_t bytes,
+		     unsigned *sectors)
+{
+	if (bytes < sizeof(*j) ||
+	    le64_to_cpu(j->magic) != jset_magic(c))
+		return 1;
+
+	*sectors = block_sectors(c);
+
+	if (vstruct_bytes(j) > bytes ||
+	    !bch2_checksum_type_valid(c, JSET_CSUM_TYPE(j)))
+		return -EINVAL;
+
+	*sectors = vstruct_sectors(j, c->block_bits);
+
+	/* as journal_nonce() in journal_io.c: */
+	struct nonce nonce = (struct nonce) {{
+		[0] = 0,
+		[1] = ((__le32 *) &j->seq)[0],
+		[2] = ((__le32 *) &j->seq)[1],
+		[3] = BCH_NONCE_JOURNAL,
+	}};
+	struct bch_csum csum = csum_vstruct(c, JSET_CSUM_TYPE(j), nonce, j);
+
+	return bch2_crc_cmp(j->csum, csum) ? -EBADMSG : 0;
+}
+
+/*
+ * Position and type of the next non deleted key in a btree node, unpacking only
+ * the position - for scans that don't look at values; returns false at the end
+ * of the node:
+ */
+bool bch2_btree_node_iter_peek_pos(struct btree_node_iter *iter,
+				   struct btree *b,
+				   struct bpos *pos, u8 *type)
+{
+	struct bkey_packed *k = bch2_btree_node_iter_peek(iter, b);
+
+	if (!k)
+		return false;
+
+	*pos	= bkey_unpack_pos(b, k);
+	*type	= k->type;
+	return true;
+}
+
+/*
+ * Copy out up to @nr entries of the in-memory error counts (the errors that
+ * are also counted in the superblock); returns the total number of entries:
+ */
+int bch2_fs_error_counts_read(struct bch_fs *c,
+			      struct bch_sb_error_entry_cpu *out, unsigned nr)
+{
+	mutex_lock(&c->fsck_error_counts_lock);
+	unsigned ret = c->fsck_error_counts.nr;
+
+	memcpy(out, c->fsck_error_counts.data, min(nr, ret) * sizeof(*out));
+	mutex_unlock(&c->fsck_error_counts_lock);
+
+	return ret;
+}
+
+/* Names of the counters in bch_fs->counters, NULL terminated: */
+const char * const bch2_fs_counter_names[] = {
+#define x(t, n, ...) (#t),
+	BCH_PERSISTENT_COUNTERS()
+#undef x
+	NULL
+};
+
+/*
+ * Set an option on a running filesystem, as writing to options/ in sysfs does:
+ * options stored in the superblock are persisted there too. Returns -ENOENT for
+ * unknown options, and -EOPNOTSUPP for options that can't be set at runtime
+ */
+int bch2_fs_opt_set(struct bch_fs *c, const char *name, const char *val)
+{
+	int id = bch2_opt_lookup(name);
+	if (id < 0)
+		return -ENOENT;
+
+	const struct bch_option *opt = bch2_opt_table + id;
+	if (!(opt->flags & OPT_RUNTIME))
+		return -EOPNOTSUPP;
+
+	if (!bch2_write_ref_tryget(c, BCH_WRITE_REF_sysfs))
+		return -BCH_ERR_erofs_no_writes;
+
+	u64 v;
+	int ret = bch2_opt_parse(c, opt, val, &v, NULL);
+	if (ret < 0)
+		goto err;
+
+	ret = bch2_opt_check_may_set(c, id, v);
+	if (ret < 0)
+		goto err;
+
+	bch2_opt_set_sb(c, opt, v);
+	bch2_opt_set_by_id(&c->opts, id, v);
+
+	if (v &&
+	    (id == Opt_background_target ||
+	     id == Opt_background_compression ||
+	     (id == Opt_compression && !c->opts.background_compression)))
+		bch2_set_rebalance_needs_scan(c, 0);
+	ret = 0;
+err:
+	bch2_write_ref_put(c, BCH_WRITE_REF_sysfs);
+	return ret;
+}
+
 /* option parsing */
 
 void bch2_opt_strs_free(struct bch_opt_strs *opts)
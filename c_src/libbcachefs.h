@@ -1,6 +1,7 @@
 #ifndef _LIBBCACHE_H
 #define _LIBBCACHE_H
 
+#include <linux/time_stats.h>
 #include <linux/uuid.h>
 #include <stdbool.h>
 
@@ -254,6 +255,120 @@ static inline void bchu_disk_resize_journal(struct bchfs_handle fs,
 
 int bchu_data(struct bchfs_handle, struct bch_ioctl_data);
 
+struct bch_fs;
+int bch2_fs_label_set(struct bch_fs *, const char *);
+int bch2_fs_uuid_set(struct bch_fs *, const __uuid_t *);
+int bch2_read_buf(struct bch_fs *, u32, u64, u64, size_t, void *);
+#define BCH_WRITE_BUF_MAX	(1U << 20)
+int bch2_write_buf(struct bch_fs *, u32, u64, u64, size_t, const void *, unsigned);
+int bch2_create_file(struct bch_fs *, u32, u64, const char *, u32, u64 *);
+int bch2_xattr_set_buf(struct bch_fs *, u32, u64, unsigned,
+		       const char *, const void *, size_t);
+int bch2_dev_evacuate_bucket(struct bch_fs *, unsigned, u64);
+int bch2_dev_discard_free_buckets(struct bch_fs *, unsigned);
+unsigned bch2_fs_warn_direct_unsupported(struct bch_fs *);
+struct btree_iter;
+void bch2_btree_iter_set_pos_outlined(struct btree_iter *, struct bpos);
+struct btree_trans;
+int bch2_fs_commit_hook_set(struct bch_fs *,
+			    void (*)(void *, struct btree_trans *, bool), void *);
+int bch2_fs_fsck_err_count_start(struct bch_fs *);
+void bch2_fs_fsck_err_count_stop(struct bch_fs *, u64 *, u64 *);
+struct printbuf;
+int bch2_bkey_invalid_nocount(struct bch_fs *, const struct bkey *,
+			      const struct bch_val *, unsigned, unsigned,
+			      struct printbuf *);
+int bch2_fs_sync(struct bch_fs *);
+int bch2_fs_set_ro(struct bch_fs *);
+int bch2_fs_set_rw(struct bch_fs *);
+int bch2_fs_journal_reclaim(struct bch_fs *, u64 *);
+
+/* All times in nanoseconds: */
+struct bch_time_stats_snapshot {
+	u64		count;
+	u64		min_ns;
+	u64		max_ns;
+	u64		mean_ns;
+	u64		stddev_ns;
+	/* time between events: */
+	u64		mean_freq_ns;
+	u64		stddev_freq_ns;
+	bool		have_quantiles;
+	u64		quantiles_ns[NR_QUANTILES];
+};
+
+int bch2_time_stats_read(struct bch_fs *, unsigned, struct bch_time_stats_snapshot *);
+
+struct io_count;
+int bch2_dev_io_done_read(struct bch_fs *, unsigned, struct io_count *);
+int bch2_dev_io_latency_read(struct bch_fs *, unsigned, unsigned,
+			     struct bch_time_stats_snapshot *);
+
+struct bch_journal_stats {
+	u64		seq;
+	u64		seq_ondisk;
+	u64		last_seq;
+	u64		last_seq_ondisk;
+	u64		flushed_seq_ondisk;
+	u64		dirty_entries;
+	u64		max_dirty_entries;
+	u64		nr_flush_writes;
+	u64		nr_noflush_writes;
+	u64		entry_bytes_written;
+	u64		nr_direct_reclaim;
+	u64		nr_background_reclaim;
+	unsigned	cur_entry_sectors;
+	unsigned	blocked;
+};
+
+/* Journal bucket indices are into the device's list of journal buckets: */
+struct bch_journal_dev_stats {
+	unsigned	nr;
+	unsigned	bucket_size;
+	unsigned	sectors_free;
+	unsigned	discard_idx;
+	unsigned	dirty_idx_ondisk;
+	unsigned	dirty_idx;
+	unsigned	cur_idx;
+};
+
+void bch2_journal_stats_read(struct bch_fs *, struct bch_journal_stats *);
+int bch2_journal_dev_stats_read(struct bch_fs *, unsigned, struct bch_journal_dev_stats *);
+
+struct jset;
+struct jset *bch2_journal_entry_next(struct bch_fs *, size_t *);
+
+struct bch_fs_usage_online;
+void bch2_fs_usage_read_put(struct bch_fs *, struct bch_fs_usage_online *);
+
+struct btree_node;
+struct bch_sb;
+int bch2_btree_node_buf_verify(struct bch_fs *, struct btree_node *, size_t);
+int bch2_sb_buf_verify(struct bch_sb *, size_t);
+int bch2_jset_verify(struct bch_fs *, struct jset *, size_t, unsigned *);
+
+struct btree_trans;
+int bch2_relink_inode_trans(struct btree_trans *, u32, u64, u64, const char *);
+
+struct bkey_s_c;
+struct extent_ptr_decoded;
+int bch2_bkey_ptrs_decode(struct bkey_s_c, struct extent_ptr_decoded *, unsigned);
+
+struct btree_node_iter;
+struct btree;
+struct bkey;
+struct bkey_s_c bch2_btree_node_iter_peek_all_unpack(struct btree_node_iter *,
+						     struct btree *, struct bkey *);
+bool bch2_btree_node_iter_peek_pos(struct btree_node_iter *, struct btree *,
+				   struct bpos *, u8 *);
+
+struct bch_sb_error_entry_cpu;
+int bch2_fs_error_counts_read(struct bch_fs *, struct bch_sb_error_entry_cpu *, unsigned);
+
+extern const char * const bch2_fs_counter_names[];
+
+int bch2_fs_opt_set(struct bch_fs *, const char *, const char *);
+
 struct dev_name {
 	unsigned	idx;
 	char		*dev;
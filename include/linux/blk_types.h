@@ -39,6 +39,11 @@ struct block_device {
 	struct gendisk *	bd_disk;
 	struct gendisk		__bd_disk;
 	int			bd_fd;
+	/* userspace only: region of bd_fd to use, see blkdev_region_add(): */
+	u64			bd_offset;
+	u64			bd_size;
+	/* userspace only: O_DIRECT was asked for but isn't supported by bd_fd: */
+	bool			bd_direct_unsupported;
 };
 
 #define bdev_kobj(_bdev) (&((_bdev)->kobj))
@@ -100,6 +100,9 @@ struct bdev_handle *bdev_open_by_path(const char *, blk_mode_t, void *,
 				      const struct blk_holder_ops *);
 int lookup_bdev(const char *path, dev_t *);
 
+int blkdev_region_add(const char *, u64, u64);
+void blkdev_region_del(const char *);
+
 struct super_block {
 	void			*s_fs_info;
 };
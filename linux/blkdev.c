@@ -2,6 +2,7 @@
 #include <alloca.h>
 #include <errno.h>
 #include <fcntl.h>
+#include <string.h>
 #include <sys/ioctl.h>
 #include <sys/stat.h>
 #include <sys/types.h>
@@ -19,6 +20,8 @@
 #include <linux/completion.h>
 #include <linux/fs.h>
 #include <linux/kthread.h>
+#include <linux/list.h>
+#include <linux/mutex.h>
 
 #include "tools-util.h"
 
@@ -151,16 +154,90 @@ sector_t get_capacity(struct gendisk *disk)
 	u64 bytes;
 	int ret;
 
+	if (bdev->bd_size)
+		return bdev->bd_size >> 9;
+
 	ret = fstat(bdev->bd_fd, &statbuf);
 	BUG_ON(ret);
 
-	if (!S_ISBLK(statbuf.st_mode))
-		return statbuf.st_size >> 9;
+	if (!S_ISBLK(statbuf.st_mode)) {
+		bytes = statbuf.st_size;
+	} else {
+		ret = ioctl(bdev->bd_fd, BLKGETSIZE64, &bytes);
+		BUG_ON(ret);
+	}
 
-	ret = ioctl(bdev->bd_fd, BLKGETSIZE64, &bytes);
-	BUG_ON(ret);
+	return (bytes - min(bytes, bdev->bd_offset)) >> 9;
+}
+
+/*
+ * Regions: use part of a file or block device in place of the whole thing,
+ * e.g. a partition within a disk image, without needing a loop device.
+ * Registered by path, and applied when that path is next opened:
+ */
+struct bdev_region {
+	struct list_head	list;
+	char			*path;
+	u64			offset;
+	u64			size;
+};
+
+static LIST_HEAD(bdev_regions);
+static DEFINE_MUTEX(bdev_regions_lock);
+
+/*
+ * @offset must be a multiple of 4096, for O_DIRECT; @size of 0 means up to the
+ * end of the device. Replaces any existing region for @path.
+ */
+int blkdev_region_add(const char *path, u64 offset, u64 size)
+{
+	struct bdev_region *r;
+
+	if (offset & 4095)
+		return -EINVAL;
+
+	blkdev_region_del(path);
+
+	r = calloc(1, sizeof(*r));
+	if (!r)
+		return -ENOMEM;
 
-	return bytes >> 9;
+	r->path		= strdup(path);
+	r->offset	= offset;
+	r->size		= size;
+
+	mutex_lock(&bdev_regions_lock);
+	list_add(&r->list, &bdev_regions);
+	mutex_unlock(&bdev_regions_lock);
+	return 0;
+}
+
+void blkdev_region_del(const char *path)
+{
+	struct bdev_region *r, *n;
+
+	mutex_lock(&bdev_regions_lock);
+	list_for_each_entry_safe(r, n, &bdev_regions, list)
+		if (!strcmp(r->path, path)) {
+			list_del(&r->list);
+			free(r->path);
+			free(r);
+		}
+	mutex_unlock(&bdev_regions_lock);
+}
+
+static void bdev_region_apply(struct block_device *bdev, const char *path)
+{
+	struct bdev_region *r;
+
+	mutex_lock(&bdev_regions_lock);
+	list_for_each_entry(r, &bdev_regions, list)
+		if (!strcmp(r->path, path)) {
+			bdev->bd_offset	= r->offset;
+			bdev->bd_size	= r->size;
+			break;
+		}
+	mutex_unlock(&bdev_regions_lock);
 }
 
 void bdev_release(struct bdev_handle *handle)
@@ -190,6 +267,21 @@ struct bdev_handle *bdev_open_by_path(const char *path, blk_mode_t mode,
 		flags |= O_EXCL;
 
 	fd = open(path, flags);
+
+	/*
+	 * Some filesystems (e.g. tmpfs) don't support O_DIRECT, and fail the
+	 * open with EINVAL: if it succeeds without, that's why, and we can fall
+	 * back to buffered IO:
+	 */
+	bool direct_unsupported = false;
+	if (fd < 0 && errno == EINVAL && (flags & O_DIRECT)) {
+		fd = open(path, flags & ~O_DIRECT);
+		if (fd >= 0) {
+			direct_unsupported = true;
+			mode |= BLK_OPEN_BUFFERED;
+		}
+	}
+
 	if (fd < 0)
 		return ERR_PTR(-errno);
 
@@ -202,9 +294,11 @@ struct bdev_handle *bdev_open_by_path(const char *path, blk_mode_t mode,
 	bdev->bd_dev		= xfstat(fd).st_rdev;
 	bdev->bd_fd		= fd;
 	bdev->bd_holder		= holder;
+	bdev->bd_direct_unsupported = direct_unsupported;
 	bdev->bd_disk		= &bdev->__bd_disk;
 	bdev->bd_disk->bdi	= &bdev->bd_disk->__bdi;
 	bdev->queue.backing_dev_info = bdev->bd_disk->bdi;
+	bdev_region_apply(bdev, path);
 
 	struct bdev_handle *handle = calloc(sizeof(*handle), 1);
 	handle->bdev	= bdev;
@@ -253,14 +347,14 @@ static void sync_read(struct bio *bio, struct iovec * iov, unsigned i)
 {
 
 	ssize_t ret = preadv(bio->bi_bdev->bd_fd, iov, i,
-			     bio->bi_iter.bi_sector << 9);
+			     bio->bi_bdev->bd_offset + (bio->bi_iter.bi_sector << 9));
 	sync_check(bio, ret);
 }
 
 static void sync_write(struct bio *bio, struct iovec * iov, unsigned i)
 {
 	ssize_t ret = pwritev2(bio->bi_bdev->bd_fd, iov, i,
-			       bio->bi_iter.bi_sector << 9,
+			       bio->bi_bdev->bd_offset + (bio->bi_iter.bi_sector << 9),
 			       bio->bi_opf & REQ_FUA ? RWF_SYNC : 0);
 	sync_check(bio, ret);
 }
@@ -368,7 +462,7 @@ static void aio_op(struct bio *bio, struct iovec *iov, unsigned i, int opcode)
 		.aio_lio_opcode	= opcode,
 		.u.c.buf        = iov,
 		.u.c.nbytes     = i,
-		.u.c.offset     = bio->bi_iter.bi_sector << 9,
+		.u.c.offset     = bio->bi_bdev->bd_offset + (bio->bi_iter.bi_sector << 9),
 
 	}, *iocbp = &iocb;
 
@@ -1,6 +1,10 @@
-use std::{path::Path, os::unix::ffi::OsStrExt, ffi::CString};
+use std::{path::Path, os::unix::ffi::OsStrExt, ffi::CString, ops::ControlFlow};
 
 use bch_bindgen::c::{bchfs_handle, BCH_IOCTL_SUBVOLUME_CREATE, BCH_IOCTL_SUBVOLUME_DESTROY, bch_ioctl_subvolume, bcache_fs_open, BCH_SUBVOL_SNAPSHOT_CREATE, bcache_fs_close};
+use bch_bindgen::c::{self, BCH_IOCTL_DATA, bch_ioctl_data, bch_ioctl_data_event, bch_data_ops, bpos, btree_id};
+use bch_bindgen::c::{BCH_IOCTL_DEV_USAGE, BCH_IOCTL_DISK_RESIZE, BCH_BY_INDEX, bch_ioctl_dev_usage, bch_ioctl_disk_resize};
+use bch_bindgen::c::{BCH_IOCTL_FSCK_ONLINE, bch_ioctl_fsck_online};
+use bch_bindgen::{POS_MIN, SPOS_MAX};
 use errno::Errno;
 
 /// A handle to a bcachefs filesystem
@@ -27,18 +31,28 @@ impl BcachefsHandle {
 pub enum BcachefsIoctl {
     SubvolumeCreate = BCH_IOCTL_SUBVOLUME_CREATE,
     SubvolumeDestroy = BCH_IOCTL_SUBVOLUME_DESTROY,
+    Data = BCH_IOCTL_DATA,
+    DevUsage = BCH_IOCTL_DEV_USAGE,
+    DiskResize = BCH_IOCTL_DISK_RESIZE,
+    FsckOnline = BCH_IOCTL_FSCK_ONLINE,
 }
 
 /// I/O control commands payloads
 #[non_exhaustive]
 pub enum BcachefsIoctlPayload {
     Subvolume(bch_ioctl_subvolume),
+    Data(bch_ioctl_data),
+    DiskResize(bch_ioctl_disk_resize),
+    FsckOnline(bch_ioctl_fsck_online),
 }
 
 impl From<&BcachefsIoctlPayload> for *const libc::c_void {
     fn from(value: &BcachefsIoctlPayload) -> Self {
         match value {
-            BcachefsIoctlPayload::Subvolume(p) => p as *const _ as *const libc::c_void
+            BcachefsIoctlPayload::Subvolume(p) => p as *const _ as *const libc::c_void,
+            BcachefsIoctlPayload::Data(p) => p as *const _ as *const libc::c_void,
+            BcachefsIoctlPayload::DiskResize(p) => p as *const _ as *const libc::c_void,
+            BcachefsIoctlPayload::FsckOnline(p) => p as *const _ as *const libc::c_void,
         }
     }
 }
@@ -46,13 +60,19 @@ impl From<&BcachefsIoctlPayload> for *const libc::c_void {
 impl BcachefsHandle {
     /// Type-safe [`libc::ioctl`] for bcachefs filesystems
     pub fn ioctl(&self, request: BcachefsIoctl, payload: &BcachefsIoctlPayload) -> Result<(), Errno> {
+        self.ioctl_ret(request, payload).map(|_| ())
+    }
+
+    /// Like [`BcachefsHandle::ioctl`], but returns the ioctl's return value
+    /// (e.g. a file descriptor) on success
+    pub fn ioctl_ret(&self, request: BcachefsIoctl, payload: &BcachefsIoctlPayload) -> Result<libc::c_int, Errno> {
         let payload_ptr: *const libc::c_void = payload.into();
         let ret = unsafe { libc::ioctl(self.inner.ioctl_fd, request as libc::Ioctl, payload_ptr) };
 
         if ret == -1 {
             Err(errno::errno())
         } else {
-            Ok(())
+            Ok(ret)
         }
     }
 
@@ -102,6 +122,204 @@ impl BcachefsHandle {
     }
 }
 
+/// A data job that walks and rewrites filesystem data, see [`BcachefsHandle::data_job`]
+#[derive(Clone, Copy, Debug)]
+pub enum DataJob {
+    /// Verify checksums of existing data
+    Scrub,
+    /// Write new replicas of data that has fewer than the configured number
+    Rereplicate,
+    /// Move all data off of the given device
+    Migrate { dev: u32 },
+    /// Rewrite btree nodes written with an older on disk format version
+    RewriteOldNodes,
+    /// Drop replicas in excess of the configured number
+    DropExtraReplicas,
+}
+
+impl DataJob {
+    fn op(&self) -> bch_data_ops {
+        match self {
+            DataJob::Scrub              => bch_data_ops::BCH_DATA_OP_scrub,
+            DataJob::Rereplicate        => bch_data_ops::BCH_DATA_OP_rereplicate,
+            DataJob::Migrate { .. }     => bch_data_ops::BCH_DATA_OP_migrate,
+            DataJob::RewriteOldNodes    => bch_data_ops::BCH_DATA_OP_rewrite_old_nodes,
+            DataJob::DropExtraReplicas  => bch_data_ops::BCH_DATA_OP_drop_extra_replicas,
+        }
+    }
+}
+
+/// Progress of a running data job, as reported by the kernel
+#[derive(Clone, Copy, Debug)]
+pub struct DataJobProgress {
+    pub data_type:      u8,
+    pub btree_id:       btree_id,
+    pub pos:            bpos,
+    pub sectors_done:   u64,
+    pub sectors_total:  u64,
+}
+
+/// How a data job finished
+#[derive(Clone, Copy, Debug)]
+pub enum DataJobStatus {
+    Completed,
+    /// The progress callback stopped the job; the last progress report may be
+    /// passed back to [`BcachefsHandle::data_job`] to resume from where it left
+    /// off
+    Cancelled(DataJobProgress),
+}
+
+impl BcachefsHandle {
+    /// Run a data job on this filesystem, starting at `start` (or the
+    /// beginning of the first btree), calling `progress` with each progress
+    /// report
+    ///
+    /// Returning [`ControlFlow::Break`] from `progress` stops the job.
+    pub fn data_job<F>(&self, job: DataJob, start: Option<(btree_id, bpos)>, mut progress: F) -> Result<DataJobStatus, Errno>
+    where F: FnMut(&DataJobProgress) -> ControlFlow<()> {
+        let (start_btree, start_pos) = start.unwrap_or((btree_id::BTREE_ID_extents, POS_MIN));
+
+        let mut arg = bch_ioctl_data {
+            op:             job.op() as u16,
+            start_btree:    start_btree as u8,
+            start_pos,
+            end_btree:      c::btree_id::BTREE_ID_NR as u8,
+            end_pos:        SPOS_MAX,
+            ..Default::default()
+        };
+        if let DataJob::Migrate { dev } = job {
+            arg.migrate.dev = dev;
+        }
+
+        let progress_fd = self.ioctl_ret(BcachefsIoctl::Data, &BcachefsIoctlPayload::Data(arg))?;
+
+        // Closing the progress fd stops the job
+        let ret = loop {
+            let mut e = bch_ioctl_data_event::default();
+            let size = std::mem::size_of::<bch_ioctl_data_event>();
+            let r = unsafe { libc::read(progress_fd, &mut e as *mut _ as *mut libc::c_void, size) };
+
+            if r != size as isize {
+                break Err(errno::errno());
+            }
+
+            if e.type_ != c::bch_data_event::BCH_DATA_EVENT_PROGRESS as u8 {
+                continue;
+            }
+
+            if e.p.data_type == u8::MAX {
+                break Ok(DataJobStatus::Completed);
+            }
+
+            let p = DataJobProgress {
+                data_type:      e.p.data_type,
+                btree_id:       unsafe { std::mem::transmute(e.p.btree_id as u32) },
+                pos:            e.p.pos,
+                sectors_done:   e.p.sectors_done,
+                sectors_total:  e.p.sectors_total,
+            };
+
+            if progress(&p).is_break() {
+                break Ok(DataJobStatus::Cancelled(p));
+            }
+
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        };
+
+        unsafe { libc::close(progress_fd) };
+        ret
+    }
+}
+
+impl BcachefsHandle {
+    /// Space usage of a member device, by index
+    pub fn dev_usage(&self, dev_idx: u32) -> Result<bch_ioctl_dev_usage, Errno> {
+        let mut u = bch_ioctl_dev_usage {
+            dev:    dev_idx as u64,
+            flags:  BCH_BY_INDEX,
+            ..Default::default()
+        };
+        let ret = unsafe { libc::ioctl(self.inner.ioctl_fd, BcachefsIoctl::DevUsage as libc::Ioctl, &mut u as *mut _) };
+
+        if ret == -1 {
+            Err(errno::errno())
+        } else {
+            Ok(u)
+        }
+    }
+
+    /// Resize the filesystem on a member device to `nbuckets` buckets
+    pub fn resize(&self, dev_idx: u32, nbuckets: u64) -> Result<(), Errno> {
+        self.ioctl(BcachefsIoctl::DiskResize, &BcachefsIoctlPayload::DiskResize(bch_ioctl_disk_resize {
+            flags:  BCH_BY_INDEX,
+            dev:    dev_idx as u64,
+            nbuckets,
+            ..Default::default()
+        }))
+    }
+
+    /// Grow the filesystem on member device `dev_idx`, at `dev_path`, to
+    /// `size` bytes
+    ///
+    /// Shrinking isn't supported, and `size` may not exceed the size of the
+    /// underlying device.
+    pub fn resize_bytes<P: AsRef<Path>>(&self, dev_idx: u32, dev_path: P, size: u64) -> anyhow::Result<()> {
+        use std::io::{Seek, SeekFrom};
+
+        let u = self.dev_usage(dev_idx)?;
+        let bucket_bytes = (u.bucket_size as u64) << 9;
+        let nbuckets = size / bucket_bytes;
+
+        // Works for both block devices and regular files:
+        let dev_size = std::fs::File::open(dev_path.as_ref())?.seek(SeekFrom::End(0))?;
+        if size > dev_size {
+            return Err(anyhow::anyhow!("new size {} larger than device {} ({} bytes)",
+                    size, dev_path.as_ref().display(), dev_size));
+        }
+
+        if nbuckets < u.nr_buckets {
+            return Err(anyhow::anyhow!("shrinking not supported (would lose data): {} buckets < current {}",
+                    nbuckets, u.nr_buckets));
+        }
+
+        if nbuckets == u.nr_buckets {
+            return Ok(());
+        }
+
+        Ok(self.resize(dev_idx, nbuckets)?)
+    }
+}
+
+impl BcachefsHandle {
+    /// Run the kernel's fsck on this mounted filesystem, calling `output`
+    /// with each line it prints, until it finishes
+    ///
+    /// `opts` are fsck options in mount option syntax, e.g. `fix_errors=no`;
+    /// nothing is sent to fsck's stdin, so avoid `fix_errors=ask`. Returns
+    /// `ENOTTY` if the running kernel doesn't support online fsck, and
+    /// `EAGAIN` if one is already running.
+    pub fn fsck_online<F>(&self, opts: &str, mut output: F) -> Result<(), Errno>
+    where F: FnMut(&str) {
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::io::FromRawFd;
+
+        let opts = CString::new(opts).map_err(|_| Errno(libc::EINVAL))?;
+        let fd = self.ioctl_ret(BcachefsIoctl::FsckOnline, &BcachefsIoctlPayload::FsckOnline(bch_ioctl_fsck_online {
+            flags:  0,
+            opts:   if opts.as_bytes().is_empty() { 0 } else { opts.as_ptr() as u64 },
+        }))?;
+
+        // fsck exits when we close its stdio fd
+        let f = unsafe { std::fs::File::from_raw_fd(fd) };
+
+        for line in BufReader::new(f).lines() {
+            let line = line.map_err(|e| Errno(e.raw_os_error().unwrap_or(libc::EIO)))?;
+            output(&line);
+        }
+        Ok(())
+    }
+}
+
 impl Drop for BcachefsHandle {
     fn drop(&mut self) {
         unsafe { bcache_fs_close(self.inner) };
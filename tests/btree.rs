@@ -0,0 +1,497 @@
+use bch_bindgen::bcachefs as c;
+use bch_bindgen::bkey::{BkeyOwned, BkeySC, BkeyValC, BkeyValidateFlags};
+use bch_bindgen::btree::{BtreeIter, BtreeIterFlags, BtreeTrans, RetryPolicy};
+use bch_bindgen::bulk::{BulkInserter, BulkInserterOpts};
+use bch_bindgen::dump::MergeOrder;
+use bch_bindgen::errcode::bch_errcode;
+use bch_bindgen::{pos, spos, PosRange};
+use bch_bindgen::testing::{TestFs, TestFsOpts};
+use std::time::Instant;
+
+mod common;
+use common::{reservation, reservation_gen, test_fs, ROOT_INO, ROOT_SUBVOL};
+
+#[test]
+fn lookup_slot() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let trans = BtreeTrans::new(t.fs());
+    let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_inodes,
+        spos(0, 0, snapshot),
+        BtreeIterFlags::SLOTS);
+
+    let k = iter.lookup_slot(spos(0, ROOT_INO, snapshot)).unwrap().expect("no root inode");
+    assert_eq!(k.k.p.offset, ROOT_INO);
+
+    assert!(iter.lookup_slot(spos(0, 1 << 40, snapshot)).unwrap().is_none());
+
+    /* and back again: */
+    let k = iter.lookup_slot(spos(0, ROOT_INO, snapshot)).unwrap().expect("no root inode");
+    assert_eq!(k.k.p.offset, ROOT_INO);
+}
+
+#[test]
+fn peek_slot_empty_extent() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let trans = BtreeTrans::new(t.fs());
+
+    /* nothing's been written to the root directory, so this is a hole: */
+    let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_extents,
+        spos(ROOT_INO, 128, snapshot),
+        BtreeIterFlags::empty());
+    assert!(iter.peek_slot().unwrap().is_none());
+}
+
+#[test]
+fn peek_slot_empty_cached() {
+    let t = test_fs();
+    let trans = BtreeTrans::new(t.fs());
+
+    let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_alloc,
+        pos(0, 1),
+        BtreeIterFlags::CACHED);
+    assert!(iter.peek_slot().unwrap().is_some());
+
+    /* past the end of the device: */
+    let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_alloc,
+        pos(0, u64::MAX >> 1),
+        BtreeIterFlags::CACHED);
+    assert!(iter.peek_slot().unwrap().is_none());
+}
+
+/* Extents in an inode, as (start, end) in sectors: */
+fn extents(t: &TestFs, inode: u64, snapshot: u32) -> Vec<(u64, u64)> {
+    let trans = BtreeTrans::new(t.fs());
+    let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_extents,
+        spos(inode, 0, snapshot),
+        BtreeIterFlags::empty());
+    let mut ret = Vec::new();
+
+    while let Some(k) = iter.peek_upto(spos(inode, u64::MAX, snapshot)).unwrap() {
+        ret.push((k.k.start_pos().unwrap().offset, k.k.p.offset));
+        iter.advance();
+    }
+    ret
+}
+
+#[test]
+fn iter_flags_validate() {
+    use BtreeIterFlags as F;
+
+    for ok in [F::empty(), F::SLOTS|F::INTENT, F::ALL_LEVELS|F::PREFETCH,
+               F::CACHED|F::CACHED_NOFILL, F::ALL_SNAPSHOTS|F::NOT_EXTENTS] {
+        assert_eq!(ok.validate(), Ok(()), "{:?}", ok);
+    }
+
+    for bad in [F::IS_EXTENTS|F::NOT_EXTENTS, F::IS_EXTENTS|F::ALL_SNAPSHOTS,
+                F::ALL_SNAPSHOTS|F::FILTER_SNAPSHOTS, F::CACHED|F::IS_EXTENTS,
+                F::CACHED|F::SLOTS, F::CACHED_NOFILL, F::SLOTS|F::ALL_LEVELS] {
+        assert_eq!(bad.validate(), Err(bch_errcode::BCH_ERR_invalid), "{:?}", bad);
+    }
+}
+
+#[test]
+fn start_pos() {
+    let k = c::bkey { size: 8, p: spos(1, 24, 2), ..Default::default() };
+    assert_eq!(k.start_pos(), Ok(spos(1, 16, 2)));
+
+    let k = c::bkey { size: 8, p: spos(1, 4, 2), ..Default::default() };
+    assert_eq!(k.start_pos(), Err(bch_errcode::BCH_ERR_invalid_bkey));
+}
+
+/* Generations of the reservations in an inode: */
+fn generations(t: &TestFs, inode: u64, snapshot: u32) -> Vec<u32> {
+    let trans = BtreeTrans::new(t.fs());
+    let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_extents,
+        spos(inode, 0, snapshot),
+        BtreeIterFlags::empty());
+    let mut ret = Vec::new();
+
+    while let Some(k) = iter.peek_upto(spos(inode, u64::MAX, snapshot)).unwrap() {
+        match k.v() {
+            BkeyValC::reservation(r) => ret.push(u32::from_le(r.generation)),
+            _ => panic!("not a reservation"),
+        }
+        iter.advance();
+    }
+    ret
+}
+
+/* Eight 8 sector reservations, the nth at [16 * n, 16 * n + 8): */
+fn insert_reservations(t: &TestFs, inode: u64, snapshot: u32) {
+    let keys: Vec<_> = (0..8)
+        .map(|n| (c::btree_id::BTREE_ID_extents, reservation(inode, 16 * n + 8, 8, snapshot)))
+        .collect();
+
+    BtreeTrans::new(t.fs()).update_many(&keys).unwrap();
+}
+
+fn inode_range(inode: u64, snapshot: u32) -> PosRange {
+    PosRange::new(spos(inode, 0, snapshot), spos(inode, u64::MAX, snapshot))
+}
+
+#[test]
+fn update_many() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let inode = ROOT_INO + 1;
+
+    insert_reservations(&t, inode, snapshot);
+
+    assert_eq!(extents(&t, inode, snapshot),
+               (0..8).map(|n| (16 * n, 16 * n + 8)).collect::<Vec<_>>());
+
+    /* Overwriting in place: */
+    BtreeTrans::new(t.fs())
+        .update_many(&[(c::btree_id::BTREE_ID_extents, reservation_gen(inode, 8, 8, snapshot, 1))])
+        .unwrap();
+    assert_eq!(generations(&t, inode, snapshot), [1, 0, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn drain_every_other_key() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let inode = ROOT_INO + 1;
+
+    insert_reservations(&t, inode, snapshot);
+
+    /* A batch size that doesn't divide the number of deletions: */
+    let nr = BtreeTrans::new(t.fs())
+        .drain(c::btree_id::BTREE_ID_extents, inode_range(inode, snapshot), 3,
+               |k| k.k.p.offset / 16 % 2 == 1)
+        .unwrap();
+
+    assert_eq!(nr, 4);
+    assert_eq!(extents(&t, inode, snapshot), [(0, 8), (32, 40), (64, 72), (96, 104)]);
+}
+
+#[test]
+fn rewrite_each() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let inode = ROOT_INO + 1;
+
+    insert_reservations(&t, inode, snapshot);
+
+    let mut seen = 0;
+    BtreeTrans::new(t.fs())
+        .rewrite_each(c::btree_id::BTREE_ID_extents, inode_range(inode, snapshot), 3, |k| {
+            seen += 1;
+            (k.k.p.offset / 16 % 2 == 0)
+                .then(|| reservation_gen(inode, k.k.p.offset, k.k.size, snapshot, 7))
+        })
+        .unwrap();
+
+    assert!(seen >= 8);
+    assert_eq!(extents(&t, inode, snapshot),
+               (0..8).map(|n| (16 * n, 16 * n + 8)).collect::<Vec<_>>());
+    assert_eq!(generations(&t, inode, snapshot), [7, 0, 7, 0, 7, 0, 7, 0]);
+}
+
+#[test]
+fn transact_retries_restarts() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let mut trans = BtreeTrans::new(t.fs());
+    let mut attempts = 0;
+
+    let ino = trans.transact(|trans| {
+        attempts += 1;
+        if attempts < 3 {
+            return Err(bch_errcode::BCH_ERR_transaction_restart_relock);
+        }
+
+        let mut iter = BtreeIter::new(trans, c::btree_id::BTREE_ID_inodes,
+            spos(0, ROOT_INO, snapshot),
+            BtreeIterFlags::SLOTS);
+        Ok(iter.peek_slot()?.map(|k| k.k.p.offset))
+    });
+
+    assert_eq!(ino, Ok(Some(ROOT_INO)));
+    assert_eq!(attempts, 3);
+
+    /* Other errors aren't retried: */
+    attempts = 0;
+    let ret: Result<(), _> = trans.transact(|_| {
+        attempts += 1;
+        Err(bch_errcode::BCH_ERR_invalid)
+    });
+    assert_eq!(ret, Err(bch_errcode::BCH_ERR_invalid));
+    assert_eq!(attempts, 1);
+}
+
+#[test]
+fn transact_with_max_attempts() {
+    let t = test_fs();
+    let mut trans = BtreeTrans::new(t.fs());
+    let mut restarts = Vec::new();
+
+    let policy = RetryPolicy {
+        max_attempts:   Some(3),
+        on_restart:     |n| restarts.push(n),
+    };
+    let ret: Result<(), _> = trans.transact_with(policy,
+        |_| Err(bch_errcode::BCH_ERR_transaction_restart_relock));
+
+    assert_eq!(ret, Err(bch_errcode::BCH_ERR_transaction_restart_relock));
+    assert_eq!(restarts, [1, 2]);
+}
+
+#[test]
+fn validate_corrupt_key() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let sb_errors = || unsafe { (*t.fs().raw).fsck_error_counts.nr };
+    let errors_before = sb_errors();
+
+    let good = reservation(ROOT_INO + 1, 16, 8, snapshot);
+    assert_eq!(good.as_sc().validate(t.fs(), c::btree_id::BTREE_ID_extents, 0,
+                                     BkeyValidateFlags::COMMIT), Ok(()));
+
+    /* An extent can't be bigger than its offset: */
+    let bad = reservation(ROOT_INO + 1, 4, 8, snapshot);
+    assert_eq!(bad.as_sc().validate(t.fs(), c::btree_id::BTREE_ID_extents, 0,
+                                    BkeyValidateFlags::COMMIT),
+               Err(bch_errcode::BCH_ERR_invalid_bkey));
+
+    let reason = bad.as_sc().invalid_reason(t.fs(), c::btree_id::BTREE_ID_extents, 0,
+                                            BkeyValidateFlags::COMMIT).unwrap();
+    assert!(reason.contains("size greater than offset"), "{}", reason);
+
+    /* Reservations aren't allowed in the inodes btree: */
+    assert_eq!(good.as_sc().validate(t.fs(), c::btree_id::BTREE_ID_inodes, 0,
+                                     BkeyValidateFlags::COMMIT),
+               Err(bch_errcode::BCH_ERR_invalid_bkey));
+
+    /* Checking keys that aren't in the filesystem mustn't count as errors: */
+    assert_eq!(sb_errors(), errors_before);
+}
+
+#[test]
+fn delete_range_extent_middle() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let inode = ROOT_INO + 1;
+    let trans = BtreeTrans::new(t.fs());
+
+    trans.update_many(&[(c::btree_id::BTREE_ID_extents, reservation(inode, 64, 64, snapshot))])
+        .unwrap();
+    assert_eq!(extents(&t, inode, snapshot), [(0, 64)]);
+
+    let (start, end) = (spos(inode, 16, snapshot), spos(inode, 48, snapshot));
+
+    let plan = trans.plan_delete_range(c::btree_id::BTREE_ID_extents, start, end).unwrap();
+    assert_eq!(plan.keys_affected, 1);
+
+    trans.delete_range(c::btree_id::BTREE_ID_extents, start, end).unwrap();
+
+    /* Only the middle is gone, the ends are trimmed: */
+    assert_eq!(extents(&t, inode, snapshot), [(0, 16), (48, 64)]);
+}
+
+/* Benchmarks: run with cargo test --test btree -- --ignored --nocapture */
+
+const BENCH_LOOKUPS: u64 = 100_000;
+
+#[test]
+#[ignore = "benchmark"]
+fn bench_lookup_slot() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let trans = BtreeTrans::new(t.fs());
+    let inodes = ROOT_INO..ROOT_INO + BENCH_LOOKUPS;
+
+    let start = Instant::now();
+    for ino in inodes.clone() {
+        let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_inodes,
+            spos(0, ino, snapshot),
+            BtreeIterFlags::SLOTS);
+        let k = iter.peek_slot().unwrap();
+        std::hint::black_box(k.map(|k| k.k.p));
+    }
+    let new_iter = start.elapsed();
+
+    let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_inodes,
+        spos(0, 0, snapshot),
+        BtreeIterFlags::SLOTS);
+    let start = Instant::now();
+    for ino in inodes {
+        let k = iter.lookup_slot(spos(0, ino, snapshot)).unwrap();
+        std::hint::black_box(k.map(|k| k.k.p));
+    }
+    let reused = start.elapsed();
+
+    println!("{} sequential inode lookups: new iterator each {:?}, reused iterator {:?} ({:.2}x)",
+             BENCH_LOOKUPS, new_iter, reused,
+             new_iter.as_secs_f64() / reused.as_secs_f64());
+}
+
+/* One alloc key per bucket: */
+fn many_keys_fs() -> TestFs {
+    TestFs::new(&TestFsOpts {
+        size:           256 << 20,
+        bucket_size:    Some(4096),
+        ..Default::default()
+    }, Default::default()).unwrap()
+}
+
+#[test]
+#[ignore = "benchmark"]
+fn bench_peek_packed() {
+    let t = many_keys_fs();
+    let trans = BtreeTrans::new(t.fs());
+
+    let start = Instant::now();
+    let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_alloc,
+        spos(0, 0, 0),
+        BtreeIterFlags::PREFETCH);
+    let mut unpacked = 0u64;
+    while let Some(k) = iter.peek_and_restart().unwrap() {
+        std::hint::black_box(k.k.p);
+        unpacked += 1;
+        iter.advance();
+    }
+    let unpacked_time = start.elapsed();
+
+    let start = Instant::now();
+    let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_alloc,
+        spos(0, 0, 0),
+        BtreeIterFlags::PREFETCH).packed();
+    let mut packed = 0u64;
+    while let Some((p, _)) = iter.peek_packed().unwrap() {
+        std::hint::black_box(p);
+        packed += 1;
+        iter.advance();
+    }
+    let packed_time = start.elapsed();
+
+    println!("alloc btree scan: peek {} keys in {:?}, peek_packed {} keys in {:?} ({:.2}x)",
+             unpacked, unpacked_time, packed, packed_time,
+             unpacked_time.as_secs_f64() / packed_time.as_secs_f64());
+}
+
+const BENCH_KEYS: u64 = 10_000;
+
+/* A new, empty regular file's inode: */
+fn inode_key(t: &TestFs, inum: u64, snapshot: u32) -> BkeyOwned {
+    let mut u: c::bch_inode_unpacked = Default::default();
+
+    unsafe {
+        let mut buf: c::bkey_inode_buf = std::mem::zeroed();
+
+        c::bch2_inode_init(t.fs().raw, &mut u, 0, 0, libc::S_IFREG as u16 | 0o644, 0,
+                           std::ptr::null_mut());
+        u.bi_inum = inum;
+        c::bch2_inode_pack(&mut buf, &u);
+
+        let k = &mut *(&mut buf as *mut c::bkey_inode_buf as *mut c::bkey_i);
+        k.k.p.snapshot = snapshot;
+        BkeySC::from(&*k).to_owned()
+    }
+}
+
+#[test]
+#[ignore = "benchmark"]
+fn bench_update_many() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let trans = BtreeTrans::new(t.fs());
+    let keys = |start: u64| -> Vec<_> {
+        (start..start + BENCH_KEYS)
+            .map(|inum| (c::btree_id::BTREE_ID_inodes, inode_key(&t, inum, snapshot)))
+            .collect()
+    };
+
+    let single = keys(1 << 20);
+    let start = Instant::now();
+    for k in single.chunks(1) {
+        trans.update_many(k).unwrap();
+    }
+    let single_time = start.elapsed();
+
+    let batched = keys(2 << 20);
+    let start = Instant::now();
+    for k in batched.chunks(BulkInserterOpts::default().max_keys) {
+        trans.update_many(k).unwrap();
+    }
+    let batched_time = start.elapsed();
+
+    println!("{} inode inserts: one per commit {:?}, {} per commit {:?} ({:.2}x)",
+             BENCH_KEYS, single_time, BulkInserterOpts::default().max_keys, batched_time,
+             single_time.as_secs_f64() / batched_time.as_secs_f64());
+}
+
+#[test]
+#[ignore = "benchmark"]
+fn bench_bulk_insert() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let trans = BtreeTrans::new(t.fs());
+
+    /* One 8 sector reservation in each of BENCH_KEYS inodes: */
+    let keys = |inode: u64| (0..BENCH_KEYS)
+        .map(move |n| reservation(inode + n, 8, 8, snapshot));
+
+    let start = Instant::now();
+    for k in keys(1 << 20) {
+        trans.update_many(&[(c::btree_id::BTREE_ID_extents, k)]).unwrap();
+    }
+    let single_time = start.elapsed();
+
+    let start = Instant::now();
+    let mut bulk = BulkInserter::new(&trans, BulkInserterOpts::default());
+    for k in keys(2 << 20) {
+        bulk.insert(c::btree_id::BTREE_ID_extents, k).unwrap();
+    }
+    bulk.flush().unwrap();
+    let bulk_time = start.elapsed();
+
+    println!("{} extent inserts: one per commit {:?}, BulkInserter {:?} in {} commits ({:.2}x)",
+             BENCH_KEYS, single_time, bulk_time, bulk.nr_commits(),
+             single_time.as_secs_f64() / bulk_time.as_secs_f64());
+}
+
+#[test]
+#[ignore = "benchmark"]
+fn bench_dump_merged() {
+    let t = many_keys_fs();
+    let btrees = [
+        c::btree_id::BTREE_ID_alloc,
+        c::btree_id::BTREE_ID_freespace,
+        c::btree_id::BTREE_ID_bucket_gens,
+        c::btree_id::BTREE_ID_inodes,
+    ];
+
+    let start = Instant::now();
+    let mut sequential = 0u64;
+    {
+        let trans = BtreeTrans::new(t.fs());
+        for btree in btrees {
+            let mut iter = BtreeIter::new(&trans, btree, pos(0, 0),
+                BtreeIterFlags::ALL_SNAPSHOTS|BtreeIterFlags::PREFETCH);
+            while let Some(k) = iter.peek_and_restart().unwrap() {
+                std::hint::black_box(k.k.p);
+                sequential += 1;
+                iter.advance();
+            }
+        }
+    }
+    let sequential_time = start.elapsed();
+
+    for order in [MergeOrder::Pos, MergeOrder::Interleaved] {
+        let start = Instant::now();
+        let mut merged = 0u64;
+        t.fs().dump_merged(&btrees, order, |_, k| {
+            std::hint::black_box(k.k.p);
+            merged += 1;
+        }).unwrap();
+        let merged_time = start.elapsed();
+
+        assert_eq!(merged, sequential);
+        println!("{} keys in {} btrees: sequential {:?}, dump_merged({:?}) {:?} ({:.2}x)",
+                 sequential, btrees.len(), sequential_time, order, merged_time,
+                 sequential_time.as_secs_f64() / merged_time.as_secs_f64());
+    }
+}
@@ -0,0 +1,99 @@
+use bch_bindgen::bcachefs as c;
+use bch_bindgen::bkey::BkeyValC;
+use bch_bindgen::btree::{BtreeIter, BtreeIterFlags, BtreeTrans};
+use bch_bindgen::errcode::bch_errcode;
+use bch_bindgen::fs::Fs;
+use bch_bindgen::pos;
+use bch_bindgen::testing::{TestFs, TestFsOpts};
+
+/* One btree node per bucket, so rewriting a node always moves it: */
+fn test_fs() -> TestFs {
+    TestFs::new(&TestFsOpts {
+        bucket_size:    Some(256 << 10),
+        extra_args:     vec!["--btree_node_size=256k".to_string()],
+        ..Default::default()
+    }, Default::default()).unwrap()
+}
+
+fn alloc(fs: &Fs, dev: u32, bucket: u64) -> c::bch_alloc_v4 {
+    let trans = BtreeTrans::new(fs);
+    let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_alloc,
+        pos(dev as u64, bucket),
+        BtreeIterFlags::SLOTS);
+    let k = iter.peek_slot().unwrap();
+
+    /* a fresh filesystem only has v4 alloc keys: */
+    match k.as_ref().map(|k| k.v()) {
+        Some(BkeyValC::alloc_v4(a)) => *a,
+        _ => Default::default(),
+    }
+}
+
+fn first_bucket_of_type(fs: &Fs, dev: u32, data_type: c::bch_data_type) -> Option<u64> {
+    let trans = BtreeTrans::new(fs);
+    let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_alloc,
+        pos(dev as u64, 0),
+        BtreeIterFlags::PREFETCH);
+
+    while let Some(k) = iter.peek_and_restart().unwrap() {
+        if k.k.p.inode != dev as u64 {
+            break;
+        }
+
+        if let BkeyValC::alloc_v4(a) = k.v() {
+            if a.data_type == data_type as u8 {
+                return Some(k.k.p.offset);
+            }
+        }
+        iter.advance();
+    }
+    None
+}
+
+#[test]
+fn evict_btree_bucket() {
+    let t = test_fs();
+    let b = first_bucket_of_type(t.fs(), 0, c::bch_data_type::BCH_DATA_btree)
+        .expect("no btree buckets");
+    assert_ne!({ alloc(t.fs(), 0, b).dirty_sectors }, 0);
+
+    t.fs().evict_bucket(0, b).unwrap();
+
+    let a = alloc(t.fs(), 0, b);
+    assert_eq!({ a.dirty_sectors }, 0);
+    assert_ne!({ a.data_type }, c::bch_data_type::BCH_DATA_btree as u8);
+}
+
+#[test]
+fn evict_bucket_bad_dev() {
+    let t = test_fs();
+
+    assert_eq!(t.fs().evict_bucket(7, 0),
+               Err(bch_errcode::BCH_ERR_ENOENT_dev_idx_not_found));
+}
+
+#[test]
+fn discard_evicted_bucket() {
+    let t = test_fs();
+    let b = first_bucket_of_type(t.fs(), 0, c::bch_data_type::BCH_DATA_btree)
+        .expect("no btree buckets");
+
+    t.fs().evict_bucket(0, b).unwrap();
+    /* freed buckets can't be discarded until the journal is flushed: */
+    t.fs().flush_journal().unwrap();
+    t.fs().discard_free_buckets(0).unwrap();
+
+    assert_eq!(first_bucket_of_type(t.fs(), 0, c::bch_data_type::BCH_DATA_need_discard), None);
+    assert_eq!({ alloc(t.fs(), 0, b).data_type }, c::bch_data_type::BCH_DATA_free as u8);
+}
+
+#[test]
+fn discard_free_buckets_errors() {
+    let t = test_fs();
+
+    assert_eq!(t.fs().discard_free_buckets(7),
+               Err(bch_errcode::BCH_ERR_ENOENT_dev_idx_not_found));
+
+    t.fs().go_read_only().unwrap();
+    assert_eq!(t.fs().discard_free_buckets(0).unwrap_err().class(), libc::EROFS);
+}
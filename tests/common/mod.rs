@@ -0,0 +1,49 @@
+//! Helpers shared by the integration tests
+
+#![allow(dead_code)]
+
+use bch_bindgen::bcachefs as c;
+use bch_bindgen::bkey::{BkeyOwned, BkeySC};
+use bch_bindgen::spos;
+use bch_bindgen::testing::{TestFs, TestFsOpts};
+
+pub const ROOT_SUBVOL: u32  = 1;
+pub const ROOT_INO: u64     = 4096;
+
+pub fn test_fs() -> TestFs {
+    TestFs::new(&TestFsOpts::default(), Default::default()).unwrap()
+}
+
+pub fn two_device_fs(replicas: u32) -> TestFs {
+    TestFs::new(&TestFsOpts {
+        nr_devices: 2,
+        extra_args: vec![format!("--replicas={}", replicas)],
+        ..Default::default()
+    }, Default::default()).unwrap()
+}
+
+/* A reservation extent covering sectors [end - size, end): */
+pub fn reservation(inode: u64, end: u64, size: u32, snapshot: u32) -> BkeyOwned {
+    reservation_gen(inode, end, size, snapshot, 0)
+}
+
+pub fn reservation_gen(inode: u64, end: u64, size: u32, snapshot: u32, generation: u32) -> BkeyOwned {
+    let u64s = (std::mem::size_of::<c::bkey>() + std::mem::size_of::<c::bch_reservation>()) / 8;
+    let mut buf = vec![0u64; u64s];
+
+    unsafe {
+        let k = &mut *(buf.as_mut_ptr() as *mut c::bkey);
+        k.u64s  = u64s as u8;
+        k.set_format(1);
+        k.type_ = c::bch_bkey_type::KEY_TYPE_reservation as u8;
+        k.size  = size;
+        k.p     = spos(inode, end, snapshot);
+
+        let v = &mut *(buf.as_mut_ptr().add(std::mem::size_of::<c::bkey>() / 8) as *mut c::bch_reservation);
+        v.generation  = generation.to_le();
+        v.nr_replicas = 1;
+    }
+
+    let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, buf.len() * 8) };
+    BkeySC::from_bytes(bytes).unwrap().to_owned()
+}
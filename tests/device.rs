@@ -0,0 +1,120 @@
+use bch_bindgen::bcachefs as c;
+use bch_bindgen::btree::{BtreeIter, BtreeIterFlags, BtreeTrans};
+use bch_bindgen::device::{DevForceFlags, ResizeError};
+use bch_bindgen::errcode::bch_errcode;
+use bch_bindgen::superblock::SuperBlock;
+use bch_bindgen::testing::TestFs;
+use bch_bindgen::{pos, PosRange};
+
+mod common;
+use common::{test_fs, two_device_fs};
+
+fn nbuckets(t: &TestFs) -> u64 {
+    unsafe { (*(*t.fs().raw).devs[0]).mi.nbuckets }
+}
+
+/* Buckets on device 0 from `start` holding dirty data, and their dirty sectors */
+fn dirty_buckets(t: &TestFs, start: u64) -> (u64, u64) {
+    let trans = BtreeTrans::new(t.fs());
+    let mut iter = BtreeIter::new_range(&trans, c::btree_id::BTREE_ID_alloc,
+        PosRange::new(pos(0, start), pos(0, u64::MAX)),
+        BtreeIterFlags::empty());
+    let (mut buckets, mut sectors) = (0, 0);
+
+    while let Some(k) = iter.peek_and_restart().unwrap() {
+        let mut a: c::bch_alloc_v4 = Default::default();
+        unsafe { c::__bch2_alloc_to_v4(c::bkey_s_c { k: k.k, v: k.v }, &mut a) };
+
+        if a.dirty_sectors != 0 {
+            buckets += 1;
+            sectors += a.dirty_sectors as u64;
+        }
+        iter.advance();
+    }
+
+    (buckets, sectors)
+}
+
+#[test]
+fn resize_device_errors() {
+    let t = test_fs();
+    let nbuckets = nbuckets(&t);
+
+    assert_eq!(t.fs().resize_device(7, nbuckets),
+               Err(ResizeError::Resize(bch_errcode::BCH_ERR_ENOENT_dev_idx_not_found)));
+
+    /* the image file is exactly the size of the filesystem: */
+    assert_eq!(t.fs().resize_device(0, nbuckets * 2),
+               Err(ResizeError::Resize(bch_errcode::BCH_ERR_device_size_too_small)));
+
+    /* the journal and btree live near the start, the end is empty: */
+    assert_eq!(t.fs().resize_device(0, nbuckets - 1),
+               Err(ResizeError::ShrinkNotSupported));
+}
+
+#[test]
+fn resize_device_has_data() {
+    let t = test_fs();
+    let bucket_size = unsafe { (*(*t.fs().raw).devs[0]).mi.bucket_size as u64 };
+    let (buckets, sectors) = dirty_buckets(&t, 1);
+
+    assert!(buckets != 0);
+    assert!(sectors >= buckets && sectors <= buckets * bucket_size);
+
+    assert_eq!(t.fs().resize_device(0, 1),
+               Err(ResizeError::ShrinkHasData { buckets, sectors }));
+}
+
+#[test]
+fn resize_device_grow() {
+    let t = test_fs();
+    let nbuckets = nbuckets(&t);
+    let bucket_bytes = unsafe { (*(*t.fs().raw).devs[0]).mi.bucket_size as u64 } << 9;
+
+    std::fs::OpenOptions::new().write(true).open(t.path()).unwrap()
+        .set_len((nbuckets + 16) * bucket_bytes).unwrap();
+
+    t.fs().resize_device_bytes(0, (nbuckets + 16) * bucket_bytes).unwrap();
+    assert_eq!(self::nbuckets(&t), nbuckets + 16);
+}
+
+fn member_state(t: &TestFs, dev: usize) -> u8 {
+    SuperBlock::from_sb(unsafe { &*(*t.fs().raw).disk_sb.sb }).members[dev].state
+}
+
+#[test]
+fn set_device_state() {
+    use c::bch_member_state::*;
+
+    let t = two_device_fs(1);
+
+    t.fs().set_device_state(1, BCH_MEMBER_STATE_ro, DevForceFlags::empty()).unwrap();
+    assert_eq!(member_state(&t, 1), BCH_MEMBER_STATE_ro as u8);
+    assert_eq!(member_state(&t, 0), BCH_MEMBER_STATE_rw as u8);
+
+    t.fs().set_device_state(1, BCH_MEMBER_STATE_rw, DevForceFlags::empty()).unwrap();
+    assert_eq!(member_state(&t, 1), BCH_MEMBER_STATE_rw as u8);
+
+    /* metadata is on device 0 only, so failing it would lose it: */
+    assert_eq!(t.fs().set_device_state(0, BCH_MEMBER_STATE_failed, DevForceFlags::empty()),
+               Err(bch_errcode::BCH_ERR_device_state_not_allowed));
+    assert_eq!(member_state(&t, 0), BCH_MEMBER_STATE_rw as u8);
+
+    assert!(t.fs().set_device_state(7, BCH_MEMBER_STATE_ro, DevForceFlags::empty()).is_err());
+}
+
+#[test]
+fn set_device_state_degraded() {
+    use c::bch_member_state::*;
+
+    let t = two_device_fs(2);
+
+    /* one rw device can't hold two replicas: */
+    assert_eq!(t.fs().set_device_state(1, BCH_MEMBER_STATE_ro, DevForceFlags::empty()),
+               Err(bch_errcode::BCH_ERR_device_state_not_allowed));
+    assert_eq!(member_state(&t, 1), BCH_MEMBER_STATE_rw as u8);
+
+    t.fs().set_device_state(1, BCH_MEMBER_STATE_ro,
+        DevForceFlags::DATA_DEGRADED|DevForceFlags::METADATA_DEGRADED).unwrap();
+    assert_eq!(member_state(&t, 1), BCH_MEMBER_STATE_ro as u8);
+}
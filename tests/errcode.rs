@@ -0,0 +1,108 @@
+use bch_bindgen::bcachefs as c;
+use bch_bindgen::btree::{BtreeIter, BtreeIterFlags, BtreeTrans};
+use bch_bindgen::errcode::{bch_errcode, BchError};
+use bch_bindgen::spos;
+
+mod common;
+use common::{test_fs, ROOT_INO, ROOT_SUBVOL};
+
+fn ret(err: bch_errcode) -> i32 {
+    -(err as i32)
+}
+
+#[test]
+fn plain_errnos_dont_map_to_private_codes() {
+    assert_eq!(bch_errcode::from_ret(-libc::EINVAL), bch_errcode::BCH_ERR_invalid);
+
+    for errno in [libc::ENOENT, libc::EIO, libc::ENOSPC, libc::ENOMEM, libc::EEXIST,
+                  libc::EBUSY, libc::EINTR, libc::EAGAIN, libc::ENOTDIR, libc::EOPNOTSUPP] {
+        let err = bch_errcode::from_ret(-errno);
+
+        assert_eq!(err, bch_errcode::UNKNOWN, "errno {} mapped to {}", errno, err);
+        assert!(!err.matches(bch_errcode::BCH_ERR_ENOENT_bkey_type_mismatch));
+        assert!(!err.matches(bch_errcode::BCH_ERR_btree_node_read_error));
+    }
+}
+
+#[test]
+fn plain_errnos_are_kept_by_bch_error() {
+    assert_eq!(BchError::try_from(-libc::EIO), Ok(BchError::Io));
+    assert_eq!(BchError::try_from(-libc::ENOSPC), Ok(BchError::NoSpace));
+    assert_eq!(BchError::try_from(-libc::EEXIST), Ok(BchError::Errno(libc::EEXIST)));
+    assert_eq!(BchError::try_from(-libc::EKEYREJECTED).map(|e| e.classify()),
+               Ok(bch_bindgen::errcode::ErrorClass::Permission));
+}
+
+#[test]
+fn non_errors_dont_panic() {
+    for r in [0, 1, i32::MIN, -4095, ret(bch_errcode::BCH_ERR_START),
+              ret(bch_errcode::BCH_ERR_MAX)] {
+        assert_eq!(bch_errcode::try_from_ret(r), None, "{}", r);
+        assert_eq!(bch_errcode::from_ret(r), bch_errcode::UNKNOWN, "{}", r);
+    }
+
+    assert!(BchError::try_from(0).is_err());
+    assert!(BchError::try_from(i32::MIN).is_err());
+}
+
+#[test]
+fn no_btree_node_codes_are_exact() {
+    for code in [bch_errcode::BCH_ERR_no_btree_node_relock,
+                 bch_errcode::BCH_ERR_no_btree_node_upgrade,
+                 bch_errcode::BCH_ERR_no_btree_node_lock_root,
+                 bch_errcode::BCH_ERR_no_btree_node_up,
+                 bch_errcode::BCH_ERR_no_btree_node_down,
+                 bch_errcode::BCH_ERR_no_btree_node_init] {
+        let err = bch_errcode::from_ret(ret(code));
+
+        assert_eq!(err, code);
+        assert!(err.matches(bch_errcode::BCH_ERR_no_btree_node));
+        assert!(!err.is_transaction_restart());
+    }
+}
+
+#[test]
+fn no_btree_node_from_path() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let trans = BtreeTrans::new(t.fs());
+    let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_inodes,
+        spos(0, ROOT_INO, snapshot),
+        BtreeIterFlags::empty());
+
+    /* not traversed yet: */
+    assert_eq!(iter.node_at(0).map(|b| b.is_some()),
+               Err(bch_errcode::BCH_ERR_no_btree_node_init));
+
+    iter.peek().unwrap().expect("no root inode");
+
+    /* a fresh filesystem's inodes btree is a single leaf: */
+    assert!(iter.node_at(0).unwrap().is_some());
+    assert!(iter.node_at(1).unwrap().is_none());
+}
+
+/*
+ * There's no error code for snapshots that are deleted but not yet cleaned
+ * up: lookups in snapshots and subvolumes that don't exist (any more) return
+ * nothing, or ENOENT_subvolume:
+ */
+#[test]
+fn missing_snapshot() {
+    let t = test_fs();
+
+    assert!(t.fs().snapshot(u32::MAX - 1).unwrap().is_none());
+    assert_eq!(t.fs().subvol_snapshot(ROOT_SUBVOL + 1),
+               Err(bch_errcode::BCH_ERR_ENOENT_subvolume));
+    assert_eq!(t.fs().subvolume_root(ROOT_SUBVOL + 1),
+               Err(bch_errcode::BCH_ERR_ENOENT_subvolume));
+}
+
+#[test]
+fn transaction_restarts_are_exact() {
+    let code = bch_errcode::BCH_ERR_transaction_restart_relock;
+    let err = bch_errcode::from_ret(ret(code));
+
+    assert_eq!(err, code);
+    assert!(err.is_transaction_restart());
+    assert_eq!(BchError::from(err), BchError::TransactionRestart);
+}
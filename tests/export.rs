@@ -0,0 +1,42 @@
+use bch_bindgen::bcachefs as c;
+use bch_bindgen::btree::BtreeTrans;
+
+mod common;
+use common::test_fs;
+
+/* magic + on disk version: */
+const EXPORT_HDR_BYTES: usize = 10;
+
+#[test]
+fn export_import_round_trip() {
+    let t = test_fs();
+    let mut buf = Vec::new();
+    let nr = t.fs().export(&[c::btree_id::BTREE_ID_inodes], &mut buf).unwrap();
+    assert!(nr > 0);
+
+    let trans = BtreeTrans::new(t.fs());
+    assert_eq!(t.fs().import(&buf[..], &trans).unwrap(), nr);
+}
+
+#[test]
+fn import_empty_export() {
+    let t = test_fs();
+    let mut buf = Vec::new();
+    t.fs().export(&[], &mut buf).unwrap();
+    assert_eq!(buf.len(), EXPORT_HDR_BYTES);
+
+    let trans = BtreeTrans::new(t.fs());
+    assert_eq!(t.fs().import(&buf[..], &trans).unwrap(), 0);
+}
+
+#[test]
+fn import_truncated_record_header() {
+    let t = test_fs();
+    let mut buf = Vec::new();
+    t.fs().export(&[c::btree_id::BTREE_ID_inodes], &mut buf).unwrap();
+
+    let trans = BtreeTrans::new(t.fs());
+    for len in EXPORT_HDR_BYTES + 1..EXPORT_HDR_BYTES + 8 {
+        assert!(t.fs().import(&buf[..len], &trans).is_err(), "{} bytes", len);
+    }
+}
@@ -0,0 +1,47 @@
+use bch_bindgen::extents::ExtentData;
+
+mod common;
+use common::{two_device_fs, ROOT_INO, ROOT_SUBVOL};
+
+#[test]
+fn replicated_extent_pointers() {
+    let t = two_device_fs(2);
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let inum = t.create_file(ROOT_SUBVOL, ROOT_INO, "file").unwrap();
+
+    t.write_file(ROOT_SUBVOL, inum, 0, &[0x5a; 65536], 2).unwrap();
+
+    let extents = t.fs().file_extents(inum, snapshot, false).unwrap();
+    assert!(!extents.is_empty());
+    assert_eq!(extents.iter().map(|e| e.size).sum::<u64>(), 128);
+
+    for e in &extents {
+        let ExtentData::Ptrs(ptrs) = &e.data else {
+            panic!("expected pointers, got {:?}", e.data);
+        };
+
+        let mut devs: Vec<_> = ptrs.iter().map(|p| p.dev).collect();
+        devs.sort();
+        assert_eq!(devs, [0, 1]);
+        assert!(ptrs.iter().all(|p| !p.cached && !p.unwritten));
+    }
+}
+
+#[test]
+fn extents_serialize() {
+    let t = two_device_fs(2);
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let inum = t.create_file(ROOT_SUBVOL, ROOT_INO, "file").unwrap();
+
+    t.write_file(ROOT_SUBVOL, inum, 0, &[1; 4096], 2).unwrap();
+
+    let extents = t.fs().file_extents(inum, snapshot, false).unwrap();
+    let json = serde_json::to_value(&extents).unwrap();
+    let e = &json[0];
+
+    assert_eq!(e["offset"], 0);
+    assert_eq!(e["size"], 8);
+    assert_eq!(e["refcount"], serde_json::Value::Null);
+    assert_eq!(e["data"]["Ptrs"].as_array().unwrap().len(), 2);
+    assert!(e["data"]["Ptrs"][0]["crc"].is_object());
+}
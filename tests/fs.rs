@@ -0,0 +1,128 @@
+use bch_bindgen::bcachefs as c;
+use bch_bindgen::btree::BtreeTrans;
+use bch_bindgen::errcode::BchError;
+use bch_bindgen::fs::Timespec;
+use bch_bindgen::testing::{TestFs, TestFsOpts};
+use std::time::{Duration, UNIX_EPOCH};
+
+mod common;
+use common::{test_fs, ROOT_INO, ROOT_SUBVOL};
+
+#[test]
+fn test_fs_cleans_up() {
+    let t = test_fs();
+    let path = t.path().to_path_buf();
+    assert!(path.exists());
+
+    drop(t);
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_fs_too_small() {
+    let opts = TestFsOpts { size: 64 << 10, ..Default::default() };
+
+    assert!(opts.size < opts.min_size());
+    assert!(TestFs::new(&opts, Default::default()).is_err());
+}
+
+#[test]
+fn label_and_uuid() {
+    let t = test_fs();
+
+    t.fs().set_label("scratch").unwrap();
+    assert_eq!(t.fs().label().unwrap(), "scratch");
+    assert!(t.fs().set_label(&"x".repeat(c::BCH_SB_LABEL_SIZE as usize + 1)).is_err());
+
+    let uuid = uuid::Uuid::from_bytes([0x42; 16]);
+    t.fs().set_uuid(uuid).unwrap();
+    assert_eq!(t.fs().uuid(), uuid);
+    assert!(t.fs().set_uuid(uuid::Uuid::nil()).is_err());
+}
+
+#[test]
+fn read_only_read_write() {
+    let t = test_fs();
+
+    t.fs().sync().unwrap();
+    t.fs().go_read_only().unwrap();
+    assert_eq!(t.fs().go_read_only(), Err(BchError::AlreadyReadOnly));
+
+    /* nothing to flush or reclaim when read-only: */
+    t.fs().sync().unwrap();
+    assert_eq!(t.fs().journal_reclaim(), Ok(0));
+
+    t.fs().go_read_write().unwrap();
+    assert_eq!(t.fs().go_read_write(), Err(BchError::AlreadyReadWrite));
+}
+
+#[test]
+fn flush_and_reclaim() {
+    let t = test_fs();
+
+    t.fs().flush_journal().unwrap();
+    t.fs().flush_key_cache().unwrap();
+    t.fs().journal_reclaim().unwrap();
+
+    let stats = t.fs().key_cache_stats();
+    assert!(stats.nr_dirty <= stats.nr_keys);
+}
+
+#[test]
+fn root_subvolume() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+
+    assert_eq!(t.fs().snapshot_subvol(snapshot).unwrap(), Some(ROOT_SUBVOL));
+    assert_eq!(t.fs().snapshot_parent(snapshot).unwrap(), None);
+    assert!(t.fs().subvol_snapshot(1000).is_err());
+}
+
+#[test]
+fn time_round_trip() {
+    let t = test_fs();
+
+    /* 2023-11-14 22:13:20.123456789 UTC, and a time before the epoch: */
+    for ts in [Timespec { tv_sec: 1_700_000_000, tv_nsec: 123_456_789 },
+               Timespec { tv_sec: -86_400, tv_nsec: 500_000_000 }] {
+        let fs_time = t.fs().timespec_to_time(ts);
+
+        assert_eq!(t.fs().time_to_timespec(fs_time), ts);
+    }
+
+    let fs_time = t.fs().timespec_to_time(Timespec { tv_sec: 1_700_000_000, tv_nsec: 123_456_789 });
+    assert_eq!(t.fs().time_to_system(fs_time),
+               UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789));
+}
+
+#[test]
+fn snapshot_view_root_inode() {
+    let t = test_fs();
+    let view = t.fs().snapshot_view(ROOT_SUBVOL).unwrap();
+    let trans = BtreeTrans::new(t.fs());
+
+    assert_eq!(view.snapshot, t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap());
+
+    let k = view.get(&trans, c::btree_id::BTREE_ID_inodes, 0, ROOT_INO).unwrap()
+        .expect("root inode not visible");
+    assert_eq!(k.k().p.offset, ROOT_INO);
+    assert_eq!(k.k().p.snapshot, view.snapshot);
+
+    assert!(view.get(&trans, c::btree_id::BTREE_ID_inodes, 0, ROOT_INO + 12345).unwrap().is_none());
+    assert!(t.fs().snapshot_view(1000).is_err());
+}
+
+#[test]
+fn set_option() {
+    let t = test_fs();
+
+    assert_eq!(t.fs().option("background_compression").unwrap(), "none");
+    t.fs().set_option("background_compression", "lz4").unwrap();
+    assert_eq!(t.fs().option("background_compression").unwrap(), "lz4");
+
+    assert_eq!(t.fs().option("no_such_option"), Err(BchError::OptionNotFound));
+    assert_eq!(t.fs().set_option("no_such_option", "1"), Err(BchError::OptionNotFound));
+    assert_eq!(t.fs().set_option("block_size", "4096"), Err(BchError::OptionNotRuntimeSettable));
+    assert!(t.fs().set_option("background_compression", "not_a_compression_type").is_err());
+    assert_eq!(t.fs().option("background_compression").unwrap(), "lz4");
+}
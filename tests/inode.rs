@@ -0,0 +1,63 @@
+use bch_bindgen::bcachefs as c;
+use bch_bindgen::bkey::BkeyValC;
+use bch_bindgen::btree::{BtreeIter, BtreeIterFlags, BtreeTrans};
+use bch_bindgen::errcode::BchError;
+use bch_bindgen::spos;
+use bch_bindgen::testing::TestFs;
+
+mod common;
+use common::{test_fs, ROOT_INO, ROOT_SUBVOL};
+
+/* A freshly formatted filesystem has one entry in the root: lost+found */
+fn lost_and_found(t: &TestFs) -> (c::bpos, u64) {
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let trans = BtreeTrans::new(t.fs());
+    let mut iter = BtreeIter::new(&trans, c::btree_id::BTREE_ID_dirents,
+        spos(ROOT_INO, 0, snapshot),
+        BtreeIterFlags::empty());
+
+    let k = iter.peek().unwrap().expect("no dirents in root");
+    match k.v() {
+        BkeyValC::dirent(d) => (k.k.p, u64::from_le(unsafe { d.__bindgen_anon_1.d_inum })),
+        _ => panic!("not a dirent"),
+    }
+}
+
+#[test]
+fn relink_orphan_linked_inode() {
+    let t = test_fs();
+    let (_, inum) = lost_and_found(&t);
+
+    assert_eq!(t.fs().relink_orphan(ROOT_SUBVOL, inum, ROOT_INO, "again"),
+               Err(BchError::InodeLinked));
+}
+
+#[test]
+fn relink_orphan_missing_inode() {
+    let t = test_fs();
+
+    assert_eq!(t.fs().relink_orphan(ROOT_SUBVOL, 1 << 40, ROOT_INO, "missing"),
+               Err(BchError::InodeNotFound));
+}
+
+#[test]
+fn relink_orphan_round_trip() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let (pos, inum) = lost_and_found(&t);
+    let links = t.fs().count_links(inum, snapshot).unwrap();
+
+    /* Orphan lost+found by deleting its dirent: */
+    BtreeTrans::new(t.fs())
+        .delete_range(c::btree_id::BTREE_ID_dirents, pos, pos)
+        .unwrap();
+    assert_eq!(t.fs().count_links(inum, snapshot).unwrap(), links - 1);
+
+    t.fs().relink_orphan(ROOT_SUBVOL, inum, ROOT_INO, "relinked").unwrap();
+
+    assert_eq!(t.fs().count_links(inum, snapshot).unwrap(), links);
+
+    /* Relinking again must fail: */
+    assert_eq!(t.fs().relink_orphan(ROOT_SUBVOL, inum, ROOT_INO, "relinked"),
+               Err(BchError::InodeLinked));
+}
@@ -0,0 +1,65 @@
+use bch_bindgen::spos;
+use bch_bindgen::testing::{TestFs, TestFsOpts};
+use std::io::Read;
+
+mod common;
+use common::{ROOT_INO, ROOT_SUBVOL};
+
+fn test_fs(block_size: u32) -> TestFs {
+    TestFs::new(&TestFsOpts {
+        block_size: Some(block_size),
+        ..Default::default()
+    }, Default::default()).unwrap()
+}
+
+#[test]
+fn offset_bytes() {
+    for block_size in [512, 4096] {
+        let t = test_fs(block_size);
+        let block = block_size as u64;
+
+        assert_eq!(t.fs().block_bytes(), block);
+
+        /* Extent offsets are in sectors whatever the block size: */
+        assert_eq!(t.fs().offset_bytes(spos(1, 8, 0)), 4096);
+
+        for bytes in [0, 1, block - 1, block, 3 * block + 7] {
+            let p = t.fs().pos_at_byte(1, bytes, 0);
+
+            assert_eq!(t.fs().offset_bytes(p), bytes / block * block);
+        }
+    }
+}
+
+#[test]
+fn read_at() {
+    let t = test_fs(4096);
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let inum = t.create_file(ROOT_SUBVOL, ROOT_INO, "file").unwrap();
+    let data: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8).collect();
+
+    /* a hole in the first block, then data: */
+    t.write_file(ROOT_SUBVOL, inum, 4096, &data, 1).unwrap();
+    assert_eq!(t.fs().inode(inum, snapshot).unwrap().unwrap().size, 12288);
+
+    let mut buf = vec![0xff; 12288];
+    assert_eq!(t.fs().read_at(inum, snapshot, 0, &mut buf), Ok(12288));
+    assert!(buf[..4096].iter().all(|&b| b == 0));
+    assert_eq!(&buf[4096..], &data[..]);
+
+    /* unaligned: */
+    let mut buf = vec![0; 100];
+    assert_eq!(t.fs().read_at(inum, snapshot, 4100, &mut buf), Ok(100));
+    assert_eq!(&buf[..], &data[4..104]);
+
+    /* short reads at EOF: */
+    assert_eq!(t.fs().read_at(inum, snapshot, 12288 - 10, &mut buf), Ok(10));
+    assert_eq!(&buf[..10], &data[8182..]);
+    assert_eq!(t.fs().read_at(inum, snapshot, 12288, &mut buf), Ok(0));
+    assert_eq!(t.fs().read_at(inum, snapshot, 1 << 20, &mut buf), Ok(0));
+
+    let mut file = Vec::new();
+    t.fs().open_file(inum, snapshot).unwrap().read_to_end(&mut file).unwrap();
+    assert_eq!(file.len(), 12288);
+    assert_eq!(&file[4096..], &data[..]);
+}
@@ -0,0 +1,35 @@
+use bch_bindgen::bcachefs as c;
+use bch_bindgen::journal::JsetEntry;
+use bch_bindgen::opt_set;
+use bch_bindgen::testing::{TestFs, TestFsOpts};
+
+#[test]
+fn journal_entries_need_keep_journal() {
+    let t = TestFs::new(&TestFsOpts::default(), Default::default()).unwrap();
+
+    assert_eq!(t.fs().journal_entries().count(), 0);
+}
+
+#[test]
+fn journal_entries() {
+    let mut fs_opts: c::bch_opts = Default::default();
+    opt_set!(fs_opts, keep_journal, 1);
+
+    let t = TestFs::new(&TestFsOpts::default(), fs_opts).unwrap();
+    let jsets: Vec<_> = t.fs().journal_entries().collect();
+
+    /* format leaves at least the entry written at shutdown: */
+    assert!(!jsets.is_empty());
+
+    for j in &jsets {
+        assert!(j.last_seq() <= j.seq());
+    }
+    assert!(jsets.windows(2).all(|w| w[0].seq() < w[1].seq()));
+
+    /* every flush entry records the btree roots: */
+    let roots = jsets.iter()
+        .flat_map(|j| j.entries())
+        .filter(|e| matches!(e, JsetEntry::BtreeRoot { key: Some(_), .. }))
+        .count();
+    assert!(roots > 0);
+}
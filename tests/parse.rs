@@ -0,0 +1,30 @@
+use bch_bindgen::bcachefs as c;
+use bch_bindgen::{spos, InvalidBpos, POS_MIN, SPOS_MAX};
+
+#[test]
+fn parse_btree_id() {
+    assert_eq!("extents".parse::<c::btree_id>().unwrap(), c::btree_id::BTREE_ID_extents);
+    assert_eq!("INODES".parse::<c::btree_id>().unwrap(), c::btree_id::BTREE_ID_inodes);
+
+    let err = "nope".parse::<c::btree_id>().unwrap_err();
+    assert_eq!(err.name, "nope");
+    assert!(err.valid.iter().any(|n| n == "extents"));
+}
+
+#[test]
+fn parse_bkey_type() {
+    assert_eq!("extent".parse::<c::bch_bkey_type>().unwrap(), c::bch_bkey_type::KEY_TYPE_extent);
+    assert!("nope".parse::<c::bch_bkey_type>().is_err());
+}
+
+#[test]
+fn parse_bpos() {
+    assert_eq!("POS_MIN".parse::<c::bpos>().unwrap(), POS_MIN);
+    assert_eq!("SPOS_MAX".parse::<c::bpos>().unwrap(), SPOS_MAX);
+    assert_eq!("1:2".parse::<c::bpos>().unwrap(), spos(1, 2, 0));
+    assert_eq!("1:2:3".parse::<c::bpos>().unwrap(), spos(1, 2, 3));
+
+    for s in ["", "1", "x:2", "1:y", "1:2:z", "1:2:", "1:2:3:4"] {
+        assert_eq!(s.parse::<c::bpos>(), Err(InvalidBpos(s.to_string())));
+    }
+}
@@ -0,0 +1,72 @@
+use bch_bindgen::bcachefs as c;
+use bch_bindgen::bkey::{BkeyOwned, BkeySC};
+use bch_bindgen::btree::BtreeTrans;
+use bch_bindgen::pos;
+use bch_bindgen::quota::{QuotaCounter, QuotaType};
+
+mod common;
+use common::{test_fs, ROOT_INO, ROOT_SUBVOL};
+
+/* A user quota key setting limits for `id`: */
+fn quota_limits(id: u32, space: (u64, u64), inodes: (u64, u64)) -> BkeyOwned {
+    let u64s = (std::mem::size_of::<c::bkey>() + std::mem::size_of::<c::bch_quota>()) / 8;
+    let mut buf = vec![0u64; u64s];
+
+    unsafe {
+        let k = &mut *(buf.as_mut_ptr() as *mut c::bkey);
+        k.u64s  = u64s as u8;
+        k.set_format(1);
+        k.type_ = c::bch_bkey_type::KEY_TYPE_quota as u8;
+        k.p     = pos(c::quota_types::QTYP_USR as u64, id as u64);
+
+        let v = &mut *(buf.as_mut_ptr().add(std::mem::size_of::<c::bkey>() / 8) as *mut c::bch_quota);
+        let spc = &mut v.c[c::quota_counters::Q_SPC as usize];
+        spc.softlimit = space.0.to_le();
+        spc.hardlimit = space.1.to_le();
+
+        let ino = &mut v.c[c::quota_counters::Q_INO as usize];
+        ino.softlimit = inodes.0.to_le();
+        ino.hardlimit = inodes.1.to_le();
+    }
+
+    let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, buf.len() * 8) };
+    BkeySC::from_bytes(bytes).unwrap().to_owned()
+}
+
+#[test]
+fn quota_usage() {
+    let t = test_fs();
+    let before = t.fs().quotas(QuotaType::User).unwrap();
+
+    /* the root directory and lost+found, owned by root: */
+    assert_eq!(before.len(), 1);
+    assert_eq!(before[0].id, 0);
+    assert!(before[0].inodes.used >= 2);
+
+    let inum = t.create_file(ROOT_SUBVOL, ROOT_INO, "file").unwrap();
+    t.write_file(ROOT_SUBVOL, inum, 0, &[1; 65536], 1).unwrap();
+
+    let after = t.fs().quotas(QuotaType::User).unwrap();
+    assert_eq!(after.len(), 1);
+    assert_eq!(after[0].inodes.used, before[0].inodes.used + 1);
+    assert_eq!(after[0].space.used, before[0].space.used + 128);
+}
+
+#[test]
+fn quota_limits_from_btree() {
+    let t = test_fs();
+
+    BtreeTrans::new(t.fs())
+        .update_many(&[(c::btree_id::BTREE_ID_quotas, quota_limits(1000, (100, 200), (10, 20)))])
+        .unwrap();
+
+    let quotas = t.fs().quotas(QuotaType::User).unwrap();
+    let q = quotas.iter().find(|q| q.id == 1000).expect("no entry for id 1000");
+
+    assert_eq!(q.space, QuotaCounter { used: 0, softlimit: 100, hardlimit: 200 });
+    assert_eq!(q.inodes, QuotaCounter { used: 0, softlimit: 10, hardlimit: 20 });
+
+    /* limits for users don't show up as group or project quotas: */
+    assert!(t.fs().quotas(QuotaType::Group).unwrap().iter().all(|q| q.id != 1000));
+    assert!(t.fs().quotas(QuotaType::Project).unwrap().iter().all(|q| q.id != 1000));
+}
@@ -0,0 +1,54 @@
+use bch_bindgen::testing::{TestFs, TestFsOpts};
+
+mod common;
+use common::{ROOT_INO, ROOT_SUBVOL};
+
+fn small_fs() -> TestFs {
+    TestFs::new(&TestFsOpts { size: 16 << 20, ..Default::default() }, Default::default()).unwrap()
+}
+
+#[test]
+fn space_available() {
+    let t = small_fs();
+    let before = t.fs().space_available();
+
+    assert!(before.capacity > 0 && before.capacity <= 16 << 20);
+    assert_eq!(before.free, before.capacity - before.used);
+    assert_eq!(before.data_replicas, 1);
+    assert!(before.user_available <= before.free);
+    assert!(before.can_write(before.user_available));
+    assert!(!before.can_write(before.user_available + 1));
+
+    let inum = t.create_file(ROOT_SUBVOL, ROOT_INO, "file").unwrap();
+    t.write_file(ROOT_SUBVOL, inum, 0, &[1; 1 << 20], 1).unwrap();
+
+    let after = t.fs().space_available();
+    assert!(after.used >= before.used + (1 << 20));
+    assert!(after.user_available < before.user_available);
+    assert!(after.nr_inodes > before.nr_inodes);
+}
+
+#[test]
+fn write_past_space_available_fails() {
+    let t = small_fs();
+    let report = t.fs().space_available();
+    let inum = t.create_file(ROOT_SUBVOL, ROOT_INO, "file").unwrap();
+    let data = vec![1; (report.user_available + (1 << 20)) as usize];
+
+    assert!(!report.can_write(data.len() as u64));
+
+    let err = t.write_file(ROOT_SUBVOL, inum, 0, &data, 1).unwrap_err();
+    assert_eq!(err.class(), libc::ENOSPC, "{}", err);
+}
+
+#[test]
+fn space_report_serializes() {
+    let t = small_fs();
+    let report = t.fs().space_available();
+    let json = serde_json::to_value(report).unwrap();
+
+    assert_eq!(json["capacity"], report.capacity);
+    assert_eq!(json["used"], report.used);
+    assert_eq!(json["user_available"], report.user_available);
+    assert_eq!(json["data_replicas"], 1);
+}
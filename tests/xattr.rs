@@ -0,0 +1,46 @@
+use bch_bindgen::bcachefs as c;
+use bch_bindgen::xattr::{XattrName, XattrNamespace};
+
+mod common;
+use common::{test_fs, ROOT_INO, ROOT_SUBVOL};
+
+#[test]
+fn xattrs_round_trip() {
+    let t = test_fs();
+    let snapshot = t.fs().subvol_snapshot(ROOT_SUBVOL).unwrap();
+    let inum = t.create_file(ROOT_SUBVOL, ROOT_INO, "file").unwrap();
+
+    assert_eq!(t.fs().xattrs(inum, snapshot).count(), 0);
+
+    t.set_xattr(ROOT_SUBVOL, inum, c::KEY_TYPE_XATTR_INDEX_USER, "foo", b"bar").unwrap();
+    t.set_xattr(ROOT_SUBVOL, inum, c::KEY_TYPE_XATTR_INDEX_TRUSTED, "foo", b"").unwrap();
+    t.set_xattr(ROOT_SUBVOL, inum, c::KEY_TYPE_XATTR_INDEX_SECURITY, "label", &[0; 300]).unwrap();
+
+    let mut xattrs: Vec<_> = t.fs().xattrs(inum, snapshot)
+        .map(|x| x.unwrap())
+        .map(|(name, v)| (name.to_string(), v))
+        .collect();
+    xattrs.sort();
+
+    assert_eq!(xattrs, [
+        ("security.label".to_string(), vec![0; 300]),
+        ("trusted.foo".to_string(), vec![]),
+        ("user.foo".to_string(), b"bar".to_vec()),
+    ]);
+
+    /* other inodes' xattrs aren't included: */
+    assert_eq!(t.fs().xattrs(ROOT_INO, snapshot).count(), 0);
+}
+
+#[test]
+fn xattr_name_display() {
+    let name = XattrName { namespace: XattrNamespace::User, name: b"foo".to_vec() };
+    assert_eq!(name.to_string(), "user.foo");
+
+    let acl = XattrName { namespace: XattrNamespace::PosixAclAccess, name: Vec::new() };
+    assert_eq!(acl.to_string(), "system.posix_acl_access");
+
+    assert_eq!(XattrNamespace::from_raw(c::KEY_TYPE_XATTR_INDEX_SECURITY as u8),
+               XattrNamespace::Security);
+    assert_eq!(XattrNamespace::from_raw(42), XattrNamespace::Unknown(42));
+}